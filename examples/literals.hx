@@ -0,0 +1,10 @@
+(:fn (check (ok bool)) void (
+  (:if ok ((println 1)) ((println 0)))
+))
+
+(:fn (main (argc u64) (argv **u8)) i32 (
+  (check (lt 1 2))
+  (check (lt (mul 3 4) 13))
+  (check (lt (sub 3 4) 0))
+  (check (lt (add 3 4) 8))
+))
@@ -1,4 +1,5 @@
 use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 
 #[derive(Debug, Clone)]
 pub struct EqF64(pub f64);
@@ -21,4 +22,9 @@ impl Ord for EqF64 {
   fn cmp(&self, other: &Self) -> Ordering {
     self.0.to_bits().cmp(&other.0.to_bits())
   }
-}
\ No newline at end of file
+}
+impl Hash for EqF64 {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.0.to_bits().hash(state)
+  }
+}
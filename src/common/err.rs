@@ -6,6 +6,7 @@ use lexpr::datum::Ref;
 use thiserror::Error;
 use crate::common::sepvec::SepVec;
 use crate::common::span::{ParseCtx, SpanPlace};
+use crate::ir3::model::{IR3BBID, IR3Type};
 
 #[derive(Debug)]
 pub struct Cerr {
@@ -70,18 +71,81 @@ pub enum CerrKind {
   UndeclaredType,
   #[error("invalid identifier")]
   InvalidIdent,
-  #[error("float literals are unsupported")]
-  FloatsLiteralsUnsupported,
   #[error("no matching function declaration")]
   NoMatchingFuncDecl,
   #[error("multiple matching function declarations:\n  {0}")]
   MultipleMatchingFuncDecls(SepVec<String>),
   #[error("unknown attribute")]
   UnknownAttribute,
+
+  // IR2->IR3
+  #[error("mismatched types, expected {0}")]
+  MismatchedTypes(String),
+  #[error("condition must be an integer")]
+  NonIntegerCondition,
+  #[error("break outside of a loop")]
+  BreakOutsideLoop,
+  #[error("unknown builtin function {0}")]
+  UnknownBuiltin(String),
 }
 
 pub type Result<T> = std::result::Result<T, Cerr>;
 
+/// An error raised while transforming IR3. IR3 has no span information,
+/// so errors are located by the function they occurred in.
+#[derive(Debug)]
+pub struct IR3Err {
+  pub func: String,
+  pub kind: IR3ErrKind
+}
+
+impl Display for IR3Err {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    writeln!(f, "error: in function {}: {}", &self.func, &self.kind)
+  }
+}
+
+impl Error for IR3Err { }
+
+impl IR3Err {
+  pub fn new<E: Into<IR3ErrKind>>(err: E, func: &str) -> Self {
+    IR3Err {
+      func: func.to_owned(),
+      kind: err.into(),
+    }
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum IR3ErrKind {
+  // Legalization
+  #[error("{0} on {1} is not supported by target {2}")]
+  UnsupportedOp(&'static str, IR3Type, &'static str),
+  #[error("type {0} is not supported by target {1}")]
+  UnsupportedType(IR3Type, &'static str),
+
+  // Verification
+  #[error("invalid IR3 in block @{0}: {1}")]
+  Invalid(IR3BBID, String),
+  #[error("symbol {0} is defined more than once")]
+  DuplicateSymbol(String),
+  #[error("invalid global: {0}")]
+  InvalidGlobal(String),
+
+  // Parsing
+  #[error("syntax error on line {0}: {1}")]
+  Syntax(usize, String),
+
+  // Interpretation
+  #[error("trapped: {0}")]
+  Trap(String),
+  /// The program called an exit function, with this exit code. Not an error for the caller of the interpreter.
+  #[error("exited with code {0}")]
+  Exit(u64),
+}
+
+pub type IR3Result<T> = std::result::Result<T, IR3Err>;
+
 trait HasSpan {
   fn span(&self) -> lexpr::parse::Span;
 }
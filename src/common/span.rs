@@ -44,6 +44,14 @@ impl SpanPlace {
     }
   }
 
+  /// The source text covered by the span, if it is on a single line.
+  pub fn text(&self) -> Option<&str> {
+    if self.start_line != self.end_line {
+      return None;
+    }
+    self.ctx.lines.get(self.start_line.checked_sub(1)?)?.get(self.start_col..self.end_col)
+  }
+
   pub fn mark_end(self) -> Self {
     SpanPlace {
       ctx: self.ctx,
@@ -6,6 +6,7 @@
 use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
 use lexpr::{Parser, Value};
+use rug::Integer;
 use lexpr::datum::Ref;
 use lexpr::parse::{NilSymbol, Options, TSymbol, KeywordSyntax};
 use crate::common;
@@ -71,8 +72,12 @@ fn parse_function(ctx: &Rc<ParseCtx>, datum: Ref) -> common::Result<Span<IR1Func
       let attr_list = ctx.get_list(el)?;
       ctx.get_assert_kw(ctx.index_list(&attr_list, 0)?, "attr")?;
       let attr_name = ctx.get_id(ctx.index_list(&attr_list, 1)?)?;
-      let attr_val = ctx.get_id(ctx.index_list(&attr_list, 2)?)?;
-      attr.insert(attr_name.t.to_owned(), Span { span: attr_list.span, t: attr_val.t.to_owned() });
+      let attr_val = match ctx.index_list(&attr_list, 2) {
+        Ok(val) => ctx.get_id(val)?.t.to_owned(),
+        // flag attributes such as `noreturn` have no value
+        Err(_) => String::new(),
+      };
+      attr.insert(attr_name.t.to_owned(), Span { span: attr_list.span, t: attr_val });
       idx += 1;
     } else {
       break;
@@ -191,6 +196,9 @@ fn parse_expr(ctx: &Rc<ParseCtx>, datum: Ref) -> common::Result<Span<IR1Expr>> {
         IR1Expr::Const(IR1Constant::U64(n))
       } else if let Some(n) = num.as_i64() {
         IR1Expr::Const(IR1Constant::I64(n))
+      } else if let Some(n) = ctx.span_of(datum).text().and_then(|v| v.parse::<Integer>().ok()) {
+        // lexpr reads integers that do not fit in 64 bits as floats, so they are parsed again from the source
+        IR1Expr::Const(IR1Constant::Big(n))
       } else if let Some(n) = num.as_f64() {
         IR1Expr::Const(IR1Constant::F64(EqF64(n)))
       } else {
@@ -1,4 +1,5 @@
 use std::collections::{BTreeMap, HashMap};
+use rug::Integer;
 use crate::common::eqf64::EqF64;
 use crate::common::span::Span;
 
@@ -78,6 +79,8 @@ pub struct IR1FuncCall {
 pub enum IR1Constant {
   I64(i64),
   U64(u64),
+  /// An integer literal that does not fit in 64 bits.
+  Big(Integer),
   F64(EqF64)
 }
 
@@ -6,8 +6,8 @@ use crate::common::sepvec::SepVec;
 use crate::common::span::{Span, SpanPlace};
 use crate::common::util::{invert, invert2};
 use crate::hxx_ir1::model::{IR1Constant, IR1Expr, IR1Func, IR1IfStmt, IR1Module, IR1Stmt, IR1StmtList, IR1VarDecl, IR1WhileStmt};
-use crate::ir2::model::{IR2Expr, IR2Func, IR2FuncAttr, IR2FuncCall, IR2FuncDecl, IR2IfStmt, IR2IntType, IR2Program, IR2Scope, IR2SetStmt, IR2Stmt, IR2Type, IR2VarDecl, IR2WhileStmt};
-use crate::ir2::type_resolve::{function_matches, infer_expr_type, ResolveType};
+use crate::ir2::model::{IR2Expr, IR2Func, IR2FuncAttr, IR2FuncCall, IR2FuncDecl, IR2FloatType, IR2IfStmt, IR2IntType, IR2Program, IR2Scope, IR2SetStmt, IR2Stmt, IR2Type, IR2VarDecl, IR2WhileStmt};
+use crate::ir2::type_resolve::{function_matches, function_takes_default_types, infer_expr_type, ResolveType};
 
 struct ProgramContext {
   pub functions: Vec<IR2FuncDecl>
@@ -109,6 +109,11 @@ fn transform_func_decl(decl: &Span<IR1Func>) -> common::Result<IR2FuncDecl> {
 fn transform_func_attr(attr_name: &str, attr_val: &Span<String>) -> common::Result<IR2FuncAttr> {
   Ok(match attr_name {
     "builtin-function" => IR2FuncAttr::BuiltinFunction(attr_val.t.clone()),
+    "noreturn" => IR2FuncAttr::NoReturn,
+    "export" => IR2FuncAttr::Export,
+    "pure" => IR2FuncAttr::Pure,
+    "readonly" => IR2FuncAttr::ReadOnly,
+    "willreturn" => IR2FuncAttr::WillReturn,
     &_ => return Err(Cerr::with_span(CerrKind::UnknownAttribute, attr_val.span.clone()))
   })
 }
@@ -200,13 +205,11 @@ fn transform_stmt_list(stmt_list: &Span<IR1StmtList>, ctx: &ProgramContext, scop
 
 fn transform_expr(expr: &Span<IR1Expr>, ctx: &ProgramContext, scope: &ScopeChain) -> common::Result<IR2Expr> {
   Ok(match &expr.t {
-    IR1Expr::Const(c) => {
-      let bigc = match c {
-        IR1Constant::I64(x) => Integer::from(*x),
-        IR1Constant::U64(x) => Integer::from(*x),
-        IR1Constant::F64(x) => return Err(Cerr::with_span(CerrKind::FloatsLiteralsUnsupported, expr.span.clone())),
-      };
-      IR2Expr::Const(bigc)
+    IR1Expr::Const(c) => match c {
+      IR1Constant::I64(x) => IR2Expr::Const(Integer::from(*x)),
+      IR1Constant::U64(x) => IR2Expr::Const(Integer::from(*x)),
+      IR1Constant::Big(x) => IR2Expr::Const(x.clone()),
+      IR1Constant::F64(x) => IR2Expr::FloatConst(x.clone()),
     }
     IR1Expr::VarName(v) => {
       IR2Expr::Var(
@@ -254,6 +257,8 @@ fn transform_type(ty: &Span<&str>) -> common::Result<IR2Type> {
     ))
   } else if ty.t == "void" {
     Ok(IR2Type::Void)
+  } else if let Some(float) = IR2FloatType::parse(&ty.t) {
+    Ok(IR2Type::Float(float))
   } else {
     Ok(IR2Type::Int(
       IR2IntType::parse(&ty.t).ok_or_else(|| Cerr::with_span(CerrKind::UndeclaredType, ty.span.clone()))?
@@ -275,11 +280,22 @@ fn validate_identifier(ident: &Span<String>) -> common::Result<&Span<String>> {
 }
 
 fn resolve_func_call(name: &str, types: &[ResolveType], ctx: &ProgramContext, span: &SpanPlace) -> common::Result<IR2FuncDecl> {
-  let matching = ctx.functions.iter()
+  let mut matching = ctx.functions.iter()
     .filter(|v| {
       v.name == name && function_matches(v, types)
     })
     .collect::<Vec<_>>();
+  // constants have no type of their own, so a call with them matches every overload of the same kind;
+  // the one taking them at their default type wins
+  if matching.len() >= 2 {
+    let defaults = matching.iter()
+      .copied()
+      .filter(|v| function_takes_default_types(v, types))
+      .collect::<Vec<_>>();
+    if defaults.len() == 1 {
+      matching = defaults;
+    }
+  }
   if matching.len() == 0 {
     return Err(Cerr::with_span(CerrKind::NoMatchingFuncDecl, span.clone()))
   }
@@ -297,4 +313,24 @@ fn resolve_func_call(name: &str, types: &[ResolveType], ctx: &ProgramContext, sp
     ))
   }
   Ok(matching[0].clone())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::hxx_ir1::from_hxx::hxx_to_ir1;
+  use crate::ir2::to_ir3::ir2_to_ir3;
+  use crate::target::Target;
+  use super::*;
+
+  #[test]
+  fn constants_prefer_i32_overloads() {
+    let srcs = [include_str!("../../support/builtins.hx"), include_str!("../../examples/literals.hx")];
+    let ir1s = srcs.iter().map(|v| hxx_to_ir1("literals.hx", v).unwrap()).collect::<Vec<_>>();
+    let module = ir2_to_ir3(&ir1_to_ir2(&ir1s).unwrap(), &Target::rv64()).unwrap();
+    let main = module.function("main").unwrap().to_string();
+    for op in ["cmp d32 slt", "smull d32", "sub d32", "add d32"] {
+      assert!(main.contains(op), "{} not in {}", op, main);
+    }
+    assert!(!main.contains("d128"));
+  }
+}
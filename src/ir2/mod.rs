@@ -12,4 +12,4 @@
 pub mod model;
 pub mod type_resolve;
 mod mangle;
-mod to_ir3;
\ No newline at end of file
+pub mod to_ir3;
\ No newline at end of file
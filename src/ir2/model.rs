@@ -1,5 +1,6 @@
 use std::fmt::{Display, Formatter, write};
 use rug::{Integer};
+use crate::common::eqf64::EqF64;
 use crate::common::err::{Cerr, CerrKind};
 use crate::common::span::{Span, SpanPlace};
 
@@ -48,6 +49,7 @@ pub struct IR2WhileStmt {
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum IR2Expr {
   Const(Integer),
+  FloatConst(EqF64),
   Var(IR2VarDecl),
   FuncCall(IR2FuncCall),
 }
@@ -68,7 +70,17 @@ pub struct IR2FuncDecl {
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum IR2FuncAttr {
-  BuiltinFunction(String)
+  BuiltinFunction(String),
+  /// Calls to the function never return, like `exit` or `abort`.
+  NoReturn,
+  /// The function is called from outside the program, so it is kept even if nothing in it calls the function.
+  Export,
+  /// The function does not access memory, so calls to it can be treated like arithmetic.
+  Pure,
+  /// The function does not write memory.
+  ReadOnly,
+  /// Calls to the function always return.
+  WillReturn,
 }
 
 impl Display for IR2FuncDecl {
@@ -101,6 +113,7 @@ pub struct IR2VarDecl {
 pub enum IR2Type {
   Void,
   Int(IR2IntType),
+  Float(IR2FloatType),
   Ptr(Box<IR2Type>),
 }
 
@@ -109,6 +122,7 @@ impl Display for IR2Type {
     match self {
       IR2Type::Void => write!(f, "void"),
       IR2Type::Int(ty) => write!(f, "{}", ty),
+      IR2Type::Float(ty) => write!(f, "{}", ty),
       IR2Type::Ptr(inner) => write!(f, "*{}", inner),
     }
   }
@@ -116,8 +130,8 @@ impl Display for IR2Type {
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum IR2IntType {
-  I8, I16, I32, I64,
-  U8, U16, U32, U64,
+  I8, I16, I32, I64, I128,
+  U8, U16, U32, U64, U128,
   Bool,
 }
 
@@ -128,10 +142,12 @@ impl Display for IR2IntType {
       IR2IntType::I16 => write!(f, "i16"),
       IR2IntType::I32 => write!(f, "i32"),
       IR2IntType::I64 => write!(f, "i64"),
+      IR2IntType::I128 => write!(f, "i128"),
       IR2IntType::U8 => write!(f, "u8"),
       IR2IntType::U16 => write!(f, "u16"),
       IR2IntType::U32 => write!(f, "u32"),
       IR2IntType::U64 => write!(f, "u64"),
+      IR2IntType::U128 => write!(f, "u128"),
       IR2IntType::Bool => write!(f, "bool")
     }
   }
@@ -144,12 +160,38 @@ impl IR2IntType {
       "i16" => IR2IntType::I16,
       "i32" => IR2IntType::I32,
       "i64" => IR2IntType::I64,
+      "i128" => IR2IntType::I128,
       "u8" => IR2IntType::U8,
       "u16" => IR2IntType::U16,
       "u32" => IR2IntType::U32,
       "u64" => IR2IntType::U64,
+      "u128" => IR2IntType::U128,
       "bool" => IR2IntType::Bool,
       &_ => return None
     })
   }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum IR2FloatType {
+  F32, F64,
+}
+
+impl Display for IR2FloatType {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      IR2FloatType::F32 => write!(f, "f32"),
+      IR2FloatType::F64 => write!(f, "f64"),
+    }
+  }
+}
+
+impl IR2FloatType {
+  pub fn parse(s: &str) -> Option<IR2FloatType> {
+    Some(match s {
+      "f32" => IR2FloatType::F32,
+      "f64" => IR2FloatType::F64,
+      &_ => return None
+    })
+  }
+}
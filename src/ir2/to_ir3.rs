@@ -0,0 +1,423 @@
+//! Lowering of IR2 into IR3.
+//!
+//! Every variable (including parameters) gets its own stack slot, and every read or write of it
+//! becomes a `ptr_load` or `ptr_store`. This keeps lowering simple, `ir3::mem2reg` turns
+//! the slots back into SSA values afterwards.
+//!
+//! Calls to functions with a `builtin-function` attribute are lowered directly to the IR3 op
+//! named by the attribute. For example, `add$d32` becomes `add d32`, and `slt$d32` becomes `cmp d32 slt`.
+//! Conversions name their source type too, so `trunc$d32$d64` becomes `trunc d32 d64`.
+//! Float compares name their mode, so `fcmp$f64$lt` becomes `fcmp f64 lt`.
+//! Atomics name their ordering, so `atomic_add$d32$seq_cst` becomes `atomic_rmw d32 add seq_cst`.
+//! Overflow-checked ops such as `sadd_overflow$d32`, and `cmpxchg`, only give their flag.
+//!
+//! Code that cannot be reached (after a `(:break)`, after a call to a `noreturn` function,
+//! or after an infinite loop) ends in `unreachable`. HXX has no return statement yet, so reaching the end
+//! of a non-void function traps, except in `main`, which returns 0 like in C.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::common;
+use crate::common::err::{Cerr, CerrKind};
+use crate::common::span::SpanPlace;
+use crate::ir2::mangle::mangle_function;
+use crate::ir2::model::{IR2Expr, IR2Func, IR2FuncAttr, IR2FuncDecl, IR2FloatType, IR2IntType, IR2Program, IR2Scope, IR2Stmt, IR2Type, IR2VarDecl};
+use crate::ir2::type_resolve::{infer_expr_type, ResolveType};
+use crate::ir3::builder::IR3OpBuilder;
+use crate::ir3::cfg::remove_unreachable_blocks;
+use crate::ir3::model::{IR3BasicBlock, IR3BBID, IR3Call, IR3CompareMode, IR3EndOp, IR3ExternFunction, IR3FloatCompareMode, IR3Function, IR3FunctionAttr, IR3Module, IR3Op, IR3OpKind, IR3Ordering, IR3RmwOp, IR3Type, IR3VarID};
+use crate::target::Target;
+
+pub fn ir2_to_ir3(program: &IR2Program, target: &Target) -> common::Result<IR3Module> {
+  let mut callees = BTreeMap::new();
+  let functions = program.funcs.iter()
+    .map(|func| lower_func(func, target, &mut callees))
+    .collect::<common::Result<Vec<_>>>()?;
+  let mut module = IR3Module {
+    functions,
+    ..Default::default()
+  };
+  // functions that are called but have no body in the program are external
+  module.externs = callees.into_values()
+    .filter(|v| module.function(&v.name).is_none())
+    .collect();
+  Ok(module)
+}
+
+/// HXX functions return at most one value.
+fn ret_types(ret: IR3Type) -> Vec<IR3Type> {
+  if ret == IR3Type::Void { vec![] } else { vec![ret] }
+}
+
+fn lower_attrs(decl: &IR2FuncDecl) -> Vec<(IR3FunctionAttr, String)> {
+  decl.attrs.iter()
+    .filter_map(|attr| match attr {
+      IR2FuncAttr::NoReturn => Some((IR3FunctionAttr::NoReturn, String::new())),
+      IR2FuncAttr::Export => Some((IR3FunctionAttr::Export, String::new())),
+      IR2FuncAttr::Pure => Some((IR3FunctionAttr::Pure, String::new())),
+      IR2FuncAttr::ReadOnly => Some((IR3FunctionAttr::ReadOnly, String::new())),
+      IR2FuncAttr::WillReturn => Some((IR3FunctionAttr::WillReturn, String::new())),
+      IR2FuncAttr::BuiltinFunction(_) => None,
+    })
+    .collect()
+}
+
+/// The name a function is known by in IR3. `main` keeps its name so that it can be found by the linker.
+pub fn symbol_name(decl: &IR2FuncDecl) -> String {
+  if decl.name == "main" {
+    decl.name.clone()
+  } else {
+    mangle_function(decl)
+  }
+}
+
+pub fn lower_type(ty: &IR2Type) -> IR3Type {
+  match ty {
+    IR2Type::Void => IR3Type::Void,
+    IR2Type::Int(int) => IR3Type::Data(match int {
+      IR2IntType::Bool => 1,
+      IR2IntType::I8 | IR2IntType::U8 => 8,
+      IR2IntType::I16 | IR2IntType::U16 => 16,
+      IR2IntType::I32 | IR2IntType::U32 => 32,
+      IR2IntType::I64 | IR2IntType::U64 => 64,
+      IR2IntType::I128 | IR2IntType::U128 => 128,
+    }),
+    IR2Type::Float(float) => IR3Type::Float(match float {
+      IR2FloatType::F32 => 32,
+      IR2FloatType::F64 => 64,
+    }),
+    IR2Type::Ptr(_) => IR3Type::Ptr,
+  }
+}
+
+/// Maps the value of a `builtin-function` attribute, such as `add$d32`, to the IR3 op implementing it.
+fn builtin_op(name: &str) -> Option<(IR3OpKind, IR3Type)> {
+  let parse_type = |ty: &str| match ty {
+    "ptr" => Some(IR3Type::Ptr),
+    "void" => Some(IR3Type::Void),
+    _ if ty.starts_with('f') => Some(IR3Type::Float(ty[1..].parse().ok()?)),
+    _ => Some(IR3Type::Data(ty.strip_prefix('d')?.parse().ok()?)),
+  };
+  let mut parts = name.split('$');
+  let op = parts.next()?;
+  let ty = parse_type(parts.next()?)?;
+  // conversions also name the type they convert from, as in `trunc$d32$d64`,
+  // float compares name their mode, as in `fcmp$f64$lt`,
+  // and atomics name their ordering, as in `atomic_add$d32$seq_cst`
+  let kind = match (op, parts.next()) {
+    ("zext", Some(src)) => IR3OpKind::Zext(parse_type(src)?),
+    ("sext", Some(src)) => IR3OpKind::Sext(parse_type(src)?),
+    ("trunc", Some(src)) => IR3OpKind::Trunc(parse_type(src)?),
+    ("fconv", Some(src)) => IR3OpKind::Fconv(parse_type(src)?),
+    ("sitofp", Some(src)) => IR3OpKind::SiToFp(parse_type(src)?),
+    ("uitofp", Some(src)) => IR3OpKind::UiToFp(parse_type(src)?),
+    ("fptosi", Some(src)) => IR3OpKind::FpToSi(parse_type(src)?),
+    ("fptoui", Some(src)) => IR3OpKind::FpToUi(parse_type(src)?),
+    ("fcmp", Some(mode)) => IR3OpKind::Fcmp(IR3FloatCompareMode::from_name(mode)?),
+    ("atomic_load", Some(ord)) => IR3OpKind::AtomicLoad(IR3Ordering::from_name(ord)?),
+    ("atomic_store", Some(ord)) => IR3OpKind::AtomicStore(IR3Ordering::from_name(ord)?),
+    ("cmpxchg", Some(ord)) => IR3OpKind::CmpXchg(IR3Ordering::from_name(ord)?),
+    ("fence", Some(ord)) => IR3OpKind::Fence(IR3Ordering::from_name(ord)?),
+    (_, Some(ord)) => {
+      let rmw = IR3RmwOp::from_name(op.strip_prefix("atomic_")?)?;
+      IR3OpKind::AtomicRmw(rmw, IR3Ordering::from_name(ord)?)
+    }
+    (_, None) => IR3OpKind::from_name(op).or_else(|| IR3CompareMode::from_name(op).map(IR3OpKind::Cmp))?,
+  };
+  if parts.next().is_some() {
+    return None;
+  }
+  Some((kind, ty))
+}
+
+fn lower_func(func: &IR2Func, target: &Target, callees: &mut BTreeMap<String, IR3ExternFunction>) -> common::Result<IR3Function> {
+  let mut lowerer = FuncLowerer {
+    target,
+    callees,
+    span: func.span.clone(),
+    slots: HashMap::new(),
+    blocks: vec![],
+    b: IR3OpBuilder::new(0),
+    cur_block: 0,
+    next_block: 1,
+    reachable: HashSet::from([0]),
+    loop_exits: vec![],
+  };
+  // all stack slots go in the entry block
+  let mut vars = func.decl.params.clone();
+  collect_vars(&func.scope, &mut vars);
+  for var in vars {
+    lowerer.slot(&var);
+  }
+  for (i, param) in func.decl.params.iter().enumerate() {
+    let ty = lower_type(&param.ty);
+    let value = lowerer.b.push(IR3OpKind::Arg(i as u32), ty, vec![]);
+    let slot = lowerer.slot(param);
+    lowerer.b.push_void(IR3OpKind::PtrStore, ty, vec![slot, value]);
+  }
+  lowerer.lower_scope(&func.scope)?;
+
+  let ret = lower_type(&func.decl.return_ty);
+  let attrs = lower_attrs(&func.decl);
+  let ending = match ret {
+    _ if attrs.iter().any(|(attr, _)| *attr == IR3FunctionAttr::NoReturn) => IR3EndOp::Trap,
+    IR3Type::Void => IR3EndOp::Ret { values: vec![] },
+    IR3Type::Data(_) if func.decl.name == "main" => IR3EndOp::Ret { values: vec![(ret, lowerer.b.push_const(ret, 0))] },
+    _ => IR3EndOp::Trap,
+  };
+  lowerer.finish_block(ending, 0);
+
+  let mut ir3 = IR3Function {
+    name: symbol_name(&func.decl),
+    args: func.decl.params.iter().map(|v| lower_type(&v.ty)).collect(),
+    ret: ret_types(ret),
+    basic_blocks: lowerer.blocks,
+    attrs,
+  };
+  remove_unreachable_blocks(&mut ir3);
+  Ok(ir3)
+}
+
+fn collect_vars(scope: &IR2Scope, vars: &mut Vec<IR2VarDecl>) {
+  vars.extend(scope.vars.iter().cloned());
+  for stmt in &scope.stmt_list {
+    match stmt {
+      IR2Stmt::If(if_stmt) => {
+        collect_vars(&if_stmt.scope1, vars);
+        if let Some(scope2) = &if_stmt.scope2 {
+          collect_vars(scope2, vars);
+        }
+      }
+      IR2Stmt::While(while_stmt) => collect_vars(&while_stmt.scope, vars),
+      _ => {}
+    }
+  }
+}
+
+struct FuncLowerer<'a> {
+  target: &'a Target,
+  /// Every non-builtin function called so far, in case it turns out to be external.
+  callees: &'a mut BTreeMap<String, IR3ExternFunction>,
+  /// Span of the statement being lowered, for errors.
+  span: SpanPlace,
+  slots: HashMap<IR2VarDecl, IR3VarID>,
+  blocks: Vec<IR3BasicBlock>,
+  b: IR3OpBuilder,
+  cur_block: IR3BBID,
+  next_block: IR3BBID,
+  /// Blocks that a reachable block branches to. Blocks are finished after all of their forward
+  /// predecessors, so this is complete for a block by the time it is finished.
+  reachable: HashSet<IR3BBID>,
+  /// Exit block of every loop the current statement is in, innermost last.
+  loop_exits: Vec<IR3BBID>,
+}
+
+impl<'a> FuncLowerer<'a> {
+  /// Returns the stack slot of a variable, allocating it if needed.
+  /// Variables from disjoint scopes with the same name and type share a slot.
+  fn slot(&mut self, var: &IR2VarDecl) -> IR3VarID {
+    if let Some(slot) = self.slots.get(var) {
+      return *slot;
+    }
+    let ty = lower_type(&var.ty);
+    let kind = IR3OpKind::StackAlloc {
+      size: self.target.size_of(ty),
+      align: self.target.align_of(ty),
+    };
+    let slot = self.b.push(kind, IR3Type::Ptr, vec![]);
+    self.slots.insert(var.clone(), slot);
+    slot
+  }
+
+  fn new_block(&mut self) -> IR3BBID {
+    self.next_block += 1;
+    self.next_block - 1
+  }
+
+  /// Ends the current block, and continues in `next`.
+  /// If the current block is unreachable, it ends in `unreachable` instead, and is removed once the function is lowered.
+  fn finish_block(&mut self, ending: IR3EndOp, next: IR3BBID) {
+    let ending = if self.reachable.contains(&self.cur_block) {
+      self.reachable.extend(ending.successors());
+      ending
+    } else {
+      IR3EndOp::Unreachable
+    };
+    let block = self.b.finish_block(self.cur_block, ending);
+    self.blocks.push(block);
+    self.cur_block = next;
+  }
+
+  fn lower_scope(&mut self, scope: &IR2Scope) -> common::Result<()> {
+    for stmt in &scope.stmt_list {
+      self.lower_stmt(stmt)?;
+    }
+    Ok(())
+  }
+
+  fn lower_stmt(&mut self, stmt: &IR2Stmt) -> common::Result<()> {
+    match stmt {
+      IR2Stmt::Set(set) => {
+        self.span = set.span.clone();
+        if let Some(var) = &set.var {
+          let matches = match infer_expr_type(&set.value) {
+            ResolveType::IntConstant => matches!(var.ty, IR2Type::Int(_)),
+            ResolveType::FloatConstant => matches!(var.ty, IR2Type::Float(_)),
+            ResolveType::Type(ty) => ty == var.ty,
+          };
+          if !matches {
+            return Err(Cerr::with_span(CerrKind::MismatchedTypes(var.ty.to_string()), set.span.clone()));
+          }
+          let ty = lower_type(&var.ty);
+          let (value, _) = self.lower_expr(&set.value, Some(ty))?.unwrap();
+          let slot = self.slot(var);
+          self.b.push_void(IR3OpKind::PtrStore, ty, vec![slot, value]);
+        } else {
+          self.lower_expr(&set.value, None)?;
+        }
+      }
+      IR2Stmt::If(if_stmt) => {
+        self.span = if_stmt.span.clone();
+        let then_block = self.new_block();
+        let else_block = if_stmt.scope2.as_ref().map(|_| self.new_block());
+        let join_block = self.new_block();
+        let branch = self.lower_branch(&if_stmt.expr, then_block, else_block.unwrap_or(join_block))?;
+        self.finish_block(branch, then_block);
+        self.lower_scope(&if_stmt.scope1)?;
+        if let (Some(scope2), Some(else_block)) = (&if_stmt.scope2, else_block) {
+          self.finish_block(IR3EndOp::Br { block: join_block }, else_block);
+          self.lower_scope(scope2)?;
+        }
+        self.finish_block(IR3EndOp::Br { block: join_block }, join_block);
+      }
+      IR2Stmt::While(while_stmt) => {
+        let header = self.new_block();
+        let body = self.new_block();
+        let exit = self.new_block();
+        self.finish_block(IR3EndOp::Br { block: header }, header);
+        self.span = while_stmt.span.clone();
+        let branch = self.lower_branch(&while_stmt.expr, body, exit)?;
+        self.finish_block(branch, body);
+        self.loop_exits.push(exit);
+        self.lower_scope(&while_stmt.scope)?;
+        self.loop_exits.pop();
+        self.finish_block(IR3EndOp::Br { block: header }, exit);
+      }
+      IR2Stmt::Break(span) => {
+        let exit = *self.loop_exits.last()
+          .ok_or_else(|| Cerr::with_span(CerrKind::BreakOutsideLoop, span.clone()))?;
+        // anything after the break is unreachable
+        let dead = self.new_block();
+        self.finish_block(IR3EndOp::Br { block: exit }, dead);
+      }
+    }
+    Ok(())
+  }
+
+  /// Lowers a branch to `block1` if `expr` is true, or `block2` otherwise.
+  /// Constant conditions become unconditional branches, so that the other side is known to be unreachable.
+  fn lower_branch(&mut self, expr: &IR2Expr, block1: IR3BBID, block2: IR3BBID) -> common::Result<IR3EndOp> {
+    if let IR2Expr::Const(c) = expr {
+      return Ok(IR3EndOp::Br { block: if c.is_zero() { block2 } else { block1 } });
+    }
+    let cond = self.lower_cond(expr)?;
+    Ok(IR3EndOp::BrIf { block1, block2, cond })
+  }
+
+  /// Lowers the condition of an `if` or `while`, which is true if it is non-zero.
+  fn lower_cond(&mut self, expr: &IR2Expr) -> common::Result<IR3VarID> {
+    let bool_ty = IR3Type::Data(1);
+    let (value, ty) = self.lower_expr(expr, Some(bool_ty))?
+      .ok_or_else(|| Cerr::with_span(CerrKind::NonIntegerCondition, self.span.clone()))?;
+    match ty {
+      IR3Type::Data(1) => Ok(value),
+      IR3Type::Data(_) => {
+        let zero = self.b.push_const(ty, 0);
+        Ok(self.b.push(IR3OpKind::Cmp(IR3CompareMode::Ne), ty, vec![value, zero]))
+      }
+      _ => Err(Cerr::with_span(CerrKind::NonIntegerCondition, self.span.clone())),
+    }
+  }
+
+  /// IR3 constants are at most 64 bits, so wider ones are built from their halves.
+  fn push_wide_const(&mut self, ty: IR3Type, value: u128) -> IR3VarID {
+    let lo = self.b.push_const(ty, value as u64);
+    if value >> 64 == 0 {
+      return lo;
+    }
+    let hi = self.b.push_const(ty, (value >> 64) as u64);
+    let shift = self.b.push_const(ty, 64);
+    let hi = self.b.push(IR3OpKind::Sll, ty, vec![hi, shift]);
+    self.b.push(IR3OpKind::Or, ty, vec![hi, lo])
+  }
+
+  /// Lowers an expression, returning its value and type, or `None` if it is a call to a void function.
+  /// `expected` gives integer and float constants their type.
+  fn lower_expr(&mut self, expr: &IR2Expr, expected: Option<IR3Type>) -> common::Result<Option<(IR3VarID, IR3Type)>> {
+    Ok(match expr {
+      IR2Expr::Const(c) => {
+        let ty = expected.unwrap_or(IR3Type::Data(64));
+        let value = match ty {
+          IR3Type::Data(1) => !c.is_zero() as u64,
+          IR3Type::Data(w) if w < 64 => c.to_u64_wrapping() & ((1 << w) - 1),
+          IR3Type::Data(w) if w > 64 => return Ok(Some((self.push_wide_const(ty, c.to_u128_wrapping()), ty))),
+          _ => c.to_u64_wrapping(),
+        };
+        Some((self.b.push_const(ty, value), ty))
+      }
+      IR2Expr::FloatConst(c) => {
+        let (ty, value) = match expected {
+          Some(IR3Type::Float(32)) => (IR3Type::Float(32), (c.0 as f32).to_bits() as u64),
+          _ => (IR3Type::Float(64), c.0.to_bits()),
+        };
+        Some((self.b.push_const(ty, value), ty))
+      }
+      IR2Expr::Var(var) => {
+        let ty = lower_type(&var.ty);
+        let slot = self.slot(var);
+        Some((self.b.push(IR3OpKind::PtrLoad, ty, vec![slot]), ty))
+      }
+      IR2Expr::FuncCall(call) => {
+        let mut args = vec![];
+        for (arg, param) in call.args.iter().zip(call.decl.params.iter()) {
+          let (value, _) = self.lower_expr(arg, Some(lower_type(&param.ty)))?.unwrap();
+          args.push(value);
+        }
+        let ret = lower_type(&call.decl.return_ty);
+        let builtin = call.decl.attrs.iter()
+          .find_map(|attr| match attr {
+            IR2FuncAttr::BuiltinFunction(name) => Some(name),
+            _ => None,
+          });
+        let (kind, ty) = if let Some(name) = builtin {
+          builtin_op(name).ok_or_else(|| Cerr::with_span(CerrKind::UnknownBuiltin(name.clone()), self.span.clone()))?
+        } else {
+          let name = symbol_name(&call.decl);
+          let arg_types = call.decl.params.iter().map(|v| lower_type(&v.ty)).collect::<Vec<_>>();
+          self.callees.entry(name.clone()).or_insert_with(|| IR3ExternFunction {
+            name: name.clone(),
+            args: arg_types.clone(),
+            ret: ret_types(ret),
+            attrs: lower_attrs(&call.decl),
+          });
+          (IR3OpKind::Call(IR3Call { symbol_name: name, arg_types, ret_types: ret_types(ret) }), IR3Type::Void)
+        };
+        let result = if ret == IR3Type::Void {
+          self.b.push_void(kind, ty, args);
+          None
+        } else if kind.is_overflow_checked() || matches!(kind, IR3OpKind::CmpXchg(_)) {
+          // builtins for ops with a flag output only give the flag
+          let output = vec![self.b.new_var(), self.b.new_var()];
+          self.b.ops.push(IR3Op { kind, ty, input: args, output: output.clone() });
+          Some((output[1], ret))
+        } else {
+          Some((self.b.push(kind, ty, args), ret))
+        };
+        if call.decl.attrs.contains(&IR2FuncAttr::NoReturn) {
+          let dead = self.new_block();
+          self.finish_block(IR3EndOp::Unreachable, dead);
+        }
+        result
+      }
+    })
+  }
+}
@@ -1,9 +1,10 @@
-use crate::ir2::model::{IR2Expr, IR2Func, IR2FuncDecl, IR2Type};
+use crate::ir2::model::{IR2Expr, IR2FloatType, IR2Func, IR2FuncDecl, IR2IntType, IR2Type};
 
 /// The inferred type of a value. This is needed because we don't know the type
-/// the user wanted an integer constant to be.
+/// the user wanted an integer or float constant to be.
 pub enum ResolveType {
   IntConstant,
+  FloatConstant,
   Type(IR2Type)
 }
 
@@ -16,6 +17,9 @@ pub fn function_matches(funcs: &IR2FuncDecl, arg_types: &[ResolveType]) -> bool
          ResolveType::IntConstant => {
            if let IR2Type::Int(_) = a.ty { true } else { false }
          }
+         ResolveType::FloatConstant => {
+           if let IR2Type::Float(_) = a.ty { true } else { false }
+         }
          ResolveType::Type(ty) => {
            a.ty == *ty
          }
@@ -23,10 +27,25 @@ pub fn function_matches(funcs: &IR2FuncDecl, arg_types: &[ResolveType]) -> bool
     )
 }
 
+/// Checks if every constant argument is passed to a parameter of its default type,
+/// which is i32 for integer constants and f64 for float constants
+pub fn function_takes_default_types(func: &IR2FuncDecl, arg_types: &[ResolveType]) -> bool {
+  func.params.iter()
+    .zip(arg_types.iter())
+    .all(|(a, b)|
+       match b {
+         ResolveType::IntConstant => a.ty == IR2Type::Int(IR2IntType::I32),
+         ResolveType::FloatConstant => a.ty == IR2Type::Float(IR2FloatType::F64),
+         ResolveType::Type(_) => true,
+       }
+    )
+}
+
 /// Infers the ResolveType of an expression
 pub fn infer_expr_type(expr: &IR2Expr) -> ResolveType {
   match expr {
     IR2Expr::Const(_) => ResolveType::IntConstant,
+    IR2Expr::FloatConst(_) => ResolveType::FloatConstant,
     IR2Expr::Var(var) => ResolveType::Type(var.ty.clone()),
     IR2Expr::FuncCall(func) => ResolveType::Type(func.decl.return_ty.clone())
   }
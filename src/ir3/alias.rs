@@ -0,0 +1,259 @@
+//! Alias analysis based on pointer provenance.
+//!
+//! Every `ptr` value is traced back to the root it was derived from with `ptr_sadd` and `ptr_uadd`:
+//! a `stack_alloc`, a global, a `ptr` argument, or some other op (a load, call, phi or `select`).
+//! Different `stack_alloc`s and different globals never overlap, and a `ptr` argument cannot point into a
+//! `stack_alloc` of the function it is passed to. A `stack_alloc` whose address never escapes can only be reached
+//! through pointers derived from it, so it does not alias any other root either.
+//! Pointers with the same root and constant offsets alias exactly when the accessed bytes overlap.
+
+use std::collections::{HashMap, HashSet};
+use crate::ir3::model::{IR3Function, IR3OpKind, IR3Type, IR3VarID};
+use crate::target::Target;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AliasResult {
+  /// The accesses never touch the same bytes.
+  No,
+  /// The accesses might touch some of the same bytes.
+  May,
+  /// The accesses always touch exactly the same bytes.
+  Must,
+}
+
+/// Where a pointer was derived from.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum PtrRoot {
+  /// The output of a `stack_alloc`.
+  Stack(IR3VarID),
+  Global(String),
+  /// A `ptr` argument, by index.
+  Arg(u32),
+  /// A pointer produced by any other op.
+  Other(IR3VarID),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PtrInfo {
+  pub root: PtrRoot,
+  /// Offset from the root in bytes, if it is a constant.
+  pub offset: Option<i64>,
+}
+
+pub struct AliasAnalysis {
+  ptrs: HashMap<IR3VarID, PtrInfo>,
+  /// `stack_alloc`s whose address is used for anything other than memory accesses and pointer arithmetic.
+  escaped: HashSet<IR3VarID>,
+  target: Target,
+}
+
+impl AliasAnalysis {
+  pub fn new(func: &IR3Function, target: &Target) -> AliasAnalysis {
+    let mut aa = AliasAnalysis { ptrs: HashMap::new(), escaped: HashSet::new(), target: target.clone() };
+    let consts = func.basic_blocks.iter()
+      .flat_map(|v| &v.instructions)
+      .filter_map(|op| match op.kind {
+        IR3OpKind::Const(c) => Some((op.output[0], (c, op.ty))),
+        _ => None,
+      })
+      .collect::<HashMap<_, _>>();
+    // definitions come before their uses in the order of the dominator tree, not necessarily in block order,
+    // so offsets are followed until nothing changes
+    let mut changed = true;
+    while changed {
+      changed = false;
+      for op in func.basic_blocks.iter().flat_map(|v| &v.instructions) {
+        if op.output_types().first() != Some(&IR3Type::Ptr) || aa.ptrs.contains_key(&op.output[0]) {
+          continue;
+        }
+        let out = op.output[0];
+        let info = match &op.kind {
+          IR3OpKind::StackAlloc { .. } => PtrInfo { root: PtrRoot::Stack(out), offset: Some(0) },
+          IR3OpKind::GlobalAddr(name) => PtrInfo { root: PtrRoot::Global(name.clone()), offset: Some(0) },
+          IR3OpKind::Arg(idx) => PtrInfo { root: PtrRoot::Arg(*idx), offset: Some(0) },
+          IR3OpKind::PtrSadd | IR3OpKind::PtrUadd => {
+            let Some(base) = aa.ptrs.get(&op.input[0]) else { continue };
+            let signed = op.kind == IR3OpKind::PtrSadd;
+            let offset = consts.get(&op.input[1])
+              .and_then(|(c, ty)| Some(aa.extend(*c, *ty, signed)).zip(base.offset))
+              .map(|(a, b)| aa.wrap(a.wrapping_add(b)));
+            PtrInfo { root: base.root.clone(), offset }
+          }
+          _ => PtrInfo { root: PtrRoot::Other(out), offset: Some(0) },
+        };
+        aa.ptrs.insert(out, info);
+        changed = true;
+      }
+    }
+    for op in func.basic_blocks.iter().flat_map(|v| &v.instructions) {
+      let addresses = match op.kind {
+        IR3OpKind::PtrLoad | IR3OpKind::PtrStore | IR3OpKind::PtrSadd | IR3OpKind::PtrUadd | IR3OpKind::AtomicLoad(_) |
+        IR3OpKind::AtomicStore(_) | IR3OpKind::AtomicRmw(..) | IR3OpKind::CmpXchg(_) => 1,
+        _ => 0,
+      };
+      for var in &op.input[addresses..] {
+        if let Some(PtrInfo { root: PtrRoot::Stack(slot), .. }) = aa.ptrs.get(var) {
+          aa.escaped.insert(*slot);
+        }
+      }
+    }
+    for bb in &func.basic_blocks {
+      for var in bb.ending.inputs() {
+        if let Some(PtrInfo { root: PtrRoot::Stack(slot), .. }) = aa.ptrs.get(&var) {
+          aa.escaped.insert(*slot);
+        }
+      }
+    }
+    aa
+  }
+
+  /// Sign or zero extends a constant offset of type `ty` to 64 bits.
+  fn extend(&self, c: u64, ty: IR3Type, signed: bool) -> i64 {
+    let width = match ty {
+      IR3Type::Data(w) => w.min(64),
+      _ => 64,
+    };
+    if width == 64 {
+      c as i64
+    } else if signed {
+      ((c << (64 - width)) as i64) >> (64 - width)
+    } else {
+      (c & ((1 << width) - 1)) as i64
+    }
+  }
+
+  /// Wraps an offset around the width of a pointer.
+  fn wrap(&self, v: i64) -> i64 {
+    let width = self.target.ptr_width;
+    if width >= 64 { v } else { (v << (64 - width)) >> (64 - width) }
+  }
+
+  /// Whether the memory `ptr` points into can be accessed by code that did not derive it from its root:
+  /// callees, and pointers loaded from memory or returned by calls.
+  pub fn is_escaped(&self, ptr: IR3VarID) -> bool {
+    match self.ptrs.get(&ptr) {
+      Some(PtrInfo { root: PtrRoot::Stack(slot), .. }) => self.escaped.contains(slot),
+      _ => true,
+    }
+  }
+
+  /// Number of bytes accessed by a load or store of `ty`.
+  pub fn access_size(&self, ty: IR3Type) -> u64 {
+    self.target.size_of(ty) as u64
+  }
+
+  /// Whether an access of `size_a` bytes at `a` can overlap an access of `size_b` bytes at `b`.
+  pub fn alias(&self, a: IR3VarID, size_a: u64, b: IR3VarID, size_b: u64) -> AliasResult {
+    if a == b {
+      return if size_a == size_b { AliasResult::Must } else { AliasResult::May };
+    }
+    let (Some(pa), Some(pb)) = (self.ptrs.get(&a), self.ptrs.get(&b)) else { return AliasResult::May };
+    if pa.root == pb.root {
+      let (Some(oa), Some(ob)) = (pa.offset, pb.offset) else { return AliasResult::May };
+      return if oa == ob && size_a == size_b {
+        AliasResult::Must
+      } else if (oa as i128) + (size_a as i128) <= ob as i128 || (ob as i128) + (size_b as i128) <= oa as i128 {
+        AliasResult::No
+      } else {
+        AliasResult::May
+      };
+    }
+    let distinct = match (&pa.root, &pb.root) {
+      (PtrRoot::Stack(_), PtrRoot::Stack(_) | PtrRoot::Global(_) | PtrRoot::Arg(_)) => true,
+      (PtrRoot::Global(_) | PtrRoot::Arg(_), PtrRoot::Stack(_)) => true,
+      (PtrRoot::Global(_), PtrRoot::Global(_)) => true,
+      (PtrRoot::Stack(slot), PtrRoot::Other(_)) | (PtrRoot::Other(_), PtrRoot::Stack(slot)) => !self.escaped.contains(slot),
+      _ => false,
+    };
+    if distinct { AliasResult::No } else { AliasResult::May }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::parse::parse_module;
+  use super::*;
+
+  fn analyze(text: &str) -> AliasAnalysis {
+    let module = parse_module(text).unwrap();
+    AliasAnalysis::new(&module.functions[0], &Target::rv64())
+  }
+
+  const FUNC: &str = "ir3function f args ptr ptr d64 returns void {
+@0:
+  $0 = stack_alloc ptr 16 8
+  $1 = stack_alloc ptr 8 8
+  $2 = arg ptr 0
+  $3 = arg ptr 1
+  $4 = global_addr ptr g1
+  $5 = global_addr ptr g2
+  $6 = const d64 4
+  $7 = ptr_uadd d64 $0 $6
+  $8 = ptr_load ptr $2
+  $9 = arg d64 2
+  $10 = ptr_uadd d64 $0 $9
+  $11 = const d64 18446744073709551612
+  $12 = ptr_sadd d64 $7 $11
+  $13 = ptr_uadd d64 $0 $6
+  br @1
+
+@1:
+  call void escape ptr $1
+  ret void
+
+}
+";
+
+  #[test]
+  fn same_root() {
+    let aa = analyze(FUNC);
+    assert_eq!(aa.alias(0, 4, 0, 4), AliasResult::Must);
+    assert_eq!(aa.alias(0, 8, 0, 4), AliasResult::May);
+    // stack + 4 - 4 is the stack slot itself
+    assert_eq!(aa.alias(0, 4, 12, 4), AliasResult::Must);
+    assert_eq!(aa.alias(7, 4, 13, 4), AliasResult::Must);
+    assert_eq!(aa.alias(0, 4, 7, 4), AliasResult::No);
+    assert_eq!(aa.alias(0, 8, 7, 4), AliasResult::May);
+    // unknown offsets
+    assert_eq!(aa.alias(0, 4, 10, 4), AliasResult::May);
+  }
+
+  #[test]
+  fn different_roots() {
+    let aa = analyze(FUNC);
+    // stack slots, globals, and stack slots against arguments
+    assert_eq!(aa.alias(0, 4, 1, 4), AliasResult::No);
+    assert_eq!(aa.alias(7, 4, 4, 4), AliasResult::No);
+    assert_eq!(aa.alias(2, 4, 1, 4), AliasResult::No);
+    assert_eq!(aa.alias(4, 4, 5, 4), AliasResult::No);
+    // arguments can point anywhere but into the function's own stack
+    assert_eq!(aa.alias(2, 4, 3, 4), AliasResult::May);
+    assert_eq!(aa.alias(2, 4, 4, 4), AliasResult::May);
+  }
+
+  #[test]
+  fn escaped_slots() {
+    let aa = analyze(FUNC);
+    // a loaded pointer cannot point into a slot whose address was never passed on, but can into one that was
+    assert_eq!(aa.alias(8, 4, 7, 4), AliasResult::No);
+    assert_eq!(aa.alias(8, 4, 1, 4), AliasResult::May);
+    assert!(!aa.is_escaped(0));
+    assert!(!aa.is_escaped(10));
+    assert!(aa.is_escaped(1));
+    assert!(aa.is_escaped(2));
+    assert!(aa.is_escaped(8));
+    // storing the address, or returning it, lets it escape too
+    let aa = analyze("ir3function f args ptr returns ptr {
+@0:
+  $0 = stack_alloc ptr 8 8
+  $1 = stack_alloc ptr 8 8
+  $2 = arg ptr 0
+  ptr_store ptr $2 $0
+  ret ptr $1
+
+}
+");
+    assert!(aa.is_escaped(0));
+    assert!(aa.is_escaped(1));
+  }
+}
@@ -0,0 +1,93 @@
+//! Optimization bisection, for finding the pass that miscompiles a program.
+//!
+//! Every run of an optional pass on a function (or on the whole module, for passes that work on it at once)
+//! asks `OptBisect::should_run` first, which numbers the runs from 1. With a limit, runs past it are skipped,
+//! and every decision is logged to stderr with its number. Passes needed for correct code, like legalization,
+//! always run and are not numbered.
+//!
+//! `find_first_bad` binary searches the limit: if the program works with limit 0 and not with all runs, the first run
+//! that makes it fail is the culprit.
+
+pub struct OptBisect {
+  limit: Option<u64>,
+  /// The runs asked for so far, as `(pass, function)`; the first one is number 1.
+  pub runs: Vec<(String, String)>,
+}
+
+impl OptBisect {
+  /// Without a limit, every pass runs and nothing is logged.
+  pub fn new(limit: Option<u64>) -> OptBisect {
+    OptBisect { limit, runs: vec![] }
+  }
+
+  pub fn should_run(&mut self, pass: &str, func: &str) -> bool {
+    self.runs.push((pass.to_owned(), func.to_owned()));
+    let index = self.runs.len() as u64;
+    let Some(limit) = self.limit else { return true };
+    let run = index <= limit;
+    eprintln!("BISECT: {}running pass ({}) {} on {}", if run { "" } else { "NOT " }, index, pass, func);
+    run
+  }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BisectResult {
+  /// The test passes with every run.
+  NoFailure,
+  /// The test fails even without optimizations.
+  AlwaysFails,
+  /// The test passes with limit `n - 1` and fails with limit `n`.
+  FirstBad(u64),
+}
+
+/// Finds the smallest limit for which `passes` returns false, with `total` runs in all.
+/// Assumes that once a limit fails, every higher one does too.
+pub fn find_first_bad(total: u64, mut passes: impl FnMut(u64) -> bool) -> BisectResult {
+  if passes(total) {
+    return BisectResult::NoFailure;
+  }
+  if !passes(0) {
+    return BisectResult::AlwaysFails;
+  }
+  // passes at `good`, fails at `bad`
+  let (mut good, mut bad) = (0, total);
+  while bad - good > 1 {
+    let mid = good + (bad - good) / 2;
+    if passes(mid) { good = mid } else { bad = mid }
+  }
+  BisectResult::FirstBad(bad)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn first_bad() {
+    assert_eq!(find_first_bad(10, |_| true), BisectResult::NoFailure);
+    assert_eq!(find_first_bad(10, |_| false), BisectResult::AlwaysFails);
+    assert_eq!(find_first_bad(0, |_| false), BisectResult::AlwaysFails);
+    for total in [1, 2, 3, 10, 1000] {
+      for n in 1..=total {
+        let mut tries = 0;
+        let result = find_first_bad(total, |limit| {
+          tries += 1;
+          limit < n
+        });
+        assert_eq!(result, BisectResult::FirstBad(n), "{} of {}", n, total);
+        // the two ends, then a binary search
+        assert!(tries <= 2 + 64 - total.leading_zeros(), "{} tries for {} of {}", tries, n, total);
+      }
+    }
+  }
+
+  #[test]
+  fn limit() {
+    let mut bisect = OptBisect::new(Some(2));
+    let ran = (0..4).map(|i| bisect.should_run("pass", &i.to_string())).collect::<Vec<_>>();
+    assert_eq!(ran, [true, true, false, false]);
+    assert_eq!(bisect.runs[3], ("pass".to_owned(), "3".to_owned()));
+    let mut bisect = OptBisect::new(None);
+    assert!((0..4).all(|_| bisect.should_run("pass", "f")));
+  }
+}
@@ -0,0 +1,62 @@
+use std::mem::take;
+use crate::ir3::model::{IR3BasicBlock, IR3BBID, IR3EndOp, IR3Op, IR3OpKind, IR3Type, IR3VarID};
+
+/// Helper for passes that rebuild the instruction list of a basic block.
+pub struct IR3OpBuilder {
+  pub ops: Vec<IR3Op>,
+  pub next_var: IR3VarID
+}
+
+impl IR3OpBuilder {
+  pub fn new(next_var: IR3VarID) -> Self {
+    IR3OpBuilder {
+      ops: vec![],
+      next_var,
+    }
+  }
+
+  pub fn new_var(&mut self) -> IR3VarID {
+    self.next_var += 1;
+    self.next_var - 1
+  }
+
+  /// Appends an op and returns its freshly allocated output variable.
+  pub fn push(&mut self, kind: IR3OpKind, ty: IR3Type, input: Vec<IR3VarID>) -> IR3VarID {
+    let output = self.new_var();
+    self.push_to(kind, ty, input, output);
+    output
+  }
+
+  /// Appends an op that writes to an already allocated variable.
+  pub fn push_to(&mut self, kind: IR3OpKind, ty: IR3Type, input: Vec<IR3VarID>, output: IR3VarID) {
+    self.ops.push(IR3Op {
+      kind,
+      ty,
+      input,
+      output: vec![output],
+    });
+  }
+
+  /// Appends an op that has no outputs, such as `ptr_store`.
+  pub fn push_void(&mut self, kind: IR3OpKind, ty: IR3Type, input: Vec<IR3VarID>) {
+    self.ops.push(IR3Op {
+      kind,
+      ty,
+      input,
+      output: vec![],
+    });
+  }
+
+  pub fn push_const(&mut self, ty: IR3Type, value: u64) -> IR3VarID {
+    self.push(IR3OpKind::Const(value), ty, vec![])
+  }
+
+  /// Moves the ops pushed so far into a new basic block.
+  pub fn finish_block(&mut self, id: IR3BBID, ending: IR3EndOp) -> IR3BasicBlock {
+    IR3BasicBlock {
+      id,
+      instructions: take(&mut self.ops),
+      ending,
+    }
+  }
+}
@@ -0,0 +1,201 @@
+//! Call graph of a module, with its strongly connected components.
+//!
+//! An edge from `f` to `g` means that `f` calls `g` or takes its address with `global_addr`.
+//! Externs are included as nodes without any outgoing edges.
+
+use std::collections::{HashMap, HashSet};
+use crate::ir3::model::{IR3GlobalInit, IR3Module, IR3OpKind};
+
+pub struct CallGraph {
+  /// Functions and externs used by each function, in the order they are first used.
+  pub callees: HashMap<String, Vec<String>>,
+  /// Strongly connected components, callees before callers.
+  pub sccs: Vec<Vec<String>>,
+  scc_of: HashMap<String, usize>,
+}
+
+impl CallGraph {
+  pub fn new(module: &IR3Module) -> CallGraph {
+    let is_func = |name: &str| module.function(name).is_some() || module.externs.iter().any(|v| v.name == name);
+    let mut callees = HashMap::new();
+    for func in &module.functions {
+      let mut list: Vec<String> = vec![];
+      for op in func.basic_blocks.iter().flat_map(|v| &v.instructions) {
+        let name = match &op.kind {
+          IR3OpKind::Call(call) => &call.symbol_name,
+          IR3OpKind::GlobalAddr(name) if is_func(name) => name,
+          _ => continue,
+        };
+        if !list.contains(name) {
+          list.push(name.clone());
+        }
+      }
+      callees.insert(func.name.clone(), list);
+    }
+    for ext in &module.externs {
+      callees.insert(ext.name.clone(), vec![]);
+    }
+    let sccs = tarjan(module.functions.iter().map(|v| &v.name).chain(module.externs.iter().map(|v| &v.name)), &callees);
+    let scc_of = sccs.iter().enumerate()
+      .flat_map(|(i, scc)| scc.iter().map(move |v| (v.clone(), i)))
+      .collect();
+    CallGraph {
+      callees,
+      sccs,
+      scc_of,
+    }
+  }
+
+  /// The index of the SCC containing `name` in `sccs`.
+  pub fn scc_of(&self, name: &str) -> usize {
+    self.scc_of[name]
+  }
+
+  /// Returns true if `name` can call itself, directly or through other functions.
+  pub fn is_recursive(&self, name: &str) -> bool {
+    self.sccs[self.scc_of(name)].len() > 1 || self.callees[name].iter().any(|v| v == name)
+  }
+
+  /// Every function and extern reachable from `roots`, including the roots themselves.
+  pub fn reachable_from<'a>(&self, roots: impl IntoIterator<Item = &'a str>) -> HashSet<String> {
+    let mut visited = HashSet::new();
+    let mut stack = roots.into_iter().map(str::to_owned).collect::<Vec<_>>();
+    while let Some(name) = stack.pop() {
+      if visited.insert(name.clone()) {
+        stack.extend(self.callees[&name].iter().cloned());
+      }
+    }
+    visited
+  }
+}
+
+/// Functions and externs whose address is stored in a global, which can be called from anywhere.
+pub fn address_taken_by_globals(module: &IR3Module) -> impl Iterator<Item = &str> {
+  let is_func = |name: &str| module.function(name).is_some() || module.externs.iter().any(|v| v.name == name);
+  module.globals.iter().filter_map(move |v| match &v.init {
+    IR3GlobalInit::Addr(name) if is_func(name) => Some(name.as_str()),
+    _ => None,
+  })
+}
+
+/// Tarjan's algorithm, iteratively. Components are found in reverse topological order.
+fn tarjan<'a>(nodes: impl Iterator<Item = &'a String>, edges: &HashMap<String, Vec<String>>) -> Vec<Vec<String>> {
+  let mut index = HashMap::<&str, usize>::new();
+  let mut lowlink = HashMap::<&str, usize>::new();
+  let mut on_stack = HashSet::<&str>::new();
+  let mut stack = vec![];
+  let mut sccs = vec![];
+  for root in nodes {
+    if index.contains_key(root.as_str()) {
+      continue;
+    }
+    let mut work = vec![(root.as_str(), 0)];
+    while let Some((node, idx)) = work.pop() {
+      if idx == 0 {
+        index.insert(node, index.len());
+        lowlink.insert(node, index[node]);
+        stack.push(node);
+        on_stack.insert(node);
+      }
+      if let Some(succ) = edges[node].get(idx).map(String::as_str) {
+        work.push((node, idx + 1));
+        if !index.contains_key(succ) {
+          work.push((succ, 0));
+        } else if on_stack.contains(succ) {
+          lowlink.insert(node, lowlink[node].min(index[succ]));
+        }
+        continue;
+      }
+      // all successors are done, so the lowlink can be passed up to the parent
+      if let Some((parent, _)) = work.last() {
+        lowlink.insert(parent, lowlink[parent].min(lowlink[node]));
+      }
+      if lowlink[node] == index[node] {
+        let mut scc = vec![];
+        loop {
+          let v = stack.pop().unwrap();
+          on_stack.remove(v);
+          scc.push(v.to_owned());
+          if v == node {
+            break;
+          }
+        }
+        sccs.push(scc);
+      }
+    }
+  }
+  sccs
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::parse::parse_module;
+  use super::*;
+
+  const MODULE: &str = r#"extern ext args d32 returns d32
+extern held returns void
+global fp ptr = c
+global ep ptr = held
+global data d32 = 3
+
+ir3function a args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = call d32 b d32 $0
+  ret d32 $1
+}
+
+ir3function b args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = call d32 c d32 $0
+  $2 = call d32 a d32 $1
+  ret d32 $2
+}
+
+ir3function c args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = call d32 ext d32 $0
+  $2 = call d32 c d32 $1
+  ret d32 $2
+}
+
+ir3function d returns ptr {
+@0:
+  $0 = global_addr ptr data
+  ret ptr $0
+}
+"#;
+
+  #[test]
+  fn sccs_callees_first() {
+    let graph = CallGraph::new(&parse_module(MODULE).unwrap());
+    assert_eq!(graph.scc_of("a"), graph.scc_of("b"));
+    assert_eq!(graph.sccs[graph.scc_of("a")].len(), 2);
+    assert!(graph.scc_of("c") < graph.scc_of("a"));
+    assert!(graph.scc_of("ext") < graph.scc_of("c"));
+    assert_eq!(graph.sccs.iter().map(Vec::len).sum::<usize>(), 6);
+    // a global that is not a function is not a callee
+    assert!(graph.callees["d"].is_empty());
+  }
+
+  #[test]
+  fn recursion() {
+    let graph = CallGraph::new(&parse_module(MODULE).unwrap());
+    // through another function, and through a self-loop
+    assert!(graph.is_recursive("a") && graph.is_recursive("b"));
+    assert!(graph.is_recursive("c"));
+    assert!(!graph.is_recursive("d") && !graph.is_recursive("ext"));
+  }
+
+  #[test]
+  fn reachable_and_address_taken() {
+    let module = parse_module(MODULE).unwrap();
+    let graph = CallGraph::new(&module);
+    let mut reachable = graph.reachable_from(["b"]).into_iter().collect::<Vec<_>>();
+    reachable.sort();
+    assert_eq!(reachable, ["a", "b", "c", "ext"]);
+    assert_eq!(address_taken_by_globals(&module).collect::<Vec<_>>(), ["c", "held"]);
+  }
+}
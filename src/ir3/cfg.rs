@@ -0,0 +1,217 @@
+//! Control flow graph analyses: predecessors, reverse postorder and dominators.
+//!
+//! The first basic block of a function is its entry. Blocks that cannot be reached from
+//! the entry are left out of every analysis here.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use crate::ir3::model::{IR3BBID, IR3EndOp, IR3Function, IR3OpKind};
+
+pub struct Cfg {
+  pub entry: IR3BBID,
+  pub succs: HashMap<IR3BBID, Vec<IR3BBID>>,
+  /// Predecessors of each block, in the order they are first found in reverse postorder.
+  pub preds: HashMap<IR3BBID, Vec<IR3BBID>>,
+  /// Reachable blocks in reverse postorder.
+  pub rpo: Vec<IR3BBID>,
+}
+
+impl Cfg {
+  pub fn new(func: &IR3Function) -> Cfg {
+    let entry = func.basic_blocks[0].id;
+    let succs = func.basic_blocks.iter()
+      .map(|bb| (bb.id, bb.ending.successors()))
+      .collect::<HashMap<_, _>>();
+    // iterative postorder DFS
+    let mut postorder = vec![];
+    let mut visited = HashSet::from([entry]);
+    let mut stack = vec![(entry, 0)];
+    while let Some((block, idx)) = stack.pop() {
+      if let Some(succ) = succs[&block].get(idx).copied() {
+        stack.push((block, idx + 1));
+        if visited.insert(succ) {
+          stack.push((succ, 0));
+        }
+      } else {
+        postorder.push(block);
+      }
+    }
+    let rpo = postorder.into_iter().rev().collect::<Vec<_>>();
+    let mut preds = rpo.iter().map(|v| (*v, vec![])).collect::<HashMap<_, _>>();
+    for block in &rpo {
+      for succ in &succs[block] {
+        let list = preds.get_mut(succ).unwrap();
+        if !list.contains(block) {
+          list.push(*block);
+        }
+      }
+    }
+    Cfg {
+      entry,
+      succs,
+      preds,
+      rpo,
+    }
+  }
+
+  pub fn is_reachable(&self, block: IR3BBID) -> bool {
+    self.preds.contains_key(&block)
+  }
+}
+
+pub struct DomTree {
+  /// Immediate dominator of every reachable block except the entry.
+  pub idom: HashMap<IR3BBID, IR3BBID>,
+  /// Blocks immediately dominated by each block, in reverse postorder.
+  pub children: HashMap<IR3BBID, Vec<IR3BBID>>,
+}
+
+impl DomTree {
+  /// Builds the dominator tree with the algorithm from
+  /// "A Simple, Fast Dominance Algorithm" (Cooper, Harvey and Kennedy).
+  pub fn new(cfg: &Cfg) -> DomTree {
+    let order = cfg.rpo.iter()
+      .enumerate()
+      .map(|(i, v)| (*v, i))
+      .collect::<HashMap<_, _>>();
+    let mut idom = HashMap::from([(cfg.entry, cfg.entry)]);
+    let mut changed = true;
+    while changed {
+      changed = false;
+      for block in cfg.rpo.iter().skip(1) {
+        let mut new_idom = None;
+        for pred in &cfg.preds[block] {
+          if !idom.contains_key(pred) {
+            continue;
+          }
+          new_idom = Some(match new_idom {
+            None => *pred,
+            Some(cur) => intersect(&idom, &order, cur, *pred),
+          });
+        }
+        let new_idom = new_idom.unwrap();
+        if idom.get(block) != Some(&new_idom) {
+          idom.insert(*block, new_idom);
+          changed = true;
+        }
+      }
+    }
+    idom.remove(&cfg.entry);
+    let mut children = cfg.rpo.iter().map(|v| (*v, vec![])).collect::<HashMap<_, _>>();
+    for block in &cfg.rpo {
+      if let Some(parent) = idom.get(block) {
+        children.get_mut(parent).unwrap().push(*block);
+      }
+    }
+    DomTree {
+      idom,
+      children,
+    }
+  }
+
+  /// Returns true if every path from the entry to `b` goes through `a`.
+  pub fn dominates(&self, a: IR3BBID, mut b: IR3BBID) -> bool {
+    loop {
+      if a == b {
+        return true;
+      }
+      match self.idom.get(&b) {
+        Some(parent) => b = *parent,
+        None => return false,
+      }
+    }
+  }
+
+  /// Computes the dominance frontier of every reachable block.
+  pub fn frontiers(&self, cfg: &Cfg) -> HashMap<IR3BBID, BTreeSet<IR3BBID>> {
+    let mut frontiers = cfg.rpo.iter().map(|v| (*v, BTreeSet::new())).collect::<HashMap<_, _>>();
+    for block in &cfg.rpo {
+      let preds = &cfg.preds[block];
+      if preds.len() < 2 {
+        continue;
+      }
+      for pred in preds {
+        let mut runner = *pred;
+        while Some(&runner) != self.idom.get(block) {
+          frontiers.get_mut(&runner).unwrap().insert(*block);
+          match self.idom.get(&runner) {
+            Some(parent) => runner = *parent,
+            None => break,
+          }
+        }
+      }
+    }
+    frontiers
+  }
+}
+
+fn intersect(idom: &HashMap<IR3BBID, IR3BBID>, order: &HashMap<IR3BBID, usize>, mut a: IR3BBID, mut b: IR3BBID) -> IR3BBID {
+  while a != b {
+    while order[&a] > order[&b] {
+      a = idom[&a];
+    }
+    while order[&b] > order[&a] {
+      b = idom[&b];
+    }
+  }
+  a
+}
+
+/// Deletes blocks that cannot be reached from the entry, along with their incoming phi edges.
+pub fn remove_unreachable_blocks(func: &mut IR3Function) {
+  let cfg = Cfg::new(func);
+  func.basic_blocks.retain(|bb| cfg.is_reachable(bb.id));
+  for bb in &mut func.basic_blocks {
+    let preds = &cfg.preds[&bb.id];
+    for op in &mut bb.instructions {
+      if let IR3OpKind::Phi(phi) = &mut op.kind {
+        let keep = phi.blocks.iter().map(|v| preds.contains(v)).collect::<Vec<_>>();
+        let mut keep_iter = keep.iter();
+        phi.blocks.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        op.input.retain(|_| *keep_iter.next().unwrap());
+      }
+    }
+  }
+}
+
+/// Merges every block into its predecessor if it is the only successor of that predecessor,
+/// and the predecessor is its only predecessor. Unreachable blocks are removed first.
+pub fn merge_blocks(func: &mut IR3Function) {
+  remove_unreachable_blocks(func);
+  let cfg = Cfg::new(func);
+  let mut replace = HashMap::new();
+  // blocks that were merged, and the block they were merged into
+  let mut merged_into = HashMap::new();
+  for block in &cfg.rpo {
+    // the ending of a merged block has moved to the block it was merged into
+    let into = merged_into.get(block).copied().unwrap_or(*block);
+    let IR3EndOp::Br { block: next } = func.basic_blocks.iter().find(|bb| bb.id == into).unwrap().ending else { continue };
+    if next == cfg.entry || next == *block || cfg.preds[&next] != [*block] {
+      continue;
+    }
+    let idx = func.basic_blocks.iter().position(|bb| bb.id == next).unwrap();
+    let bb = func.basic_blocks.remove(idx);
+    let mut instructions = vec![];
+    for op in bb.instructions {
+      // phis have a single input here
+      if let IR3OpKind::Phi(_) = op.kind {
+        replace.insert(op.output[0], op.input[0]);
+      } else {
+        instructions.push(op);
+      }
+    }
+    let target = func.basic_blocks.iter_mut().find(|bb| bb.id == into).unwrap();
+    target.instructions.extend(instructions);
+    target.ending = bb.ending;
+    merged_into.insert(next, into);
+  }
+  // phis in the successors of merged blocks now come from the block they were merged into
+  for bb in &mut func.basic_blocks {
+    for op in &mut bb.instructions {
+      if let IR3OpKind::Phi(phi) = &mut op.kind {
+        phi.blocks.iter_mut().for_each(|v| *v = merged_into.get(v).copied().unwrap_or(*v));
+      }
+    }
+  }
+  func.replace_uses(&replace);
+}
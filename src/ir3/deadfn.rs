@@ -0,0 +1,90 @@
+//! Dead function elimination.
+//!
+//! Removes functions and externs that cannot be reached from `main`, from functions with
+//! the `export` attribute, or from globals that hold the address of a function or extern.
+//! A module without any of these is a library with nothing to export, and ends up empty.
+
+use crate::ir3::callgraph::{address_taken_by_globals, CallGraph};
+use crate::ir3::model::{IR3FunctionAttr, IR3Module};
+
+pub fn remove_dead_functions(module: &mut IR3Module) {
+  let graph = CallGraph::new(module);
+  let roots = module.functions.iter()
+    .filter(|v| v.name == "main" || v.has_attr(IR3FunctionAttr::Export))
+    .map(|v| v.name.as_str())
+    .chain(address_taken_by_globals(module));
+  let live = graph.reachable_from(roots);
+  module.functions.retain(|v| live.contains(&v.name));
+  module.externs.retain(|v| live.contains(&v.name));
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use crate::target::Target;
+  use super::*;
+
+  const MODULE: &str = r#"extern used returns void
+extern unused returns void
+extern held returns void
+global fp ptr = by_global
+global ep ptr = held
+
+ir3function main returns void {
+@0:
+  call void helper
+  ret void
+}
+
+ir3function helper returns void {
+@0:
+  call void used
+  ret void
+}
+
+ir3function dead returns void {
+@0:
+  call void unused
+  call void dead
+  ret void
+}
+
+ir3function lib attrs export returns void {
+@0:
+  ret void
+}
+
+ir3function by_global returns void {
+@0:
+  ret void
+}
+"#;
+
+  fn names(module: &IR3Module) -> Vec<&str> {
+    module.functions.iter().map(|v| v.name.as_str()).chain(module.externs.iter().map(|v| v.name.as_str())).collect()
+  }
+
+  #[test]
+  fn keeps_roots() {
+    let mut module = parse_module(MODULE).unwrap();
+    remove_dead_functions(&mut module);
+    verify_module(&module).unwrap();
+    assert_eq!(names(&module), ["main", "helper", "lib", "by_global", "used", "held"]);
+    // the extern only referenced by a global can still be resolved
+    let target = Target::rv64();
+    let mut interp = Interpreter::new(&module, &target);
+    interp.define_extern("used", |_| vec![]);
+    interp.call("main", &[]).unwrap();
+  }
+
+  #[test]
+  fn library_without_roots() {
+    let mut module = parse_module(&MODULE.replace("global fp ptr = by_global\nglobal ep ptr = held\n", "")
+      .replace("main", "not_main")
+      .replace(" attrs export", "")).unwrap();
+    remove_dead_functions(&mut module);
+    assert!(names(&module).is_empty());
+  }
+}
@@ -0,0 +1,419 @@
+//! Dead store elimination and store-to-load forwarding.
+//!
+//! A `ptr_load` is replaced with a value that is known to be in memory already: the data of an earlier `ptr_store`,
+//! or the result of an earlier `ptr_load`, of the same type through a pointer that must alias it.
+//! The earlier access is searched for backwards through the block, then through its dominators, as long as
+//! nothing on the way (including every block on a path between the dominator and the block) may write to the loaded bytes.
+//!
+//! A `ptr_store` is removed if on every path from it, the same bytes are stored to again before anything may read them,
+//! or the function returns and the store was to a `stack_alloc` whose address does not escape.
+//!
+//! Whether two accesses overlap is decided by `ir3::alias`. Calls may access any memory whose address escapes,
+//! unless the callee is `pure` (accesses no memory) or `readonly` (only reads it). Atomic ops and fences may make
+//! escaped memory visible to other threads, so they count as reading and writing all of it.
+
+use std::collections::{HashMap, HashSet};
+use crate::ir3::alias::{AliasAnalysis, AliasResult};
+use crate::ir3::cfg::{Cfg, DomTree};
+use crate::ir3::model::{IR3EndOp, IR3Function, IR3FunctionAttr, IR3Module, IR3Op, IR3OpKind, IR3Type, IR3VarID, IR3BBID};
+use crate::target::Target;
+
+/// Maximum number of blocks looked at to forward one load or remove one store.
+const MAX_WALK_BLOCKS: usize = 64;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum CallAccess {
+  None,
+  Reads,
+  Writes,
+}
+
+/// An access of `size` bytes at `ptr`.
+#[derive(Copy, Clone)]
+struct Access {
+  ptr: IR3VarID,
+  size: u64,
+}
+
+struct Memory<'a> {
+  aa: AliasAnalysis,
+  calls: &'a HashMap<String, CallAccess>,
+}
+
+impl Memory<'_> {
+  fn overlaps(&self, ptr: IR3VarID, ty: IR3Type, acc: Access) -> bool {
+    self.aa.alias(ptr, self.aa.access_size(ty), acc.ptr, acc.size) != AliasResult::No
+  }
+
+  fn call_access(&self, op: &IR3Op) -> CallAccess {
+    let IR3OpKind::Call(call) = &op.kind else { return CallAccess::None };
+    self.calls.get(&call.symbol_name).copied().unwrap_or(CallAccess::Writes)
+  }
+
+  /// Whether `op` may write any of the bytes of `acc`.
+  fn may_write(&self, op: &IR3Op, acc: Access) -> bool {
+    match op.kind {
+      IR3OpKind::PtrStore => self.overlaps(op.input[0], op.ty, acc),
+      IR3OpKind::AtomicLoad(_) | IR3OpKind::AtomicStore(_) | IR3OpKind::AtomicRmw(..) | IR3OpKind::CmpXchg(_) => {
+        self.aa.is_escaped(acc.ptr) || self.overlaps(op.input[0], op.ty, acc)
+      }
+      IR3OpKind::Fence(_) => self.aa.is_escaped(acc.ptr),
+      IR3OpKind::Call(_) => self.call_access(op) == CallAccess::Writes && self.aa.is_escaped(acc.ptr),
+      _ => false,
+    }
+  }
+
+  /// Whether `op` may read any of the bytes of `acc`.
+  fn may_read(&self, op: &IR3Op, acc: Access) -> bool {
+    match op.kind {
+      IR3OpKind::PtrLoad => self.overlaps(op.input[0], op.ty, acc),
+      IR3OpKind::AtomicLoad(_) | IR3OpKind::AtomicStore(_) | IR3OpKind::AtomicRmw(..) | IR3OpKind::CmpXchg(_) => {
+        self.aa.is_escaped(acc.ptr) || self.overlaps(op.input[0], op.ty, acc)
+      }
+      IR3OpKind::Fence(_) => self.aa.is_escaped(acc.ptr),
+      IR3OpKind::Call(_) => self.call_access(op) != CallAccess::None && self.aa.is_escaped(acc.ptr),
+      _ => false,
+    }
+  }
+
+  /// Whether `op` stores exactly the bytes of `acc`.
+  fn overwrites(&self, op: &IR3Op, acc: Access) -> bool {
+    op.kind == IR3OpKind::PtrStore && self.aa.alias(op.input[0], self.aa.access_size(op.ty), acc.ptr, acc.size) == AliasResult::Must
+  }
+}
+
+/// Forwards stores to loads and removes dead stores in every function of the module.
+pub fn eliminate_dead_stores(module: &mut IR3Module, target: &Target) {
+  let names = module.functions.iter().map(|v| &v.name).chain(module.externs.iter().map(|v| &v.name));
+  let calls = names
+    .map(|name| {
+      let attrs = module.function_attrs(name).unwrap_or(&[]);
+      let access = if attrs.iter().any(|(v, _)| *v == IR3FunctionAttr::Pure) {
+        CallAccess::None
+      } else if attrs.iter().any(|(v, _)| *v == IR3FunctionAttr::ReadOnly) {
+        CallAccess::Reads
+      } else {
+        CallAccess::Writes
+      };
+      (name.clone(), access)
+    })
+    .collect::<HashMap<_, _>>();
+  for func in &mut module.functions {
+    forward_loads(func, &Memory { aa: AliasAnalysis::new(func, target), calls: &calls });
+    remove_dead_stores(func, &Memory { aa: AliasAnalysis::new(func, target), calls: &calls });
+  }
+}
+
+/// The result of scanning ops for the value stored at an address.
+enum Scan {
+  Found(IR3VarID),
+  Clobbered,
+  NotFound,
+}
+
+/// Searches `ops` backwards for a store or load of `ty` at exactly the bytes of `acc`.
+fn scan_back(ops: &[IR3Op], mem: &Memory, ty: IR3Type, acc: Access) -> Scan {
+  for op in ops.iter().rev() {
+    let same = matches!(op.kind, IR3OpKind::PtrStore | IR3OpKind::PtrLoad)
+      && op.ty == ty
+      && mem.aa.alias(op.input[0], acc.size, acc.ptr, acc.size) == AliasResult::Must;
+    if same {
+      return Scan::Found(if op.kind == IR3OpKind::PtrStore { op.input[1] } else { op.output[0] });
+    }
+    if mem.may_write(op, acc) {
+      return Scan::Clobbered;
+    }
+  }
+  Scan::NotFound
+}
+
+/// Finds a value that the `ptr_load` at op `end` of `block` can be replaced with.
+fn available_value(func: &IR3Function, cfg: &Cfg, dom: &DomTree, mem: &Memory, block: IR3BBID, end: usize) -> Option<IR3VarID> {
+  let index = func.basic_blocks.iter().enumerate().map(|(i, v)| (v.id, i)).collect::<HashMap<_, _>>();
+  let load = &func.basic_blocks[index[&block]].instructions[end];
+  let (ty, acc) = (load.ty, Access { ptr: load.input[0], size: mem.aa.access_size(load.ty) });
+  let mut cur = block;
+  let mut ops = &func.basic_blocks[index[&block]].instructions[..end];
+  let mut walked = 0;
+  loop {
+    match scan_back(ops, mem, ty, acc) {
+      Scan::Found(v) => return Some(v),
+      Scan::Clobbered => return None,
+      Scan::NotFound => {}
+    }
+    let idom = *dom.idom.get(&cur)?;
+    // the blocks on paths from the dominator to `cur`, which includes `cur` itself if it is in a loop
+    let mut between = HashSet::new();
+    let mut stack = cfg.preds[&cur].clone();
+    while let Some(v) = stack.pop() {
+      if v != idom && between.insert(v) {
+        stack.extend(&cfg.preds[&v]);
+      }
+    }
+    walked += between.len() + 1;
+    let clobbered = between.iter()
+      .flat_map(|v| &func.basic_blocks[index[v]].instructions)
+      .any(|op| mem.may_write(op, acc));
+    if walked > MAX_WALK_BLOCKS || clobbered {
+      return None;
+    }
+    cur = idom;
+    ops = &func.basic_blocks[index[&idom]].instructions;
+  }
+}
+
+fn forward_loads(func: &mut IR3Function, mem: &Memory) {
+  let cfg = Cfg::new(func);
+  let dom = DomTree::new(&cfg);
+  let mut replaced = HashMap::new();
+  for bb in &func.basic_blocks {
+    if !cfg.is_reachable(bb.id) {
+      continue;
+    }
+    for (i, op) in bb.instructions.iter().enumerate() {
+      if op.kind != IR3OpKind::PtrLoad {
+        continue;
+      }
+      if let Some(value) = available_value(func, &cfg, &dom, mem, bb.id, i) {
+        replaced.insert(op.output[0], value);
+      }
+    }
+  }
+  for bb in &mut func.basic_blocks {
+    bb.instructions.retain(|op| op.kind != IR3OpKind::PtrLoad || !replaced.contains_key(&op.output[0]));
+  }
+  func.replace_uses(&replaced);
+}
+
+/// Searches `ops` for the first op that overwrites (true) or may read (false) the bytes of `acc`.
+fn scan_forward(ops: &[IR3Op], mem: &Memory, acc: Access) -> Option<bool> {
+  for op in ops {
+    if mem.overwrites(op, acc) {
+      return Some(true);
+    }
+    if mem.may_read(op, acc) {
+      return Some(false);
+    }
+  }
+  None
+}
+
+/// Finds out whether the bytes of a store are overwritten before they are read, on every path from a point in a block.
+struct DeadStoreWalk<'a> {
+  func: &'a IR3Function,
+  mem: &'a Memory<'a>,
+  index: HashMap<IR3BBID, usize>,
+  acc: Access,
+  /// Blocks that have been entered, with the result if it is known yet.
+  visited: HashMap<IR3BBID, Option<bool>>,
+}
+
+impl DeadStoreWalk<'_> {
+  /// Whether the bytes are dead on every path leaving `block`.
+  fn dead_after(&mut self, block: IR3BBID) -> bool {
+    match &self.func.basic_blocks[self.index[&block]].ending {
+      IR3EndOp::Ret { .. } | IR3EndOp::Trap => !self.mem.aa.is_escaped(self.acc.ptr),
+      IR3EndOp::Unreachable => true,
+      ending => ending.successors().into_iter().all(|v| self.dead_from(v)),
+    }
+  }
+
+  /// Whether the bytes are dead on every path from the start of `block`.
+  fn dead_from(&mut self, block: IR3BBID) -> bool {
+    match self.visited.get(&block) {
+      Some(result) => return result.unwrap_or(false),
+      None if self.visited.len() >= MAX_WALK_BLOCKS => return false,
+      None => {}
+    }
+    self.visited.insert(block, None);
+    let ops = &self.func.basic_blocks[self.index[&block]].instructions;
+    let dead = match scan_forward(ops, self.mem, self.acc) {
+      Some(dead) => dead,
+      None => self.dead_after(block),
+    };
+    self.visited.insert(block, Some(dead));
+    dead
+  }
+}
+
+fn remove_dead_stores(func: &mut IR3Function, mem: &Memory) {
+  let cfg = Cfg::new(func);
+  let index = func.basic_blocks.iter().enumerate().map(|(i, v)| (v.id, i)).collect::<HashMap<_, _>>();
+  let mut dead = HashSet::new();
+  for bb in &func.basic_blocks {
+    if !cfg.is_reachable(bb.id) {
+      continue;
+    }
+    for (i, op) in bb.instructions.iter().enumerate() {
+      if op.kind != IR3OpKind::PtrStore {
+        continue;
+      }
+      let acc = Access { ptr: op.input[0], size: mem.aa.access_size(op.ty) };
+      // stores to memory that nothing ever reads are dead, whatever the control flow
+      let never_read = !mem.aa.is_escaped(acc.ptr) && func.basic_blocks.iter()
+        .flat_map(|v| &v.instructions)
+        .all(|op| !mem.may_read(op, acc));
+      let is_dead = never_read || match scan_forward(&bb.instructions[i + 1..], mem, acc) {
+        Some(dead) => dead,
+        None => DeadStoreWalk { func, mem, index: index.clone(), acc, visited: HashMap::new() }.dead_after(bb.id),
+      };
+      if is_dead {
+        dead.insert((bb.id, i));
+      }
+    }
+  }
+  for bb in &mut func.basic_blocks {
+    let mut i = 0;
+    bb.instructions.retain(|_| {
+      i += 1;
+      !dead.contains(&(bb.id, i - 1))
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::parse::parse_module;
+  use super::*;
+
+  /// Runs the pass on a function `f` with two `ptr` arguments made of `blocks`, and returns it.
+  fn dse(blocks: &str) -> IR3Function {
+    let mut module = parse_module(&format!("extern writer args ptr returns void
+extern reader attrs readonly args ptr returns void
+extern pure_fn attrs pure args ptr returns void
+
+ir3function f args ptr ptr returns d32 {{
+{}
+}}
+", blocks)).unwrap();
+    eliminate_dead_stores(&mut module, &Target::rv64());
+    module.functions.remove(0)
+  }
+
+  fn count(func: &IR3Function, kind: IR3OpKind) -> usize {
+    func.basic_blocks.iter().flat_map(|v| &v.instructions).filter(|op| op.kind == kind).count()
+  }
+
+  #[test]
+  fn forwards_must_alias_stores() {
+    let func = dse("@0:
+  $0 = stack_alloc ptr 4 4
+  $1 = stack_alloc ptr 4 4
+  $2 = const d32 1
+  $3 = const d32 2
+  ptr_store d32 $0 $2
+  ptr_store d32 $1 $3
+  $4 = ptr_load d32 $0
+  ret d32 $4
+");
+    // the store to the other slot does not get in the way, and neither slot is read afterwards
+    assert_eq!(func.basic_blocks[0].ending, IR3EndOp::Ret { values: vec![(IR3Type::Data(32), 2)] });
+    assert_eq!(count(&func, IR3OpKind::PtrLoad), 0);
+    assert_eq!(count(&func, IR3OpKind::PtrStore), 0);
+  }
+
+  #[test]
+  fn keeps_may_alias_accesses() {
+    let func = dse("@0:
+  $0 = arg ptr 0
+  $1 = arg ptr 1
+  $2 = const d32 1
+  $3 = const d32 2
+  ptr_store d32 $0 $2
+  ptr_store d32 $1 $3
+  $4 = ptr_load d32 $0
+  ret d32 $4
+");
+    assert_eq!(count(&func, IR3OpKind::PtrLoad), 1);
+    assert_eq!(count(&func, IR3OpKind::PtrStore), 2);
+    // a store overwritten through the same pointer is dead, even though the memory escapes
+    let func = dse("@0:
+  $0 = arg ptr 0
+  $2 = const d32 1
+  $3 = const d32 2
+  ptr_store d32 $0 $2
+  ptr_store d32 $0 $3
+  ret d32 $2
+");
+    assert_eq!(count(&func, IR3OpKind::PtrStore), 1);
+  }
+
+  #[test]
+  fn calls_access_escaped_slots() {
+    // (callee, loads left, stores left when overwritten after the call)
+    for (callee, loads, stores) in [("pure_fn", 0, 1), ("reader", 0, 2), ("writer", 1, 2)] {
+      let forwarded = dse(&format!("@0:
+  $0 = stack_alloc ptr 4 4
+  $1 = arg ptr 0
+  $2 = const d32 1
+  call void writer ptr $0
+  ptr_store d32 $0 $2
+  call void {} ptr $1
+  $4 = ptr_load d32 $0
+  ret d32 $4
+", callee));
+      assert_eq!(count(&forwarded, IR3OpKind::PtrLoad), loads, "{}", callee);
+      // the escaped slot can still be read after the function returns
+      assert_eq!(count(&forwarded, IR3OpKind::PtrStore), 1, "{}", callee);
+      let overwritten = dse(&format!("@0:
+  $0 = stack_alloc ptr 4 4
+  $1 = arg ptr 0
+  $2 = const d32 1
+  $3 = const d32 2
+  call void writer ptr $0
+  ptr_store d32 $0 $2
+  call void {} ptr $1
+  ptr_store d32 $0 $3
+  ret d32 $2
+", callee));
+      assert_eq!(count(&overwritten, IR3OpKind::PtrStore), stores, "{}", callee);
+    }
+  }
+
+  #[test]
+  fn calls_do_not_access_private_slots() {
+    let func = dse("@0:
+  $0 = stack_alloc ptr 4 4
+  $1 = arg ptr 0
+  $2 = const d32 1
+  ptr_store d32 $0 $2
+  call void writer ptr $1
+  $4 = ptr_load d32 $0
+  ret d32 $4
+");
+    assert_eq!(count(&func, IR3OpKind::PtrLoad), 0);
+    assert_eq!(count(&func, IR3OpKind::PtrStore), 0);
+  }
+
+  #[test]
+  fn loop_back_edges() {
+    let looped = |slot: &str| dse(&format!("@0:
+  $0 = stack_alloc ptr 4 4
+  $1 = stack_alloc ptr 4 4
+  $2 = const d32 1
+  $3 = const d32 2
+  ptr_store d32 $0 $2
+  br @1
+
+@1:
+  $4 = ptr_load d32 $0
+  $5 = cmp d32 eq $4 $3
+  br_if $5 @3 @2
+
+@2:
+  ptr_store d32 {} $3
+  br @1
+
+@3:
+  ret d32 $4
+", slot));
+    // the store in the loop reaches the load in the header through the back edge, and is read there
+    let func = looped("$0");
+    assert_eq!(count(&func, IR3OpKind::PtrLoad), 1);
+    assert_eq!(count(&func, IR3OpKind::PtrStore), 2);
+    // a store to another slot does not
+    let func = looped("$1");
+    assert_eq!(count(&func, IR3OpKind::PtrLoad), 0);
+    assert_eq!(count(&func, IR3OpKind::PtrStore), 0);
+  }
+}
@@ -0,0 +1,270 @@
+//! Inference of the `pure`, `readonly`, `noreturn` and `willreturn` function attributes.
+//!
+//! Functions are visited bottom-up over the SCCs of the call graph, so callees are done before their callers.
+//! Within an SCC, calls to other members are assumed to be pure, so that mutually recursive functions
+//! can still be pure. Externs only have the attributes they are declared with.
+//!
+//! Memory that a function allocates with `stack_alloc` is not visible to anyone else once it returns,
+//! so accesses through pointers derived from it do not count as memory accesses here.
+
+use std::collections::{HashMap, HashSet};
+use crate::ir3::callgraph::CallGraph;
+use crate::ir3::cfg::Cfg;
+use crate::ir3::model::{IR3EndOp, IR3Function, IR3FunctionAttr, IR3Module, IR3OpKind};
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+enum Effect {
+  Pure,
+  ReadOnly,
+  Writes,
+}
+
+pub fn infer_function_attrs(module: &mut IR3Module) {
+  let graph = CallGraph::new(module);
+  for scc in &graph.sccs {
+    if module.function(&scc[0]).is_none() {
+      continue;
+    }
+    let recursive = graph.is_recursive(&scc[0]);
+    let effect = scc.iter()
+      .map(|name| function_effect(module, module.function(name).unwrap(), scc))
+      .max()
+      .unwrap();
+    let will_return = scc.iter()
+      .map(|name| !recursive && will_return(module, module.function(name).unwrap()))
+      .collect::<Vec<_>>();
+    for (name, will_return) in scc.iter().zip(will_return) {
+      let func = module.functions.iter_mut().find(|v| v.name == *name).unwrap();
+      let no_return = func.basic_blocks.iter().all(|v| !matches!(v.ending, IR3EndOp::Ret { .. }));
+      let attrs = [
+        (IR3FunctionAttr::Pure, effect == Effect::Pure),
+        (IR3FunctionAttr::ReadOnly, effect == Effect::ReadOnly),
+        (IR3FunctionAttr::NoReturn, no_return),
+        (IR3FunctionAttr::WillReturn, will_return && !no_return),
+      ];
+      for (attr, holds) in attrs {
+        if holds && !func.has_attr(attr) {
+          func.attrs.push((attr, String::new()));
+        }
+      }
+    }
+  }
+}
+
+fn callee_effect(module: &IR3Module, name: &str) -> Effect {
+  let attrs = module.function_attrs(name).unwrap_or(&[]);
+  if attrs.iter().any(|(v, _)| *v == IR3FunctionAttr::Pure) {
+    Effect::Pure
+  } else if attrs.iter().any(|(v, _)| *v == IR3FunctionAttr::ReadOnly) {
+    Effect::ReadOnly
+  } else {
+    Effect::Writes
+  }
+}
+
+/// The strongest effect of any op in `func`, treating calls to members of `scc` as pure.
+fn function_effect(module: &IR3Module, func: &IR3Function, scc: &[String]) -> Effect {
+  let mut local = HashSet::new();
+  let mut effect = Effect::Pure;
+  for op in func.basic_blocks.iter().flat_map(|v| &v.instructions) {
+    let op_effect = match &op.kind {
+      IR3OpKind::StackAlloc { .. } => {
+        local.insert(op.output[0]);
+        Effect::Pure
+      }
+      IR3OpKind::PtrUadd | IR3OpKind::PtrSadd => {
+        if local.contains(&op.input[0]) {
+          local.insert(op.output[0]);
+        }
+        Effect::Pure
+      }
+      IR3OpKind::PtrLoad if local.contains(&op.input[0]) => Effect::Pure,
+      IR3OpKind::PtrStore if local.contains(&op.input[0]) => Effect::Pure,
+      IR3OpKind::PtrLoad | IR3OpKind::AtomicLoad(_) => Effect::ReadOnly,
+      IR3OpKind::PtrStore | IR3OpKind::AtomicStore(_) | IR3OpKind::AtomicRmw(..) | IR3OpKind::CmpXchg(_) | IR3OpKind::Fence(_) => {
+        Effect::Writes
+      }
+      // globals are only memory once they are accessed
+      IR3OpKind::GlobalAddr(_) => Effect::Pure,
+      IR3OpKind::Call(call) if scc.contains(&call.symbol_name) => Effect::Pure,
+      IR3OpKind::Call(call) => callee_effect(module, &call.symbol_name),
+      _ => Effect::Pure,
+    };
+    effect = effect.max(op_effect);
+  }
+  effect
+}
+
+/// Whether every call to `func` returns or traps: it has no loops, and only calls functions that return.
+fn will_return(module: &IR3Module, func: &IR3Function) -> bool {
+  let cfg = Cfg::new(func);
+  let order = cfg.rpo.iter().enumerate().map(|(i, v)| (*v, i)).collect::<HashMap<_, _>>();
+  let has_loop = cfg.rpo.iter()
+    .any(|block| cfg.succs[block].iter().any(|succ| order[succ] <= order[block]));
+  let calls_return = func.basic_blocks.iter()
+    .flat_map(|v| &v.instructions)
+    .all(|op| match &op.kind {
+      IR3OpKind::Call(call) => module.function_attrs(&call.symbol_name)
+        .is_some_and(|attrs| attrs.iter().any(|(v, _)| *v == IR3FunctionAttr::WillReturn)),
+      _ => true,
+    });
+  !has_loop && calls_return
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::parse::parse_module;
+  use super::*;
+  use IR3FunctionAttr::*;
+
+  const MODULE: &str = r#"extern sq attrs pure willreturn args d32 returns d32
+extern log args d32 returns void
+global counter d32
+
+ir3function even args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = const d32 0
+  $2 = cmp d32 eq $0 $1
+  br_if $2 @1 @2
+
+@1:
+  $3 = const d32 1
+  ret d32 $3
+
+@2:
+  $4 = const d32 1
+  $5 = sub d32 $0 $4
+  $6 = call d32 odd d32 $5
+  ret d32 $6
+}
+
+ir3function odd args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = const d32 0
+  $2 = cmp d32 eq $0 $1
+  br_if $2 @1 @2
+
+@1:
+  ret d32 $1
+
+@2:
+  $3 = const d32 1
+  $4 = sub d32 $0 $3
+  $5 = call d32 even d32 $4
+  ret d32 $5
+}
+
+ir3function local args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = stack_alloc ptr 8 4
+  $2 = const d32 4
+  $3 = ptr_uadd ptr $1 $2
+  ptr_store d32 $3 $0
+  $4 = ptr_load d32 $3
+  $5 = call d32 sq d32 $4
+  ret d32 $5
+}
+
+ir3function reads returns d32 {
+@0:
+  $0 = global_addr ptr counter
+  $1 = ptr_load d32 $0
+  ret d32 $1
+}
+
+ir3function calls_reads returns d32 {
+@0:
+  $0 = call d32 reads
+  ret d32 $0
+}
+
+ir3function writes args d32 returns void {
+@0:
+  $0 = arg d32 0
+  $1 = global_addr ptr counter
+  ptr_store d32 $1 $0
+  ret void
+}
+
+ir3function logs args d32 returns void {
+@0:
+  $0 = arg d32 0
+  call void log d32 $0
+  ret void
+}
+
+ir3function fails args d32 returns void {
+@0:
+  $0 = arg d32 0
+  $1 = const d32 0
+  $2 = cmp d32 eq $0 $1
+  br_if $2 @1 @2
+
+@1:
+  trap
+
+@2:
+  unreachable
+}
+
+ir3function spins args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  br @1
+
+@1:
+  $1 = phi d32 $0 @0 $3 @1
+  $2 = const d32 1
+  $3 = sub d32 $1 $2
+  $4 = cmp d32 ne $3 $2
+  br_if $4 @1 @2
+
+@2:
+  ret d32 $3
+}
+"#;
+
+  fn attrs(module: &IR3Module, name: &str) -> Vec<IR3FunctionAttr> {
+    module.function_attrs(name).unwrap().iter().map(|(v, _)| *v).collect()
+  }
+
+  #[test]
+  fn infers_attrs() {
+    let mut module = parse_module(MODULE).unwrap();
+    infer_function_attrs(&mut module);
+    // mutually recursive functions in one SCC can be pure, but may not return
+    assert_eq!(attrs(&module, "even"), [Pure]);
+    assert_eq!(attrs(&module, "odd"), [Pure]);
+    // accesses to its own stack memory are not effects
+    assert_eq!(attrs(&module, "local"), [Pure, WillReturn]);
+    assert_eq!(attrs(&module, "reads"), [ReadOnly, WillReturn]);
+    assert_eq!(attrs(&module, "calls_reads"), [ReadOnly, WillReturn]);
+    assert_eq!(attrs(&module, "writes"), [WillReturn]);
+    // the extern is not declared willreturn
+    assert!(attrs(&module, "logs").is_empty());
+    assert_eq!(attrs(&module, "fails"), [Pure, NoReturn]);
+    assert_eq!(attrs(&module, "spins"), [Pure]);
+  }
+
+  #[test]
+  fn self_recursion_may_not_return() {
+    let text = MODULE.replace("call d32 odd d32 $5", "call d32 even d32 $5");
+    let mut module = parse_module(&text).unwrap();
+    infer_function_attrs(&mut module);
+    assert_eq!(attrs(&module, "even"), [Pure]);
+    // `odd` only calls `even`, which is not willreturn
+    assert_eq!(attrs(&module, "odd"), [Pure]);
+  }
+
+  #[test]
+  fn keeps_declared_attrs() {
+    let text = MODULE.replace("ir3function writes args", "ir3function writes attrs willreturn args");
+    let mut module = parse_module(&text).unwrap();
+    infer_function_attrs(&mut module);
+    assert_eq!(attrs(&module, "writes"), [WillReturn]);
+    assert_eq!(attrs(&module, "sq"), [Pure, WillReturn]);
+  }
+}
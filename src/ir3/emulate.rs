@@ -0,0 +1,693 @@
+//! Emulation of extended instructions.
+//!
+//! Multiplication, division and bit manipulation are extended instructions, which not every target implements
+//! (see `TargetFeatures`). This pass replaces them with basic instructions:
+//! - Multiplication by a constant with few set bits becomes a shift-and-add sequence.
+//! - Unsigned division and remainder by a power of two become a shift or mask.
+//! - Bit counts, rotates and byte swaps become branchless shift-and-mask sequences.
+//! - Overflow-checked ops become the plain op, with the flag computed from the operands and the result.
+//! - Everything else becomes a call to a runtime routine that is generated in IR3 by this pass,
+//!   named `_HXrt$<op>$d<n>`. Signed operations are computed with the unsigned routines plus a sign fixup.
+//!   Division and remainder share `_HXrt$udivmod$d<n>`, which returns both the quotient and the remainder.
+//!
+//! `smulh`, `umulh`, division and bit manipulation are also emulated for data wider than any legal width,
+//! since type legalization can only split the basic operations into halves.
+//! This pass has to run before legalization, the generated routines are legalized like any other function.
+
+use std::collections::{BTreeSet, HashMap};
+use std::mem::take;
+use crate::common::err::IR3Result;
+use crate::ir3::builder::IR3OpBuilder;
+use crate::ir3::model::{IR3BasicBlock, IR3Call, IR3CompareMode, IR3EndOp, IR3Function, IR3Module, IR3Op, IR3OpKind, IR3Phi, IR3Type, IR3VarID};
+use crate::target::Target;
+
+/// Multiplications by constants with more set bits than this are done with a runtime call.
+const INLINE_MUL_MAX_TERMS: u32 = 4;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+enum Routine {
+  Umull,
+  Umulh,
+  /// Returns both the quotient and the remainder.
+  Udivmod,
+}
+
+impl Routine {
+  fn symbol_name(self, width: u32) -> String {
+    let op = match self {
+      Routine::Umull => "umull",
+      Routine::Umulh => "umulh",
+      Routine::Udivmod => "udivmod",
+    };
+    format!("_HXrt${}$d{}", op, width)
+  }
+
+  fn ret_count(self) -> usize {
+    if self == Routine::Udivmod { 2 } else { 1 }
+  }
+}
+
+pub fn emulate_extended_ops(module: &mut IR3Module, target: &Target) -> IR3Result<()> {
+  // generated routines are appended to the module, and may need routines themselves
+  let mut i = 0;
+  while i < module.functions.len() {
+    let mut needed = BTreeSet::new();
+    emulate_func(&mut module.functions[i], target, &mut needed);
+    for (routine, width) in needed {
+      let name = routine.symbol_name(width);
+      if !module.has_symbol(&name) {
+        module.functions.push(generate_routine(routine, name, IR3Type::Data(width)));
+      }
+    }
+    i += 1;
+  }
+  Ok(())
+}
+
+fn needs_emulation(kind: &IR3OpKind, width: u32, target: &Target) -> bool {
+  let widest = *target.legal_widths.last().unwrap();
+  match kind {
+    IR3OpKind::Smull | IR3OpKind::Umull => !target.features.mul,
+    IR3OpKind::Smulh | IR3OpKind::Umulh => !target.features.mul || width > widest,
+    IR3OpKind::Sdiv | IR3OpKind::Udiv | IR3OpKind::Srem | IR3OpKind::Urem => !target.features.div || width > widest,
+    IR3OpKind::Clz | IR3OpKind::Ctz | IR3OpKind::Popcnt | IR3OpKind::Rotl | IR3OpKind::Rotr => {
+      !target.features.bitmanip || width > widest
+    }
+    IR3OpKind::Bswap => !target.features.bswap || width > widest,
+    IR3OpKind::SaddOverflow | IR3OpKind::UaddOverflow | IR3OpKind::SsubOverflow |
+    IR3OpKind::UsubOverflow | IR3OpKind::SmulOverflow | IR3OpKind::UmulOverflow => {
+      !target.features.overflow || !target.is_legal_width(width)
+    }
+    _ => false
+  }
+}
+
+fn emulate_func(func: &mut IR3Function, target: &Target, needed: &mut BTreeSet<(Routine, u32)>) {
+  let consts = func.basic_blocks.iter()
+    .flat_map(|bb| bb.instructions.iter())
+    .filter_map(|op| if let IR3OpKind::Const(v) = op.kind { Some((op.output[0], v)) } else { None })
+    .collect::<HashMap<_, _>>();
+  let mut next_var = func.next_var_id();
+  let mut replace = HashMap::new();
+  for bb in &mut func.basic_blocks {
+    let mut b = IR3OpBuilder::new(next_var);
+    for op in take(&mut bb.instructions) {
+      let width = if let IR3Type::Data(w) = op.ty { w } else { 0 };
+      if !needs_emulation(&op.kind, width, target) {
+        b.ops.push(op);
+        continue;
+      }
+      if op.kind.is_overflow_checked() {
+        let (result, overflow) = emulate_overflow(&mut b, &op, width, target, &consts, needed);
+        replace.insert(op.output[0], result);
+        replace.insert(op.output[1], overflow);
+        continue;
+      }
+      let result = match op.kind {
+        IR3OpKind::Clz | IR3OpKind::Ctz | IR3OpKind::Popcnt | IR3OpKind::Rotl | IR3OpKind::Rotr | IR3OpKind::Bswap => {
+          emulate_bitmanip(&mut b, &op, width)
+        }
+        _ => emulate_op(&mut b, &op, width, &consts, needed),
+      };
+      replace.insert(op.output[0], result);
+    }
+    bb.instructions = b.ops;
+    next_var = b.next_var;
+  }
+  func.replace_uses(&replace);
+}
+
+fn emulate_op(
+  b: &mut IR3OpBuilder,
+  op: &IR3Op,
+  width: u32,
+  consts: &HashMap<IR3VarID, u64>,
+  needed: &mut BTreeSet<(Routine, u32)>
+) -> IR3VarID {
+  let ty = op.ty;
+  let (x, y) = (op.input[0], op.input[1]);
+  if width == 1 {
+    // with one bit, the upper half of a product is always 0.
+    // division still goes through the routine, which traps if the divisor is 0
+    match op.kind {
+      IR3OpKind::Smull | IR3OpKind::Umull => return b.push(IR3OpKind::And, ty, vec![x, y]),
+      IR3OpKind::Smulh | IR3OpKind::Umulh => return b.push_const(ty, 0),
+      _ => {}
+    }
+  }
+  // calls a routine, and returns its output number `idx`
+  let mut call = |b: &mut IR3OpBuilder, routine: Routine, idx: usize, x: IR3VarID, y: IR3VarID| {
+    needed.insert((routine, width));
+    let output = (0..routine.ret_count()).map(|_| b.new_var()).collect::<Vec<_>>();
+    let ret_types = vec![ty; routine.ret_count()];
+    b.ops.push(IR3Op {
+      kind: IR3OpKind::Call(IR3Call { symbol_name: routine.symbol_name(width), arg_types: vec![ty, ty], ret_types }),
+      ty: IR3Type::Void,
+      input: vec![x, y],
+      output: output.clone(),
+    });
+    output[idx]
+  };
+  match op.kind {
+    IR3OpKind::Smull | IR3OpKind::Umull => {
+      // the lower half of a product is the same for signed and unsigned operands
+      let by_const = consts.get(&y).map(|c| (x, *c))
+        .or_else(|| consts.get(&x).map(|c| (y, *c)))
+        .filter(|(_, c)| c.count_ones() <= INLINE_MUL_MAX_TERMS);
+      if let Some((var, c)) = by_const {
+        shift_and_add(b, var, c, ty)
+      } else {
+        call(b, Routine::Umull, 0, x, y)
+      }
+    }
+    IR3OpKind::Umulh => call(b, Routine::Umulh, 0, x, y),
+    IR3OpKind::Smulh => {
+      // smulh(x, y) = umulh(x, y) - (x < 0 ? y : 0) - (y < 0 ? x : 0)
+      let high = call(b, Routine::Umulh, 0, x, y);
+      let sign_shift = b.push_const(ty, (width - 1) as u64);
+      let x_sign = b.push(IR3OpKind::Sra, ty, vec![x, sign_shift]);
+      let y_sign = b.push(IR3OpKind::Sra, ty, vec![y, sign_shift]);
+      let fix1 = b.push(IR3OpKind::And, ty, vec![x_sign, y]);
+      let fix2 = b.push(IR3OpKind::And, ty, vec![y_sign, x]);
+      let high = b.push(IR3OpKind::Sub, ty, vec![high, fix1]);
+      b.push(IR3OpKind::Sub, ty, vec![high, fix2])
+    }
+    IR3OpKind::Udiv | IR3OpKind::Urem => {
+      match consts.get(&y).filter(|c| c.is_power_of_two()) {
+        Some(c) if op.kind == IR3OpKind::Udiv => {
+          let shift = b.push_const(ty, c.trailing_zeros() as u64);
+          b.push(IR3OpKind::Srl, ty, vec![x, shift])
+        }
+        Some(c) => {
+          let mask = b.push_const(ty, c - 1);
+          b.push(IR3OpKind::And, ty, vec![x, mask])
+        }
+        None => {
+          let idx = if op.kind == IR3OpKind::Udiv { 0 } else { 1 };
+          call(b, Routine::Udivmod, idx, x, y)
+        }
+      }
+    }
+    IR3OpKind::Sdiv | IR3OpKind::Srem => {
+      // divide the absolute values, then fix up the sign of the result.
+      // the quotient is negative if the signs differ, the remainder takes the sign of the dividend.
+      let sign_shift = b.push_const(ty, (width - 1) as u64);
+      let x_sign = b.push(IR3OpKind::Sra, ty, vec![x, sign_shift]);
+      let y_sign = b.push(IR3OpKind::Sra, ty, vec![y, sign_shift]);
+      let x_abs = b.push(IR3OpKind::Xor, ty, vec![x, x_sign]);
+      let x_abs = b.push(IR3OpKind::Sub, ty, vec![x_abs, x_sign]);
+      let y_abs = b.push(IR3OpKind::Xor, ty, vec![y, y_sign]);
+      let y_abs = b.push(IR3OpKind::Sub, ty, vec![y_abs, y_sign]);
+      let (res, res_sign) = if op.kind == IR3OpKind::Sdiv {
+        (call(b, Routine::Udivmod, 0, x_abs, y_abs), b.push(IR3OpKind::Xor, ty, vec![x_sign, y_sign]))
+      } else {
+        (call(b, Routine::Udivmod, 1, x_abs, y_abs), x_sign)
+      };
+      let res = b.push(IR3OpKind::Xor, ty, vec![res, res_sign]);
+      b.push(IR3OpKind::Sub, ty, vec![res, res_sign])
+    }
+    _ => unreachable!()
+  }
+}
+
+/// Pushes a multiplication, emulating it if the target needs that.
+fn push_mul(
+  b: &mut IR3OpBuilder,
+  kind: IR3OpKind,
+  op: &IR3Op,
+  width: u32,
+  target: &Target,
+  consts: &HashMap<IR3VarID, u64>,
+  needed: &mut BTreeSet<(Routine, u32)>
+) -> IR3VarID {
+  let mul = IR3Op { kind, ty: op.ty, input: op.input.clone(), output: vec![] };
+  if needs_emulation(&mul.kind, width, target) {
+    emulate_op(b, &mul, width, consts, needed)
+  } else {
+    b.push(mul.kind, mul.ty, mul.input)
+  }
+}
+
+/// Returns the wrapped result and the overflow flag.
+fn emulate_overflow(
+  b: &mut IR3OpBuilder,
+  op: &IR3Op,
+  width: u32,
+  target: &Target,
+  consts: &HashMap<IR3VarID, u64>,
+  needed: &mut BTreeSet<(Routine, u32)>
+) -> (IR3VarID, IR3VarID) {
+  let ty = op.ty;
+  let (x, y) = (op.input[0], op.input[1]);
+  let is_negative = |b: &mut IR3OpBuilder, v: IR3VarID| {
+    let zero = b.push_const(ty, 0);
+    b.push(IR3OpKind::Cmp(IR3CompareMode::SLt), ty, vec![v, zero])
+  };
+  match op.kind {
+    IR3OpKind::UaddOverflow => {
+      let res = b.push(IR3OpKind::Add, ty, vec![x, y]);
+      (res, b.push(IR3OpKind::Cmp(IR3CompareMode::ULt), ty, vec![res, x]))
+    }
+    IR3OpKind::UsubOverflow => {
+      let res = b.push(IR3OpKind::Sub, ty, vec![x, y]);
+      (res, b.push(IR3OpKind::Cmp(IR3CompareMode::ULt), ty, vec![x, y]))
+    }
+    IR3OpKind::SaddOverflow => {
+      // the result has a different sign than both operands
+      let res = b.push(IR3OpKind::Add, ty, vec![x, y]);
+      let t1 = b.push(IR3OpKind::Xor, ty, vec![x, res]);
+      let t2 = b.push(IR3OpKind::Xor, ty, vec![y, res]);
+      let t = b.push(IR3OpKind::And, ty, vec![t1, t2]);
+      (res, is_negative(b, t))
+    }
+    IR3OpKind::SsubOverflow => {
+      // the operands have different signs, and the result has a different sign than `x`
+      let res = b.push(IR3OpKind::Sub, ty, vec![x, y]);
+      let t1 = b.push(IR3OpKind::Xor, ty, vec![x, y]);
+      let t2 = b.push(IR3OpKind::Xor, ty, vec![x, res]);
+      let t = b.push(IR3OpKind::And, ty, vec![t1, t2]);
+      (res, is_negative(b, t))
+    }
+    IR3OpKind::UmulOverflow => {
+      // the upper half of the full product is not zero
+      let res = push_mul(b, IR3OpKind::Umull, op, width, target, consts, needed);
+      let high = push_mul(b, IR3OpKind::Umulh, op, width, target, consts, needed);
+      let zero = b.push_const(ty, 0);
+      (res, b.push(IR3OpKind::Cmp(IR3CompareMode::Ne), ty, vec![high, zero]))
+    }
+    IR3OpKind::SmulOverflow => {
+      // the upper half of the full product is not just the sign of the lower half
+      let res = push_mul(b, IR3OpKind::Smull, op, width, target, consts, needed);
+      let high = push_mul(b, IR3OpKind::Smulh, op, width, target, consts, needed);
+      let sign_shift = b.push_const(ty, (width - 1) as u64);
+      let sign = b.push(IR3OpKind::Sra, ty, vec![res, sign_shift]);
+      (res, b.push(IR3OpKind::Cmp(IR3CompareMode::Ne), ty, vec![high, sign]))
+    }
+    _ => unreachable!()
+  }
+}
+
+fn emulate_bitmanip(b: &mut IR3OpBuilder, op: &IR3Op, width: u32) -> IR3VarID {
+  let ty = op.ty;
+  let x = op.input[0];
+  match op.kind {
+    IR3OpKind::Popcnt => popcnt(b, x, ty, width),
+    IR3OpKind::Clz => {
+      // smear the highest set bit into every bit below it, then count the zeroes above it
+      let mut v = x;
+      let mut shift = 1;
+      while shift < width {
+        let s = b.push_const(ty, shift as u64);
+        let t = b.push(IR3OpKind::Srl, ty, vec![v, s]);
+        v = b.push(IR3OpKind::Or, ty, vec![v, t]);
+        shift *= 2;
+      }
+      let v = b.push(IR3OpKind::Not, ty, vec![v]);
+      popcnt(b, v, ty, width)
+    }
+    IR3OpKind::Ctz => {
+      // !x & (x - 1) has exactly the trailing zeroes set
+      let one = b.push_const(ty, 1);
+      let below = b.push(IR3OpKind::Sub, ty, vec![x, one]);
+      let v = b.push(IR3OpKind::Not, ty, vec![x]);
+      let v = b.push(IR3OpKind::And, ty, vec![v, below]);
+      popcnt(b, v, ty, width)
+    }
+    IR3OpKind::Rotl | IR3OpKind::Rotr => {
+      // the second shift is split in two, so that a rotate by 0 never shifts by the full width
+      let m = b.push_const(ty, (width - 1) as u64);
+      let one = b.push_const(ty, 1);
+      let amt = b.push(IR3OpKind::And, ty, vec![op.input[1], m]);
+      let inv = b.push(IR3OpKind::Xor, ty, vec![amt, m]);
+      let (first, second) = if op.kind == IR3OpKind::Rotl {
+        (IR3OpKind::Sll, IR3OpKind::Srl)
+      } else {
+        (IR3OpKind::Srl, IR3OpKind::Sll)
+      };
+      let v1 = b.push(first, ty, vec![x, amt]);
+      let v2 = b.push(second.clone(), ty, vec![x, one]);
+      let v2 = b.push(second, ty, vec![v2, inv]);
+      b.push(IR3OpKind::Or, ty, vec![v1, v2])
+    }
+    IR3OpKind::Bswap => {
+      let byte_mask = b.push_const(ty, 0xff);
+      let mut acc = None;
+      for i in 0..width / 8 {
+        let (from, to) = (i * 8, width - 8 - i * 8);
+        let mut v = x;
+        if from > 0 {
+          let s = b.push_const(ty, from as u64);
+          v = b.push(IR3OpKind::Srl, ty, vec![v, s]);
+        }
+        if to > 0 {
+          v = b.push(IR3OpKind::And, ty, vec![v, byte_mask]);
+          let s = b.push_const(ty, to as u64);
+          v = b.push(IR3OpKind::Sll, ty, vec![v, s]);
+        }
+        acc = Some(match acc {
+          None => v,
+          Some(acc) => b.push(IR3OpKind::Or, ty, vec![acc, v]),
+        });
+      }
+      acc.unwrap()
+    }
+    _ => unreachable!()
+  }
+}
+
+/// Counts the set bits of `x` by adding them up in parallel, in fields that double in width every step.
+fn popcnt(b: &mut IR3OpBuilder, x: IR3VarID, ty: IR3Type, width: u32) -> IR3VarID {
+  if width == 1 {
+    return x;
+  }
+  if width > 64 {
+    // the bit patterns below do not fit in a constant, so count each half separately
+    let half = IR3Type::Data(width / 2);
+    let shift = b.push_const(ty, (width / 2) as u64);
+    let lo = b.push(IR3OpKind::Trunc(ty), half, vec![x]);
+    let hi = b.push(IR3OpKind::Srl, ty, vec![x, shift]);
+    let hi = b.push(IR3OpKind::Trunc(ty), half, vec![hi]);
+    let lo = popcnt(b, lo, half, width / 2);
+    let hi = popcnt(b, hi, half, width / 2);
+    let v = b.push(IR3OpKind::Add, half, vec![lo, hi]);
+    return b.push(IR3OpKind::Zext(half), ty, vec![v]);
+  }
+  // repeating bit patterns, cut down to the width
+  let pattern = |b: &mut IR3OpBuilder, p: u64| b.push_const(ty, p >> (64 - width));
+  let one = b.push_const(ty, 1);
+  let two = b.push_const(ty, 2);
+  let four = b.push_const(ty, 4);
+  // 2 bit fields: x - ((x >> 1) & 0b01...)
+  let m = pattern(b, u64::MAX / 3);
+  let t = b.push(IR3OpKind::Srl, ty, vec![x, one]);
+  let t = b.push(IR3OpKind::And, ty, vec![t, m]);
+  let v = b.push(IR3OpKind::Sub, ty, vec![x, t]);
+  // 4 bit fields
+  let m = pattern(b, u64::MAX / 5);
+  let lo = b.push(IR3OpKind::And, ty, vec![v, m]);
+  let hi = b.push(IR3OpKind::Srl, ty, vec![v, two]);
+  let hi = b.push(IR3OpKind::And, ty, vec![hi, m]);
+  let v = b.push(IR3OpKind::Add, ty, vec![lo, hi]);
+  // bytes
+  let m = pattern(b, u64::MAX / 17);
+  let t = b.push(IR3OpKind::Srl, ty, vec![v, four]);
+  let v = b.push(IR3OpKind::Add, ty, vec![v, t]);
+  let mut v = b.push(IR3OpKind::And, ty, vec![v, m]);
+  if width > 8 {
+    // sum the bytes into the lowest one, the count always fits
+    let mut shift = 8;
+    while shift < width {
+      let s = b.push_const(ty, shift as u64);
+      let t = b.push(IR3OpKind::Srl, ty, vec![v, s]);
+      v = b.push(IR3OpKind::Add, ty, vec![v, t]);
+      shift *= 2;
+    }
+    let byte_mask = b.push_const(ty, 0xff);
+    v = b.push(IR3OpKind::And, ty, vec![v, byte_mask]);
+  }
+  v
+}
+
+/// Multiplies `var` by the constant `c` by adding up shifted copies of `var`.
+fn shift_and_add(b: &mut IR3OpBuilder, var: IR3VarID, c: u64, ty: IR3Type) -> IR3VarID {
+  let mut acc = None;
+  for bit in 0..64 {
+    if c & (1 << bit) == 0 {
+      continue;
+    }
+    let term = if bit == 0 {
+      var
+    } else {
+      let shift = b.push_const(ty, bit);
+      b.push(IR3OpKind::Sll, ty, vec![var, shift])
+    };
+    acc = Some(match acc {
+      None => term,
+      Some(acc) => b.push(IR3OpKind::Add, ty, vec![acc, term]),
+    });
+  }
+  acc.unwrap_or_else(|| b.push_const(ty, 0))
+}
+
+fn generate_routine(routine: Routine, name: String, ty: IR3Type) -> IR3Function {
+  let basic_blocks = match routine {
+    Routine::Umull => generate_umull(ty),
+    Routine::Umulh => generate_umulh(ty),
+    Routine::Udivmod => generate_udivmod(ty),
+  };
+  IR3Function {
+    name,
+    args: vec![ty, ty],
+    ret: vec![ty; routine.ret_count()],
+    basic_blocks,
+    attrs: vec![],
+  }
+}
+
+/// Shift-and-add multiplication:
+/// ```text
+/// while b != 0 { if b & 1 { res += a }; a <<= 1; b >>= 1 }
+/// ```
+fn generate_umull(ty: IR3Type) -> Vec<IR3BasicBlock> {
+  let mut b = IR3OpBuilder::new(0);
+  let a = b.push(IR3OpKind::Arg(0), ty, vec![]);
+  let c = b.push(IR3OpKind::Arg(1), ty, vec![]);
+  let zero = b.push_const(ty, 0);
+  let one = b.push_const(ty, 1);
+  let (next_a, next_c, next_res) = (b.new_var(), b.new_var(), b.new_var());
+  let entry = b.finish_block(0, IR3EndOp::Br { block: 1 });
+
+  let phi = || IR3OpKind::Phi(IR3Phi { blocks: vec![0, 2] });
+  let cur_a = b.push(phi(), ty, vec![a, next_a]);
+  let cur_c = b.push(phi(), ty, vec![c, next_c]);
+  let cur_res = b.push(phi(), ty, vec![zero, next_res]);
+  let cond = b.push(IR3OpKind::Cmp(IR3CompareMode::Ne), ty, vec![cur_c, zero]);
+  let header = b.finish_block(1, IR3EndOp::BrIf { block1: 2, block2: 3, cond });
+
+  let bit = b.push(IR3OpKind::And, ty, vec![cur_c, one]);
+  let mask = b.push(IR3OpKind::Sub, ty, vec![zero, bit]);
+  let term = b.push(IR3OpKind::And, ty, vec![cur_a, mask]);
+  b.push_to(IR3OpKind::Add, ty, vec![cur_res, term], next_res);
+  b.push_to(IR3OpKind::Sll, ty, vec![cur_a, one], next_a);
+  b.push_to(IR3OpKind::Srl, ty, vec![cur_c, one], next_c);
+  let body = b.finish_block(2, IR3EndOp::Br { block: 1 });
+
+  let exit = b.finish_block(3, IR3EndOp::Ret { values: vec![(ty, cur_res)] });
+  vec![entry, header, body, exit]
+}
+
+/// Computes the upper half of a product from four half-width products,
+/// which all fit in the full width without overflowing.
+fn generate_umulh(ty: IR3Type) -> Vec<IR3BasicBlock> {
+  let IR3Type::Data(width) = ty else { unreachable!() };
+  let half = width / 2;
+  let mut b = IR3OpBuilder::new(0);
+  let a = b.push(IR3OpKind::Arg(0), ty, vec![]);
+  let c = b.push(IR3OpKind::Arg(1), ty, vec![]);
+  let shift = b.push_const(ty, half as u64);
+  let mask = b.push_const(ty, u64::MAX >> (64 - half));
+  let a_lo = b.push(IR3OpKind::And, ty, vec![a, mask]);
+  let a_hi = b.push(IR3OpKind::Srl, ty, vec![a, shift]);
+  let c_lo = b.push(IR3OpKind::And, ty, vec![c, mask]);
+  let c_hi = b.push(IR3OpKind::Srl, ty, vec![c, shift]);
+  let lo_lo = b.push(IR3OpKind::Umull, ty, vec![a_lo, c_lo]);
+  let lo_hi = b.push(IR3OpKind::Umull, ty, vec![a_lo, c_hi]);
+  let hi_lo = b.push(IR3OpKind::Umull, ty, vec![a_hi, c_lo]);
+  let hi_hi = b.push(IR3OpKind::Umull, ty, vec![a_hi, c_hi]);
+  // carries out of the middle column
+  let mid = b.push(IR3OpKind::Srl, ty, vec![lo_lo, shift]);
+  let t = b.push(IR3OpKind::And, ty, vec![lo_hi, mask]);
+  let mid = b.push(IR3OpKind::Add, ty, vec![mid, t]);
+  let t = b.push(IR3OpKind::And, ty, vec![hi_lo, mask]);
+  let mid = b.push(IR3OpKind::Add, ty, vec![mid, t]);
+  let mid = b.push(IR3OpKind::Srl, ty, vec![mid, shift]);
+  let t = b.push(IR3OpKind::Srl, ty, vec![lo_hi, shift]);
+  let res = b.push(IR3OpKind::Add, ty, vec![hi_hi, t]);
+  let t = b.push(IR3OpKind::Srl, ty, vec![hi_lo, shift]);
+  let res = b.push(IR3OpKind::Add, ty, vec![res, t]);
+  let res = b.push(IR3OpKind::Add, ty, vec![res, mid]);
+  vec![b.finish_block(0, IR3EndOp::Ret { values: vec![(ty, res)] })]
+}
+
+/// Restoring division, one quotient bit per iteration:
+/// ```text
+/// for i in (0..n).rev() { rem = rem << 1 | (a >> i) & 1; if rem >= b { rem -= b; quot |= 1 << i } }
+/// ```
+/// Dividing by zero traps, like the native ops.
+fn generate_udivmod(ty: IR3Type) -> Vec<IR3BasicBlock> {
+  let IR3Type::Data(width) = ty else { unreachable!() };
+  let d1 = IR3Type::Data(1);
+  let mut b = IR3OpBuilder::new(0);
+  let a = b.push(IR3OpKind::Arg(0), ty, vec![]);
+  let c = b.push(IR3OpKind::Arg(1), ty, vec![]);
+  let zero = b.push_const(ty, 0);
+  let one = b.push_const(ty, 1);
+  let top_shift = b.push_const(ty, (width - 1) as u64);
+  let count = b.push_const(ty, width as u64);
+  let (next_quot, next_rem, next_count) = (b.new_var(), b.new_var(), b.new_var());
+  let by_zero = b.push(IR3OpKind::Cmp(IR3CompareMode::Eq), ty, vec![c, zero]);
+  let entry = b.finish_block(0, IR3EndOp::BrIf { block1: 4, block2: 1, cond: by_zero });
+
+  let phi = || IR3OpKind::Phi(IR3Phi { blocks: vec![0, 2] });
+  let cur_quot = b.push(phi(), ty, vec![zero, next_quot]);
+  let cur_rem = b.push(phi(), ty, vec![zero, next_rem]);
+  let cur_count = b.push(phi(), ty, vec![count, next_count]);
+  let cond = b.push(IR3OpKind::Cmp(IR3CompareMode::Ne), ty, vec![cur_count, zero]);
+  let header = b.finish_block(1, IR3EndOp::BrIf { block1: 2, block2: 3, cond });
+
+  b.push_to(IR3OpKind::Sub, ty, vec![cur_count, one], next_count);
+  let bit = b.push(IR3OpKind::Srl, ty, vec![a, next_count]);
+  let bit = b.push(IR3OpKind::And, ty, vec![bit, one]);
+  // the bit shifted out of the remainder means it is definitely larger than the divisor
+  let overflow = b.push(IR3OpKind::Srl, ty, vec![cur_rem, top_shift]);
+  let overflow = b.push(IR3OpKind::Cmp(IR3CompareMode::Ne), ty, vec![overflow, zero]);
+  let rem = b.push(IR3OpKind::Sll, ty, vec![cur_rem, one]);
+  let rem = b.push(IR3OpKind::Or, ty, vec![rem, bit]);
+  let fits = b.push(IR3OpKind::Cmp(IR3CompareMode::UGe), ty, vec![rem, c]);
+  let fits = b.push(IR3OpKind::Or, d1, vec![fits, overflow]);
+  let fits = b.push(IR3OpKind::Zext(d1), ty, vec![fits]);
+  let mask = b.push(IR3OpKind::Sub, ty, vec![zero, fits]);
+  let sub = b.push(IR3OpKind::And, ty, vec![c, mask]);
+  b.push_to(IR3OpKind::Sub, ty, vec![rem, sub], next_rem);
+  let quot_bit = b.push(IR3OpKind::Sll, ty, vec![fits, next_count]);
+  b.push_to(IR3OpKind::Or, ty, vec![cur_quot, quot_bit], next_quot);
+  let body = b.finish_block(2, IR3EndOp::Br { block: 1 });
+
+  let exit = b.finish_block(3, IR3EndOp::Ret { values: vec![(ty, cur_quot), (ty, cur_rem)] });
+  let trap = b.finish_block(4, IR3EndOp::Trap);
+  vec![entry, header, body, exit, trap]
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use super::*;
+
+  const BINARY: &[&str] = &[
+    "smull", "umull", "smulh", "umulh", "sdiv", "udiv", "srem", "urem", "rotl", "rotr",
+    "sadd_overflow", "uadd_overflow", "ssub_overflow", "usub_overflow", "smul_overflow", "umul_overflow",
+  ];
+  const UNARY: &[&str] = &["clz", "ctz", "popcnt", "bswap"];
+
+  fn module_for(w: u32) -> IR3Module {
+    let mut text = String::new();
+    for kind in BINARY {
+      let (outs, rets, ret) = if kind.ends_with("overflow") {
+        ("$2 $3", format!("d{w} d1"), format!("d{w} $2 d1 $3"))
+      } else {
+        ("$2", format!("d{w}"), format!("d{w} $2"))
+      };
+      text += &format!(
+        "ir3function {kind} args d{w} d{w} returns {rets} {{\n@0:\n  $0 = arg d{w} 0\n  $1 = arg d{w} 1\n  \
+         {outs} = {kind} d{w} $0 $1\n  ret {ret}\n}}\n\n"
+      );
+    }
+    for kind in UNARY {
+      text += &format!(
+        "ir3function {kind} args d{w} returns d{w} {{\n@0:\n  $0 = arg d{w} 0\n  $1 = {kind} d{w} $0\n  ret d{w} $1\n}}\n\n"
+      );
+    }
+    // multiplications by constants with few and many set bits, and divisions by a power of two
+    let by_const = [("mul_few", "umull", 10), ("mul_many", "umull", 0x5555), ("udiv_pow2", "udiv", 8), ("urem_pow2", "urem", 8)];
+    for (name, kind, c) in by_const {
+      text += &format!(
+        "ir3function {name} args d{w} returns d{w} {{\n@0:\n  $0 = arg d{w} 0\n  $1 = const d{w} {}\n  \
+         $2 = {kind} d{w} $0 $1\n  ret d{w} $2\n}}\n\n",
+        c & (u64::MAX >> (64 - w))
+      );
+    }
+    parse_module(&text).unwrap()
+  }
+
+  fn operands(w: u32) -> Vec<u64> {
+    let mut vals = vec![
+      0, 1, 2, 3, 7, 10, w as u64, 1 << (w - 1), (1 << (w - 1)) - 1, (1 << (w - 1)) + 1,
+      u64::MAX, u64::MAX - 1, 0x0123_4567_89ab_cdef, 0xfedc_ba98_7654_3210,
+    ];
+    vals.iter_mut().for_each(|v| *v &= u64::MAX >> (64 - w));
+    vals.sort();
+    vals.dedup();
+    vals
+  }
+
+  fn count_ops(module: &IR3Module, name: &str) -> usize {
+    module.functions.iter()
+      .flat_map(|f| f.basic_blocks.iter().flat_map(|bb| bb.instructions.iter()))
+      .filter(|op| op.kind.name() == name)
+      .count()
+  }
+
+  #[test]
+  fn matches_native_ops() {
+    // rv64i lacks multiplication and division, rv64 lacks bit manipulation, x86_64 has everything
+    for name in ["rv64i", "rv64", "x86_64", "rv32"] {
+      let target = Target::from_name(name).unwrap();
+      for w in [8, 16, 32, 64] {
+        let module = module_for(w);
+        let mut emulated = module.clone();
+        emulate_extended_ops(&mut emulated, &target).unwrap();
+        verify_module(&emulated).unwrap();
+        let mut native_interp = Interpreter::new(&module, &target);
+        let mut emulated_interp = Interpreter::new(&emulated, &target);
+        let vals = operands(w);
+        for func in &module.functions {
+          for a in &vals {
+            for b in &vals {
+              let args = if func.args.len() == 2 { vec![*a, *b] } else { vec![*a] };
+              let expected = native_interp.call(&func.name, &args).ok();
+              let got = emulated_interp.call(&func.name, &args).ok();
+              assert_eq!(got, expected, "{} d{} {}({:#x}, {:#x})", name, w, func.name, a, b);
+            }
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn division_by_zero_traps() {
+    let target = Target::from_name("rv64i").unwrap();
+    let mut module = module_for(32);
+    emulate_extended_ops(&mut module, &target).unwrap();
+    let mut interp = Interpreter::new(&module, &target);
+    for name in ["udiv", "urem", "sdiv", "srem", "_HXrt$udivmod$d32"] {
+      assert!(interp.call(name, &[5, 0]).is_err(), "{}", name);
+    }
+    assert_eq!(interp.call("_HXrt$udivmod$d32", &[47, 5]).unwrap(), vec![9, 2]);
+  }
+
+  #[test]
+  fn generates_routines_only_when_needed() {
+    let mut module = module_for(32);
+    emulate_extended_ops(&mut module, &Target::from_name("rv64i").unwrap()).unwrap();
+    for routine in ["_HXrt$umull$d32", "_HXrt$umulh$d32", "_HXrt$udivmod$d32"] {
+      assert!(module.function(routine).is_some(), "{}", routine);
+    }
+    // the routines only use basic ops, so nothing extended is left
+    for kind in BINARY.iter().chain(UNARY) {
+      assert_eq!(count_ops(&module, kind), 0, "{}", kind);
+    }
+    // the multiplication by 10 is inlined, the one by 0x5555 is not
+    let calls = |name: &str| {
+      let func = module.function(name).unwrap().clone();
+      count_ops(&IR3Module { functions: vec![func], ..Default::default() }, "call")
+    };
+    assert_eq!(calls("mul_few"), 0);
+    assert_eq!(calls("mul_many"), 1);
+    assert_eq!(calls("udiv_pow2"), 0);
+
+    // rv64 has multiplication and division, but still needs bit manipulation emulated
+    let mut module = module_for(32);
+    emulate_extended_ops(&mut module, &Target::rv64()).unwrap();
+    assert!(!module.functions.iter().any(|f| f.name.starts_with("_HXrt$")));
+    assert_eq!(count_ops(&module, "udiv"), 2);
+    assert_eq!(count_ops(&module, "clz"), 0);
+    // d64 is wider than any legal width on rv32, so division is emulated even with the feature
+    let mut module = module_for(64);
+    emulate_extended_ops(&mut module, &Target::rv32()).unwrap();
+    assert!(module.function("_HXrt$udivmod$d64").is_some());
+    assert!(module.function("_HXrt$umull$d64").is_none());
+  }
+}
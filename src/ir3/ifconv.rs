@@ -0,0 +1,288 @@
+//! If-conversion: replaces small branches that only pick between values with `select`.
+//!
+//! A region is converted when a `br_if` splits into one or two arms that both rejoin at the same block,
+//! either as a diamond (`head -> arm1, arm2 -> join`) or a triangle (`head -> arm -> join`, `head -> join`).
+//! The arms must only contain ops that are safe to execute unconditionally (no memory accesses, calls or division),
+//! and are moved into the head. The phis of the join then become `select`s on the branch condition.
+//!
+//! Both arms are executed after conversion, so the total cost is limited by `MAX_COST`.
+//! Straight-line chains of blocks are merged along the way, so that nested regions can be converted from the inside out.
+
+use std::collections::HashMap;
+use crate::ir3::cfg::{merge_blocks, Cfg};
+use crate::ir3::model::{IR3BBID, IR3EndOp, IR3Function, IR3Op, IR3OpKind, IR3Phi, IR3VarID};
+
+/// Maximum number of ops in the arms plus `select`s created for a region to be converted.
+const MAX_COST: usize = 6;
+
+pub fn if_convert(func: &mut IR3Function) {
+  // converting an inner region turns it into a straight line, which can make the enclosing region convertible
+  merge_blocks(func);
+  while convert_one(func) {
+    merge_blocks(func);
+  }
+}
+
+/// Whether an op can be executed on a path where it originally was not.
+fn is_speculatable(op: &IR3Op) -> bool {
+  !matches!(op.kind,
+    IR3OpKind::PtrLoad | IR3OpKind::PtrStore | IR3OpKind::Call(_) | IR3OpKind::StackAlloc { .. } | IR3OpKind::Phi(_) |
+    IR3OpKind::Sdiv | IR3OpKind::Udiv | IR3OpKind::Srem | IR3OpKind::Urem |
+    IR3OpKind::AtomicLoad(_) | IR3OpKind::AtomicStore(_) | IR3OpKind::AtomicRmw(..) | IR3OpKind::CmpXchg(_) | IR3OpKind::Fence(_))
+}
+
+struct Region {
+  head: IR3BBID,
+  join: IR3BBID,
+  /// Where the value of the join's phis comes from when the condition is true, and when it is false.
+  /// This is either an arm, or the head itself in a triangle.
+  sources: [IR3BBID; 2],
+  arms: Vec<IR3BBID>,
+}
+
+/// Finds and converts one region. Returns false if there is none left.
+fn convert_one(func: &mut IR3Function) -> bool {
+  let cfg = Cfg::new(func);
+  let index = func.basic_blocks.iter()
+    .enumerate()
+    .map(|(i, bb)| (bb.id, i))
+    .collect::<HashMap<_, _>>();
+  let br_target = |block: IR3BBID| match func.basic_blocks[index[&block]].ending {
+    IR3EndOp::Br { block } => Some(block),
+    _ => None,
+  };
+  for head in &cfg.rpo {
+    let IR3EndOp::BrIf { block1, block2, cond } = func.basic_blocks[index[head]].ending else { continue };
+    if block1 == block2 {
+      continue;
+    }
+    let join = match (br_target(block1), br_target(block2)) {
+      (Some(t1), Some(t2)) if t1 == t2 => t1,
+      (Some(t1), _) if t1 == block2 => block2,
+      (_, Some(t2)) if t2 == block1 => block1,
+      _ => continue,
+    };
+    if join == *head {
+      continue;
+    }
+    let sources = [block1, block2].map(|v| if v == join { *head } else { v });
+    let arms = sources.iter().copied().filter(|v| v != head).collect::<Vec<_>>();
+    let arms_ok = arms.iter().all(|arm| {
+      cfg.preds[arm] == [*head] && func.basic_blocks[index[arm]].instructions.iter().all(is_speculatable)
+    });
+    if !arms_ok {
+      continue;
+    }
+    let join_bb = &func.basic_blocks[index[&join]];
+    let phis = join_bb.instructions.iter()
+      .map_while(|op| if let IR3OpKind::Phi(phi) = &op.kind { Some((phi, op)) } else { None })
+      .collect::<Vec<_>>();
+    let input_of = |phi: &IR3Phi, op: &IR3Op, block: IR3BBID| {
+      phi.blocks.iter().position(|v| *v == block).map(|i| op.input[i])
+    };
+    let selects = phis.iter()
+      .filter(|(phi, op)| input_of(phi, op, sources[0]) != input_of(phi, op, sources[1]))
+      .count();
+    let cost = arms.iter().map(|arm| func.basic_blocks[index[arm]].instructions.len()).sum::<usize>() + selects;
+    if cost > MAX_COST {
+      continue;
+    }
+    apply(func, &index, Region { head: *head, join, sources, arms }, cond);
+    return true;
+  }
+  false
+}
+
+fn apply(func: &mut IR3Function, index: &HashMap<IR3BBID, usize>, region: Region, cond: IR3VarID) {
+  let mut next_var = func.next_var_id();
+  let mut moved = vec![];
+  for arm in &region.arms {
+    let bb = &mut func.basic_blocks[index[arm]];
+    moved.append(&mut bb.instructions);
+    bb.ending = IR3EndOp::Unreachable;
+  }
+  // merge the two phi inputs into a single one from the head
+  let mut selects = vec![];
+  for op in &mut func.basic_blocks[index[&region.join]].instructions {
+    let IR3OpKind::Phi(phi) = &mut op.kind else { break };
+    let [a, b] = region.sources.map(|source| {
+      let idx = phi.blocks.iter().position(|v| *v == source).unwrap();
+      phi.blocks.remove(idx);
+      op.input.remove(idx)
+    });
+    let value = if a == b {
+      a
+    } else {
+      selects.push(IR3Op { kind: IR3OpKind::Select, ty: op.ty, input: vec![cond, a, b], output: vec![next_var] });
+      next_var += 1;
+      next_var - 1
+    };
+    phi.blocks.push(region.head);
+    op.input.push(value);
+  }
+  let head = &mut func.basic_blocks[index[&region.head]];
+  head.instructions.extend(moved);
+  head.instructions.extend(selects);
+  head.ending = IR3EndOp::Br { block: region.join };
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use crate::target::Target;
+  use super::*;
+
+  const DIAMOND: &str = r#"ir3function f args d32 d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = arg d32 1
+  $2 = cmp d32 ult $0 $1
+  br_if $2 @1 @2
+
+@1:
+  $3 = add d32 $0 $1
+  br @3
+
+@2:
+  $4 = sub d32 $0 $1
+  br @3
+
+@3:
+  $5 = phi d32 $3 @1 $4 @2
+  $6 = phi d32 $0 @1 $0 @2
+  $7 = xor d32 $5 $6
+  ret d32 $7
+}
+"#;
+
+  const TRIANGLE: &str = r#"ir3function f args d32 d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = arg d32 1
+  $2 = cmp d32 slt $0 $1
+  br_if $2 @2 @1
+
+@1:
+  $3 = sll d32 $0 $1
+  br @2
+
+@2:
+  $4 = phi d32 $1 @0 $3 @1
+  ret d32 $4
+}
+"#;
+
+  /// The outer region only becomes convertible once the inner diamond is a straight line.
+  const NESTED: &str = r#"ir3function f args d32 d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = arg d32 1
+  $2 = cmp d32 ult $0 $1
+  $3 = cmp d32 eq $0 $1
+  br_if $2 @1 @5
+
+@1:
+  br_if $3 @2 @3
+
+@2:
+  $4 = add d32 $0 $1
+  br @4
+
+@3:
+  $5 = sub d32 $0 $1
+  br @4
+
+@4:
+  $6 = phi d32 $4 @2 $5 @3
+  br @6
+
+@5:
+  $7 = xor d32 $0 $1
+  br @6
+
+@6:
+  $8 = phi d32 $6 @4 $7 @5
+  ret d32 $8
+}
+"#;
+
+  /// Converts `f`, and checks that it still computes the same values.
+  fn convert(text: &str) -> IR3Function {
+    let module = parse_module(text).unwrap();
+    let mut converted = module.clone();
+    if_convert(&mut converted.functions[0]);
+    verify_module(&converted).unwrap();
+    let target = Target::rv64();
+    let mut orig_interp = Interpreter::new(&module, &target);
+    let mut converted_interp = Interpreter::new(&converted, &target);
+    for a in [0, 1, 2, 7, 0x8000_0000, 0xffff_ffff] {
+      for b in [0, 1, 3, 7, 0xffff_fffe] {
+        let expected = orig_interp.call("f", &[a, b]).ok();
+        assert_eq!(converted_interp.call("f", &[a, b]).ok(), expected, "f({}, {})", a, b);
+      }
+    }
+    converted.functions[0].clone()
+  }
+
+  fn count(func: &IR3Function, kind: IR3OpKind) -> usize {
+    func.basic_blocks.iter().flat_map(|bb| bb.instructions.iter()).filter(|op| op.kind == kind).count()
+  }
+
+  fn has_branch(func: &IR3Function) -> bool {
+    func.basic_blocks.iter().any(|bb| matches!(bb.ending, IR3EndOp::BrIf { .. }))
+  }
+
+  #[test]
+  fn diamond() {
+    let func = convert(DIAMOND);
+    assert_eq!(func.basic_blocks.len(), 1);
+    // the phi with equal inputs needs no select
+    assert_eq!(count(&func, IR3OpKind::Select), 1);
+    let select = func.basic_blocks[0].instructions.iter().find(|op| op.kind == IR3OpKind::Select).unwrap();
+    assert_eq!(select.input, vec![2, 3, 4]);
+  }
+
+  #[test]
+  fn triangle() {
+    let func = convert(TRIANGLE);
+    assert_eq!(func.basic_blocks.len(), 1);
+    let select = func.basic_blocks[0].instructions.iter().find(|op| op.kind == IR3OpKind::Select).unwrap();
+    // the true edge goes straight to the join, so the head's value comes first
+    assert_eq!(select.input, vec![2, 1, 3]);
+  }
+
+  #[test]
+  fn nested_inside_out() {
+    let func = convert(NESTED);
+    assert_eq!(func.basic_blocks.len(), 1);
+    assert_eq!(count(&func, IR3OpKind::Select), 2);
+  }
+
+  #[test]
+  fn rejects_unsafe_arms() {
+    for op in ["udiv d32 $0 $1", "srem d32 $0 $1"] {
+      let func = convert(&DIAMOND.replace("sub d32 $0 $1", op));
+      assert!(has_branch(&func), "{}", op);
+      assert_eq!(count(&func, IR3OpKind::Select), 0);
+    }
+    let text = DIAMOND
+      .replace("  $2 = cmp", "  $8 = stack_alloc ptr 4 4\n  ptr_store d32 $8 $1\n  $2 = cmp")
+      .replace("  $4 = sub d32 $0 $1\n", "  $4 = ptr_load d32 $8\n");
+    assert!(has_branch(&convert(&text)));
+  }
+
+  #[test]
+  fn cost_limit() {
+    // `adds` ops in one arm, one in the other, and a select
+    let diamond_with = |adds: usize| {
+      let chain = (0..adds)
+        .map(|i| format!("  ${} = add d32 ${} $1\n", 10 + i, if i == 0 { 0 } else { 9 + i }))
+        .collect::<String>();
+      DIAMOND.replace("  $3 = add d32 $0 $1\n", &format!("{}  $3 = add d32 ${} $0\n", chain, 9 + adds))
+    };
+    assert!(!has_branch(&convert(&diamond_with(MAX_COST - 3))));
+    assert!(has_branch(&convert(&diamond_with(MAX_COST - 2))));
+  }
+}
@@ -0,0 +1,436 @@
+//! Reference interpreter for IR3.
+//!
+//! Runs a module directly, without a backend. Memory is a single byte array:
+//! globals and rodata are laid out first, followed by the stack, which grows upwards.
+//! Memory past the top of the stack is not accessible.
+//! Address 0 is never valid, so that null pointers trap. Functions get addresses past the end
+//! of memory, so taking their address works but accessing it traps.
+//!
+//! Extern functions are provided by the host through `Interpreter::define_extern`.
+//! Traps (such as division by zero or an out of bounds access) are reported as `IR3ErrKind::Trap`.
+//! Data wider than 64 bits is not interpreted; such modules have to be legalized first.
+
+use std::collections::{HashMap, HashSet};
+use crate::common::err::{IR3Err, IR3ErrKind, IR3Result};
+use crate::ir3::model::{IR3EndOp, IR3FloatCompareMode, IR3Function, IR3GlobalInit, IR3Module, IR3Op, IR3OpKind, IR3RmwOp, IR3Type, IR3VarID};
+use crate::target::Target;
+
+/// Start of the address range used for function addresses.
+const FUNCTION_ADDR_BASE: u64 = 0x7000_0000;
+/// Maximum stack size, in bytes.
+const STACK_SIZE: usize = 1 << 20;
+
+type ExternFn<'a> = Box<dyn FnMut(&[u64]) -> Vec<u64> + 'a>;
+
+pub struct Interpreter<'a> {
+  module: &'a IR3Module,
+  target: &'a Target,
+  pub memory: Vec<u8>,
+  symbols: HashMap<String, u64>,
+  /// Lowest address of the stack.
+  stack_base: usize,
+  stack_ptr: usize,
+  externs: HashMap<String, ExternFn<'a>>,
+  /// Externs that end the program, with their first argument as the exit code.
+  exits: HashSet<String>,
+  /// Number of instructions that may still be executed, to catch infinite loops.
+  pub fuel: u64,
+}
+
+/// Keeps only the lower `width` bits.
+fn mask(v: u64, width: u32) -> u64 {
+  if width >= 64 { v } else { v & ((1 << width) - 1) }
+}
+
+fn sign_extend(v: u64, width: u32) -> i64 {
+  if width >= 64 { v as i64 } else { ((v << (64 - width)) as i64) >> (64 - width) }
+}
+
+/// Rotates the lower `width` bits of `v` left by `n < width`. The result still has to be masked.
+fn rotate_left(v: u64, n: u32, width: u32) -> u64 {
+  if n == 0 { v } else { v << n | v >> (width - n) }
+}
+
+/// Computes an overflow-checked op on inputs of the given width, returning the unmasked result
+/// and whether the exact result did not fit.
+fn eval_overflow(kind: &IR3OpKind, a: &[u64], width: u32) -> (u64, bool) {
+  let (u0, u1) = (a[0] as u128, a[1] as u128);
+  let (s0, s1) = (sign_extend(a[0], width) as i128, sign_extend(a[1], width) as i128);
+  let exact = match kind {
+    IR3OpKind::UaddOverflow => return (a[0].wrapping_add(a[1]), (u0 + u1) >> width != 0),
+    IR3OpKind::UsubOverflow => return (a[0].wrapping_sub(a[1]), a[0] < a[1]),
+    IR3OpKind::UmulOverflow => return (a[0].wrapping_mul(a[1]), (u0 * u1) >> width != 0),
+    IR3OpKind::SaddOverflow => s0 + s1,
+    IR3OpKind::SsubOverflow => s0 - s1,
+    IR3OpKind::SmulOverflow => s0 * s1,
+    _ => unreachable!(),
+  };
+  let fits = exact >= -(1 << (width - 1)) && exact < 1 << (width - 1);
+  (exact as u64, !fits)
+}
+
+/// Reads the bits of an `f32` or `f64`. Every `f32` is exactly representable as an `f64`.
+fn to_f64(v: u64, ty: IR3Type) -> f64 {
+  if ty == IR3Type::Float(32) { f32::from_bits(v as u32) as f64 } else { f64::from_bits(v) }
+}
+
+/// Rounds `v` to an `f32` or `f64`, and returns its bits.
+fn from_f64(v: f64, ty: IR3Type) -> u64 {
+  if ty == IR3Type::Float(32) { (v as f32).to_bits() as u64 } else { v.to_bits() }
+}
+
+/// Converts an integer to the nearest `f32` or `f64`, and returns its bits.
+fn int_to_float(v: i128, ty: IR3Type) -> u64 {
+  if ty == IR3Type::Float(32) { (v as f32).to_bits() as u64 } else { (v as f64).to_bits() }
+}
+
+/// Computes a float op. `f32` arithmetic is done on `f64`s and then rounded, which gives the same
+/// result as computing in `f32` directly, since `f64` has more than twice the precision.
+fn eval_float(kind: &IR3OpKind, ty: IR3Type, a: &[u64]) -> u64 {
+  let x = |i: usize| to_f64(a[i], ty);
+  match kind {
+    IR3OpKind::Fadd => from_f64(x(0) + x(1), ty),
+    IR3OpKind::Fsub => from_f64(x(0) - x(1), ty),
+    IR3OpKind::Fmul => from_f64(x(0) * x(1), ty),
+    IR3OpKind::Fdiv => from_f64(x(0) / x(1), ty),
+    IR3OpKind::Fneg => from_f64(-x(0), ty),
+    IR3OpKind::Fsqrt => from_f64(x(0).sqrt(), ty),
+    IR3OpKind::Fcmp(mode) => (match mode {
+      IR3FloatCompareMode::Eq => x(0) == x(1),
+      IR3FloatCompareMode::Ne => x(0) != x(1),
+      IR3FloatCompareMode::Lt => x(0) < x(1),
+      IR3FloatCompareMode::Gt => x(0) > x(1),
+      IR3FloatCompareMode::Le => x(0) <= x(1),
+      IR3FloatCompareMode::Ge => x(0) >= x(1),
+      IR3FloatCompareMode::Uno => x(0).is_nan() || x(1).is_nan(),
+    }) as u64,
+    IR3OpKind::Fconv(src) => from_f64(to_f64(a[0], *src), ty),
+    // out of range values are unspecified, so saturating like Rust does is fine
+    IR3OpKind::FpToSi(src) => to_f64(a[0], *src) as i64 as u64,
+    IR3OpKind::FpToUi(src) => to_f64(a[0], *src) as u64,
+    _ => unreachable!(),
+  }
+}
+
+impl<'a> Interpreter<'a> {
+  pub fn new(module: &'a IR3Module, target: &'a Target) -> Self {
+    let mut interp = Interpreter {
+      module,
+      target,
+      memory: vec![0; 16],
+      symbols: HashMap::new(),
+      stack_base: 0,
+      stack_ptr: 0,
+      externs: HashMap::new(),
+      exits: HashSet::new(),
+      fuel: u64::MAX,
+    };
+    for data in &module.rodata {
+      let addr = interp.alloc_static(data.bytes.len(), data.align);
+      interp.memory[addr..addr + data.bytes.len()].copy_from_slice(&data.bytes);
+      interp.symbols.insert(data.name.clone(), addr as u64);
+    }
+    for global in &module.globals {
+      let addr = interp.alloc_static(target.size_of(global.ty) as usize, target.align_of(global.ty));
+      interp.symbols.insert(global.name.clone(), addr as u64);
+    }
+    for (i, func) in module.functions.iter().enumerate() {
+      interp.symbols.insert(func.name.clone(), FUNCTION_ADDR_BASE + i as u64);
+    }
+    for (i, ext) in module.externs.iter().enumerate() {
+      interp.symbols.insert(ext.name.clone(), FUNCTION_ADDR_BASE + (module.functions.len() + i) as u64);
+    }
+    // initializers can refer to any symbol, so they are written once every address is known
+    for global in &module.globals {
+      let value = match &global.init {
+        IR3GlobalInit::Zero => 0,
+        IR3GlobalInit::Const(v) => *v,
+        IR3GlobalInit::Addr(name) => interp.symbols[name],
+      };
+      let addr = interp.symbols[&global.name];
+      interp.store(addr, global.ty, value).unwrap();
+    }
+    interp.stack_base = interp.memory.len().next_multiple_of(16);
+    interp.stack_ptr = interp.stack_base;
+    interp
+  }
+
+  fn alloc_static(&mut self, size: usize, align: u32) -> usize {
+    let addr = self.memory.len().next_multiple_of(align.max(1) as usize);
+    self.memory.resize(addr + size, 0);
+    addr
+  }
+
+  /// Defines an extern function. It returns one value for each return type of the extern.
+  pub fn define_extern(&mut self, name: &str, f: impl FnMut(&[u64]) -> Vec<u64> + 'a) {
+    self.externs.insert(name.to_owned(), Box::new(f));
+  }
+
+  /// Defines an extern function that ends the program, like `exit`.
+  /// Calls to it fail with `IR3ErrKind::Exit`, carrying the first argument as the exit code.
+  pub fn define_exit(&mut self, name: &str) {
+    self.exits.insert(name.to_owned());
+  }
+
+  pub fn symbol_addr(&self, name: &str) -> Option<u64> {
+    self.symbols.get(name).copied()
+  }
+
+  fn trap<T>(&self, func: &str, msg: impl Into<String>) -> IR3Result<T> {
+    Err(IR3Err::new(IR3ErrKind::Trap(msg.into()), func))
+  }
+
+  fn ptr_width(&self) -> u32 {
+    self.target.ptr_width
+  }
+
+  fn width_of(&self, ty: IR3Type) -> u32 {
+    match ty {
+      IR3Type::Data(w) | IR3Type::Float(w) => w,
+      IR3Type::Ptr => self.ptr_width(),
+      IR3Type::Void => 0,
+    }
+  }
+
+  fn access(&self, addr: u64, ty: IR3Type) -> Option<std::ops::Range<usize>> {
+    let size = self.target.size_of(ty) as usize;
+    let addr = usize::try_from(addr).ok()?;
+    if addr == 0 || addr.checked_add(size)? > self.memory.len() {
+      return None;
+    }
+    Some(addr..addr + size)
+  }
+
+  pub fn load(&self, addr: u64, ty: IR3Type) -> Option<u64> {
+    let range = self.access(addr, ty)?;
+    let bytes = &self.memory[range];
+    // little-endian
+    let v = bytes.iter().rev().fold(0, |v, byte| (v << 8) | *byte as u64);
+    Some(mask(v, self.width_of(ty)))
+  }
+
+  pub fn store(&mut self, addr: u64, ty: IR3Type, value: u64) -> Option<()> {
+    let range = self.access(addr, ty)?;
+    for (i, byte) in self.memory[range].iter_mut().enumerate() {
+      *byte = (value.checked_shr(8 * i as u32).unwrap_or(0)) as u8;
+    }
+    Some(())
+  }
+
+  /// Calls a function or extern by name, and returns all of its return values.
+  pub fn call(&mut self, name: &str, args: &[u64]) -> IR3Result<Vec<u64>> {
+    if let Some(func) = self.module.function(name) {
+      return self.run(func, args);
+    }
+    if self.exits.contains(name) {
+      let code = args.first().copied().unwrap_or(0);
+      return Err(IR3Err::new(IR3ErrKind::Exit(code), name));
+    }
+    match self.externs.get_mut(name) {
+      Some(f) => Ok(f(args)),
+      None => self.trap(name, "call to an extern function with no definition"),
+    }
+  }
+
+  fn run(&mut self, func: &'a IR3Function, args: &[u64]) -> IR3Result<Vec<u64>> {
+    let saved_stack_ptr = self.stack_ptr;
+    let mut vars: HashMap<IR3VarID, u64> = HashMap::new();
+    let blocks = func.basic_blocks.iter()
+      .map(|bb| (bb.id, bb))
+      .collect::<HashMap<_, _>>();
+    let mut block = &func.basic_blocks[0];
+    let mut prev = None;
+    let result = loop {
+      // phis read their inputs all at once, before any of them are written
+      let mut phi_values = vec![];
+      for op in &block.instructions {
+        let IR3OpKind::Phi(phi) = &op.kind else { break };
+        let idx = phi.blocks.iter().position(|v| Some(*v) == prev);
+        let Some(idx) = idx else {
+          return self.trap(&func.name, format!("phi in @{} has no input for the previous block", block.id));
+        };
+        phi_values.push((op.output[0], vars[&op.input[idx]]));
+      }
+      vars.extend(phi_values);
+      for op in &block.instructions {
+        if self.fuel == 0 {
+          return self.trap(&func.name, "ran out of fuel");
+        }
+        self.fuel -= 1;
+        if let IR3OpKind::Phi(_) = op.kind {
+          continue;
+        }
+        if self.width_of(op.ty) > 64 {
+          return self.trap(&func.name, format!("`{}` has to be legalized first", op));
+        }
+        let inputs = op.input.iter().map(|v| vars[v]).collect::<Vec<_>>();
+        if let IR3OpKind::Call(call) = &op.kind {
+          let results = self.call(&call.symbol_name, &inputs)?;
+          if results.len() != op.output.len() {
+            return self.trap(&call.symbol_name, format!("returned {} values instead of {}", results.len(), op.output.len()));
+          }
+          for ((var, ty), v) in op.output.iter().zip(&call.ret_types).zip(results) {
+            vars.insert(*var, mask(v, self.width_of(*ty)));
+          }
+          continue;
+        }
+        if let IR3OpKind::CmpXchg(_) = op.kind {
+          let (old, exchanged) = self.cmpxchg(func, op, &inputs)?;
+          vars.insert(op.output[0], old);
+          vars.insert(op.output[1], exchanged as u64);
+          continue;
+        }
+        if op.kind.is_overflow_checked() {
+          let (result, overflow) = eval_overflow(&op.kind, &inputs, self.width_of(op.ty));
+          vars.insert(op.output[0], mask(result, self.width_of(op.ty)));
+          vars.insert(op.output[1], overflow as u64);
+          continue;
+        }
+        if let Some(result) = self.eval(func, args, op, &inputs)? {
+          vars.insert(op.output[0], mask(result, self.width_of(op.return_type())));
+        }
+      }
+      let next = match &block.ending {
+        IR3EndOp::Br { block } => *block,
+        IR3EndOp::BrIf { block1, block2, cond } => if vars[cond] != 0 { *block1 } else { *block2 },
+        IR3EndOp::Ret { values } => break values.iter().map(|(_, v)| vars[v]).collect(),
+        IR3EndOp::Unreachable => return self.trap(&func.name, "reached unreachable code"),
+        IR3EndOp::Trap => return self.trap(&func.name, "trap instruction"),
+        IR3EndOp::Switch { cond, cases, default } => {
+          let value = vars[cond];
+          cases.iter().find(|(v, _)| *v == value).map_or(*default, |(_, block)| *block)
+        }
+      };
+      prev = Some(block.id);
+      block = blocks[&next];
+    };
+    self.stack_ptr = saved_stack_ptr;
+    Ok(result)
+  }
+
+  /// Returns the old value, and whether it was replaced.
+  fn cmpxchg(&mut self, func: &'a IR3Function, op: &IR3Op, a: &[u64]) -> IR3Result<(u64, bool)> {
+    let Some(old) = self.load(a[0], op.ty) else {
+      return self.trap(&func.name, format!("atomic access to invalid address {:#x}", a[0]));
+    };
+    // legalized IR3 may pass a wider value
+    let exchanged = old == mask(a[1], self.width_of(op.ty));
+    if exchanged {
+      self.store(a[0], op.ty, a[2]);
+    }
+    Ok((old, exchanged))
+  }
+
+  fn eval(&mut self, func: &'a IR3Function, args: &[u64], op: &IR3Op, a: &[u64]) -> IR3Result<Option<u64>> {
+    let width = self.width_of(op.ty);
+    let s = |i: usize| sign_extend(a[i], width);
+    // shift amounts wrap around, like on RISC-V
+    let shift = |i: usize| (a[i] % width.max(1) as u64) as u32;
+    let divisor = |i: usize| if a[i] == 0 { self.trap(&func.name, "division by zero") } else { Ok(a[i]) };
+    Ok(Some(match &op.kind {
+      IR3OpKind::Add => a[0].wrapping_add(a[1]),
+      IR3OpKind::Sub => a[0].wrapping_sub(a[1]),
+      IR3OpKind::And => a[0] & a[1],
+      IR3OpKind::Or => a[0] | a[1],
+      IR3OpKind::Xor => a[0] ^ a[1],
+      IR3OpKind::Not => !a[0],
+      IR3OpKind::Sll => a[0] << shift(1),
+      IR3OpKind::Srl => a[0] >> shift(1),
+      IR3OpKind::Sra => (s(0) >> shift(1)) as u64,
+      IR3OpKind::Rotl => rotate_left(a[0], shift(1), width),
+      IR3OpKind::Rotr => rotate_left(a[0], (width - shift(1)) % width.max(1), width),
+      // inputs are already masked to their width, so only the bits above it have to be discounted
+      IR3OpKind::Clz => (a[0].leading_zeros() - (64 - width)) as u64,
+      IR3OpKind::Ctz => a[0].trailing_zeros().min(width) as u64,
+      IR3OpKind::Popcnt => a[0].count_ones() as u64,
+      IR3OpKind::Bswap => a[0].swap_bytes() >> (64 - width),
+      IR3OpKind::Smull | IR3OpKind::Umull => a[0].wrapping_mul(a[1]),
+      IR3OpKind::Umulh => ((a[0] as u128 * a[1] as u128) >> width) as u64,
+      IR3OpKind::Smulh => ((s(0) as i128 * s(1) as i128) >> width) as u64,
+      IR3OpKind::Udiv => a[0] / divisor(1)?,
+      IR3OpKind::Urem => a[0] % divisor(1)?,
+      IR3OpKind::Sdiv => { divisor(1)?; s(0).wrapping_div(s(1)) as u64 }
+      IR3OpKind::Srem => { divisor(1)?; s(0).wrapping_rem(s(1)) as u64 }
+      IR3OpKind::Cmp(mode) => mode.holds(a[0], a[1], width.max(1)) as u64,
+      IR3OpKind::Fadd | IR3OpKind::Fsub | IR3OpKind::Fmul | IR3OpKind::Fdiv | IR3OpKind::Fneg |
+      IR3OpKind::Fsqrt | IR3OpKind::Fcmp(_) | IR3OpKind::Fconv(_) | IR3OpKind::FpToSi(_) | IR3OpKind::FpToUi(_) => {
+        eval_float(&op.kind, op.ty, a)
+      }
+      IR3OpKind::SiToFp(src) => int_to_float(sign_extend(a[0], self.width_of(*src)) as i128, op.ty),
+      IR3OpKind::UiToFp(_) => int_to_float(a[0] as i128, op.ty),
+      IR3OpKind::Const(v) => *v,
+      IR3OpKind::Zext(_) | IR3OpKind::Trunc(_) => a[0],
+      IR3OpKind::Sext(src) => sign_extend(a[0], self.width_of(*src)) as u64,
+      IR3OpKind::PtrLoad | IR3OpKind::AtomicLoad(_) => match self.load(a[0], op.ty) {
+        Some(v) => v,
+        None => return self.trap(&func.name, format!("load from invalid address {:#x}", a[0])),
+      },
+      IR3OpKind::PtrStore | IR3OpKind::AtomicStore(_) => {
+        if self.store(a[0], op.ty, a[1]).is_none() {
+          return self.trap(&func.name, format!("store to invalid address {:#x}", a[0]));
+        }
+        return Ok(None);
+      }
+      // there is only one thread, so atomics are plain memory accesses and fences do nothing
+      IR3OpKind::AtomicRmw(rmw, _) => {
+        let Some(old) = self.load(a[0], op.ty) else {
+          return self.trap(&func.name, format!("atomic access to invalid address {:#x}", a[0]));
+        };
+        let new = match rmw {
+          IR3RmwOp::Add => old.wrapping_add(a[1]),
+          IR3RmwOp::And => old & a[1],
+          IR3RmwOp::Or => old | a[1],
+          IR3RmwOp::Xor => old ^ a[1],
+          IR3RmwOp::Xchg => a[1],
+        };
+        self.store(a[0], op.ty, new);
+        old
+      }
+      IR3OpKind::Fence(_) => return Ok(None),
+      IR3OpKind::PtrUadd => a[0].wrapping_add(a[1]),
+      IR3OpKind::PtrSadd => a[0].wrapping_add(s(1) as u64),
+      IR3OpKind::StackAlloc { size, align } => {
+        let addr = self.stack_ptr.next_multiple_of(*align as usize);
+        if addr + *size as usize > self.stack_base + STACK_SIZE {
+          return self.trap(&func.name, "stack overflow");
+        }
+        self.stack_ptr = addr + *size as usize;
+        if self.memory.len() < self.stack_ptr {
+          self.memory.resize(self.stack_ptr, 0);
+        }
+        addr as u64
+      }
+      IR3OpKind::GlobalAddr(name) => self.symbols[name],
+      IR3OpKind::Arg(idx) => args[*idx as usize],
+      IR3OpKind::SaddOverflow | IR3OpKind::UaddOverflow | IR3OpKind::SsubOverflow |
+      IR3OpKind::UsubOverflow | IR3OpKind::SmulOverflow | IR3OpKind::UmulOverflow |
+      IR3OpKind::CmpXchg(_) | IR3OpKind::Call(_) | IR3OpKind::Phi(_) => unreachable!(),
+      IR3OpKind::Select => if a[0] != 0 { a[1] } else { a[2] },
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::parse::parse_module;
+  use super::*;
+
+  #[test]
+  fn exit_ends_the_run() {
+    let module = parse_module("extern exit attrs noreturn args d32 returns void
+
+ir3function main returns d32 {
+@0:
+  $0 = const d32 3
+  call void exit d32 $0
+  trap
+
+}
+").unwrap();
+    let target = Target::rv64();
+    let mut interp = Interpreter::new(&module, &target);
+    interp.define_exit("exit");
+    let err = interp.call("main", &[]).unwrap_err();
+    assert!(matches!(err.kind, IR3ErrKind::Exit(3)));
+  }
+}
@@ -0,0 +1,299 @@
+//! Interprocedural constant propagation and function specialization.
+//!
+//! If every call to a function passes the same constant for an argument, the `arg` op in the callee
+//! is replaced with that constant. Call sites that pass other constants get a clone of the callee
+//! with those constants substituted, named `<callee>$spec$<c1>$<c2>...` (with `_` for arguments that are not substituted).
+//! Only arguments that decide something (compares, branches, `select`s, multiplication, division and shifts) are
+//! specialized on, since those are the ones that let the clone be simplified.
+//! Cloning is limited to small, non-recursive callees and to `SPECIALIZE_BUDGET` ops for the whole module.
+//!
+//! Signatures are never changed, so callers still pass every argument.
+//! Functions that may be called from elsewhere (`main`, `export` functions and functions whose address is taken) are left alone.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::ir3::callgraph::{address_taken_by_globals, CallGraph};
+use crate::ir3::model::{IR3EndOp, IR3Function, IR3FunctionAttr, IR3Module, IR3OpKind};
+
+/// Largest callee, in ops, that is cloned for specialization.
+const MAX_SPECIALIZE_SIZE: usize = 40;
+/// Maximum number of ops added by cloning across the module.
+const SPECIALIZE_BUDGET: usize = 400;
+
+/// A call site, as the index of the calling function, its block and op.
+type CallSite = (usize, usize, usize);
+
+pub fn propagate_constants(module: &mut IR3Module) {
+  let graph = CallGraph::new(module);
+  let mut fixed = module.functions.iter()
+    .filter(|v| v.name == "main" || v.has_attr(IR3FunctionAttr::Export))
+    .map(|v| v.name.clone())
+    .chain(address_taken_by_globals(module).map(str::to_owned))
+    .collect::<HashSet<_>>();
+  for op in module.functions.iter().flat_map(|v| &v.basic_blocks).flat_map(|v| &v.instructions) {
+    if let IR3OpKind::GlobalAddr(name) = &op.kind {
+      fixed.insert(name.clone());
+    }
+  }
+  // the constant passed for each argument at every call site of each candidate
+  let mut sites = BTreeMap::<String, Vec<(CallSite, Vec<Option<u64>>)>>::new();
+  for (fi, func) in module.functions.iter().enumerate() {
+    let consts = func.basic_blocks.iter()
+      .flat_map(|v| &v.instructions)
+      .filter_map(|op| match op.kind {
+        IR3OpKind::Const(c) => Some((op.output[0], c)),
+        _ => None,
+      })
+      .collect::<HashMap<_, _>>();
+    for (bi, bb) in func.basic_blocks.iter().enumerate() {
+      for (oi, op) in bb.instructions.iter().enumerate() {
+        let IR3OpKind::Call(call) = &op.kind else { continue };
+        if fixed.contains(&call.symbol_name) || module.function(&call.symbol_name).is_none() {
+          continue;
+        }
+        let args = op.input.iter().map(|v| consts.get(v).copied()).collect();
+        sites.entry(call.symbol_name.clone()).or_default().push(((fi, bi, oi), args));
+      }
+    }
+  }
+  // arguments that are the same constant everywhere
+  let mut uniform = HashMap::new();
+  for (name, calls) in &sites {
+    let args = (0..calls[0].1.len())
+      .map(|i| calls.iter().map(|(_, args)| args[i]).reduce(|a, b| if a == b { a } else { None }).flatten())
+      .collect::<Vec<_>>();
+    let func = module.functions.iter_mut().find(|v| v.name == *name).unwrap();
+    substitute_args(func, &args);
+    uniform.insert(name.clone(), args);
+  }
+  // clones for the remaining constants
+  let mut budget = SPECIALIZE_BUDGET;
+  let mut clones = BTreeMap::<String, IR3Function>::new();
+  for (name, calls) in &sites {
+    let size = module.function(name).unwrap().basic_blocks.iter().map(|v| v.instructions.len() + 1).sum::<usize>();
+    if size > MAX_SPECIALIZE_SIZE || graph.is_recursive(name) {
+      continue;
+    }
+    let callee = module.function(name).unwrap().clone();
+    let deciding = deciding_args(&callee);
+    for ((fi, bi, oi), args) in calls {
+      let args = args.iter().zip(&uniform[name]).zip(&deciding)
+        .map(|((arg, uniform), deciding)| if uniform.is_none() && *deciding { *arg } else { None })
+        .collect::<Vec<_>>();
+      if args.iter().all(Option::is_none) {
+        continue;
+      }
+      let clone_name = format!("{}$spec${}", name, args.iter()
+        .map(|v| v.map_or("_".to_owned(), |v| v.to_string()))
+        .collect::<Vec<_>>()
+        .join("$"));
+      if !clones.contains_key(&clone_name) {
+        if size > budget {
+          continue;
+        }
+        budget -= size;
+        let mut clone = callee.clone();
+        clone.name = clone_name.clone();
+        substitute_args(&mut clone, &args);
+        clones.insert(clone_name.clone(), clone);
+      }
+      let IR3OpKind::Call(call) = &mut module.functions[*fi].basic_blocks[*bi].instructions[*oi].kind else { unreachable!() };
+      call.symbol_name = clone_name;
+    }
+  }
+  module.functions.extend(clones.into_values());
+}
+
+/// Whether each argument of `func` is used by an op that becomes simpler when it is constant.
+fn deciding_args(func: &IR3Function) -> Vec<bool> {
+  let mut arg_of = HashMap::new();
+  let mut deciding = vec![false; func.args.len()];
+  for op in func.basic_blocks.iter().flat_map(|v| &v.instructions) {
+    if let IR3OpKind::Arg(idx) = op.kind {
+      arg_of.insert(op.output[0], idx as usize);
+    }
+  }
+  for bb in &func.basic_blocks {
+    for op in &bb.instructions {
+      let inputs = match op.kind {
+        IR3OpKind::Cmp(_) => &op.input[..],
+        IR3OpKind::Select => &op.input[..1],
+        IR3OpKind::Smull | IR3OpKind::Umull | IR3OpKind::Sdiv | IR3OpKind::Udiv | IR3OpKind::Srem | IR3OpKind::Urem |
+        IR3OpKind::Sll | IR3OpKind::Srl | IR3OpKind::Sra => &op.input[..],
+        _ => continue,
+      };
+      for var in inputs {
+        if let Some(idx) = arg_of.get(var) {
+          deciding[*idx] = true;
+        }
+      }
+    }
+    if let IR3EndOp::BrIf { cond, .. } | IR3EndOp::Switch { cond, .. } = &bb.ending {
+      if let Some(idx) = arg_of.get(cond) {
+        deciding[*idx] = true;
+      }
+    }
+  }
+  deciding
+}
+
+/// Replaces the `arg` ops of `func` with the given constants.
+fn substitute_args(func: &mut IR3Function, args: &[Option<u64>]) {
+  for op in func.basic_blocks.iter_mut().flat_map(|v| &mut v.instructions) {
+    if let IR3OpKind::Arg(idx) = op.kind {
+      if let Some(value) = args[idx as usize] {
+        op.kind = IR3OpKind::Const(value);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use crate::target::Target;
+  use super::*;
+
+  /// A function whose first argument decides a compare.
+  fn is_one(name: &str, attrs: &str) -> String {
+    format!(
+      "ir3function {name}{attrs} args d32 d32 returns d32 {{\n@0:\n  $0 = arg d32 0\n  $1 = arg d32 1\n  $2 = const d32 1\n  \
+       $3 = cmp d32 eq $0 $2\n  $4 = add d32 $1 $2\n  $5 = select d32 $3 $4 $1\n  ret d32 $5\n}}\n\n"
+    )
+  }
+
+  fn module() -> IR3Module {
+    let mut text = "global fp ptr = held\n\n".to_owned();
+    text += &is_one("pick", "");
+    text += &is_one("lib", " attrs export");
+    text += &is_one("held", "");
+    text += &is_one("taken", "");
+    text += r#"ir3function scale args d32 d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = arg d32 1
+  $2 = umull d32 $0 $1
+  ret d32 $2
+}
+
+ir3function add2 args d32 d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = arg d32 1
+  $2 = add d32 $0 $1
+  ret d32 $2
+}
+
+ir3function main args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = const d32 1
+  $2 = const d32 2
+  $3 = const d32 3
+  $4 = call d32 pick d32 $1 d32 $0
+  $5 = call d32 pick d32 $2 d32 $0
+  $6 = call d32 pick d32 $1 d32 $3
+  $7 = call d32 scale d32 $0 d32 $3
+  $8 = call d32 scale d32 $4 d32 $3
+  $9 = call d32 add2 d32 $1 d32 $0
+  $10 = call d32 add2 d32 $2 d32 $0
+  $11 = call d32 lib d32 $1 d32 $0
+  $12 = call d32 held d32 $1 d32 $0
+  $13 = call d32 taken d32 $1 d32 $0
+  $14 = global_addr ptr taken
+  $15 = add d32 $4 $5
+  $16 = add d32 $15 $6
+  $17 = add d32 $16 $7
+  $18 = add d32 $17 $8
+  $19 = add d32 $18 $9
+  $20 = add d32 $19 $10
+  $21 = add d32 $20 $11
+  $22 = add d32 $21 $12
+  $23 = add d32 $22 $13
+  ret d32 $23
+}
+"#;
+    parse_module(&text).unwrap()
+  }
+
+  fn arg_count(func: &IR3Function) -> usize {
+    func.basic_blocks.iter().flat_map(|v| &v.instructions).filter(|op| matches!(op.kind, IR3OpKind::Arg(_))).count()
+  }
+
+  fn callees(func: &IR3Function) -> Vec<&str> {
+    func.basic_blocks.iter()
+      .flat_map(|v| &v.instructions)
+      .filter_map(|op| if let IR3OpKind::Call(call) = &op.kind { Some(call.symbol_name.as_str()) } else { None })
+      .collect()
+  }
+
+  #[test]
+  fn substitutes_and_specializes() {
+    let orig = module();
+    let mut module = orig.clone();
+    propagate_constants(&mut module);
+    verify_module(&module).unwrap();
+    let target = Target::rv64();
+    let mut orig_interp = Interpreter::new(&orig, &target);
+    let mut interp = Interpreter::new(&module, &target);
+    for x in [0, 1, 2, 1000, u32::MAX as u64] {
+      assert_eq!(interp.call("main", &[x]).unwrap(), orig_interp.call("main", &[x]).unwrap());
+    }
+    // one clone per distinct constant, shared by the call sites passing it
+    let names = module.functions.iter().map(|v| v.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(&names[orig.functions.len()..], ["pick$spec$1$_", "pick$spec$2$_"]);
+    assert_eq!(arg_count(module.function("pick$spec$1$_").unwrap()), 1);
+    assert_eq!(
+      callees(module.function("main").unwrap()),
+      ["pick$spec$1$_", "pick$spec$2$_", "pick$spec$1$_", "scale", "scale", "add2", "add2", "lib", "held", "taken"]
+    );
+    // the constant passed everywhere is substituted in place
+    assert_eq!(arg_count(module.function("scale").unwrap()), 1);
+    // the first argument of `add2` does not decide anything, and the others may be called from elsewhere
+    for name in ["add2", "lib", "held", "taken", "main"] {
+      assert_eq!(arg_count(module.function(name).unwrap()), orig.function(name).unwrap().args.len(), "{}", name);
+    }
+  }
+
+  /// `callee` with its first argument deciding a compare, padded to `size`, called with `calls` different constants.
+  fn sized(size: usize, calls: usize) -> IR3Module {
+    let mut text = "ir3function callee args d32 d32 returns d32 {\n@0:\n  $0 = arg d32 0\n  $1 = arg d32 1\n  \
+                    $2 = cmp d32 eq $0 $1\n  $3 = zext d32 d1 $2\n".to_owned();
+    // 4 ops so far, and the ending counts as one
+    for i in 4..size - 1 {
+      text += &format!("  ${} = add d32 ${} $1\n", i, i - 1);
+    }
+    text += &format!("  ret d32 ${}\n}}\n\nir3function main args d32 returns void {{\n@0:\n  $0 = arg d32 0\n", size - 2);
+    for i in 0..calls {
+      text += &format!("  ${} = const d32 {}\n  ${} = call d32 callee d32 ${} d32 $0\n", 2 * i + 1, i, 2 * i + 2, 2 * i + 1);
+    }
+    text += "  ret void\n}\n";
+    parse_module(&text).unwrap()
+  }
+
+  #[test]
+  fn limits() {
+    let clones = |mut module: IR3Module| {
+      propagate_constants(&mut module);
+      verify_module(&module).unwrap();
+      module.functions.len() - 2
+    };
+    assert_eq!(clones(sized(MAX_SPECIALIZE_SIZE, 2)), 2);
+    assert_eq!(clones(sized(MAX_SPECIALIZE_SIZE + 1, 2)), 0);
+    // the budget runs out, the remaining call sites keep calling the original
+    let calls = SPECIALIZE_BUDGET / MAX_SPECIALIZE_SIZE + 2;
+    assert_eq!(clones(sized(MAX_SPECIALIZE_SIZE, calls)), SPECIALIZE_BUDGET / MAX_SPECIALIZE_SIZE);
+  }
+
+  #[test]
+  fn skips_recursive_callees() {
+    let text = is_one("rec", "").replace("  ret d32 $5", "  $6 = call d32 rec d32 $0 d32 $5\n  ret d32 $6")
+      + "ir3function main args d32 returns d32 {\n@0:\n  $0 = arg d32 0\n  $1 = const d32 1\n  $2 = const d32 2\n  \
+         $3 = call d32 rec d32 $1 d32 $0\n  $4 = call d32 rec d32 $2 d32 $0\n  $5 = add d32 $3 $4\n  ret d32 $5\n}\n";
+    let mut module = parse_module(&text).unwrap();
+    propagate_constants(&mut module);
+    assert_eq!(module.functions.len(), 2);
+    assert_eq!(callees(module.function("main").unwrap()), ["rec", "rec"]);
+  }
+}
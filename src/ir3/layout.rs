@@ -0,0 +1,194 @@
+//! Basic block layout.
+//!
+//! Blocks are ordered so that each branch falls through to its most likely successor where possible.
+//! Branch probabilities come from execution counts of each block if a profile is available, and otherwise
+//! from static heuristics:
+//! - Edges that stay in a loop are taken much more often than edges that leave it.
+//! - Blocks that only lead to `unreachable` or `trap` are cold, and are never taken if there is another choice.
+//!
+//! The layout is built greedily from the entry block: each block is followed by its most likely successor that has
+//! not been placed yet. When there is none, the next chain starts at the first unplaced block in reverse postorder.
+//! Cold blocks go last.
+
+use std::collections::{HashMap, HashSet};
+use crate::ir3::cfg::{Cfg, DomTree};
+use crate::ir3::loops::find_loops;
+use crate::ir3::model::{IR3BBID, IR3EndOp, IR3Function};
+
+/// Relative weight of an edge that stays in a loop, against one that leaves it.
+const LOOP_STAY_WEIGHT: f64 = 31.0;
+
+/// Number of times each block was executed.
+pub type BlockCounts = HashMap<IR3BBID, u64>;
+
+/// Blocks from which every path ends in `unreachable` or `trap`.
+pub fn cold_blocks(func: &IR3Function) -> HashSet<IR3BBID> {
+  let mut cold = HashSet::new();
+  let mut changed = true;
+  while changed {
+    changed = false;
+    for bb in &func.basic_blocks {
+      let succs = bb.ending.successors();
+      let is_cold = match bb.ending {
+        IR3EndOp::Unreachable | IR3EndOp::Trap => true,
+        IR3EndOp::Ret { .. } => false,
+        _ => succs.iter().all(|v| cold.contains(v)),
+      };
+      if is_cold && cold.insert(bb.id) {
+        changed = true;
+      }
+    }
+  }
+  cold
+}
+
+/// The probability of each edge `(from, to)` of the CFG being taken when `from` is left.
+pub fn branch_probabilities(func: &IR3Function, counts: Option<&BlockCounts>) -> HashMap<(IR3BBID, IR3BBID), f64> {
+  let cfg = Cfg::new(func);
+  let dom = DomTree::new(&cfg);
+  let loops = find_loops(&cfg, &dom);
+  let cold = cold_blocks(func);
+  let mut probs = HashMap::new();
+  for block in &cfg.rpo {
+    let succs = &cfg.succs[block];
+    // the innermost loop containing the block
+    let lp = loops.iter().find(|v| v.blocks.contains(block));
+    let static_weight = |succ: &IR3BBID| {
+      if cold.contains(succ) && !cold.contains(block) {
+        0.0
+      } else if lp.is_some_and(|v| v.blocks.contains(succ)) {
+        LOOP_STAY_WEIGHT
+      } else {
+        1.0
+      }
+    };
+    // blocks added after the profile was taken have no counts
+    let counted = counts.and_then(|counts| succs.iter().map(|v| counts.get(v).map(|c| *c as f64)).collect::<Option<Vec<_>>>());
+    let weights = match counted {
+      Some(weights) if weights.iter().sum::<f64>() > 0.0 => weights,
+      _ => succs.iter().map(static_weight).collect(),
+    };
+    let total = weights.iter().sum::<f64>();
+    for (succ, weight) in succs.iter().zip(weights) {
+      let prob = if total > 0.0 { weight / total } else { 1.0 / succs.len() as f64 };
+      probs.insert((*block, *succ), prob);
+    }
+  }
+  probs
+}
+
+/// Reorders the blocks of `func` to make the likely successor of each block follow it. The entry block stays first.
+pub fn layout_blocks(func: &mut IR3Function, counts: Option<&BlockCounts>) {
+  let cfg = Cfg::new(func);
+  let probs = branch_probabilities(func, counts);
+  let cold = cold_blocks(func);
+  let mut placed = HashSet::new();
+  let mut order = vec![];
+  let mut cur = Some(cfg.entry);
+  while let Some(block) = cur {
+    placed.insert(block);
+    order.push(block);
+    let mut succs = cfg.succs[&block].iter()
+      .filter(|v| !placed.contains(*v) && (cold.contains(&block) || !cold.contains(*v)))
+      .collect::<Vec<_>>();
+    // stable, so ties keep the order of the successors
+    succs.sort_by(|a, b| probs[&(block, **b)].total_cmp(&probs[&(block, **a)]));
+    cur = succs.first().map(|v| **v)
+      .or_else(|| cfg.rpo.iter().find(|v| !placed.contains(*v) && !cold.contains(*v)).copied())
+      .or_else(|| cfg.rpo.iter().find(|v| !placed.contains(*v)).copied());
+  }
+  let position = order.iter().enumerate().map(|(i, v)| (*v, i)).collect::<HashMap<_, _>>();
+  // unreachable blocks keep their relative order at the end
+  func.basic_blocks.sort_by_key(|v| position.get(&v.id).copied().unwrap_or(usize::MAX));
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::parse::parse_module;
+  use super::*;
+
+  /// A loop, and a path to `unreachable`, with the blocks out of order.
+  const FUNC: &str = r#"ir3function f args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = const d32 0
+  $2 = cmp d32 eq $0 $1
+  br_if $2 @5 @1
+
+@4:
+  ret d32 $3
+
+@6:
+  unreachable
+
+@3:
+  $6 = const d32 1
+  $5 = sub d32 $3 $6
+  br @2
+
+@5:
+  br @6
+
+@1:
+  br @2
+
+@2:
+  $3 = phi d32 $0 @1 $5 @3
+  $4 = cmp d32 ne $3 $1
+  br_if $4 @3 @4
+}
+"#;
+
+  fn func() -> IR3Function {
+    parse_module(FUNC).unwrap().functions.remove(0)
+  }
+
+  fn order(func: &IR3Function) -> Vec<IR3BBID> {
+    func.basic_blocks.iter().map(|v| v.id).collect()
+  }
+
+  #[test]
+  fn cold() {
+    let mut cold = cold_blocks(&func()).into_iter().collect::<Vec<_>>();
+    cold.sort();
+    assert_eq!(cold, [5, 6]);
+  }
+
+  #[test]
+  fn static_probabilities() {
+    let probs = branch_probabilities(&func(), None);
+    assert_eq!(probs[&(0, 5)], 0.0);
+    assert_eq!(probs[&(0, 1)], 1.0);
+    assert_eq!(probs[&(2, 3)], LOOP_STAY_WEIGHT / (LOOP_STAY_WEIGHT + 1.0));
+    assert_eq!(probs[&(2, 4)], 1.0 / (LOOP_STAY_WEIGHT + 1.0));
+    assert_eq!(probs[&(3, 2)], 1.0);
+    // within the cold path, the only successor is still taken
+    assert_eq!(probs[&(5, 6)], 1.0);
+  }
+
+  #[test]
+  fn profile_probabilities() {
+    let counts = BlockCounts::from([(0, 1), (1, 1), (2, 100), (3, 1), (4, 99), (5, 0), (6, 0)]);
+    let probs = branch_probabilities(&func(), Some(&counts));
+    assert_eq!(probs[&(2, 3)], 0.01);
+    assert_eq!(probs[&(2, 4)], 0.99);
+    // successors without counts, or that were never run, fall back to the static weights
+    let counts = BlockCounts::from([(0, 1), (1, 1), (2, 100), (4, 99)]);
+    let probs = branch_probabilities(&func(), Some(&counts));
+    assert_eq!(probs[&(2, 3)], LOOP_STAY_WEIGHT / (LOOP_STAY_WEIGHT + 1.0));
+    let counts = BlockCounts::from([(0, 0), (5, 0), (1, 0)]);
+    assert_eq!(branch_probabilities(&func(), Some(&counts))[&(0, 5)], 0.0);
+  }
+
+  #[test]
+  fn fallthrough_order() {
+    let mut f = func();
+    layout_blocks(&mut f, None);
+    // the loop body follows its header, the exit comes after, and the cold path goes last
+    assert_eq!(order(&f), [0, 1, 2, 3, 4, 5, 6]);
+    let counts = BlockCounts::from([(0, 1), (1, 1), (2, 100), (3, 1), (4, 99), (5, 0), (6, 0)]);
+    let mut f = func();
+    layout_blocks(&mut f, Some(&counts));
+    assert_eq!(order(&f), [0, 1, 2, 4, 3, 5, 6]);
+  }
+}
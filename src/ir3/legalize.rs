@@ -0,0 +1,904 @@
+//! Type legalization.
+//!
+//! IR3 allows arithmetic on any `d<n>`, but targets can only compute on the widths
+//! listed in `Target::legal_widths`. This pass rewrites a function so that every
+//! arithmetic op uses a legal width, while keeping the wraparound semantics of the original width.
+//!
+//! - Data that is wider than any legal width (such as `d64` on rv32) is expanded into
+//!   pairs of (low, high) halves. Only data exactly twice the widest legal width can be expanded.
+//! - Data that is narrower than a legal width is promoted to the next legal width.
+//!   Promoted values are always kept zero-extended: ops that can carry garbage into the upper bits
+//!   are masked afterwards, and ops that look at the sign bit sign-extend their operands first.
+//!
+//! See the "Legalization" section of `doc/ir3.md` for the shape of the output.
+
+use std::collections::HashMap;
+use std::mem::take;
+use crate::common::err::{IR3Err, IR3ErrKind, IR3Result};
+use crate::ir3::builder::IR3OpBuilder;
+use crate::ir3::model::{IR3Call, IR3CompareMode, IR3EndOp, IR3Function, IR3Module, IR3Op, IR3OpKind, IR3Phi, IR3Type, IR3VarID};
+use crate::target::Target;
+
+/// Legalizes every function in the module, along with the signatures of external functions.
+pub fn legalize_module(module: &mut IR3Module, target: &Target) -> IR3Result<()> {
+  for func in &mut module.functions {
+    legalize_types(func, target)?;
+  }
+  for ext in &mut module.externs {
+    ext.args = legalize_signature(&ext.args, &ext.name, target)?;
+    ext.ret = legalize_signature(&ext.ret, &ext.name, target)?;
+  }
+  Ok(())
+}
+
+/// Legalizes the argument or return types of a function: wide types are split into halves,
+/// and narrow types are promoted.
+fn legalize_signature(types: &[IR3Type], func: &str, target: &Target) -> IR3Result<Vec<IR3Type>> {
+  let widest = *target.legal_widths.last().unwrap();
+  let mut legal = vec![];
+  for ty in types {
+    if matches!(ty, IR3Type::Float(_)) && !target.features.float {
+      return Err(IR3Err::new(IR3ErrKind::UnsupportedType(*ty, target.name), func));
+    }
+    match width_of(*ty) {
+      Some(w) if w == widest * 2 => legal.extend([IR3Type::Data(widest); 2]),
+      Some(w) if w > widest => return Err(IR3Err::new(IR3ErrKind::UnsupportedType(*ty, target.name), func)),
+      _ => legal.push(promote_type(*ty, target)),
+    }
+  }
+  Ok(legal)
+}
+
+pub fn legalize_types(func: &mut IR3Function, target: &Target) -> IR3Result<()> {
+  check_atomics(func, target)?;
+  check_floats(func, target)?;
+  expand_wide(func, target)?;
+  promote_narrow(func, target)?;
+  Ok(())
+}
+
+/// All ones in the lower `width` bits.
+fn mask(width: u32) -> u64 {
+  if width >= 64 { u64::MAX } else { (1 << width) - 1 }
+}
+
+fn width_of(ty: IR3Type) -> Option<u32> {
+  if let IR3Type::Data(w) = ty { Some(w) } else { None }
+}
+
+fn unsupported(func: &str, kind: &IR3OpKind, ty: IR3Type, target: &Target) -> IR3Err {
+  IR3Err::new(IR3ErrKind::UnsupportedOp(kind.name(), ty, target.name), func)
+}
+
+/// Atomics cannot be split or emulated, so they have to be of a width the target supports.
+fn check_atomics(func: &IR3Function, target: &Target) -> IR3Result<()> {
+  let widest = *target.atomic_widths.last().unwrap();
+  for op in func.basic_blocks.iter().flat_map(|bb| bb.instructions.iter()) {
+    let width = width_of(op.ty).unwrap_or(target.ptr_width);
+    let supported = match op.kind {
+      IR3OpKind::AtomicLoad(_) | IR3OpKind::AtomicStore(_) => width <= widest,
+      IR3OpKind::AtomicRmw(..) | IR3OpKind::CmpXchg(_) => target.atomic_widths.contains(&width),
+      _ => true,
+    };
+    if !supported {
+      return Err(unsupported(&func.name, &op.kind, op.ty, target));
+    }
+  }
+  Ok(())
+}
+
+/// There is no soft-float emulation, so floats need a target with an FPU.
+fn check_floats(func: &IR3Function, target: &Target) -> IR3Result<()> {
+  if target.features.float {
+    return Ok(());
+  }
+  let var_types = func.var_types();
+  let all_types = var_types.values().chain(func.args.iter()).chain(func.ret.iter());
+  for ty in all_types {
+    if let IR3Type::Float(_) = ty {
+      return Err(IR3Err::new(IR3ErrKind::UnsupportedType(*ty, target.name), &func.name));
+    }
+  }
+  Ok(())
+}
+
+// ---- Expansion ----
+
+fn expand_wide(func: &mut IR3Function, target: &Target) -> IR3Result<()> {
+  let widest = *target.legal_widths.last().unwrap();
+  let wide = IR3Type::Data(widest * 2);
+  let half = IR3Type::Data(widest);
+  let var_types = func.var_types();
+  let all_types = || var_types.values().chain(func.args.iter()).chain(func.ret.iter());
+  for ty in all_types() {
+    if width_of(*ty).is_some_and(|w| w > widest) && *ty != wide {
+      return Err(IR3Err::new(IR3ErrKind::UnsupportedType(*ty, target.name), &func.name));
+    }
+  }
+  if !all_types().any(|ty| *ty == wide) {
+    return Ok(());
+  }
+
+  // allocate the halves up front, since phis can refer to variables defined later
+  let mut next_var = func.next_var_id();
+  let mut pairs = HashMap::new();
+  for (var, ty) in &var_types {
+    if *ty == wide {
+      pairs.insert(*var, (next_var, next_var + 1));
+      next_var += 2;
+    }
+  }
+  let mut arg_map = vec![];
+  let mut new_args = vec![];
+  for ty in &func.args {
+    arg_map.push(new_args.len() as u32);
+    if *ty == wide {
+      new_args.push(half);
+      new_args.push(half);
+    } else {
+      new_args.push(*ty);
+    }
+  }
+
+  let mut ctx = ExpandCtx {
+    func_name: &func.name,
+    target,
+    widest,
+    half,
+    wide,
+    pairs: &pairs,
+    arg_map: &arg_map,
+    replace: HashMap::new(),
+  };
+  for bb in &mut func.basic_blocks {
+    let mut b = IR3OpBuilder::new(next_var);
+    for op in take(&mut bb.instructions) {
+      ctx.expand_op(&mut b, op)?;
+    }
+    bb.instructions = b.ops;
+    next_var = b.next_var;
+    match &mut bb.ending {
+      IR3EndOp::Ret { values } => {
+        *values = values.iter()
+          .flat_map(|(ty, var)| if *ty == wide {
+            let (lo, hi) = pairs[var];
+            vec![(half, lo), (half, hi)]
+          } else {
+            vec![(*ty, *var)]
+          })
+          .collect();
+      }
+      // `ir3::switch` lowers these into compares first
+      IR3EndOp::Switch { cond, .. } if pairs.contains_key(cond) => {
+        return Err(IR3Err::new(IR3ErrKind::UnsupportedOp("switch", wide, target.name), &func.name));
+      }
+      _ => {}
+    }
+  }
+  let replace = ctx.replace;
+  func.args = new_args;
+  func.ret = func.ret.iter().flat_map(|ty| if *ty == wide { vec![half; 2] } else { vec![*ty] }).collect();
+  func.replace_uses(&replace);
+  Ok(())
+}
+
+struct ExpandCtx<'a> {
+  func_name: &'a str,
+  target: &'a Target,
+  widest: u32,
+  half: IR3Type,
+  wide: IR3Type,
+  pairs: &'a HashMap<IR3VarID, (IR3VarID, IR3VarID)>,
+  arg_map: &'a [u32],
+  replace: HashMap<IR3VarID, IR3VarID>,
+}
+
+impl<'a> ExpandCtx<'a> {
+  fn pair(&self, var: IR3VarID) -> (IR3VarID, IR3VarID) {
+    self.pairs[&var]
+  }
+
+  /// Replaces wide values in a list of typed values with their halves.
+  fn split_values(&self, types: &[IR3Type], vars: &[IR3VarID]) -> (Vec<IR3Type>, Vec<IR3VarID>) {
+    let mut new_types = vec![];
+    let mut new_vars = vec![];
+    for (ty, var) in types.iter().zip(vars) {
+      if *ty == self.wide {
+        let (l, h) = self.pair(*var);
+        new_types.extend([self.half; 2]);
+        new_vars.extend([l, h]);
+      } else {
+        new_types.push(*ty);
+        new_vars.push(*var);
+      }
+    }
+    (new_types, new_vars)
+  }
+
+  fn expand_op(&mut self, b: &mut IR3OpBuilder, op: IR3Op) -> IR3Result<()> {
+    let half = self.half;
+    let d1 = IR3Type::Data(1);
+    match &op.kind {
+      IR3OpKind::Arg(idx) => {
+        let new_idx = self.arg_map[*idx as usize];
+        if op.ty == self.wide {
+          let (rl, rh) = self.pair(op.output[0]);
+          b.push_to(IR3OpKind::Arg(new_idx), half, vec![], rl);
+          b.push_to(IR3OpKind::Arg(new_idx + 1), half, vec![], rh);
+        } else {
+          b.ops.push(IR3Op { kind: IR3OpKind::Arg(new_idx), ..op });
+        }
+        return Ok(());
+      }
+      IR3OpKind::Call(call) => {
+        let (arg_types, input) = self.split_values(&call.arg_types, &op.input);
+        let (ret_types, output) = self.split_values(&call.ret_types, &op.output);
+        b.ops.push(IR3Op {
+          kind: IR3OpKind::Call(IR3Call { symbol_name: call.symbol_name.clone(), arg_types, ret_types }),
+          ty: op.ty,
+          input,
+          output,
+        });
+        return Ok(());
+      }
+      IR3OpKind::Sext(src) | IR3OpKind::Zext(src) | IR3OpKind::SiToFp(src) | IR3OpKind::UiToFp(src) if *src == self.wide => {
+        return Err(unsupported(self.func_name, &op.kind, op.ty, self.target));
+      }
+      IR3OpKind::Trunc(src) if *src == self.wide => {
+        let (al, ah) = self.pair(op.input[0]);
+        if op.ty == self.wide {
+          let (rl, rh) = self.pair(op.output[0]);
+          self.replace.insert(rl, al);
+          self.replace.insert(rh, ah);
+        } else if op.ty == half {
+          self.replace.insert(op.output[0], al);
+        } else {
+          b.push_to(IR3OpKind::Trunc(half), op.ty, vec![al], op.output[0]);
+        }
+        return Ok(());
+      }
+      _ if op.ty != self.wide => {
+        b.ops.push(op);
+        return Ok(());
+      }
+      _ => {}
+    }
+
+    let m = self.widest - 1;
+    match &op.kind {
+      IR3OpKind::Const(v) => {
+        let (rl, rh) = self.pair(op.output[0]);
+        b.push_to(IR3OpKind::Const(v & mask(self.widest)), half, vec![], rl);
+        b.push_to(IR3OpKind::Const(v.checked_shr(self.widest).unwrap_or(0)), half, vec![], rh);
+      }
+      IR3OpKind::And | IR3OpKind::Or | IR3OpKind::Xor => {
+        let ((al, ah), (bl, bh)) = (self.pair(op.input[0]), self.pair(op.input[1]));
+        let (rl, rh) = self.pair(op.output[0]);
+        b.push_to(op.kind.clone(), half, vec![al, bl], rl);
+        b.push_to(op.kind.clone(), half, vec![ah, bh], rh);
+      }
+      IR3OpKind::Not => {
+        let (al, ah) = self.pair(op.input[0]);
+        let (rl, rh) = self.pair(op.output[0]);
+        b.push_to(IR3OpKind::Not, half, vec![al], rl);
+        b.push_to(IR3OpKind::Not, half, vec![ah], rh);
+      }
+      IR3OpKind::Add => {
+        let ((al, ah), (bl, bh)) = (self.pair(op.input[0]), self.pair(op.input[1]));
+        let (rl, rh) = self.pair(op.output[0]);
+        b.push_to(IR3OpKind::Add, half, vec![al, bl], rl);
+        let carry = b.push(IR3OpKind::Cmp(IR3CompareMode::ULt), half, vec![rl, al]);
+        let carry = b.push(IR3OpKind::Zext(d1), half, vec![carry]);
+        let h = b.push(IR3OpKind::Add, half, vec![ah, bh]);
+        b.push_to(IR3OpKind::Add, half, vec![h, carry], rh);
+      }
+      IR3OpKind::Sub => {
+        let ((al, ah), (bl, bh)) = (self.pair(op.input[0]), self.pair(op.input[1]));
+        let (rl, rh) = self.pair(op.output[0]);
+        b.push_to(IR3OpKind::Sub, half, vec![al, bl], rl);
+        let borrow = b.push(IR3OpKind::Cmp(IR3CompareMode::ULt), half, vec![al, bl]);
+        let borrow = b.push(IR3OpKind::Zext(d1), half, vec![borrow]);
+        let h = b.push(IR3OpKind::Sub, half, vec![ah, bh]);
+        b.push_to(IR3OpKind::Sub, half, vec![h, borrow], rh);
+      }
+      IR3OpKind::Smull | IR3OpKind::Umull => {
+        // the low half of a product is the same for signed and unsigned operands
+        let ((al, ah), (bl, bh)) = (self.pair(op.input[0]), self.pair(op.input[1]));
+        let (rl, rh) = self.pair(op.output[0]);
+        b.push_to(IR3OpKind::Umull, half, vec![al, bl], rl);
+        let carry = b.push(IR3OpKind::Umulh, half, vec![al, bl]);
+        let cross1 = b.push(IR3OpKind::Umull, half, vec![al, bh]);
+        let cross2 = b.push(IR3OpKind::Umull, half, vec![ah, bl]);
+        let h = b.push(IR3OpKind::Add, half, vec![carry, cross1]);
+        b.push_to(IR3OpKind::Add, half, vec![h, cross2], rh);
+      }
+      IR3OpKind::Cmp(mode) => {
+        let ((al, ah), (bl, bh)) = (self.pair(op.input[0]), self.pair(op.input[1]));
+        let out = op.output[0];
+        match mode {
+          IR3CompareMode::Eq | IR3CompareMode::Ne => {
+            let l = b.push(IR3OpKind::Xor, half, vec![al, bl]);
+            let h = b.push(IR3OpKind::Xor, half, vec![ah, bh]);
+            let diff = b.push(IR3OpKind::Or, half, vec![l, h]);
+            let zero = b.push_const(half, 0);
+            b.push_to(IR3OpKind::Cmp(*mode), half, vec![diff, zero], out);
+          }
+          _ => {
+            // the high halves decide, unless they are equal
+            let high = b.push(IR3OpKind::Cmp(strict_mode(*mode)), half, vec![ah, bh]);
+            let high_eq = b.push(IR3OpKind::Cmp(IR3CompareMode::Eq), half, vec![ah, bh]);
+            let low = b.push(IR3OpKind::Cmp(unsigned_mode(*mode)), half, vec![al, bl]);
+            let low = b.push(IR3OpKind::And, d1, vec![high_eq, low]);
+            b.push_to(IR3OpKind::Or, d1, vec![high, low], out);
+          }
+        }
+      }
+      IR3OpKind::Sll | IR3OpKind::Srl | IR3OpKind::Sra => {
+        let ((al, ah), (sl, _)) = (self.pair(op.input[0]), self.pair(op.input[1]));
+        let (rl, rh) = self.pair(op.output[0]);
+        // the amount wraps at the full width, which only the low half decides
+        let wrap = b.push_const(half, (2 * self.widest - 1) as u64);
+        let sl = b.push(IR3OpKind::And, half, vec![sl, wrap]);
+        // masks selecting between shifting by less than a half and shifting by a half or more
+        let half_width = b.push_const(half, self.widest as u64);
+        let big = b.push(IR3OpKind::Cmp(IR3CompareMode::UGe), half, vec![sl, half_width]);
+        let big = b.push(IR3OpKind::Zext(d1), half, vec![big]);
+        let zero = b.push_const(half, 0);
+        let big_mask = b.push(IR3OpKind::Sub, half, vec![zero, big]);
+        let small_mask = b.push(IR3OpKind::Not, half, vec![big_mask]);
+        let m_const = b.push_const(half, m as u64);
+        let one = b.push_const(half, 1);
+        let amt = b.push(IR3OpKind::And, half, vec![sl, m_const]);
+        // shifting by (m - amt) then by 1 avoids shifting by the full width when amt is 0
+        let amt_inv = b.push(IR3OpKind::Xor, half, vec![amt, m_const]);
+        if op.kind == IR3OpKind::Sll {
+          let lo_small = b.push(IR3OpKind::Sll, half, vec![al, amt]);
+          let spill = b.push(IR3OpKind::Srl, half, vec![al, one]);
+          let spill = b.push(IR3OpKind::Srl, half, vec![spill, amt_inv]);
+          let hi_small = b.push(IR3OpKind::Sll, half, vec![ah, amt]);
+          let hi_small = b.push(IR3OpKind::Or, half, vec![hi_small, spill]);
+          b.push_to(IR3OpKind::And, half, vec![lo_small, small_mask], rl);
+          let h1 = b.push(IR3OpKind::And, half, vec![hi_small, small_mask]);
+          let h2 = b.push(IR3OpKind::And, half, vec![lo_small, big_mask]);
+          b.push_to(IR3OpKind::Or, half, vec![h1, h2], rh);
+        } else {
+          let hi_small = b.push(op.kind.clone(), half, vec![ah, amt]);
+          let spill = b.push(IR3OpKind::Sll, half, vec![ah, one]);
+          let spill = b.push(IR3OpKind::Sll, half, vec![spill, amt_inv]);
+          let lo_small = b.push(IR3OpKind::Srl, half, vec![al, amt]);
+          let lo_small = b.push(IR3OpKind::Or, half, vec![lo_small, spill]);
+          let l1 = b.push(IR3OpKind::And, half, vec![lo_small, small_mask]);
+          let l2 = b.push(IR3OpKind::And, half, vec![hi_small, big_mask]);
+          b.push_to(IR3OpKind::Or, half, vec![l1, l2], rl);
+          if op.kind == IR3OpKind::Srl {
+            b.push_to(IR3OpKind::And, half, vec![hi_small, small_mask], rh);
+          } else {
+            let sign = b.push(IR3OpKind::Sra, half, vec![ah, m_const]);
+            let h1 = b.push(IR3OpKind::And, half, vec![hi_small, small_mask]);
+            let h2 = b.push(IR3OpKind::And, half, vec![sign, big_mask]);
+            b.push_to(IR3OpKind::Or, half, vec![h1, h2], rh);
+          }
+        }
+      }
+      IR3OpKind::Zext(src) | IR3OpKind::Sext(src) => {
+        let (rl, rh) = self.pair(op.output[0]);
+        let lo = if *src == half {
+          self.replace.insert(rl, op.input[0]);
+          op.input[0]
+        } else {
+          let kind = if let IR3OpKind::Zext(_) = op.kind { IR3OpKind::Zext(*src) } else { IR3OpKind::Sext(*src) };
+          b.push_to(kind, half, op.input.clone(), rl);
+          rl
+        };
+        if let IR3OpKind::Zext(_) = op.kind {
+          b.push_to(IR3OpKind::Const(0), half, vec![], rh);
+        } else {
+          let m_const = b.push_const(half, m as u64);
+          b.push_to(IR3OpKind::Sra, half, vec![lo, m_const], rh);
+        }
+      }
+      IR3OpKind::PtrLoad | IR3OpKind::PtrStore => {
+        let ptr = op.input[0];
+        let offset = b.push_const(half, (self.widest / 8) as u64);
+        // the low half comes first in memory
+        let (lo_ptr, hi_ptr) = (ptr, b.push(IR3OpKind::PtrUadd, half, vec![ptr, offset]));
+        if op.kind == IR3OpKind::PtrLoad {
+          let (rl, rh) = self.pair(op.output[0]);
+          b.push_to(IR3OpKind::PtrLoad, half, vec![lo_ptr], rl);
+          b.push_to(IR3OpKind::PtrLoad, half, vec![hi_ptr], rh);
+        } else {
+          let (dl, dh) = self.pair(op.input[1]);
+          b.push_void(IR3OpKind::PtrStore, half, vec![lo_ptr, dl]);
+          b.push_void(IR3OpKind::PtrStore, half, vec![hi_ptr, dh]);
+        }
+      }
+      IR3OpKind::PtrSadd | IR3OpKind::PtrUadd => {
+        // offsets wider than a pointer are truncated, so only the low half matters
+        let (ol, _) = self.pair(op.input[1]);
+        b.push_to(op.kind.clone(), half, vec![op.input[0], ol], op.output[0]);
+      }
+      IR3OpKind::Select => {
+        let cond = op.input[0];
+        let ((al, ah), (bl, bh)) = (self.pair(op.input[1]), self.pair(op.input[2]));
+        let (rl, rh) = self.pair(op.output[0]);
+        b.push_to(IR3OpKind::Select, half, vec![cond, al, bl], rl);
+        b.push_to(IR3OpKind::Select, half, vec![cond, ah, bh], rh);
+      }
+      IR3OpKind::Phi(phi) => {
+        let (rl, rh) = self.pair(op.output[0]);
+        let (lows, highs): (Vec<_>, Vec<_>) = op.input.iter().map(|v| self.pair(*v)).unzip();
+        b.push_to(IR3OpKind::Phi(IR3Phi { blocks: phi.blocks.clone() }), half, lows, rl);
+        b.push_to(IR3OpKind::Phi(IR3Phi { blocks: phi.blocks.clone() }), half, highs, rh);
+      }
+      _ => return Err(unsupported(self.func_name, &op.kind, op.ty, self.target)),
+    }
+    Ok(())
+  }
+}
+
+/// The strict (non-equal) version of an ordering comparison.
+fn strict_mode(mode: IR3CompareMode) -> IR3CompareMode {
+  match mode {
+    IR3CompareMode::ULe => IR3CompareMode::ULt,
+    IR3CompareMode::UGe => IR3CompareMode::UGt,
+    IR3CompareMode::SLe => IR3CompareMode::SLt,
+    IR3CompareMode::SGe => IR3CompareMode::SGt,
+    _ => mode,
+  }
+}
+
+/// The unsigned version of an ordering comparison.
+fn unsigned_mode(mode: IR3CompareMode) -> IR3CompareMode {
+  match mode {
+    IR3CompareMode::SLt => IR3CompareMode::ULt,
+    IR3CompareMode::SGt => IR3CompareMode::UGt,
+    IR3CompareMode::SLe => IR3CompareMode::ULe,
+    IR3CompareMode::SGe => IR3CompareMode::UGe,
+    _ => mode,
+  }
+}
+
+// ---- Promotion ----
+
+fn promote_narrow(func: &mut IR3Function, target: &Target) -> IR3Result<()> {
+  let mut next_var = func.next_var_id();
+  let mut ctx = PromoteCtx {
+    func_name: &func.name,
+    target,
+    replace: HashMap::new(),
+  };
+  for bb in &mut func.basic_blocks {
+    let mut b = IR3OpBuilder::new(next_var);
+    for op in take(&mut bb.instructions) {
+      ctx.promote_op(&mut b, op)?;
+    }
+    bb.instructions = b.ops;
+    next_var = b.next_var;
+    if let IR3EndOp::Ret { values } = &mut bb.ending {
+      values.iter_mut().for_each(|(ty, _)| *ty = ctx.promote(*ty));
+    }
+  }
+  let replace = ctx.replace;
+  func.args = func.args.iter().map(|ty| promote_type(*ty, target)).collect();
+  func.ret = func.ret.iter().map(|ty| promote_type(*ty, target)).collect();
+  func.replace_uses(&replace);
+  Ok(())
+}
+
+fn promote_type(ty: IR3Type, target: &Target) -> IR3Type {
+  match ty {
+    IR3Type::Data(w) => IR3Type::Data(target.promoted_width(w).unwrap_or(w)),
+    _ => ty
+  }
+}
+
+struct PromoteCtx<'a> {
+  func_name: &'a str,
+  target: &'a Target,
+  replace: HashMap<IR3VarID, IR3VarID>,
+}
+
+impl<'a> PromoteCtx<'a> {
+  fn promote(&self, ty: IR3Type) -> IR3Type {
+    promote_type(ty, self.target)
+  }
+
+  /// Sign-extends the lower `width` bits of a promoted value to the whole register.
+  fn sext_in_reg(&self, b: &mut IR3OpBuilder, var: IR3VarID, width: u32, reg: IR3Type) -> IR3VarID {
+    let reg_width = width_of(reg).unwrap();
+    if width == reg_width {
+      return var;
+    }
+    let shift = b.push_const(reg, (reg_width - width) as u64);
+    let v = b.push(IR3OpKind::Sll, reg, vec![var, shift]);
+    b.push(IR3OpKind::Sra, reg, vec![v, shift])
+  }
+
+  /// Clears everything above the lower `width` bits of a promoted value.
+  fn mask_to(&self, b: &mut IR3OpBuilder, var: IR3VarID, width: u32, reg: IR3Type) -> IR3VarID {
+    if Some(width) == width_of(reg) {
+      return var;
+    }
+    let m = b.push_const(reg, mask(width));
+    b.push(IR3OpKind::And, reg, vec![var, m])
+  }
+
+  fn promote_op(&mut self, b: &mut IR3OpBuilder, op: IR3Op) -> IR3Result<()> {
+    let reg = self.promote(op.ty);
+    let narrow = reg != op.ty;
+    let width = width_of(op.ty).unwrap_or(0);
+    let result = match &op.kind {
+      IR3OpKind::Cmp(_) | IR3OpKind::Fcmp(_) => {
+        let mut input = op.input.clone();
+        if narrow && matches!(op.kind, IR3OpKind::Cmp(mode) if is_signed(mode)) {
+          input = input.iter().map(|v| self.sext_in_reg(b, *v, width, reg)).collect();
+        }
+        let cond_reg = self.promote(IR3Type::Data(1));
+        if cond_reg == IR3Type::Data(1) {
+          b.push(op.kind.clone(), reg, input)
+        } else {
+          let cond = b.push(op.kind.clone(), reg, input);
+          b.push(IR3OpKind::Zext(IR3Type::Data(1)), cond_reg, vec![cond])
+        }
+      }
+      IR3OpKind::Zext(src) | IR3OpKind::Sext(src) => {
+        let src_reg = self.promote(*src);
+        let src_width = width_of(*src).unwrap();
+        if !narrow && src_reg == *src {
+          b.ops.push(op);
+          return Ok(());
+        }
+        let mut v = op.input[0];
+        if src_reg != reg {
+          v = b.push(IR3OpKind::Zext(src_reg), reg, vec![v]);
+        }
+        if let IR3OpKind::Sext(_) = op.kind {
+          v = self.sext_in_reg(b, v, src_width, reg);
+          v = self.mask_to(b, v, width, reg);
+        }
+        v
+      }
+      IR3OpKind::Call(call) => {
+        let arg_types = call.arg_types.iter().map(|ty| self.promote(*ty)).collect();
+        let ret_types = call.ret_types.iter().map(|ty| self.promote(*ty)).collect();
+        b.ops.push(IR3Op {
+          kind: IR3OpKind::Call(IR3Call { symbol_name: call.symbol_name.clone(), arg_types, ret_types }),
+          ..op
+        });
+        return Ok(());
+      }
+      IR3OpKind::CmpXchg(_) => {
+        // like loads, the old value is extended right away
+        let cond_reg = self.promote(IR3Type::Data(1));
+        let old = if narrow { b.new_var() } else { op.output[0] };
+        let flag = if cond_reg != IR3Type::Data(1) { b.new_var() } else { op.output[1] };
+        b.ops.push(IR3Op { output: vec![old, flag], ..op.clone() });
+        if narrow {
+          b.push_to(IR3OpKind::Zext(op.ty), reg, vec![old], op.output[0]);
+        }
+        if flag != op.output[1] {
+          b.push_to(IR3OpKind::Zext(IR3Type::Data(1)), cond_reg, vec![flag], op.output[1]);
+        }
+        return Ok(());
+      }
+      // `ir3::emulate` expands these on illegal widths, so only the flag needs promoting
+      IR3OpKind::SaddOverflow | IR3OpKind::UaddOverflow | IR3OpKind::SsubOverflow |
+      IR3OpKind::UsubOverflow | IR3OpKind::SmulOverflow | IR3OpKind::UmulOverflow => {
+        let cond_reg = self.promote(IR3Type::Data(1));
+        if narrow {
+          return Err(unsupported(self.func_name, &op.kind, op.ty, self.target));
+        }
+        if cond_reg == IR3Type::Data(1) {
+          b.ops.push(op);
+        } else {
+          let flag = b.new_var();
+          b.ops.push(IR3Op { output: vec![op.output[0], flag], ..op.clone() });
+          b.push_to(IR3OpKind::Zext(IR3Type::Data(1)), cond_reg, vec![flag], op.output[1]);
+        }
+        return Ok(());
+      }
+      IR3OpKind::SiToFp(src) | IR3OpKind::UiToFp(src) => {
+        let src_reg = self.promote(*src);
+        if src_reg == *src {
+          b.ops.push(op);
+          return Ok(());
+        }
+        if let IR3OpKind::SiToFp(_) = op.kind {
+          let v = self.sext_in_reg(b, op.input[0], width_of(*src).unwrap(), src_reg);
+          b.push(IR3OpKind::SiToFp(src_reg), op.ty, vec![v])
+        } else {
+          b.push(IR3OpKind::UiToFp(src_reg), op.ty, op.input.clone())
+        }
+      }
+      _ if !narrow => {
+        b.ops.push(op);
+        return Ok(());
+      }
+      // floats are never promoted
+      IR3OpKind::Fadd | IR3OpKind::Fsub | IR3OpKind::Fmul | IR3OpKind::Fdiv | IR3OpKind::Fneg |
+      IR3OpKind::Fsqrt | IR3OpKind::Fconv(_) => unreachable!(),
+      IR3OpKind::FpToSi(_) | IR3OpKind::FpToUi(_) => {
+        // the result is unspecified if it does not fit, so converting to the register width is fine
+        let v = b.push(op.kind.clone(), reg, op.input.clone());
+        self.mask_to(b, v, width, reg)
+      }
+      IR3OpKind::And | IR3OpKind::Or | IR3OpKind::Xor | IR3OpKind::Udiv | IR3OpKind::Urem |
+      IR3OpKind::Arg(_) | IR3OpKind::Phi(_) | IR3OpKind::PtrUadd | IR3OpKind::StackAlloc { .. } |
+      IR3OpKind::GlobalAddr(_) | IR3OpKind::Select | IR3OpKind::Popcnt => {
+        b.push(op.kind.clone(), reg, op.input.clone())
+      }
+      IR3OpKind::Trunc(src) => {
+        let mut v = op.input[0];
+        let src_reg = self.promote(*src);
+        if src_reg != reg {
+          v = b.push(IR3OpKind::Trunc(src_reg), reg, vec![v]);
+        }
+        self.mask_to(b, v, width, reg)
+      }
+      IR3OpKind::Clz => {
+        // the promoted bits above the value are all zero
+        let v = b.push(IR3OpKind::Clz, reg, op.input.clone());
+        let extra = b.push_const(reg, (width_of(reg).unwrap() - width) as u64);
+        b.push(IR3OpKind::Sub, reg, vec![v, extra])
+      }
+      IR3OpKind::Ctz => {
+        // a bit just above the value stops the count at the original width
+        let stop = b.push_const(reg, 1 << width);
+        let v = b.push(IR3OpKind::Or, reg, vec![op.input[0], stop]);
+        b.push(IR3OpKind::Ctz, reg, vec![v])
+      }
+      IR3OpKind::Rotl | IR3OpKind::Rotr => {
+        // rotating in the wider register would bring in zeroes, so combine two shifts instead.
+        // shifting by the full original width is fine, since it is less than the register width.
+        let m = b.push_const(reg, (width - 1) as u64);
+        let full = b.push_const(reg, width as u64);
+        let amt = b.push(IR3OpKind::And, reg, vec![op.input[1], m]);
+        let inv = b.push(IR3OpKind::Sub, reg, vec![full, amt]);
+        let (first, second) = if op.kind == IR3OpKind::Rotl {
+          (IR3OpKind::Sll, IR3OpKind::Srl)
+        } else {
+          (IR3OpKind::Srl, IR3OpKind::Sll)
+        };
+        let v1 = b.push(first, reg, vec![op.input[0], amt]);
+        let v2 = b.push(second, reg, vec![op.input[0], inv]);
+        let v = b.push(IR3OpKind::Or, reg, vec![v1, v2]);
+        self.mask_to(b, v, width, reg)
+      }
+      IR3OpKind::Bswap => {
+        // the swapped bytes end up at the top of the register
+        let v = b.push(IR3OpKind::Bswap, reg, op.input.clone());
+        let shift = b.push_const(reg, (width_of(reg).unwrap() - width) as u64);
+        b.push(IR3OpKind::Srl, reg, vec![v, shift])
+      }
+      IR3OpKind::Add | IR3OpKind::Sub | IR3OpKind::Smull | IR3OpKind::Umull | IR3OpKind::Not => {
+        let v = b.push(op.kind.clone(), reg, op.input.clone());
+        self.mask_to(b, v, width, reg)
+      }
+      IR3OpKind::Sll | IR3OpKind::Srl | IR3OpKind::Sra => {
+        // the amount wraps at the original width, not the register width
+        let m = b.push_const(reg, (width - 1) as u64);
+        let amt = b.push(IR3OpKind::And, reg, vec![op.input[1], m]);
+        let a = if op.kind == IR3OpKind::Sra {
+          self.sext_in_reg(b, op.input[0], width, reg)
+        } else {
+          op.input[0]
+        };
+        let v = b.push(op.kind.clone(), reg, vec![a, amt]);
+        if op.kind == IR3OpKind::Srl {
+          v
+        } else {
+          self.mask_to(b, v, width, reg)
+        }
+      }
+      IR3OpKind::Sdiv | IR3OpKind::Srem => {
+        let a = self.sext_in_reg(b, op.input[0], width, reg);
+        let c = self.sext_in_reg(b, op.input[1], width, reg);
+        let v = b.push(op.kind.clone(), reg, vec![a, c]);
+        self.mask_to(b, v, width, reg)
+      }
+      IR3OpKind::Umulh | IR3OpKind::Smulh => {
+        // the full product has to fit in the promoted register
+        if width * 2 > width_of(reg).unwrap() {
+          return Err(unsupported(self.func_name, &op.kind, op.ty, self.target));
+        }
+        let shift = b.push_const(reg, width as u64);
+        if op.kind == IR3OpKind::Umulh {
+          let v = b.push(IR3OpKind::Umull, reg, op.input.clone());
+          b.push(IR3OpKind::Srl, reg, vec![v, shift])
+        } else {
+          let a = self.sext_in_reg(b, op.input[0], width, reg);
+          let c = self.sext_in_reg(b, op.input[1], width, reg);
+          let v = b.push(IR3OpKind::Smull, reg, vec![a, c]);
+          let v = b.push(IR3OpKind::Sra, reg, vec![v, shift]);
+          self.mask_to(b, v, width, reg)
+        }
+      }
+      IR3OpKind::Const(v) => {
+        b.push(IR3OpKind::Const(v & mask(width)), reg, vec![])
+      }
+      IR3OpKind::PtrLoad | IR3OpKind::AtomicLoad(_) | IR3OpKind::AtomicRmw(..) => {
+        // memory accesses of any width are legal, the loaded value is extended right away
+        let v = b.push(op.kind.clone(), op.ty, op.input.clone());
+        b.push(IR3OpKind::Zext(op.ty), reg, vec![v])
+      }
+      IR3OpKind::PtrStore | IR3OpKind::AtomicStore(_) | IR3OpKind::Fence(_) => {
+        // stores only write the lower bits of a promoted value
+        b.ops.push(op);
+        return Ok(());
+      }
+      IR3OpKind::PtrSadd => {
+        let off = self.sext_in_reg(b, op.input[1], width, reg);
+        b.push(IR3OpKind::PtrSadd, reg, vec![op.input[0], off])
+      }
+    };
+    self.replace.insert(op.output[0], result);
+    Ok(())
+  }
+}
+
+fn is_signed(mode: IR3CompareMode) -> bool {
+  matches!(mode, IR3CompareMode::SLt | IR3CompareMode::SGt | IR3CompareMode::SLe | IR3CompareMode::SGe)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use super::*;
+
+  const BINARY: &[&str] = &["add", "sub", "and", "or", "xor", "sll", "srl", "sra", "smull", "umull"];
+  const BINARY_UNSPLIT: &[&str] = &["rotl", "rotr", "smulh", "umulh", "sdiv", "udiv", "srem", "urem"];
+  const UNARY_UNSPLIT: &[&str] = &["clz", "ctz", "popcnt", "bswap"];
+  const OVERFLOW: &[&str] = &[
+    "sadd_overflow", "uadd_overflow", "ssub_overflow", "usub_overflow", "smul_overflow", "umul_overflow",
+  ];
+  const MODES: &[&str] = &["ult", "ugt", "ule", "uge", "slt", "sgt", "sle", "sge", "eq", "ne"];
+
+  /// Builds a module with a function for each op kind that the target can legalize at `d<w>`.
+  fn module_for(w: u32, target: &Target) -> String {
+    let widest = *target.legal_widths.last().unwrap();
+    let split = w > widest;
+    let mut text = String::new();
+    let mut add = |name: String, args: &str, ret: &str, body: String| {
+      text += &format!("ir3function {} args {} returns {} {{\n@0:\n{}}}\n\n", name, args, ret, body);
+    };
+    let two_args = format!("  $0 = arg d{w} 0\n  $1 = arg d{w} 1\n");
+    let binary = BINARY.iter().chain(if split { &[] } else { BINARY_UNSPLIT });
+    for kind in binary {
+      let body = format!("{two_args}  $2 = {kind} d{w} $0 $1\n  ret d{w} $2\n");
+      add(kind.to_string(), &format!("d{w} d{w}"), &format!("d{w}"), body);
+    }
+    let unary = ["not"].iter().chain(if split { &[] } else { UNARY_UNSPLIT });
+    for kind in unary {
+      let body = format!("  $0 = arg d{w} 0\n  $1 = {kind} d{w} $0\n  ret d{w} $1\n");
+      add(kind.to_string(), &format!("d{w}"), &format!("d{w}"), body);
+    }
+    if target.is_legal_width(w) {
+      for kind in OVERFLOW {
+        let body = format!("{two_args}  $2 $3 = {kind} d{w} $0 $1\n  ret d{w} $2 d1 $3\n");
+        add(kind.to_string(), &format!("d{w} d{w}"), &format!("d{w} d1"), body);
+      }
+    }
+    for mode in MODES {
+      let body = format!("{two_args}  $2 = cmp d{w} {mode} $0 $1\n  ret d1 $2\n");
+      add(format!("cmp_{}", mode), &format!("d{w} d{w}"), "d1", body);
+    }
+    for w2 in [8, 16, 32, 64].into_iter().filter(|w2| *w2 != w && *w2 <= widest * 2) {
+      let kinds: &[&str] = if w2 > w { &["zext", "sext"] } else { &["trunc"] };
+      for kind in kinds {
+        let body = format!("  $0 = arg d{w} 0\n  $1 = {kind} d{w2} d{w} $0\n  ret d{w2} $1\n");
+        add(format!("{}_d{}", kind, w2), &format!("d{w}"), &format!("d{w2}"), body);
+      }
+    }
+    let body = format!("  $0 = arg d{w} 0\n  $1 = const d{w} {}\n  $2 = xor d{w} $0 $1\n  ret d{w} $2\n", mask(w) / 3);
+    add("const".to_owned(), &format!("d{w}"), &format!("d{w}"), body);
+    let body = format!("{two_args}  $2 = cmp d{w} ult $0 $1\n  $3 = select d{w} $2 $0 $1\n  ret d{w} $3\n");
+    add("select".to_owned(), &format!("d{w} d{w}"), &format!("d{w}"), body);
+    let body = format!(
+      "  $0 = arg d{w} 0\n  $1 = stack_alloc ptr 16 8\n  ptr_store d{w} $1 $0\n  $2 = ptr_load d{w} $1\n  ret d{w} $2\n"
+    );
+    add("memory".to_owned(), &format!("d{w}"), &format!("d{w}"), body);
+    let body = format!(
+      "{two_args}  $2 = cmp d{w} ult $0 $1\n  br_if $2 @1 @2\n\n@1:\n  $3 = add d{w} $0 $1\n  br @2\n\n\
+       @2:\n  $4 = phi d{w} $0 @0 $3 @1\n  ret d{w} $4\n"
+    );
+    add("phi".to_owned(), &format!("d{w} d{w}"), &format!("d{w}"), body);
+    text
+  }
+
+  /// Calls a legalized function with the original arguments, splitting and joining wide values.
+  fn call_legalized(interp: &mut Interpreter, orig: &IR3Function, legal: &IR3Function, args: &[u64]) -> Option<Vec<u64>> {
+    let half = |ty: &IR3Type| width_of(*ty).unwrap_or(0) / 2;
+    let mut legal_args = vec![];
+    for (ty, v) in orig.args.iter().zip(args) {
+      if legal.args.len() > orig.args.len() && width_of(*ty) == Some(64) {
+        legal_args.extend([v & mask(half(ty)), v >> half(ty)]);
+      } else {
+        legal_args.push(*v);
+      }
+    }
+    let values = interp.call(&legal.name, &legal_args).ok()?;
+    let mut values = values.into_iter();
+    let split_ret = legal.ret.len() > orig.ret.len();
+    Some(orig.ret.iter().map(|ty| {
+      let lo = values.next().unwrap();
+      if split_ret && width_of(*ty) == Some(64) { lo | values.next().unwrap() << half(ty) } else { lo }
+    }).collect())
+  }
+
+  /// Operands that exercise carries, signs and every interesting shift amount.
+  fn operands(w: u32, widest: u32) -> Vec<u64> {
+    let mut vals = vec![
+      0, 1, 2, (w - 1) as u64, w as u64, (w + 1) as u64, widest as u64, 2 * widest as u64,
+      1 << (w - 1), (1 << (w - 1)) - 1, u64::MAX, 0x0123_4567_89ab_cdef, 0xfedc_ba98_7654_3210,
+    ];
+    vals.iter_mut().for_each(|v| *v &= mask(w));
+    vals.sort();
+    vals.dedup();
+    vals
+  }
+
+  #[test]
+  fn matches_interpreter() {
+    for target in [Target::rv32(), Target::rv64()] {
+      let widest = *target.legal_widths.last().unwrap();
+      for w in [8, 16, 32, 64] {
+        let module = parse_module(&module_for(w, &target)).unwrap();
+        let mut legal = module.clone();
+        legalize_module(&mut legal, &target).unwrap_or_else(|e| panic!("{} d{}: {:?}", target.name, w, e));
+        verify_module(&legal).unwrap();
+        let mut orig_interp = Interpreter::new(&module, &target);
+        let mut legal_interp = Interpreter::new(&legal, &target);
+        let vals = operands(w, widest);
+        for func in &module.functions {
+          let legal_func = legal.function(&func.name).unwrap();
+          for a in &vals {
+            for b in &vals {
+              let args = if func.args.len() == 2 { vec![*a, *b] } else { vec![*a] };
+              let expected = orig_interp.call(&func.name, &args).ok();
+              let got = call_legalized(&mut legal_interp, func, legal_func, &args);
+              assert_eq!(got, expected, "{} d{} {}({:#x}, {:#x})", target.name, w, func.name, a, b);
+            }
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn narrow_shift_amount_wraps() {
+    let module = parse_module(
+      "ir3function f args d8 d8 returns d8 {\n@0:\n  $0 = arg d8 0\n  $1 = arg d8 1\n  $2 = sll d8 $0 $1\n  ret d8 $2\n}\n"
+    ).unwrap();
+    let target = Target::rv64();
+    let mut legal = module.clone();
+    legalize_module(&mut legal, &target).unwrap();
+    assert_eq!(Interpreter::new(&legal, &target).call("f", &[3, 9]).unwrap(), vec![6]);
+  }
+
+  #[test]
+  fn split_shift_amount_wraps() {
+    let module = parse_module(
+      "ir3function f args d64 d64 returns d64 {\n@0:\n  $0 = arg d64 0\n  $1 = arg d64 1\n  $2 = sll d64 $0 $1\n  ret d64 $2\n}\n"
+    ).unwrap();
+    let target = Target::rv32();
+    let mut legal = module.clone();
+    legalize_module(&mut legal, &target).unwrap();
+    // x << 64 is x, in halves
+    assert_eq!(Interpreter::new(&legal, &target).call("f", &[5, 7, 64, 0]).unwrap(), vec![5, 7]);
+  }
+
+  #[test]
+  fn rejects_unsupported() {
+    let module = parse_module(
+      "ir3function f args d8 d8 returns d8 {\n@0:\n  $0 = arg d8 0\n  $1 = arg d8 1\n  $2 $3 = uadd_overflow d8 $0 $1\n  ret d8 $2\n}\n"
+    ).unwrap();
+    assert!(legalize_module(&mut module.clone(), &Target::rv64()).is_err());
+    let module = parse_module(
+      "ir3function f args d64 d64 returns d64 {\n@0:\n  $0 = arg d64 0\n  $1 = arg d64 1\n  $2 = udiv d64 $0 $1\n  ret d64 $2\n}\n"
+    ).unwrap();
+    assert!(legalize_module(&mut module.clone(), &Target::rv32()).is_err());
+    assert!(legalize_module(&mut module.clone(), &Target::rv64()).is_ok());
+  }
+}
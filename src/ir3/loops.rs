@@ -0,0 +1,250 @@
+//! Natural loops, their basic induction variables, and trip counts.
+//!
+//! A basic induction variable is a phi in the loop header that starts at some value on entry,
+//! and has a constant added to it (`add` or `sub`) on every trip around the loop.
+//! The trip count (the number of times the body runs) is known when the loop is only left by
+//! a `br_if` in its header on a `cmp` between an induction variable with a constant start and a constant.
+
+use std::collections::{BTreeSet, HashMap};
+use crate::ir3::cfg::{Cfg, DomTree};
+use crate::ir3::model::{IR3BBID, IR3EndOp, IR3Function, IR3OpKind, IR3Type, IR3VarID};
+
+/// Trip counts larger than this are not computed.
+const MAX_TRIP_COUNT: u64 = 1 << 16;
+
+pub struct Loop {
+  pub header: IR3BBID,
+  /// Blocks that branch back to the header.
+  pub latches: Vec<IR3BBID>,
+  /// Every block of the loop, including the header.
+  pub blocks: BTreeSet<IR3BBID>,
+}
+
+impl Loop {
+  /// Blocks outside the loop that are branched to from inside it.
+  pub fn exits(&self, cfg: &Cfg) -> BTreeSet<IR3BBID> {
+    self.blocks.iter()
+      .flat_map(|v| &cfg.succs[v])
+      .filter(|v| !self.blocks.contains(v))
+      .copied()
+      .collect()
+  }
+}
+
+/// Finds the natural loops of a function. Inner loops come before the loops containing them.
+pub fn find_loops(cfg: &Cfg, dom: &DomTree) -> Vec<Loop> {
+  let mut loops = Vec::<Loop>::new();
+  for block in &cfg.rpo {
+    for header in &cfg.succs[block] {
+      if !dom.dominates(*header, *block) {
+        continue;
+      }
+      let mut blocks = BTreeSet::from([*header]);
+      let mut stack = vec![*block];
+      while let Some(v) = stack.pop() {
+        if blocks.insert(v) {
+          stack.extend(&cfg.preds[&v]);
+        }
+      }
+      match loops.iter_mut().find(|v| v.header == *header) {
+        Some(lp) => {
+          lp.latches.push(*block);
+          lp.blocks.extend(blocks);
+        }
+        None => loops.push(Loop { header: *header, latches: vec![*block], blocks }),
+      }
+    }
+  }
+  loops.sort_by_key(|v| v.blocks.len());
+  loops
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InductionVar {
+  /// The phi in the loop header.
+  pub var: IR3VarID,
+  pub ty: IR3Type,
+  /// Value on entry to the loop.
+  pub init: IR3VarID,
+  /// Added on every trip around the loop, modulo the width of `ty`.
+  pub step: u64,
+}
+
+/// Values of the `const` ops in a function.
+pub fn constants(func: &IR3Function) -> HashMap<IR3VarID, u64> {
+  func.basic_blocks.iter()
+    .flat_map(|v| &v.instructions)
+    .filter_map(|op| match op.kind {
+      IR3OpKind::Const(c) => Some((op.output[0], c)),
+      _ => None,
+    })
+    .collect()
+}
+
+fn mask(v: u64, width: u32) -> u64 {
+  if width >= 64 { v } else { v & ((1 << width) - 1) }
+}
+
+/// Finds the basic induction variables of a loop with a single latch.
+pub fn induction_vars(func: &IR3Function, lp: &Loop) -> Vec<InductionVar> {
+  let [latch] = lp.latches[..] else { return vec![] };
+  let consts = constants(func);
+  let defs = func.basic_blocks.iter()
+    .filter(|v| lp.blocks.contains(&v.id))
+    .flat_map(|v| &v.instructions)
+    .filter(|op| op.output.len() == 1)
+    .map(|op| (op.output[0], op))
+    .collect::<HashMap<_, _>>();
+  let header = func.basic_blocks.iter().find(|v| v.id == lp.header).unwrap();
+  let mut ivs = vec![];
+  for op in &header.instructions {
+    let IR3OpKind::Phi(phi) = &op.kind else { continue };
+    let IR3Type::Data(width) = op.ty else { continue };
+    if phi.blocks.len() != 2 {
+      continue;
+    }
+    let back = if phi.blocks[0] == latch { 0 } else { 1 };
+    if phi.blocks[back] != latch || lp.blocks.contains(&phi.blocks[1 - back]) {
+      continue;
+    }
+    let Some(next) = defs.get(&op.input[back]) else { continue };
+    let step = match (&next.kind, &next.input[..]) {
+      (IR3OpKind::Add, [a, b]) if *a == op.output[0] => consts.get(b).copied(),
+      (IR3OpKind::Add, [a, b]) if *b == op.output[0] => consts.get(a).copied(),
+      (IR3OpKind::Sub, [a, b]) if *a == op.output[0] => consts.get(b).map(|v| v.wrapping_neg()),
+      _ => None,
+    };
+    if let Some(step) = step {
+      ivs.push(InductionVar { var: op.output[0], ty: op.ty, init: op.input[1 - back], step: mask(step, width) });
+    }
+  }
+  ivs
+}
+
+/// The exit test of a counted loop.
+pub struct TripCount {
+  pub iv: InductionVar,
+  /// How many times the body runs.
+  pub count: u64,
+  /// The block the header branches to when the loop is left.
+  pub exit: IR3BBID,
+}
+
+/// Computes how many times the body of a loop runs, if that is a constant.
+pub fn trip_count(func: &IR3Function, cfg: &Cfg, lp: &Loop) -> Option<TripCount> {
+  let exits = lp.exits(cfg);
+  let header = func.basic_blocks.iter().find(|v| v.id == lp.header).unwrap();
+  let IR3EndOp::BrIf { block1, block2, cond } = header.ending else { return None };
+  // the header has to be the only way out
+  let leaving = lp.blocks.iter()
+    .filter(|v| cfg.succs[v].iter().any(|succ| exits.contains(succ)))
+    .count();
+  if exits.len() != 1 || leaving != 1 || lp.blocks.contains(&block1) == lp.blocks.contains(&block2) {
+    return None;
+  }
+  let (stay, exit) = if lp.blocks.contains(&block1) { (true, block2) } else { (false, block1) };
+  let cmp = header.instructions.iter().find(|op| op.output == [cond])?;
+  let IR3OpKind::Cmp(mode) = cmp.kind else { return None };
+  let consts = constants(func);
+  let ivs = induction_vars(func, lp);
+  let (iv, bound, iv_first) = ivs.iter().find_map(|iv| match cmp.input[..] {
+    [a, b] if a == iv.var => Some((iv, consts.get(&b)?, true)),
+    [a, b] if b == iv.var => Some((iv, consts.get(&a)?, false)),
+    _ => None,
+  })?;
+  let width = match iv.ty {
+    IR3Type::Data(w) => w,
+    _ => return None,
+  };
+  let mut value = mask(*consts.get(&iv.init)?, width);
+  let bound = mask(*bound, width);
+  let mut count = 0;
+  loop {
+    let (a, b) = if iv_first { (value, bound) } else { (bound, value) };
+    if mode.holds(a, b, width) != stay {
+      break;
+    }
+    count += 1;
+    if count > MAX_TRIP_COUNT {
+      return None;
+    }
+    value = mask(value.wrapping_add(iv.step), width);
+  }
+  Some(TripCount { iv: iv.clone(), count, exit })
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::parse::parse_module;
+  use super::*;
+
+  /// A loop whose induction variable `iv` starts at `init`, is updated by `step` (such as `add 3`), and which runs
+  /// while `cond` (such as `ult iv bound`) holds. The operands of the compare are `iv`, `bound` or `arg`.
+  fn counted_loop(ty: &str, init: u64, step: &str, cond: &str, bound: u64) -> IR3Function {
+    let (step_op, step) = step.split_once(' ').unwrap();
+    let [mode, lhs, rhs] = cond.split(' ').collect::<Vec<_>>()[..] else { panic!() };
+    let var = |v: &str| match v {
+      "iv" => "$3",
+      "bound" => "$6",
+      _ => "$2",
+    };
+    let text = format!("ir3function f args {ty} returns void {{
+@0:
+  $0 = const {ty} {init}
+  $1 = const {ty} {step}
+  $2 = arg {ty} 0
+  $6 = const {ty} {bound}
+  br @1
+
+@1:
+  $3 = phi {ty} $0 @0 $5 @2
+  $4 = cmp {ty} {mode} {} {}
+  br_if $4 @2 @3
+
+@2:
+  $5 = {step_op} {ty} $3 $1
+  br @1
+
+@3:
+  ret void
+
+}}
+", var(lhs), var(rhs));
+    parse_module(&text).unwrap().functions.remove(0)
+  }
+
+  fn trips(func: &IR3Function) -> Option<u64> {
+    let cfg = Cfg::new(func);
+    let loops = find_loops(&cfg, &DomTree::new(&cfg));
+    assert_eq!(loops.len(), 1);
+    trip_count(func, &cfg, &loops[0]).map(|v| v.count)
+  }
+
+  #[test]
+  fn counts_sub_steps() {
+    assert_eq!(trips(&counted_loop("d32", 10, "sub 1", "ugt iv bound", 0)), Some(10));
+    assert_eq!(trips(&counted_loop("d32", 25, "sub 3", "sgt iv bound", 6)), Some(7));
+    // subtracting -1 counts up
+    assert_eq!(trips(&counted_loop("d32", 0, "sub 4294967295", "slt iv bound", 10)), Some(10));
+  }
+
+  #[test]
+  fn counts_ne_and_ult_exits() {
+    assert_eq!(trips(&counted_loop("d32", 0, "add 2", "ne iv bound", 10)), Some(5));
+    assert_eq!(trips(&counted_loop("d32", 0, "add 3", "ult iv bound", 10)), Some(4));
+    assert_eq!(trips(&counted_loop("d32", 20, "add 1", "ult iv bound", 10)), Some(0));
+    // with the bound first, the compare is turned around
+    assert_eq!(trips(&counted_loop("d32", 0, "add 3", "ugt bound iv", 10)), Some(4));
+    // the bound has to be a constant
+    assert_eq!(trips(&counted_loop("d32", 0, "add 1", "ult iv arg", 0)), None);
+  }
+
+  #[test]
+  fn counts_wrapping_ivs() {
+    assert_eq!(trips(&counted_loop("d8", 250, "add 1", "ne iv bound", 4)), Some(10));
+    // 0, 3, ..., 255, 2, 5, ..., 254, 1 wraps around twice
+    assert_eq!(trips(&counted_loop("d8", 0, "add 3", "ne iv bound", 1)), Some(171));
+    // an even induction variable never reaches 1
+    assert_eq!(trips(&counted_loop("d8", 0, "add 2", "ne iv bound", 1)), None);
+  }
+}
@@ -0,0 +1,313 @@
+//! Promotion of stack slots to SSA values ("mem2reg").
+//!
+//! A `stack_alloc` can be promoted if its pointer never escapes, that is, it is only ever used
+//! as the address of `ptr_load`s and `ptr_store`s, which all access the same type.
+//! Loads are replaced by the value last stored on every path, with phis inserted
+//! at the iterated dominance frontier of the stores (only where the slot is live).
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use crate::ir3::cfg::{Cfg, DomTree};
+use crate::ir3::model::{IR3BBID, IR3Function, IR3Op, IR3OpKind, IR3Phi, IR3Type, IR3VarID};
+
+struct Slot {
+  var: IR3VarID,
+  ty: IR3Type,
+}
+
+pub fn mem2reg(func: &mut IR3Function) {
+  let slots = find_promotable(func);
+  if slots.is_empty() {
+    return;
+  }
+  let slot_idx = slots.iter()
+    .enumerate()
+    .map(|(i, slot)| (slot.var, i))
+    .collect::<HashMap<_, _>>();
+  let cfg = Cfg::new(func);
+  let dom = DomTree::new(&cfg);
+  let frontiers = dom.frontiers(&cfg);
+  let blocks = func.basic_blocks.iter()
+    .enumerate()
+    .map(|(i, bb)| (bb.id, i))
+    .collect::<HashMap<_, _>>();
+
+  // find where each slot is stored to and where it is live on entry
+  let mut def_blocks = vec![BTreeSet::new(); slots.len()];
+  let mut live_in = vec![HashSet::new(); slots.len()];
+  for bb in &func.basic_blocks {
+    if !cfg.is_reachable(bb.id) {
+      continue;
+    }
+    let mut stored = HashSet::new();
+    for op in &bb.instructions {
+      match op.kind {
+        IR3OpKind::PtrStore => if let Some(i) = slot_idx.get(&op.input[0]) {
+          def_blocks[*i].insert(bb.id);
+          stored.insert(*i);
+        }
+        IR3OpKind::PtrLoad => if let Some(i) = slot_idx.get(&op.input[0]) {
+          if !stored.contains(i) {
+            live_in[*i].insert(bb.id);
+          }
+        }
+        _ => {}
+      }
+    }
+  }
+  for (i, live) in live_in.iter_mut().enumerate() {
+    let mut worklist = live.iter().copied().collect::<Vec<_>>();
+    while let Some(block) = worklist.pop() {
+      for pred in &cfg.preds[&block] {
+        if !def_blocks[i].contains(pred) && live.insert(*pred) {
+          worklist.push(*pred);
+        }
+      }
+    }
+  }
+
+  // place phis at the iterated dominance frontier
+  let mut next_var = func.next_var_id();
+  // (block, slot) -> phi output
+  let mut phis = BTreeMap::new();
+  for (i, defs) in def_blocks.iter().enumerate() {
+    let mut worklist = defs.iter().copied().collect::<Vec<_>>();
+    while let Some(block) = worklist.pop() {
+      for frontier in &frontiers[&block] {
+        if !live_in[i].contains(frontier) || phis.contains_key(&(*frontier, i)) {
+          continue;
+        }
+        phis.insert((*frontier, i), next_var);
+        next_var += 1;
+        if !defs.contains(frontier) {
+          worklist.push(*frontier);
+        }
+      }
+    }
+  }
+
+  // IR3 has no undefined value, so reading a slot that was never written gives 0 (or +0.0),
+  // or the slot's own address for pointers, since there are no pointer constants.
+  let undef = slots.iter()
+    .map(|slot| {
+      if let IR3Type::Data(_) | IR3Type::Float(_) = slot.ty {
+        next_var += 1;
+        next_var - 1
+      } else {
+        slot.var
+      }
+    })
+    .collect::<Vec<_>>();
+
+  let mut rename = Rename {
+    cfg: &cfg,
+    dom: &dom,
+    blocks: &blocks,
+    slot_idx: &slot_idx,
+    phis: &phis,
+    undef: &undef,
+    current: undef.iter().map(|v| vec![*v]).collect(),
+    phi_inputs: HashMap::new(),
+    replace: HashMap::new(),
+    undef_used: vec![false; slots.len()],
+  };
+  rename.rename_block(func, cfg.entry);
+  let Rename { phi_inputs, replace, undef_used, .. } = rename;
+
+  // materialize the phis, then drop the ones that ended up unused
+  for ((block, i), var) in &phis {
+    let bb = &mut func.basic_blocks[blocks[block]];
+    let inputs = &phi_inputs[var];
+    let preds = &cfg.preds[block];
+    bb.instructions.insert(0, IR3Op {
+      kind: IR3OpKind::Phi(IR3Phi { blocks: preds.clone() }),
+      ty: slots[*i].ty,
+      input: preds.iter().map(|p| inputs[p]).collect(),
+      output: vec![*var],
+    });
+  }
+  for (i, slot) in slots.iter().enumerate() {
+    if undef_used[i] && undef[i] != slot.var {
+      func.basic_blocks[0].instructions.insert(0, IR3Op {
+        kind: IR3OpKind::Const(0),
+        ty: slot.ty,
+        input: vec![],
+        output: vec![undef[i]],
+      });
+    }
+  }
+  func.replace_uses(&replace);
+  remove_dead_phis(func, phis.values().copied().collect());
+  remove_unused_slots(func, &slots);
+}
+
+struct Rename<'a> {
+  cfg: &'a Cfg,
+  dom: &'a DomTree,
+  /// Index of each block in the function.
+  blocks: &'a HashMap<IR3BBID, usize>,
+  slot_idx: &'a HashMap<IR3VarID, usize>,
+  phis: &'a BTreeMap<(IR3BBID, usize), IR3VarID>,
+  /// The value each slot has before it is first stored to.
+  undef: &'a [IR3VarID],
+  /// The values stored to each slot on the way down the dominator tree; the last one is current.
+  current: Vec<Vec<IR3VarID>>,
+  /// phi output -> incoming value for each predecessor
+  phi_inputs: HashMap<IR3VarID, HashMap<IR3BBID, IR3VarID>>,
+  replace: HashMap<IR3VarID, IR3VarID>,
+  undef_used: Vec<bool>,
+}
+
+impl<'a> Rename<'a> {
+  /// Walks the dominator tree, tracking the current value of every slot.
+  fn rename_block(&mut self, func: &mut IR3Function, block: IR3BBID) {
+    let (undef, current) = (self.undef, &mut self.current);
+    let depths = current.iter().map(|v| v.len()).collect::<Vec<_>>();
+    for ((phi_block, i), var) in self.phis.range((block, 0)..=(block, usize::MAX)) {
+      debug_assert_eq!(*phi_block, block);
+      current[*i].push(*var);
+    }
+    let bb = &mut func.basic_blocks[self.blocks[&block]];
+    let mut removed = vec![];
+    for (idx, op) in bb.instructions.iter().enumerate() {
+      let slot = match op.kind {
+        IR3OpKind::PtrLoad | IR3OpKind::PtrStore => self.slot_idx.get(&op.input[0]).copied(),
+        _ => None
+      };
+      let Some(i) = slot else { continue };
+      if op.kind == IR3OpKind::PtrLoad {
+        let value = *current[i].last().unwrap();
+        if value == undef[i] {
+          self.undef_used[i] = true;
+        }
+        self.replace.insert(op.output[0], value);
+      } else {
+        current[i].push(op.input[1]);
+      }
+      removed.push(idx);
+    }
+    for idx in removed.into_iter().rev() {
+      bb.instructions.remove(idx);
+    }
+    for succ in &self.cfg.succs[&block] {
+      for ((_, i), var) in self.phis.range((*succ, 0)..=(*succ, usize::MAX)) {
+        let value = *current[*i].last().unwrap();
+        if value == undef[*i] {
+          self.undef_used[*i] = true;
+        }
+        self.phi_inputs.entry(*var).or_default().insert(block, value);
+      }
+    }
+    for child in &self.dom.children[&block] {
+      self.rename_block(func, *child);
+    }
+    for (stack, depth) in self.current.iter_mut().zip(depths) {
+      stack.truncate(depth);
+    }
+  }
+}
+
+/// Finds `stack_alloc`s whose pointer is only used to load and store a single type.
+fn find_promotable(func: &IR3Function) -> Vec<Slot> {
+  let mut candidates = func.basic_blocks[0].instructions.iter()
+    .filter(|op| matches!(op.kind, IR3OpKind::StackAlloc { .. }))
+    .map(|op| (op.output[0], None))
+    .collect::<BTreeMap<IR3VarID, Option<IR3Type>>>();
+  let mut escaped = HashSet::new();
+  for bb in &func.basic_blocks {
+    for op in &bb.instructions {
+      for (idx, var) in op.input.iter().enumerate() {
+        let Some(ty) = candidates.get_mut(var) else { continue };
+        let is_address = idx == 0 && matches!(op.kind, IR3OpKind::PtrLoad | IR3OpKind::PtrStore);
+        if !is_address || ty.is_some_and(|ty| ty != op.ty) {
+          escaped.insert(*var);
+        }
+        *ty = Some(op.ty);
+      }
+    }
+    for var in bb.ending.inputs() {
+      escaped.insert(var);
+    }
+  }
+  candidates.into_iter()
+    .filter(|(var, ty)| !escaped.contains(var) && ty.is_some())
+    .map(|(var, ty)| Slot { var, ty: ty.unwrap() })
+    .collect()
+}
+
+/// Removes inserted phis that are not used by anything other than other dead phis.
+fn remove_dead_phis(func: &mut IR3Function, inserted: HashSet<IR3VarID>) {
+  let mut live = HashSet::new();
+  let mut worklist = vec![];
+  let mut phi_inputs = HashMap::new();
+  for bb in &func.basic_blocks {
+    for op in &bb.instructions {
+      if op.output.len() == 1 && inserted.contains(&op.output[0]) {
+        phi_inputs.insert(op.output[0], op.input.clone());
+      } else {
+        worklist.extend(op.input.iter().copied());
+      }
+    }
+    worklist.extend(bb.ending.inputs());
+  }
+  while let Some(var) = worklist.pop() {
+    if live.insert(var) {
+      if let Some(inputs) = phi_inputs.get(&var) {
+        worklist.extend(inputs.iter().copied());
+      }
+    }
+  }
+  for bb in &mut func.basic_blocks {
+    bb.instructions.retain(|op| op.output.len() != 1 || !inserted.contains(&op.output[0]) || live.contains(&op.output[0]));
+  }
+}
+
+fn remove_unused_slots(func: &mut IR3Function, slots: &[Slot]) {
+  let used = func.basic_blocks.iter()
+    .flat_map(|bb| bb.instructions.iter())
+    .flat_map(|op| op.input.iter().copied())
+    .collect::<HashSet<_>>();
+  let slots = slots.iter().map(|v| v.var).collect::<HashSet<_>>();
+  func.basic_blocks[0].instructions.retain(|op| {
+    !(matches!(op.kind, IR3OpKind::StackAlloc { .. }) && slots.contains(&op.output[0]) && !used.contains(&op.output[0]))
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use crate::target::Target;
+  use super::*;
+
+  #[test]
+  fn uninitialized_float() {
+    let mut module = parse_module(r#"ir3function f args d32 returns f64 {
+@0:
+  $0 = arg d32 0
+  $1 = stack_alloc ptr 8 8
+  $2 = const d32 0
+  $3 = cmp d32 ne $0 $2
+  br_if $3 @1 @2
+
+@1:
+  $4 = const f64 1.5
+  ptr_store f64 $1 $4
+  br @2
+
+@2:
+  $5 = ptr_load f64 $1
+  ret f64 $5
+}
+"#).unwrap();
+    mem2reg(&mut module.functions[0]);
+    verify_module(&module).unwrap();
+    let ops = module.functions[0].basic_blocks.iter().flat_map(|bb| bb.instructions.iter()).collect::<Vec<_>>();
+    assert!(!ops.iter().any(|op| matches!(op.kind, IR3OpKind::StackAlloc { .. } | IR3OpKind::PtrLoad)));
+    assert!(ops.iter().any(|op| op.kind == IR3OpKind::Const(0) && op.ty == IR3Type::Float(64)));
+    let target = Target::rv64();
+    let mut interp = Interpreter::new(&module, &target);
+    assert_eq!(interp.call("f", &[0]).unwrap(), vec![0]);
+    assert_eq!(interp.call("f", &[1]).unwrap(), vec![1.5f64.to_bits()]);
+  }
+}
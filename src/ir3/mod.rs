@@ -3,5 +3,28 @@
 //! Documentation on IR3 can be found in `doc/ir3.md`.
 //!
 
-mod model;
-
+pub mod model;
+pub mod parse;
+pub mod builder;
+pub mod legalize;
+pub mod emulate;
+pub mod cfg;
+pub mod mem2reg;
+pub mod verify;
+pub mod interp;
+pub mod switch;
+pub mod ifconv;
+pub mod sret;
+pub mod callgraph;
+pub mod deadfn;
+pub mod effects;
+pub mod ipcp;
+pub mod tailcall;
+pub mod loops;
+pub mod unroll;
+pub mod alias;
+pub mod dse;
+pub mod layout;
+pub mod schedule;
+pub mod profile;
+pub mod bisect;
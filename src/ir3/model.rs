@@ -1,89 +1,294 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Write};
 use crate::common::util::join;
 
+/// A whole program (or one translation unit of it), as consumed by backends.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct IR3Module {
+  pub externs: Vec<IR3ExternFunction>,
+  pub globals: Vec<IR3Global>,
+  pub rodata: Vec<IR3Data>,
+  pub functions: Vec<IR3Function>,
+}
+
+impl Display for IR3Module {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    for ext in &self.externs {
+      writeln!(f, "{}", ext)?;
+    }
+    for global in &self.globals {
+      writeln!(f, "{}", global)?;
+    }
+    for data in &self.rodata {
+      writeln!(f, "{}", data)?;
+    }
+    for func in &self.functions {
+      writeln!(f, "\n{}", func)?;
+    }
+    Ok(())
+  }
+}
+
+impl IR3Module {
+  pub fn function(&self, name: &str) -> Option<&IR3Function> {
+    self.functions.iter().find(|v| v.name == name)
+  }
+
+  /// Looks up the attributes of a function or extern.
+  pub fn function_attrs(&self, name: &str) -> Option<&[(IR3FunctionAttr, String)]> {
+    self.function(name).map(|v| v.attrs.as_slice())
+      .or_else(|| self.externs.iter().find(|v| v.name == name).map(|v| v.attrs.as_slice()))
+  }
+
+  /// Returns true if `name` is defined or declared by this module.
+  pub fn has_symbol(&self, name: &str) -> bool {
+    self.functions.iter().any(|v| v.name == name)
+      || self.externs.iter().any(|v| v.name == name)
+      || self.globals.iter().any(|v| v.name == name)
+      || self.rodata.iter().any(|v| v.name == name)
+  }
+}
+
+/// A function defined outside the module.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct IR3ExternFunction {
+  pub name: String,
+  pub args: Vec<IR3Type>,
+  pub ret: Vec<IR3Type>,
+  pub attrs: Vec<(IR3FunctionAttr, String)>,
+}
+
+impl Display for IR3ExternFunction {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "extern {}", &self.name)?;
+    if !self.attrs.is_empty() {
+      write!(f, " attrs {}", fmt_attrs(&self.attrs))?;
+    }
+    if !self.args.is_empty() {
+      write!(f, " args {}", join(&self.args, " "))?;
+    }
+    write!(f, " returns {}", fmt_types(&self.ret))
+  }
+}
+
+/// A mutable global variable.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct IR3Global {
+  pub name: String,
+  pub ty: IR3Type,
+  pub init: IR3GlobalInit,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum IR3GlobalInit {
+  Zero,
+  Const(u64),
+  /// The address of another global symbol. Only valid for `ptr` globals.
+  Addr(String),
+}
+
+impl Display for IR3Global {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "global {} {}", &self.name, &self.ty)?;
+    match &self.init {
+      IR3GlobalInit::Zero => Ok(()),
+      IR3GlobalInit::Const(v) => write!(f, " = {}", v),
+      IR3GlobalInit::Addr(name) => write!(f, " = {}", name),
+    }
+  }
+}
+
+/// A read-only blob of bytes, such as a string literal.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct IR3Data {
+  pub name: String,
+  pub align: u32,
+  pub bytes: Vec<u8>,
+}
+
+impl Display for IR3Data {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "rodata {} align {} \"", &self.name, self.align)?;
+    for byte in &self.bytes {
+      match byte {
+        b'"' | b'\\' => write!(f, "\\{}", *byte as char)?,
+        0x20..=0x7e => f.write_char(*byte as char)?,
+        _ => write!(f, "\\x{:02x}", byte)?,
+      }
+    }
+    f.write_char('"')
+  }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct IR3Function {
-  name: String,
-  args: Vec<IR3Type>,
-  ret: IR3Type,
-  basic_blocks: Vec<IR3BasicBlock>,
-  attrs: Vec<(IR3FunctionAttr, String)>
+  pub name: String,
+  pub args: Vec<IR3Type>,
+  /// Types of the returned values, empty for functions that return nothing.
+  pub ret: Vec<IR3Type>,
+  pub basic_blocks: Vec<IR3BasicBlock>,
+  pub attrs: Vec<(IR3FunctionAttr, String)>
 }
 
 impl Display for IR3Function {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
     write!(f, "ir3function {}", &self.name)?;
     if !self.attrs.is_empty() {
-      let attr_str = self.attrs.iter()
-        .map(|(attr, val)| format!("{}={}", attr, val))
-        .collect::<Vec<_>>()
-        .join(" ");
-      write!(f, " attrs {}", attr_str)?;
+      write!(f, " attrs {}", fmt_attrs(&self.attrs))?;
     }
     if !self.args.is_empty() {
       write!(f, " args {}", join(&self.args, " "))?;
     }
-    write!(f, " returns {} {{\n{}\n}}", &self.ret, join(&self.basic_blocks, "\n"))?;
+    write!(f, " returns {} {{\n{}\n}}", fmt_types(&self.ret), join(&self.basic_blocks, "\n"))?;
     Ok(())
   }
 } 
 
+/// Formats a list of return types, which is `void` if there are none.
+fn fmt_types(types: &[IR3Type]) -> String {
+  if types.is_empty() { IR3Type::Void.to_string() } else { join(types, " ") }
+}
+
+fn fmt_attrs(attrs: &[(IR3FunctionAttr, String)]) -> String {
+  attrs.iter()
+    .map(|(attr, val)| if val.is_empty() { attr.to_string() } else { format!("{}={}", attr, val) })
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+impl IR3Function {
+  pub fn has_attr(&self, attr: IR3FunctionAttr) -> bool {
+    self.attrs.iter().any(|(v, _)| *v == attr)
+  }
+
+  /// Returns an ID that is higher than every variable ID used in this function.
+  pub fn next_var_id(&self) -> IR3VarID {
+    self.basic_blocks.iter()
+      .flat_map(|bb| bb.instructions.iter())
+      .flat_map(|op| op.input.iter().chain(op.output.iter()))
+      .max()
+      .map_or(0, |v| v + 1)
+  }
+
+  /// Returns an ID that is higher than every basic block ID in this function.
+  pub fn next_block_id(&self) -> IR3BBID {
+    self.basic_blocks.iter()
+      .map(|bb| bb.id)
+      .max()
+      .map_or(0, |v| v + 1)
+  }
+
+  /// Collects the type of every variable defined in this function.
+  pub fn var_types(&self) -> HashMap<IR3VarID, IR3Type> {
+    self.basic_blocks.iter()
+      .flat_map(|bb| bb.instructions.iter())
+      .flat_map(|op| op.output.iter().copied().zip(op.output_types()))
+      .collect()
+  }
+
+  /// Rewrites every use of a variable that is a key in `map` to use the mapped variable instead.
+  /// Chains of replacements are followed to the end.
+  pub fn replace_uses(&mut self, map: &HashMap<IR3VarID, IR3VarID>) {
+    if map.is_empty() {
+      return;
+    }
+    let resolve = |mut v: IR3VarID| {
+      while let Some(next) = map.get(&v) {
+        v = *next;
+      }
+      v
+    };
+    for bb in &mut self.basic_blocks {
+      for op in &mut bb.instructions {
+        op.input.iter_mut().for_each(|v| *v = resolve(*v));
+      }
+      bb.ending.inputs_mut().into_iter().for_each(|v| *v = resolve(*v));
+    }
+  }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct IR3BasicBlock {
-  id: IR3BBID,
-  instructions: Vec<IR3Op>,
-  ending: IR3EndOp
+  pub id: IR3BBID,
+  pub instructions: Vec<IR3Op>,
+  pub ending: IR3EndOp
 }
 
 impl Display for IR3BasicBlock {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-    writeln!(f, "@{}:\n  {}\n  {}", self.id, join(&self.instructions, "\n  "), self.ending)
+    writeln!(f, "@{}:", self.id)?;
+    for op in &self.instructions {
+      writeln!(f, "  {}", op)?;
+    }
+    writeln!(f, "  {}", self.ending)
   }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct IR3Op {
-  kind: IR3OpKind,
-  ty: IR3Type,
-  input: Vec<IR3VarID>,
-  output: Vec<IR3VarID>
+  pub kind: IR3OpKind,
+  pub ty: IR3Type,
+  pub input: Vec<IR3VarID>,
+  pub output: Vec<IR3VarID>
 }
 
 impl Display for IR3Op {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt_outputs(f: &mut Formatter<'_>, s: &IR3Op) -> std::fmt::Result {
+      if !s.output.is_empty() {
+        write!(f, "${} = ", join(&s.output, " $"))?;
+      }
+      Ok(())
+    }
+    fn fmt_inputs(f: &mut Formatter<'_>, s: &IR3Op) -> std::fmt::Result {
+      for var in &s.input {
+        write!(f, " ${}", var)?;
+      }
+      Ok(())
+    }
     fn fmt1(f: &mut Formatter<'_>, s: &IR3Op) -> std::fmt::Result {
-      write!(f, "${} = {} {} ${}", join(&s.output, " $"), s.kind.name(), s.ty, join(&s.input, " $"))
+      fmt_outputs(f, s)?;
+      write!(f, "{} {}", s.kind.name(), s.ty)?;
+      fmt_inputs(f, s)
     }
     fn fmt2(f: &mut Formatter<'_>, s: &IR3Op, opt: impl Display) -> std::fmt::Result {
-      write!(f, "${} = {} {} {} ${}", join(&s.output, " $"), s.kind.name(), s.ty, opt, join(&s.input, " $"))
+      fmt_outputs(f, s)?;
+      write!(f, "{} {} {}", s.kind.name(), s.ty, opt)?;
+      fmt_inputs(f, s)
     }
     fn fmt3(f: &mut Formatter<'_>, s: &IR3Op, call_info: &IR3Call) -> std::fmt::Result {
-      write!(
-        f, "${} = {} {} {} {}", join(&s.output, " $"), s.kind.name(), s.ty, &call_info.symbol_name,
-        call_info.arg_types
-          .iter()
-          .zip(s.input.iter())
-          .map(|(ty, var)| format!("{} {}", ty, var))
-          .collect::<Vec<String>>()
-          .join(" ")
-      )
+      fmt_outputs(f, s)?;
+      write!(f, "{} {} {}", s.kind.name(), fmt_types(&call_info.ret_types), &call_info.symbol_name)?;
+      for (ty, var) in call_info.arg_types.iter().zip(s.input.iter()) {
+        write!(f, " {} ${}", ty, var)?;
+      }
+      Ok(())
     }
     fn fmt4(f: &mut Formatter<'_>, s: &IR3Op, phi: &IR3Phi) -> std::fmt::Result {
-      write!(
-        f, "${} = {} {} {}", join(&s.output, " $"), s.kind.name(), s.ty,
-        phi.blocks
-          .iter()
-          .zip(s.input.iter())
-          .map(|(block, var)| format!("{} {}", var, block))
-          .collect::<Vec<String>>()
-          .join(" ")
-      )
+      fmt_outputs(f, s)?;
+      write!(f, "{} {}", s.kind.name(), s.ty)?;
+      for (block, var) in phi.blocks.iter().zip(s.input.iter()) {
+        write!(f, " ${} @{}", var, block)?;
+      }
+      Ok(())
     }
     match &self.kind {
       IR3OpKind::Cmp(mode) => fmt2(f, self, mode),
-      IR3OpKind::Const(v) => fmt2(f, self, v),
-      IR3OpKind::Sext(ty2) | IR3OpKind::Zext(ty2) => fmt2(f, self, ty2),
+      IR3OpKind::Fcmp(mode) => fmt2(f, self, mode),
+      IR3OpKind::Const(v) => match self.ty {
+        IR3Type::Float(32) => fmt2(f, self, format!("{:?}", f32::from_bits(*v as u32))),
+        IR3Type::Float(_) => fmt2(f, self, format!("{:?}", f64::from_bits(*v))),
+        _ => fmt2(f, self, v),
+      },
+      IR3OpKind::Sext(ty2) | IR3OpKind::Zext(ty2) | IR3OpKind::Trunc(ty2) |
+      IR3OpKind::Fconv(ty2) | IR3OpKind::SiToFp(ty2) | IR3OpKind::UiToFp(ty2) |
+      IR3OpKind::FpToSi(ty2) | IR3OpKind::FpToUi(ty2) => fmt2(f, self, ty2),
+      IR3OpKind::StackAlloc { size, align } => fmt2(f, self, format!("{} {}", size, align)),
       IR3OpKind::Arg(idx) => fmt2(f, self, idx),
+      IR3OpKind::GlobalAddr(name) => fmt2(f, self, name),
+      IR3OpKind::AtomicLoad(ord) | IR3OpKind::AtomicStore(ord) | IR3OpKind::CmpXchg(ord) => fmt2(f, self, ord),
+      IR3OpKind::AtomicRmw(rmw, ord) => fmt2(f, self, format!("{} {}", rmw, ord)),
+      IR3OpKind::Fence(ord) => write!(f, "fence {}", ord),
       IR3OpKind::Call(call) => fmt3(f, self, &call),
       IR3OpKind::Phi(phi) => fmt4(f, self, &phi),
       _ => fmt1(f, self),
@@ -103,6 +308,12 @@ pub enum IR3OpKind {
   Sll,
   Srl,
   Sra,
+  Rotl,
+  Rotr,
+  Clz,
+  Ctz,
+  Popcnt,
+  Bswap,
   
   Smull,
   Umull,
@@ -113,18 +324,65 @@ pub enum IR3OpKind {
   Srem,
   Urem,
   
+  /// Overflow-checked arithmetic. These have two outputs: the wrapped result, and a `d1` that is set
+  /// if the result did not fit in the type.
+  SaddOverflow,
+  UaddOverflow,
+  SsubOverflow,
+  UsubOverflow,
+  SmulOverflow,
+  UmulOverflow,
+  
+  /// IEEE 754 arithmetic on `f32` and `f64`, rounding to nearest-even.
+  Fadd,
+  Fsub,
+  Fmul,
+  Fdiv,
+  Fneg,
+  Fsqrt,
+  /// Compares two floats. The result is a `d1`.
+  Fcmp(IR3FloatCompareMode),
+  
   PtrLoad,
   PtrStore,
   PtrUadd,
   PtrSadd,
+  StackAlloc {
+    size: u32,
+    align: u32
+  },
+  /// Address of a global, rodata blob, or function in the module.
+  GlobalAddr(String),
+  
+  AtomicLoad(IR3Ordering),
+  AtomicStore(IR3Ordering),
+  /// Atomically applies the operation to the value in memory, with the inputs `[ptr, value]`. The output is the old value.
+  AtomicRmw(IR3RmwOp, IR3Ordering),
+  /// Atomically replaces the value in memory with `new` if it equals `expected`, with the inputs `[ptr, expected, new]`.
+  /// The outputs are the old value, and a `d1` that is set if the exchange happened.
+  CmpXchg(IR3Ordering),
+  Fence(IR3Ordering),
   
+  /// Constants of float type hold the bits of the float.
   Const(u64),
   Sext(IR3Type),
   Zext(IR3Type),
+  Trunc(IR3Type),
+  /// Converts between `f32` and `f64`.
+  Fconv(IR3Type),
+  /// Converts signed or unsigned data to the nearest float.
+  SiToFp(IR3Type),
+  UiToFp(IR3Type),
+  /// Converts a float to data, rounding towards zero. The result is unspecified if it does not fit,
+  /// or if the float is NaN.
+  FpToSi(IR3Type),
+  FpToUi(IR3Type),
   
   Arg(u32),
   Call(IR3Call),
   Phi(IR3Phi),
+  /// `cond ? a : b`, with the inputs `[cond, a, b]`.
+  Select,
 }
 
 impl IR3Op {
@@ -135,14 +393,27 @@ impl IR3Op {
     }
   }
   
+  /// The type of the first output of the op. Calls and overflow-checked ops can have several outputs,
+  /// see `output_types`.
   pub fn return_type(&self) -> IR3Type {
     match &self.kind {
-      IR3OpKind::Cmp(_) => IR3Type::Data(1),
+      IR3OpKind::Cmp(_) | IR3OpKind::Fcmp(_) => IR3Type::Data(1),
       IR3OpKind::PtrSadd | IR3OpKind::PtrUadd => IR3Type::Ptr,
-      IR3OpKind::PtrStore => IR3Type::Void,
+      IR3OpKind::PtrStore | IR3OpKind::AtomicStore(_) | IR3OpKind::Fence(_) => IR3Type::Void,
       _ => self.ty
     }
   }
+
+  /// The types of every output of the op.
+  pub fn output_types(&self) -> Vec<IR3Type> {
+    match &self.kind {
+      IR3OpKind::Call(call) => call.ret_types.clone(),
+      IR3OpKind::CmpXchg(_) => vec![self.ty, IR3Type::Data(1)],
+      _ if self.kind.is_overflow_checked() => vec![self.ty, IR3Type::Data(1)],
+      _ if self.return_type() == IR3Type::Void => vec![],
+      _ => vec![self.return_type()],
+    }
+  }
 }
 
 impl IR3OpKind {
@@ -158,6 +429,12 @@ impl IR3OpKind {
       IR3OpKind::Sll => "sll",
       IR3OpKind::Srl => "srl",
       IR3OpKind::Sra => "sra",
+      IR3OpKind::Rotl => "rotl",
+      IR3OpKind::Rotr => "rotr",
+      IR3OpKind::Clz => "clz",
+      IR3OpKind::Ctz => "ctz",
+      IR3OpKind::Popcnt => "popcnt",
+      IR3OpKind::Bswap => "bswap",
       IR3OpKind::Smull => "smull",
       IR3OpKind::Umull => "umull",
       IR3OpKind::Smulh => "smulh",
@@ -166,18 +443,98 @@ impl IR3OpKind {
       IR3OpKind::Udiv => "udiv",
       IR3OpKind::Srem => "srem",
       IR3OpKind::Urem => "urem",
+      IR3OpKind::SaddOverflow => "sadd_overflow",
+      IR3OpKind::UaddOverflow => "uadd_overflow",
+      IR3OpKind::SsubOverflow => "ssub_overflow",
+      IR3OpKind::UsubOverflow => "usub_overflow",
+      IR3OpKind::SmulOverflow => "smul_overflow",
+      IR3OpKind::UmulOverflow => "umul_overflow",
+      IR3OpKind::Fadd => "fadd",
+      IR3OpKind::Fsub => "fsub",
+      IR3OpKind::Fmul => "fmul",
+      IR3OpKind::Fdiv => "fdiv",
+      IR3OpKind::Fneg => "fneg",
+      IR3OpKind::Fsqrt => "fsqrt",
+      IR3OpKind::Fcmp(_) => "fcmp",
       IR3OpKind::PtrLoad => "ptr_load",
       IR3OpKind::PtrStore => "ptr_store",
       IR3OpKind::PtrUadd => "ptr_uadd",
       IR3OpKind::PtrSadd => "ptr_sadd",
+      IR3OpKind::StackAlloc { .. } => "stack_alloc",
+      IR3OpKind::GlobalAddr(_) => "global_addr",
+      IR3OpKind::AtomicLoad(_) => "atomic_load",
+      IR3OpKind::AtomicStore(_) => "atomic_store",
+      IR3OpKind::AtomicRmw(..) => "atomic_rmw",
+      IR3OpKind::CmpXchg(_) => "cmpxchg",
+      IR3OpKind::Fence(_) => "fence",
       IR3OpKind::Const(_) => "const",
       IR3OpKind::Sext(_) => "sext",
       IR3OpKind::Zext(_) => "zext",
+      IR3OpKind::Trunc(_) => "trunc",
+      IR3OpKind::Fconv(_) => "fconv",
+      IR3OpKind::SiToFp(_) => "sitofp",
+      IR3OpKind::UiToFp(_) => "uitofp",
+      IR3OpKind::FpToSi(_) => "fptosi",
+      IR3OpKind::FpToUi(_) => "fptoui",
       IR3OpKind::Arg(_) => "arg",
       IR3OpKind::Call(_) => "call",
-      IR3OpKind::Phi(_) => "phi"
+      IR3OpKind::Phi(_) => "phi",
+      IR3OpKind::Select => "select",
     }
   }
+
+  /// Looks up an op kind that takes no parameters by its name.
+  pub fn from_name(name: &str) -> Option<IR3OpKind> {
+    Some(match name {
+      "add" => IR3OpKind::Add,
+      "sub" => IR3OpKind::Sub,
+      "and" => IR3OpKind::And,
+      "or" => IR3OpKind::Or,
+      "xor" => IR3OpKind::Xor,
+      "not" => IR3OpKind::Not,
+      "sll" => IR3OpKind::Sll,
+      "srl" => IR3OpKind::Srl,
+      "sra" => IR3OpKind::Sra,
+      "rotl" => IR3OpKind::Rotl,
+      "rotr" => IR3OpKind::Rotr,
+      "clz" => IR3OpKind::Clz,
+      "ctz" => IR3OpKind::Ctz,
+      "popcnt" => IR3OpKind::Popcnt,
+      "bswap" => IR3OpKind::Bswap,
+      "smull" => IR3OpKind::Smull,
+      "umull" => IR3OpKind::Umull,
+      "smulh" => IR3OpKind::Smulh,
+      "umulh" => IR3OpKind::Umulh,
+      "sdiv" => IR3OpKind::Sdiv,
+      "udiv" => IR3OpKind::Udiv,
+      "srem" => IR3OpKind::Srem,
+      "urem" => IR3OpKind::Urem,
+      "sadd_overflow" => IR3OpKind::SaddOverflow,
+      "uadd_overflow" => IR3OpKind::UaddOverflow,
+      "ssub_overflow" => IR3OpKind::SsubOverflow,
+      "usub_overflow" => IR3OpKind::UsubOverflow,
+      "smul_overflow" => IR3OpKind::SmulOverflow,
+      "umul_overflow" => IR3OpKind::UmulOverflow,
+      "fadd" => IR3OpKind::Fadd,
+      "fsub" => IR3OpKind::Fsub,
+      "fmul" => IR3OpKind::Fmul,
+      "fdiv" => IR3OpKind::Fdiv,
+      "fneg" => IR3OpKind::Fneg,
+      "fsqrt" => IR3OpKind::Fsqrt,
+      "ptr_load" => IR3OpKind::PtrLoad,
+      "ptr_store" => IR3OpKind::PtrStore,
+      "ptr_uadd" => IR3OpKind::PtrUadd,
+      "ptr_sadd" => IR3OpKind::PtrSadd,
+      "select" => IR3OpKind::Select,
+      &_ => return None
+    })
+  }
+
+  pub fn is_overflow_checked(&self) -> bool {
+    matches!(self,
+      IR3OpKind::SaddOverflow | IR3OpKind::UaddOverflow | IR3OpKind::SsubOverflow |
+      IR3OpKind::UsubOverflow | IR3OpKind::SmulOverflow | IR3OpKind::UmulOverflow)
+  }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -190,9 +547,72 @@ pub enum IR3EndOp {
     block2: IR3BBID,
     cond: IR3VarID
   },
+  /// Returns the values, which match the return types of the function.
   Ret {
-    ty: IR3Type,
-    var: IR3VarID
+    values: Vec<(IR3Type, IR3VarID)>
+  },
+  /// Multi-way branch on a data value. Cases must have distinct values.
+  Switch {
+    cond: IR3VarID,
+    cases: Vec<(u64, IR3BBID)>,
+    default: IR3BBID
+  },
+  /// Control never reaches the end of this block. Reaching it anyway is undefined behaviour.
+  Unreachable,
+  /// Stops the program abnormally.
+  Trap
+}
+
+impl IR3EndOp {
+  pub fn inputs_mut(&mut self) -> Vec<&mut IR3VarID> {
+    match self {
+      IR3EndOp::Br { .. } => vec![],
+      IR3EndOp::BrIf { cond, .. } => vec![cond],
+      IR3EndOp::Ret { values } => values.iter_mut().map(|(_, var)| var).collect(),
+      IR3EndOp::Switch { cond, .. } => vec![cond],
+      IR3EndOp::Unreachable | IR3EndOp::Trap => vec![],
+    }
+  }
+
+  pub fn inputs(&self) -> Vec<IR3VarID> {
+    match self {
+      IR3EndOp::Br { .. } => vec![],
+      IR3EndOp::BrIf { cond, .. } => vec![*cond],
+      IR3EndOp::Ret { values } => values.iter().map(|(_, var)| *var).collect(),
+      IR3EndOp::Switch { cond, .. } => vec![*cond],
+      IR3EndOp::Unreachable | IR3EndOp::Trap => vec![],
+    }
+  }
+
+  pub fn successors(&self) -> Vec<IR3BBID> {
+    match self {
+      IR3EndOp::Br { block } => vec![*block],
+      IR3EndOp::BrIf { block1, block2, .. } => vec![*block1, *block2],
+      IR3EndOp::Ret { .. } | IR3EndOp::Unreachable | IR3EndOp::Trap => vec![],
+      IR3EndOp::Switch { cases, default, .. } => {
+        let mut succs = vec![*default];
+        for (_, block) in cases {
+          if !succs.contains(block) {
+            succs.push(*block);
+          }
+        }
+        succs
+      }
+    }
+  }
+
+  /// Returns every branch target, including repeated ones.
+  pub fn successors_mut(&mut self) -> Vec<&mut IR3BBID> {
+    match self {
+      IR3EndOp::Br { block } => vec![block],
+      IR3EndOp::BrIf { block1, block2, .. } => vec![block1, block2],
+      IR3EndOp::Ret { .. } | IR3EndOp::Unreachable | IR3EndOp::Trap => vec![],
+      IR3EndOp::Switch { cases, default, .. } => {
+        let mut succs = vec![default];
+        succs.extend(cases.iter_mut().map(|(_, block)| block));
+        succs
+      }
+    }
   }
 }
 
@@ -205,9 +625,25 @@ impl Display for IR3EndOp {
       IR3EndOp::BrIf { block1, block2, cond } => {
         write!(f, "br_if ${} @{} @{}", cond, block1, block2)
       }
-      IR3EndOp::Ret { ty, var } => {
-        write!(f, "ret {} ${}", ty, var)
+      IR3EndOp::Ret { values } if values.is_empty() => {
+        write!(f, "ret {}", IR3Type::Void)
+      }
+      IR3EndOp::Ret { values } => {
+        write!(f, "ret")?;
+        for (ty, var) in values {
+          write!(f, " {} ${}", ty, var)?;
+        }
+        Ok(())
+      }
+      IR3EndOp::Switch { cond, cases, default } => {
+        write!(f, "switch ${} @{}", cond, default)?;
+        for (value, block) in cases {
+          write!(f, " {} @{}", value, block)?;
+        }
+        Ok(())
       }
+      IR3EndOp::Unreachable => write!(f, "unreachable"),
+      IR3EndOp::Trap => write!(f, "trap"),
     }
   }
 }
@@ -227,6 +663,22 @@ pub enum IR3CompareMode {
 }
 
 impl IR3CompareMode {
+  pub fn from_name(name: &str) -> Option<IR3CompareMode> {
+    Some(match name {
+      "ult" => IR3CompareMode::ULt,
+      "ugt" => IR3CompareMode::UGt,
+      "ule" => IR3CompareMode::ULe,
+      "uge" => IR3CompareMode::UGe,
+      "slt" => IR3CompareMode::SLt,
+      "sgt" => IR3CompareMode::SGt,
+      "sle" => IR3CompareMode::SLe,
+      "sge" => IR3CompareMode::SGe,
+      "eq" => IR3CompareMode::Eq,
+      "ne" => IR3CompareMode::Ne,
+      &_ => return None
+    })
+  }
+
   pub fn name(self) -> &'static str {
     match self {
       IR3CompareMode::ULt => "ult",
@@ -241,6 +693,23 @@ impl IR3CompareMode {
       IR3CompareMode::Ne => "ne"
     }
   }
+
+  /// Compares two `d<width>` values, which must not have bits set above `width`.
+  pub fn holds(self, a: u64, b: u64, width: u32) -> bool {
+    let s = |v: u64| ((v << (64 - width)) as i64) >> (64 - width);
+    match self {
+      IR3CompareMode::ULt => a < b,
+      IR3CompareMode::UGt => a > b,
+      IR3CompareMode::ULe => a <= b,
+      IR3CompareMode::UGe => a >= b,
+      IR3CompareMode::SLt => s(a) < s(b),
+      IR3CompareMode::SGt => s(a) > s(b),
+      IR3CompareMode::SLe => s(a) <= s(b),
+      IR3CompareMode::SGe => s(a) >= s(b),
+      IR3CompareMode::Eq => a == b,
+      IR3CompareMode::Ne => a != b,
+    }
+  }
 }
 
 impl Display for IR3CompareMode {
@@ -249,26 +718,179 @@ impl Display for IR3CompareMode {
   }
 }
 
+/// Float comparisons. All of them are false if either input is NaN, except for `ne`, which is true,
+/// and `uno`, which is true only then.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum IR3FloatCompareMode {
+  Eq,
+  Ne,
+  Lt,
+  Gt,
+  Le,
+  Ge,
+  Uno
+}
+
+impl IR3FloatCompareMode {
+  pub fn from_name(name: &str) -> Option<IR3FloatCompareMode> {
+    Some(match name {
+      "eq" => IR3FloatCompareMode::Eq,
+      "ne" => IR3FloatCompareMode::Ne,
+      "lt" => IR3FloatCompareMode::Lt,
+      "gt" => IR3FloatCompareMode::Gt,
+      "le" => IR3FloatCompareMode::Le,
+      "ge" => IR3FloatCompareMode::Ge,
+      "uno" => IR3FloatCompareMode::Uno,
+      &_ => return None
+    })
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      IR3FloatCompareMode::Eq => "eq",
+      IR3FloatCompareMode::Ne => "ne",
+      IR3FloatCompareMode::Lt => "lt",
+      IR3FloatCompareMode::Gt => "gt",
+      IR3FloatCompareMode::Le => "le",
+      IR3FloatCompareMode::Ge => "ge",
+      IR3FloatCompareMode::Uno => "uno"
+    }
+  }
+}
+
+impl Display for IR3FloatCompareMode {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+/// Memory ordering of an atomic op, with the same meaning as in C11.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum IR3Ordering {
+  Relaxed,
+  Acquire,
+  Release,
+  AcqRel,
+  SeqCst
+}
+
+impl IR3Ordering {
+  pub fn from_name(name: &str) -> Option<IR3Ordering> {
+    Some(match name {
+      "relaxed" => IR3Ordering::Relaxed,
+      "acquire" => IR3Ordering::Acquire,
+      "release" => IR3Ordering::Release,
+      "acq_rel" => IR3Ordering::AcqRel,
+      "seq_cst" => IR3Ordering::SeqCst,
+      &_ => return None
+    })
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      IR3Ordering::Relaxed => "relaxed",
+      IR3Ordering::Acquire => "acquire",
+      IR3Ordering::Release => "release",
+      IR3Ordering::AcqRel => "acq_rel",
+      IR3Ordering::SeqCst => "seq_cst"
+    }
+  }
+}
+
+impl Display for IR3Ordering {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum IR3RmwOp {
+  Add,
+  And,
+  Or,
+  Xor,
+  /// Stores the value as is.
+  Xchg
+}
+
+impl IR3RmwOp {
+  pub fn from_name(name: &str) -> Option<IR3RmwOp> {
+    Some(match name {
+      "add" => IR3RmwOp::Add,
+      "and" => IR3RmwOp::And,
+      "or" => IR3RmwOp::Or,
+      "xor" => IR3RmwOp::Xor,
+      "xchg" => IR3RmwOp::Xchg,
+      &_ => return None
+    })
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      IR3RmwOp::Add => "add",
+      IR3RmwOp::And => "and",
+      IR3RmwOp::Or => "or",
+      IR3RmwOp::Xor => "xor",
+      IR3RmwOp::Xchg => "xchg"
+    }
+  }
+}
+
+impl Display for IR3RmwOp {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct IR3Call {
-  symbol_name: String,
-  arg_types: Vec<IR3Type>
+  pub symbol_name: String,
+  pub arg_types: Vec<IR3Type>,
+  /// Types of the outputs of the call. The `ty` of a call op is always `void`.
+  pub ret_types: Vec<IR3Type>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct IR3Phi {
-  blocks: Vec<IR3BBID>
+  pub blocks: Vec<IR3BBID>
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum IR3FunctionAttr {
-  BuiltinFunction
+  BuiltinFunction,
+  /// The function never returns. Its value is empty.
+  NoReturn,
+  /// The function is visible outside the module, so it is kept even if nothing calls it. Its value is empty.
+  Export,
+  /// The function does not access memory and only calls other pure functions. Its value is empty.
+  Pure,
+  /// The function may read memory, but does not write it. Its value is empty.
+  ReadOnly,
+  /// Every call to the function returns (or traps). Its value is empty.
+  WillReturn,
 }
 
 impl IR3FunctionAttr {
+  pub fn from_name(name: &str) -> Option<IR3FunctionAttr> {
+    Some(match name {
+      "builtin-function" => IR3FunctionAttr::BuiltinFunction,
+      "noreturn" => IR3FunctionAttr::NoReturn,
+      "export" => IR3FunctionAttr::Export,
+      "pure" => IR3FunctionAttr::Pure,
+      "readonly" => IR3FunctionAttr::ReadOnly,
+      "willreturn" => IR3FunctionAttr::WillReturn,
+      _ => return None,
+    })
+  }
+
   pub fn name(self) -> &'static str {
     match self {
-      IR3FunctionAttr::BuiltinFunction => "builtin-function"
+      IR3FunctionAttr::BuiltinFunction => "builtin-function",
+      IR3FunctionAttr::NoReturn => "noreturn",
+      IR3FunctionAttr::Export => "export",
+      IR3FunctionAttr::Pure => "pure",
+      IR3FunctionAttr::ReadOnly => "readonly",
+      IR3FunctionAttr::WillReturn => "willreturn",
     }
   }
 }
@@ -285,6 +907,8 @@ pub type IR3BBID = u32;
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum IR3Type {
   Data(u32),
+  /// An IEEE 754 binary float, either 32 or 64 bits wide.
+  Float(u32),
   Ptr,
   Void
 }
@@ -293,6 +917,7 @@ impl Display for IR3Type {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
     match self {
       IR3Type::Data(w) => write!(f, "d{}", w),
+      IR3Type::Float(w) => write!(f, "f{}", w),
       IR3Type::Ptr => write!(f, "ptr"),
       IR3Type::Void => write!(f, "void"),
     }
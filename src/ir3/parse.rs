@@ -0,0 +1,457 @@
+//! Parser for the text form of IR3, as printed by the `Display` impls of `ir3::model`.
+//!
+//! The text is line based: every extern, global, rodata blob, block label, op and terminator is on a line of its own,
+//! and the blocks of a function are enclosed in `ir3function ... {` and `}`. Tokens are separated by whitespace,
+//! except in the string of a rodata blob. Float constants round trip exactly, except for the payload of NaNs.
+
+use std::str::FromStr;
+use crate::common::err::{IR3Err, IR3ErrKind, IR3Result};
+use crate::ir3::model::{
+  IR3BasicBlock, IR3BBID, IR3Call, IR3CompareMode, IR3Data, IR3EndOp, IR3ExternFunction, IR3FloatCompareMode,
+  IR3Function, IR3FunctionAttr, IR3Global, IR3GlobalInit, IR3Module, IR3Op, IR3OpKind, IR3Ordering, IR3Phi,
+  IR3RmwOp, IR3Type, IR3VarID,
+};
+
+/// The attributes, argument types and return types of a function.
+type Signature = (Vec<(IR3FunctionAttr, String)>, Vec<IR3Type>, Vec<IR3Type>);
+
+/// The tokens of one line.
+struct Line<'a> {
+  no: usize,
+  /// The function or symbol the line belongs to, for error messages.
+  func: &'a str,
+  tokens: Vec<&'a str>,
+  pos: usize,
+}
+
+impl<'a> Line<'a> {
+  fn new(no: usize, func: &'a str, text: &'a str) -> Self {
+    Line { no, func, tokens: text.split_whitespace().collect(), pos: 0 }
+  }
+
+  fn err<T>(&self, msg: impl Into<String>) -> IR3Result<T> {
+    Err(IR3Err::new(IR3ErrKind::Syntax(self.no, msg.into()), self.func))
+  }
+
+  fn peek(&self) -> Option<&'a str> {
+    self.tokens.get(self.pos).copied()
+  }
+
+  fn next(&mut self, what: &str) -> IR3Result<&'a str> {
+    match self.peek() {
+      Some(token) => {
+        self.pos += 1;
+        Ok(token)
+      }
+      None => self.err(format!("expected {}", what)),
+    }
+  }
+
+  fn expect(&mut self, token: &str) -> IR3Result<()> {
+    if self.next(token)? != token {
+      return self.err(format!("expected {}", token));
+    }
+    Ok(())
+  }
+
+  fn end(&self) -> IR3Result<()> {
+    match self.peek() {
+      Some(token) => self.err(format!("unexpected {}", token)),
+      None => Ok(()),
+    }
+  }
+
+  fn parse<T: FromStr>(&mut self, what: &str) -> IR3Result<T> {
+    let token = self.next(what)?;
+    token.parse().or_else(|_| self.err(format!("expected {}, found {}", what, token)))
+  }
+
+  fn named<T>(&mut self, what: &str, from_name: impl Fn(&str) -> Option<T>) -> IR3Result<T> {
+    let token = self.next(what)?;
+    from_name(token).map_or_else(|| self.err(format!("expected {}, found {}", what, token)), Ok)
+  }
+
+  fn ty(&mut self) -> IR3Result<IR3Type> {
+    self.named("a type", parse_type)
+  }
+
+  fn var(&mut self) -> IR3Result<IR3VarID> {
+    self.named("a variable", |v| v.strip_prefix('$')?.parse().ok())
+  }
+
+  fn block(&mut self) -> IR3Result<IR3BBID> {
+    self.named("a block", |v| v.strip_prefix('@')?.parse().ok())
+  }
+
+  /// Parses variables until the end of the line.
+  fn vars(&mut self) -> IR3Result<Vec<IR3VarID>> {
+    let mut vars = vec![];
+    while self.peek().is_some() {
+      vars.push(self.var()?);
+    }
+    Ok(vars)
+  }
+
+  /// Parses types for as long as there are some, where a lone `void` stands for none.
+  fn types(&mut self) -> IR3Result<Vec<IR3Type>> {
+    if self.peek() == Some("void") {
+      self.pos += 1;
+      return Ok(vec![]);
+    }
+    let mut types = vec![self.ty()?];
+    while let Some(ty) = self.peek().and_then(parse_type) {
+      types.push(ty);
+      self.pos += 1;
+    }
+    Ok(types)
+  }
+
+  /// Parses `[attrs <attr> ...] [args <type> ...] returns <type> ...`.
+  fn signature(&mut self) -> IR3Result<Signature> {
+    let mut attrs = vec![];
+    if self.peek() == Some("attrs") {
+      self.pos += 1;
+      while let Some(token) = self.peek().filter(|v| *v != "args" && *v != "returns") {
+        let (name, value) = token.split_once('=').unwrap_or((token, ""));
+        let Some(attr) = IR3FunctionAttr::from_name(name) else { return self.err(format!("unknown attribute {}", name)) };
+        attrs.push((attr, value.to_owned()));
+        self.pos += 1;
+      }
+    }
+    let mut args = vec![];
+    if self.peek() == Some("args") {
+      self.pos += 1;
+      while self.peek().is_some_and(|v| v != "returns") {
+        args.push(self.ty()?);
+      }
+    }
+    self.expect("returns")?;
+    Ok((attrs, args, self.types()?))
+  }
+}
+
+fn parse_type(token: &str) -> Option<IR3Type> {
+  match token {
+    "ptr" => Some(IR3Type::Ptr),
+    "void" => Some(IR3Type::Void),
+    _ if token.starts_with('f') => Some(IR3Type::Float(token[1..].parse().ok()?)),
+    _ => Some(IR3Type::Data(token.strip_prefix('d')?.parse().ok()?)),
+  }
+}
+
+pub fn parse_module(text: &str) -> IR3Result<IR3Module> {
+  let mut module = IR3Module::default();
+  let mut lines = text.lines().enumerate().map(|(i, v)| (i + 1, v)).filter(|(_, v)| !v.trim().is_empty());
+  while let Some((no, text)) = lines.next() {
+    let mut line = Line::new(no, "", text);
+    let name = line.tokens.get(1).copied().unwrap_or("");
+    line.func = name;
+    match line.next("a declaration")? {
+      "extern" => {
+        line.pos += 1;
+        let (attrs, args, ret) = line.signature()?;
+        line.end()?;
+        module.externs.push(IR3ExternFunction { name: name.to_owned(), args, ret, attrs });
+      }
+      "global" => {
+        line.pos += 1;
+        let ty = line.ty()?;
+        let init = if line.peek().is_some() {
+          line.expect("=")?;
+          let init = line.next("an initializer")?;
+          init.parse().map_or_else(|_| IR3GlobalInit::Addr(init.to_owned()), IR3GlobalInit::Const)
+        } else {
+          IR3GlobalInit::Zero
+        };
+        line.end()?;
+        module.globals.push(IR3Global { name: name.to_owned(), ty, init });
+      }
+      "rodata" => module.rodata.push(parse_rodata(line, text)?),
+      "ir3function" => module.functions.push(parse_function(line, &mut lines)?),
+      token => return line.err(format!("expected a declaration, found {}", token)),
+    }
+  }
+  Ok(module)
+}
+
+fn parse_rodata(mut line: Line, text: &str) -> IR3Result<IR3Data> {
+  let name = line.next("a name")?.to_owned();
+  line.expect("align")?;
+  let align = line.parse("an alignment")?;
+  let Some(quoted) = text.split_once('"').map(|(_, v)| v) else { return line.err("expected a string") };
+  let mut bytes = vec![];
+  let mut chars = quoted.bytes();
+  loop {
+    match chars.next() {
+      Some(b'"') => break,
+      Some(b'\\') => match chars.next() {
+        Some(b'x') => {
+          let hex = [chars.next(), chars.next()].into_iter().flatten().collect::<Vec<_>>();
+          let byte = std::str::from_utf8(&hex).ok().and_then(|v| u8::from_str_radix(v, 16).ok());
+          let Some(byte) = byte.filter(|_| hex.len() == 2) else { return line.err("invalid escape") };
+          bytes.push(byte);
+        }
+        Some(byte @ (b'"' | b'\\')) => bytes.push(byte),
+        _ => return line.err("invalid escape"),
+      },
+      Some(byte) => bytes.push(byte),
+      None => return line.err("unterminated string"),
+    }
+  }
+  if !chars.all(|v| v.is_ascii_whitespace()) {
+    return line.err("unexpected text after the string");
+  }
+  Ok(IR3Data { name, align, bytes })
+}
+
+fn parse_function<'a>(mut line: Line<'a>, lines: &mut impl Iterator<Item = (usize, &'a str)>) -> IR3Result<IR3Function> {
+  let name = line.next("a name")?;
+  let (attrs, args, ret) = line.signature()?;
+  line.expect("{")?;
+  line.end()?;
+  let mut basic_blocks = vec![];
+  let mut block = None;
+  let mut instructions = vec![];
+  loop {
+    let Some((no, text)) = lines.next() else { return line.err("unterminated function") };
+    let mut line = Line::new(no, name, text);
+    let first = line.peek().unwrap();
+    if first == "}" {
+      line.pos += 1;
+      line.end()?;
+      if block.is_some() {
+        return line.err("block without a terminator");
+      }
+      break;
+    }
+    if let Some(label) = first.strip_suffix(':') {
+      line.pos += 1;
+      line.end()?;
+      if block.is_some() {
+        return line.err("block without a terminator");
+      }
+      block = Some(Line::new(no, name, label).block()?);
+      continue;
+    }
+    let Some(id) = block else { return line.err("expected a block label") };
+    if let Some(ending) = parse_ending(&mut line)? {
+      basic_blocks.push(IR3BasicBlock { id, instructions: std::mem::take(&mut instructions), ending });
+      block = None;
+    } else {
+      instructions.push(parse_op(&mut line)?);
+    }
+  }
+  Ok(IR3Function { name: name.to_owned(), args, ret, basic_blocks, attrs })
+}
+
+/// Parses a terminator, or returns `None` if the line is not one.
+fn parse_ending(line: &mut Line) -> IR3Result<Option<IR3EndOp>> {
+  let ending = match line.peek().unwrap() {
+    "br" => {
+      line.pos += 1;
+      IR3EndOp::Br { block: line.block()? }
+    }
+    "br_if" => {
+      line.pos += 1;
+      IR3EndOp::BrIf { cond: line.var()?, block1: line.block()?, block2: line.block()? }
+    }
+    "ret" => {
+      line.pos += 1;
+      let mut values = vec![];
+      if line.peek() == Some("void") {
+        line.pos += 1;
+      } else {
+        while line.peek().is_some() {
+          values.push((line.ty()?, line.var()?));
+        }
+      }
+      IR3EndOp::Ret { values }
+    }
+    "switch" => {
+      line.pos += 1;
+      let (cond, default) = (line.var()?, line.block()?);
+      let mut cases = vec![];
+      while line.peek().is_some() {
+        cases.push((line.parse("a case value")?, line.block()?));
+      }
+      IR3EndOp::Switch { cond, cases, default }
+    }
+    "unreachable" => {
+      line.pos += 1;
+      IR3EndOp::Unreachable
+    }
+    "trap" => {
+      line.pos += 1;
+      IR3EndOp::Trap
+    }
+    _ => return Ok(None),
+  };
+  line.end()?;
+  Ok(Some(ending))
+}
+
+fn parse_op(line: &mut Line) -> IR3Result<IR3Op> {
+  let mut output = vec![];
+  if line.tokens.contains(&"=") {
+    while line.peek() != Some("=") {
+      output.push(line.var()?);
+    }
+    line.pos += 1;
+  }
+  let name = line.next("an op")?;
+  let (kind, ty) = match name {
+    "fence" => (IR3OpKind::Fence(line.named("an ordering", IR3Ordering::from_name)?), IR3Type::Void),
+    "call" => {
+      let ret_types = line.types()?;
+      let symbol_name = line.next("a symbol")?.to_owned();
+      let (mut arg_types, mut input) = (vec![], vec![]);
+      while line.peek().is_some() {
+        arg_types.push(line.ty()?);
+        input.push(line.var()?);
+      }
+      let call = IR3Call { symbol_name, arg_types, ret_types };
+      line.end()?;
+      return Ok(IR3Op { kind: IR3OpKind::Call(call), ty: IR3Type::Void, input, output });
+    }
+    "phi" => {
+      let ty = line.ty()?;
+      let (mut blocks, mut input) = (vec![], vec![]);
+      while line.peek().is_some() {
+        input.push(line.var()?);
+        blocks.push(line.block()?);
+      }
+      return Ok(IR3Op { kind: IR3OpKind::Phi(IR3Phi { blocks }), ty, input, output });
+    }
+    _ => {
+      let ty = line.ty()?;
+      let kind = match name {
+        "cmp" => IR3OpKind::Cmp(line.named("a compare mode", IR3CompareMode::from_name)?),
+        "fcmp" => IR3OpKind::Fcmp(line.named("a compare mode", IR3FloatCompareMode::from_name)?),
+        "const" => IR3OpKind::Const(match ty {
+          IR3Type::Float(32) => line.parse::<f32>("a float")?.to_bits() as u64,
+          IR3Type::Float(_) => line.parse::<f64>("a float")?.to_bits(),
+          _ => line.parse("a constant")?,
+        }),
+        "sext" => IR3OpKind::Sext(line.ty()?),
+        "zext" => IR3OpKind::Zext(line.ty()?),
+        "trunc" => IR3OpKind::Trunc(line.ty()?),
+        "fconv" => IR3OpKind::Fconv(line.ty()?),
+        "sitofp" => IR3OpKind::SiToFp(line.ty()?),
+        "uitofp" => IR3OpKind::UiToFp(line.ty()?),
+        "fptosi" => IR3OpKind::FpToSi(line.ty()?),
+        "fptoui" => IR3OpKind::FpToUi(line.ty()?),
+        "stack_alloc" => IR3OpKind::StackAlloc { size: line.parse("a size")?, align: line.parse("an alignment")? },
+        "arg" => IR3OpKind::Arg(line.parse("an argument index")?),
+        "global_addr" => IR3OpKind::GlobalAddr(line.next("a symbol")?.to_owned()),
+        "atomic_load" => IR3OpKind::AtomicLoad(line.named("an ordering", IR3Ordering::from_name)?),
+        "atomic_store" => IR3OpKind::AtomicStore(line.named("an ordering", IR3Ordering::from_name)?),
+        "cmpxchg" => IR3OpKind::CmpXchg(line.named("an ordering", IR3Ordering::from_name)?),
+        "atomic_rmw" => {
+          let rmw = line.named("an atomic op", IR3RmwOp::from_name)?;
+          IR3OpKind::AtomicRmw(rmw, line.named("an ordering", IR3Ordering::from_name)?)
+        }
+        _ => match IR3OpKind::from_name(name) {
+          Some(kind) => kind,
+          None => return line.err(format!("unknown op {}", name)),
+        },
+      };
+      (kind, ty)
+    }
+  };
+  let input = line.vars()?;
+  Ok(IR3Op { kind, ty, input, output })
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::hxx_ir1::from_hxx::hxx_to_ir1;
+  use crate::hxx_ir1::to_ir2::ir1_to_ir2;
+  use crate::ir2::to_ir3::ir2_to_ir3;
+  use crate::target::Target;
+  use super::*;
+
+  const MODULE: &str = r#"extern puts attrs readonly willreturn args ptr returns d32
+extern exit attrs noreturn args d32 returns void
+extern pair returns d32 d64
+global counter d64
+global limit d32 = 4294967295
+global self_ptr ptr = self_ptr
+rodata str0 align 1 "say \"hi\"\\\x00\x0a~"
+
+ir3function main attrs export args d32 ptr returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = arg ptr 1
+  $2 = const d32 7
+  $3 = const f32 0.1
+  $4 = const f64 -inf
+  $5 = add d32 $0 $2
+  $6 = cmp d32 slt $5 $2
+  $7 = fcmp f64 uno $4 $4
+  $8 = sext d64 d32 $5
+  $9 = fptosi d32 f32 $3
+  $10 = stack_alloc ptr 16 8
+  ptr_store d64 $10 $8
+  $11 = ptr_load d64 $10
+  $12 = global_addr ptr counter
+  $13 = atomic_rmw d64 add seq_cst $12 $11
+  $14 $15 = cmpxchg d64 acq_rel $12 $13 $11
+  fence seq_cst
+  $16 $17 = uadd_overflow d32 $5 $0
+  $18 $19 = call d32 d64 pair
+  call void exit d32 $5
+  br_if $6 @1 @2
+
+@1:
+  switch $5 @2 0 @3 5 @2
+
+@2:
+  $20 = phi d32 $5 @0 $0 @1
+  $21 = select d32 $6 $20 $2
+  ret d32 $21
+
+@3:
+  unreachable
+
+}
+
+ir3function noop returns void {
+@0:
+  ret void
+
+}
+
+ir3function fails attrs pure willreturn returns void {
+@0:
+  trap
+
+}
+"#;
+
+  #[test]
+  fn roundtrip_text() {
+    let module = parse_module(MODULE).unwrap();
+    assert_eq!(module.to_string(), MODULE);
+    assert_eq!(module.globals[1].init, IR3GlobalInit::Const(u32::MAX as u64));
+    assert_eq!(module.globals[2].init, IR3GlobalInit::Addr("self_ptr".to_owned()));
+    assert_eq!(module.rodata[0].bytes, b"say \"hi\"\\\0\n~");
+  }
+
+  #[test]
+  fn roundtrip_compiled() {
+    let target = Target::from_name("rv32").unwrap();
+    let srcs = [include_str!("../../support/builtins.hx"), include_str!("../../examples/test.hx")];
+    let ir1s = srcs.iter().map(|v| hxx_to_ir1("test.hx", v).unwrap()).collect::<Vec<_>>();
+    let module = ir2_to_ir3(&ir1_to_ir2(&ir1s).unwrap(), &target).unwrap();
+    assert_eq!(parse_module(&module.to_string()).unwrap(), module);
+  }
+
+  #[test]
+  fn syntax_errors() {
+    let err = parse_module("ir3function f returns void {\n@0:\n  $0 = frob d32\n  ret void\n}\n").unwrap_err();
+    assert_eq!(err.func, "f");
+    assert!(matches!(err.kind, IR3ErrKind::Syntax(3, _)));
+    assert!(parse_module("ir3function f returns void {\n@0:\n  $0 = const d32 1\n}\n").is_err());
+    assert!(parse_module("rodata s align 1 \"abc").is_err());
+  }
+}
@@ -0,0 +1,257 @@
+//! Block execution profiles.
+//!
+//! `instrument` gives every block of every function a `d64` counter global, named `_HX$prof$<function>$<block>`,
+//! which the block increments when it is entered. Whoever runs the program reads the counters when it exits
+//! (`read_counters` does this for the interpreter) and writes them out as a `Profile`.
+//!
+//! Profiles are text, with one line per function: its name, a hash of its CFG, and `<block>:<count>` for every block.
+//! Counts are only used if the hash still matches, since block IDs mean nothing once the function has changed.
+//! Both instrumentation and the hash are taken at the same point of the pipeline, so later passes
+//! see the counts of blocks they have not renamed.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use crate::ir3::interp::Interpreter;
+use crate::ir3::layout::BlockCounts;
+use crate::ir3::model::{IR3BBID, IR3Function, IR3Global, IR3GlobalInit, IR3Module, IR3Op, IR3OpKind, IR3Type};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FunctionProfile {
+  /// `cfg_hash` of the function when it was instrumented.
+  pub hash: u64,
+  pub counts: BlockCounts,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Profile {
+  pub functions: HashMap<String, FunctionProfile>,
+}
+
+impl Display for Profile {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let mut names = self.functions.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+      let func = &self.functions[name];
+      let mut counts = func.counts.iter().collect::<Vec<_>>();
+      counts.sort();
+      write!(f, "{} {:016x}", name, func.hash)?;
+      for (block, count) in counts {
+        write!(f, " {}:{}", block, count)?;
+      }
+      writeln!(f)?;
+    }
+    Ok(())
+  }
+}
+
+impl Profile {
+  pub fn parse(text: &str) -> Result<Profile, String> {
+    let mut profile = Profile::default();
+    for (i, line) in text.lines().enumerate().filter(|(_, v)| !v.trim().is_empty()) {
+      let err = || format!("invalid profile line {}: \"{}\"", i + 1, line);
+      let mut parts = line.split_whitespace();
+      let name = parts.next().ok_or_else(err)?;
+      let hash = parts.next().and_then(|v| u64::from_str_radix(v, 16).ok()).ok_or_else(err)?;
+      let counts = parts
+        .map(|v| {
+          let (block, count) = v.split_once(':')?;
+          Some((block.parse::<IR3BBID>().ok()?, count.parse::<u64>().ok()?))
+        })
+        .collect::<Option<BlockCounts>>()
+        .ok_or_else(err)?;
+      profile.functions.insert(name.to_owned(), FunctionProfile { hash, counts });
+    }
+    Ok(profile)
+  }
+
+  /// The counts of every function in `module` that the profile matches,
+  /// and the names of functions whose profile is stale.
+  pub fn matching_counts(&self, module: &IR3Module) -> (HashMap<String, BlockCounts>, Vec<String>) {
+    let mut counts = HashMap::new();
+    let mut stale = vec![];
+    for func in &module.functions {
+      let Some(profile) = self.functions.get(&func.name) else { continue };
+      if profile.hash == cfg_hash(func) {
+        counts.insert(func.name.clone(), profile.counts.clone());
+      } else {
+        stale.push(func.name.clone());
+      }
+    }
+    (counts, stale)
+  }
+}
+
+/// A hash of the blocks of a function and the edges between them (FNV-1a).
+pub fn cfg_hash(func: &IR3Function) -> u64 {
+  let mut hash = 0xcbf29ce484222325u64;
+  let mut add = |v: u64| {
+    for byte in v.to_le_bytes() {
+      hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+  };
+  add(func.basic_blocks.len() as u64);
+  for bb in &func.basic_blocks {
+    add(bb.id as u64);
+    let succs = bb.ending.successors();
+    add(succs.len() as u64);
+    succs.into_iter().for_each(|v| add(v as u64));
+  }
+  hash
+}
+
+fn counter_name(func: &str, block: IR3BBID) -> String {
+  format!("_HX$prof${}${}", func, block)
+}
+
+/// The counters of an instrumented function.
+pub struct FunctionCounters {
+  pub name: String,
+  pub hash: u64,
+  pub blocks: Vec<IR3BBID>,
+}
+
+/// Adds a counter to every block of every function. Returns what is needed to read the counters back.
+pub fn instrument(module: &mut IR3Module) -> Vec<FunctionCounters> {
+  let mut instrumented = vec![];
+  let d64 = IR3Type::Data(64);
+  for func in &mut module.functions {
+    let hash = cfg_hash(func);
+    let mut next_var = func.next_var_id();
+    for bb in &mut func.basic_blocks {
+      let name = counter_name(&func.name, bb.id);
+      let (addr, old, one, new) = (next_var, next_var + 1, next_var + 2, next_var + 3);
+      next_var += 4;
+      let ops = [
+        IR3Op { kind: IR3OpKind::GlobalAddr(name.clone()), ty: IR3Type::Ptr, input: vec![], output: vec![addr] },
+        IR3Op { kind: IR3OpKind::PtrLoad, ty: d64, input: vec![addr], output: vec![old] },
+        IR3Op { kind: IR3OpKind::Const(1), ty: d64, input: vec![], output: vec![one] },
+        IR3Op { kind: IR3OpKind::Add, ty: d64, input: vec![old, one], output: vec![new] },
+        IR3Op { kind: IR3OpKind::PtrStore, ty: d64, input: vec![addr, new], output: vec![] },
+      ];
+      // after the phis and `arg` ops, which have to come first
+      let at = bb.instructions.iter()
+        .take_while(|op| matches!(op.kind, IR3OpKind::Phi(_) | IR3OpKind::Arg(_)))
+        .count();
+      bb.instructions.splice(at..at, ops);
+      module.globals.push(IR3Global { name, ty: d64, init: IR3GlobalInit::Zero });
+    }
+    instrumented.push(FunctionCounters {
+      name: func.name.clone(),
+      hash,
+      blocks: func.basic_blocks.iter().map(|v| v.id).collect(),
+    });
+  }
+  instrumented
+}
+
+/// Reads the counters of an instrumented module from the memory of an interpreter that ran it.
+pub fn read_counters(instrumented: &[FunctionCounters], interp: &Interpreter) -> Profile {
+  let mut profile = Profile::default();
+  for func in instrumented {
+    let counts = func.blocks.iter()
+      .filter_map(|block| {
+        let addr = interp.symbol_addr(&counter_name(&func.name, *block))?;
+        Some((*block, interp.load(addr, IR3Type::Data(64))?))
+      })
+      .collect();
+    profile.functions.insert(func.name.clone(), FunctionProfile { hash: func.hash, counts });
+  }
+  profile
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::model::IR3EndOp;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use crate::target::Target;
+  use super::*;
+
+  /// Adds up `n - 1 + ... + 0`.
+  const MODULE: &str = r#"ir3function sum args d64 returns d64 {
+@0:
+  $0 = arg d64 0
+  $1 = const d64 0
+  $2 = const d64 1
+  br @1
+
+@1:
+  $3 = phi d64 $1 @0 $5 @2
+  $4 = phi d64 $1 @0 $6 @2
+  $7 = cmp d64 ult $3 $0
+  br_if $7 @2 @3
+
+@2:
+  $5 = add d64 $3 $2
+  $6 = add d64 $4 $3
+  br @1
+
+@3:
+  ret d64 $4
+}
+
+ir3function main args d64 returns d64 {
+@0:
+  $0 = arg d64 0
+  $1 = call d64 sum d64 $0
+  $2 = call d64 sum d64 $1
+  ret d64 $2
+}
+"#;
+
+  #[test]
+  fn text_round_trip() {
+    let profile = Profile {
+      functions: HashMap::from([
+        ("b".to_owned(), FunctionProfile { hash: 0x1234, counts: BlockCounts::from([(3, 7), (0, 1)]) }),
+        ("a$x".to_owned(), FunctionProfile { hash: u64::MAX, counts: BlockCounts::new() }),
+      ]),
+    };
+    let text = profile.to_string();
+    assert_eq!(text, "a$x ffffffffffffffff\nb 0000000000001234 0:1 3:7\n");
+    assert_eq!(Profile::parse(&format!("\n{}\n  \n", text)).unwrap(), profile);
+    for bad in ["f", "f xyz", "f 12 0:", "f 12 0-1", "f 12 a:1"] {
+      assert!(Profile::parse(bad).is_err(), "{}", bad);
+    }
+  }
+
+  #[test]
+  fn stale_profiles() {
+    let module = parse_module(MODULE).unwrap();
+    let sum = module.function("sum").unwrap();
+    let profile = Profile {
+      functions: HashMap::from([
+        ("sum".to_owned(), FunctionProfile { hash: cfg_hash(sum), counts: BlockCounts::from([(0, 1)]) }),
+        ("main".to_owned(), FunctionProfile { hash: cfg_hash(sum), counts: BlockCounts::from([(0, 1)]) }),
+        ("gone".to_owned(), FunctionProfile { hash: 0, counts: BlockCounts::new() }),
+      ]),
+    };
+    let (counts, stale) = profile.matching_counts(&module);
+    assert_eq!(counts.keys().collect::<Vec<_>>(), ["sum"]);
+    assert_eq!(stale, ["main"]);
+    // changing an edge changes the hash
+    let mut changed = sum.clone();
+    changed.basic_blocks[1].ending = IR3EndOp::BrIf { block1: 3, block2: 2, cond: 7 };
+    assert_ne!(cfg_hash(&changed), cfg_hash(sum));
+  }
+
+  #[test]
+  fn counts_from_interpreter() {
+    let orig = parse_module(MODULE).unwrap();
+    let mut module = orig.clone();
+    let counters = instrument(&mut module);
+    verify_module(&module).unwrap();
+    let target = Target::rv64();
+    let mut interp = Interpreter::new(&module, &target);
+    // sum(4) = 6, then sum(6) = 15
+    assert_eq!(interp.call("main", &[4]).unwrap(), vec![15]);
+    let profile = read_counters(&counters, &interp);
+    assert_eq!(profile.functions["sum"].counts, BlockCounts::from([(0, 2), (1, 12), (2, 10), (3, 2)]));
+    assert_eq!(profile.functions["main"].counts, BlockCounts::from([(0, 1)]));
+    // the profile applies to the uninstrumented module too
+    let (counts, stale) = Profile::parse(&profile.to_string()).unwrap().matching_counts(&orig);
+    assert!(stale.is_empty());
+    assert_eq!(counts["sum"][&1], 12);
+  }
+}
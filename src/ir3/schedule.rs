@@ -0,0 +1,190 @@
+//! Instruction scheduling for in-order pipelines.
+//!
+//! Each block is split into regions at calls, atomic ops and fences, which stay where they are.
+//! Within a region, the ops are list scheduled on their dependence DAG: an op depends on the ops defining its inputs
+//! (for as many cycles as their latency in the target's `SchedModel`), and loads and stores stay in order if they may alias.
+//! Every cycle, the ready op with the longest latency path to the end of the region is issued next.
+//! Phis and `arg` ops stay at the start of their block.
+//!
+//! This runs on IR3 before register allocation. Once there is a register allocator, it should run again
+//! on its output, so that spill code is scheduled too.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use crate::ir3::alias::{AliasAnalysis, AliasResult};
+use crate::ir3::model::{IR3Function, IR3Op, IR3OpKind};
+use crate::target::{SchedModel, Target};
+
+/// Cycles until the result of `op` can be used.
+fn latency(op: &IR3Op, model: &SchedModel) -> u32 {
+  match op.kind {
+    IR3OpKind::PtrLoad | IR3OpKind::AtomicLoad(_) => model.load,
+    IR3OpKind::Smull | IR3OpKind::Umull | IR3OpKind::Smulh | IR3OpKind::Umulh |
+    IR3OpKind::SmulOverflow | IR3OpKind::UmulOverflow => model.mul,
+    IR3OpKind::Sdiv | IR3OpKind::Udiv | IR3OpKind::Srem | IR3OpKind::Urem => model.div,
+    IR3OpKind::Fadd | IR3OpKind::Fsub | IR3OpKind::Fmul | IR3OpKind::Fcmp(_) | IR3OpKind::Fconv(_) |
+    IR3OpKind::SiToFp(_) | IR3OpKind::UiToFp(_) | IR3OpKind::FpToSi(_) | IR3OpKind::FpToUi(_) => model.float,
+    IR3OpKind::Fdiv | IR3OpKind::Fsqrt => model.fdiv,
+    _ => 1,
+  }
+}
+
+/// Ops that nothing is moved across.
+fn is_barrier(op: &IR3Op) -> bool {
+  matches!(op.kind, IR3OpKind::Call(_) | IR3OpKind::AtomicLoad(_) | IR3OpKind::AtomicStore(_) |
+    IR3OpKind::AtomicRmw(..) | IR3OpKind::CmpXchg(_) | IR3OpKind::Fence(_))
+}
+
+pub fn schedule_blocks(func: &mut IR3Function, target: &Target) {
+  let Some(model) = &target.sched else { return };
+  let aa = AliasAnalysis::new(func, target);
+  for bb in &mut func.basic_blocks {
+    let fixed = bb.instructions.iter()
+      .take_while(|op| matches!(op.kind, IR3OpKind::Phi(_) | IR3OpKind::Arg(_)))
+      .count();
+    let mut ops = bb.instructions.split_off(fixed);
+    let mut region = vec![];
+    for op in ops.drain(..) {
+      if is_barrier(&op) {
+        bb.instructions.extend(schedule_region(std::mem::take(&mut region), model, &aa));
+        bb.instructions.push(op);
+      } else {
+        region.push(op);
+      }
+    }
+    bb.instructions.extend(schedule_region(region, model, &aa));
+  }
+}
+
+/// Whether `later` has to stay after `earlier` because they access memory that may overlap.
+fn memory_dependent(earlier: &IR3Op, later: &IR3Op, aa: &AliasAnalysis) -> bool {
+  let is_store = |op: &IR3Op| op.kind == IR3OpKind::PtrStore;
+  let is_access = |op: &IR3Op| matches!(op.kind, IR3OpKind::PtrLoad | IR3OpKind::PtrStore);
+  is_access(earlier) && is_access(later) && (is_store(earlier) || is_store(later))
+    && aa.alias(earlier.input[0], aa.access_size(earlier.ty), later.input[0], aa.access_size(later.ty)) != AliasResult::No
+}
+
+/// Reorders the ops of a region without barriers.
+fn schedule_region(ops: Vec<IR3Op>, model: &SchedModel, aa: &AliasAnalysis) -> Vec<IR3Op> {
+  if ops.len() < 2 {
+    return ops;
+  }
+  let defs = ops.iter()
+    .enumerate()
+    .flat_map(|(i, op)| op.output.iter().map(move |v| (*v, i)))
+    .collect::<HashMap<_, _>>();
+  // edges to each op from the ops it depends on, with the number of cycles it has to wait
+  let mut preds = vec![vec![]; ops.len()];
+  let mut succs = vec![vec![]; ops.len()];
+  for (j, op) in ops.iter().enumerate() {
+    let mut add = |i: usize, cycles: u32| {
+      preds[j].push((i, cycles));
+      succs[i].push((j, cycles));
+    };
+    for var in &op.input {
+      if let Some(i) = defs.get(var) {
+        add(*i, latency(&ops[*i], model));
+      }
+    }
+    for (i, prev) in ops[..j].iter().enumerate() {
+      if memory_dependent(prev, op, aa) {
+        add(i, 1);
+      }
+    }
+  }
+  // length of the longest path from each op to the end of the region
+  let mut height = vec![0; ops.len()];
+  for i in (0..ops.len()).rev() {
+    height[i] = succs[i].iter()
+      .map(|(j, cycles)| cycles + height[*j])
+      .max()
+      .unwrap_or(latency(&ops[i], model));
+  }
+  let mut waiting = preds.iter().map(Vec::len).collect::<Vec<_>>();
+  // the cycle each op can issue at, once all its dependencies have issued
+  let mut earliest = vec![0; ops.len()];
+  let mut ready = (0..ops.len()).filter(|i| waiting[*i] == 0).collect::<Vec<_>>();
+  let mut order = vec![];
+  let mut cycle = 0;
+  while !ready.is_empty() {
+    // the most critical op that is ready now, or the one that will be ready first if the pipeline has to stall
+    let pos = (0..ready.len())
+      .filter(|v| earliest[ready[*v]] <= cycle)
+      .max_by_key(|v| (height[ready[*v]], Reverse(ready[*v])))
+      .or_else(|| (0..ready.len()).min_by_key(|v| (earliest[ready[*v]], Reverse(height[ready[*v]]), ready[*v])))
+      .unwrap();
+    let next = ready.swap_remove(pos);
+    cycle = cycle.max(earliest[next]);
+    for (j, cycles) in &succs[next] {
+      earliest[*j] = earliest[*j].max(cycle + cycles);
+      waiting[*j] -= 1;
+      if waiting[*j] == 0 {
+        ready.push(*j);
+      }
+    }
+    order.push(next);
+    cycle += 1;
+  }
+  let mut ops = ops.into_iter().map(Some).collect::<Vec<_>>();
+  order.into_iter().map(|i| ops[i].take().unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use super::*;
+
+  /// Schedules `body` for rv64, and returns the kinds of the ops after the `arg`s.
+  fn schedule(body: &str) -> Vec<&'static str> {
+    let text = format!(
+      "extern ext returns void\nglobal g d64\n\nir3function f args ptr ptr d64 returns d64 {{\n@0:\n  \
+       $0 = arg ptr 0\n  $1 = arg ptr 1\n  $2 = arg d64 2\n{}}}\n",
+      body
+    );
+    let mut module = parse_module(&text).unwrap();
+    schedule_blocks(&mut module.functions[0], &Target::rv64());
+    verify_module(&module).unwrap();
+    let ops = &module.functions[0].basic_blocks[0].instructions;
+    assert!(ops[..3].iter().all(|op| matches!(op.kind, IR3OpKind::Arg(_))));
+    ops[3..].iter().map(|op| op.kind.name()).collect()
+  }
+
+  #[test]
+  fn hoists_loads() {
+    let ops = schedule(
+      "  $3 = add d64 $2 $2\n  $4 = xor d64 $3 $2\n  $5 = ptr_load d64 $0\n  $6 = add d64 $5 $4\n  ret d64 $6\n"
+    );
+    assert_eq!(ops, ["ptr_load", "add", "xor", "add"]);
+  }
+
+  #[test]
+  fn barriers_stay() {
+    for barrier in [
+      "call void ext", "fence seq_cst", "$9 = atomic_load d64 seq_cst $1", "$9 = atomic_rmw d64 add seq_cst $1 $2",
+    ] {
+      let ops = schedule(&format!(
+        "  $3 = add d64 $2 $2\n  $4 = xor d64 $3 $2\n  {}\n  $5 = ptr_load d64 $0\n  $6 = add d64 $5 $4\n  ret d64 $6\n",
+        barrier
+      ));
+      let kind = barrier.split(' ').find(|v| !v.starts_with('$') && *v != "=").unwrap();
+      assert_eq!(ops, ["add", "xor", kind, "ptr_load", "add"], "{}", barrier);
+    }
+  }
+
+  #[test]
+  fn memory_order() {
+    // the pointer arguments may point to the same memory
+    let ops = schedule(
+      "  $3 = add d64 $2 $2\n  ptr_store d64 $1 $3\n  $4 = ptr_load d64 $0\n  $5 = add d64 $4 $2\n  ret d64 $5\n"
+    );
+    assert_eq!(ops, ["add", "ptr_store", "ptr_load", "add"]);
+    // a stack slot and a global do not overlap
+    let ops = schedule(
+      "  $3 = stack_alloc ptr 8 8\n  $4 = global_addr ptr g\n  $5 = add d64 $2 $2\n  ptr_store d64 $3 $5\n  \
+       $6 = ptr_load d64 $4\n  $7 = add d64 $6 $2\n  ret d64 $7\n"
+    );
+    let (store, load) = (ops.iter().position(|v| *v == "ptr_store"), ops.iter().position(|v| *v == "ptr_load"));
+    assert!(load < store, "{:?}", ops);
+  }
+}
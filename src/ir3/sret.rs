@@ -0,0 +1,251 @@
+//! Lowering of functions that return more values than the calling convention has return registers for.
+//!
+//! Such a function gets a hidden `ptr` argument in front of its other arguments, pointing to a buffer
+//! that it stores the returned values into instead. Callers allocate the buffer in their entry block
+//! and load the values back after the call. External functions get the same signature change.
+//! Lowered functions lose their `pure` and `readonly` attributes, since they now write memory.
+//!
+//! This runs after legalization, so wide values have already been split into halves.
+
+use std::collections::HashMap;
+use std::mem::take;
+use crate::ir3::builder::IR3OpBuilder;
+use crate::ir3::model::{IR3Call, IR3EndOp, IR3Function, IR3FunctionAttr, IR3Module, IR3Op, IR3OpKind, IR3Type, IR3VarID};
+use crate::target::Target;
+
+/// Where each returned value is placed in the sret buffer.
+struct SretLayout {
+  offsets: Vec<u32>,
+  size: u32,
+  align: u32,
+}
+
+impl SretLayout {
+  fn new(types: &[IR3Type], target: &Target) -> Self {
+    let mut offsets = vec![];
+    let mut size = 0u32;
+    let mut align = 1;
+    for ty in types {
+      size = size.next_multiple_of(target.align_of(*ty));
+      offsets.push(size);
+      size += target.size_of(*ty);
+      align = align.max(target.align_of(*ty));
+    }
+    SretLayout { offsets, size: size.next_multiple_of(align), align }
+  }
+}
+
+pub fn lower_sret(module: &mut IR3Module, target: &Target) {
+  let max = target.max_ret_values();
+  let lowered = module.functions.iter()
+    .map(|f| (&f.name, &f.ret))
+    .chain(module.externs.iter().map(|f| (&f.name, &f.ret)))
+    .filter(|(_, ret)| ret.len() > max)
+    .map(|(name, ret)| (name.clone(), SretLayout::new(ret, target)))
+    .collect::<HashMap<_, _>>();
+  if lowered.is_empty() {
+    return;
+  }
+  for func in &mut module.functions {
+    if let Some(layout) = lowered.get(&func.name) {
+      lower_callee(func, layout, target);
+      func.attrs.retain(|(attr, _)| !writes_sret(*attr));
+    }
+    lower_calls(func, &lowered, target);
+  }
+  for ext in &mut module.externs {
+    if lowered.contains_key(&ext.name) {
+      ext.args.insert(0, IR3Type::Ptr);
+      ext.ret.clear();
+      ext.attrs.retain(|(attr, _)| !writes_sret(*attr));
+    }
+  }
+}
+
+/// Attributes that no longer hold once a function stores its results through the sret pointer.
+fn writes_sret(attr: IR3FunctionAttr) -> bool {
+  matches!(attr, IR3FunctionAttr::Pure | IR3FunctionAttr::ReadOnly)
+}
+
+fn offset_ptr(b: &mut IR3OpBuilder, ptr: IR3VarID, offset: u32, target: &Target) -> IR3VarID {
+  if offset == 0 {
+    return ptr;
+  }
+  let offset_ty = IR3Type::Data(target.ptr_width);
+  let offset = b.push_const(offset_ty, offset as u64);
+  b.push(IR3OpKind::PtrUadd, offset_ty, vec![ptr, offset])
+}
+
+/// Makes a function take the sret pointer as its first argument and store its results through it.
+fn lower_callee(func: &mut IR3Function, layout: &SretLayout, target: &Target) {
+  let mut b = IR3OpBuilder::new(func.next_var_id());
+  let sret = b.push(IR3OpKind::Arg(0), IR3Type::Ptr, vec![]);
+  let mut entry_ops = take(&mut b.ops);
+  for bb in &mut func.basic_blocks {
+    for op in &mut bb.instructions {
+      if let IR3OpKind::Arg(idx) = &mut op.kind {
+        *idx += 1;
+      }
+    }
+    let IR3EndOp::Ret { values } = &mut bb.ending else { continue };
+    for ((ty, var), offset) in values.drain(..).zip(&layout.offsets) {
+      let ptr = offset_ptr(&mut b, sret, *offset, target);
+      b.push_void(IR3OpKind::PtrStore, ty, vec![ptr, var]);
+    }
+    bb.instructions.append(&mut b.ops);
+  }
+  entry_ops.append(&mut func.basic_blocks[0].instructions);
+  func.basic_blocks[0].instructions = entry_ops;
+  func.args.insert(0, IR3Type::Ptr);
+  func.ret.clear();
+}
+
+/// Rewrites calls to lowered functions to pass a buffer and load the results from it.
+fn lower_calls(func: &mut IR3Function, lowered: &HashMap<String, SretLayout>, target: &Target) {
+  let mut b = IR3OpBuilder::new(func.next_var_id());
+  let mut slots = vec![];
+  for bb in &mut func.basic_blocks {
+    for op in take(&mut bb.instructions) {
+      let IR3OpKind::Call(call) = &op.kind else {
+        b.ops.push(op);
+        continue;
+      };
+      let Some(layout) = lowered.get(&call.symbol_name) else {
+        b.ops.push(op);
+        continue;
+      };
+      let slot = b.new_var();
+      slots.push(IR3Op {
+        kind: IR3OpKind::StackAlloc { size: layout.size, align: layout.align },
+        ty: IR3Type::Ptr,
+        input: vec![],
+        output: vec![slot],
+      });
+      let mut arg_types = vec![IR3Type::Ptr];
+      arg_types.extend(&call.arg_types);
+      let mut input = vec![slot];
+      input.extend(&op.input);
+      b.push_void(IR3OpKind::Call(IR3Call {
+        symbol_name: call.symbol_name.clone(),
+        arg_types,
+        ret_types: vec![],
+      }), IR3Type::Void, input);
+      for ((ty, output), offset) in call.ret_types.iter().zip(&op.output).zip(&layout.offsets) {
+        let ptr = offset_ptr(&mut b, slot, *offset, target);
+        b.push_to(IR3OpKind::PtrLoad, *ty, vec![ptr], *output);
+      }
+    }
+    bb.instructions = take(&mut b.ops);
+  }
+  slots.append(&mut func.basic_blocks[0].instructions);
+  func.basic_blocks[0].instructions = slots;
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::parse::parse_module;
+  use super::*;
+
+  const MODULE: &str = "extern ext3 attrs readonly args d32 returns d32 d32 d32
+
+ir3function three attrs pure willreturn args d64 d64 returns d32 d64 d32 {
+@0:
+  $0 = arg d64 0
+  $1 = arg d64 1
+  $2 = const d32 1
+  $3 = trunc d32 d64 $1
+  br @1
+
+@1:
+  ret d32 $2 d64 $0 d32 $3
+
+}
+
+ir3function main returns d64 {
+@0:
+  $0 = const d64 40
+  $1 = const d64 2
+  br @1
+
+@1:
+  $2 $3 $4 = call d32 d64 d32 three d64 $0 d64 $1
+  $5 = zext d64 d32 $2
+  $6 = zext d64 d32 $4
+  $7 = add d64 $3 $5
+  $8 = add d64 $7 $6
+  ret d64 $8
+
+}
+";
+
+  #[test]
+  fn lowers_callee() {
+    let mut module = parse_module(MODULE).unwrap();
+    lower_sret(&mut module, &Target::rv64());
+    assert_eq!(module.functions[0].to_string(), "ir3function three attrs willreturn args ptr d64 d64 returns void {
+@0:
+  $4 = arg ptr 0
+  $0 = arg d64 1
+  $1 = arg d64 2
+  $2 = const d32 1
+  $3 = trunc d32 d64 $1
+  br @1
+
+@1:
+  ptr_store d32 $4 $2
+  $5 = const d64 8
+  $6 = ptr_uadd d64 $4 $5
+  ptr_store d64 $6 $0
+  $7 = const d64 16
+  $8 = ptr_uadd d64 $4 $7
+  ptr_store d32 $8 $3
+  ret void
+
+}");
+    assert_eq!(module.externs[0].to_string(), "extern ext3 args ptr d32 returns void");
+  }
+
+  #[test]
+  fn lowers_caller() {
+    let mut module = parse_module(MODULE).unwrap();
+    lower_sret(&mut module, &Target::rv64());
+    assert_eq!(module.functions[1].to_string(), "ir3function main returns d64 {
+@0:
+  $9 = stack_alloc ptr 24 8
+  $0 = const d64 40
+  $1 = const d64 2
+  br @1
+
+@1:
+  call void three ptr $9 d64 $0 d64 $1
+  $2 = ptr_load d32 $9
+  $10 = const d64 8
+  $11 = ptr_uadd d64 $9 $10
+  $3 = ptr_load d64 $11
+  $12 = const d64 16
+  $13 = ptr_uadd d64 $9 $12
+  $4 = ptr_load d32 $13
+  $5 = zext d64 d32 $2
+  $6 = zext d64 d32 $4
+  $7 = add d64 $3 $5
+  $8 = add d64 $7 $6
+  ret d64 $8
+
+}");
+  }
+
+  #[test]
+  fn keeps_results() {
+    let target = Target::rv64();
+    let module = parse_module(MODULE).unwrap();
+    let mut lowered = module.clone();
+    lower_sret(&mut lowered, &target);
+    assert_eq!(Interpreter::new(&module, &target).call("main", &[]).unwrap(), vec![43]);
+    assert_eq!(Interpreter::new(&lowered, &target).call("main", &[]).unwrap(), vec![43]);
+    // lowered functions return nothing, so lowering again does nothing
+    let mut again = lowered.clone();
+    lower_sret(&mut again, &target);
+    assert_eq!(again, lowered);
+  }
+}
@@ -0,0 +1,314 @@
+//! Lowering of `switch` terminators.
+//!
+//! The cases of a switch are sorted and grouped into clusters:
+//! - Dense runs of cases become jump tables. These are kept as a `switch` with just the cases of the run,
+//!   which backends emit as a bounds check followed by an indexed jump.
+//! - Runs of consecutive values that all go to the same block become a single range check.
+//! - Everything else is compared one value at a time.
+//!
+//! The clusters are then searched with a balanced tree of `br_if`s. A switch that is one
+//! jump table as a whole is left untouched.
+
+use std::collections::HashMap;
+use std::mem::take;
+use crate::ir3::builder::IR3OpBuilder;
+use crate::ir3::model::{IR3BasicBlock, IR3BBID, IR3CompareMode, IR3EndOp, IR3Function, IR3OpKind, IR3Type, IR3VarID};
+use crate::target::Target;
+
+/// Jump tables with fewer cases than this are not worth the indirect jump.
+const MIN_JUMP_TABLE_CASES: usize = 4;
+/// Minimum percentage of jump table entries that have to be real cases (not the default).
+const MIN_JUMP_TABLE_DENSITY: u128 = 40;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Cluster {
+  Table(Vec<(u64, IR3BBID)>),
+  Range(u64, u64, IR3BBID),
+  Value(u64, IR3BBID),
+}
+
+impl Cluster {
+  fn low(&self) -> u64 {
+    match self {
+      Cluster::Table(cases) => cases[0].0,
+      Cluster::Range(low, _, _) | Cluster::Value(low, _) => *low,
+    }
+  }
+}
+
+pub fn lower_switches(func: &mut IR3Function, target: &Target) {
+  let var_types = func.var_types();
+  let widest = *target.legal_widths.last().unwrap();
+  let mut lowering = SwitchLowering {
+    next_block: func.next_block_id(),
+    b: IR3OpBuilder::new(func.next_var_id()),
+    blocks: vec![],
+  };
+  for i in 0..func.basic_blocks.len() {
+    let IR3EndOp::Switch { cond, cases, default } = &func.basic_blocks[i].ending else { continue };
+    let (cond, default) = (*cond, *default);
+    let ty = var_types[&cond];
+    // jump table indices have to fit in a register
+    let tables = matches!(ty, IR3Type::Data(w) if w <= widest);
+    // cases that go to the default block are redundant
+    let cases = cases.iter().copied().filter(|(_, block)| *block != default).collect::<Vec<_>>();
+    let clusters = cluster_cases(cases, tables);
+    if let [Cluster::Table(_)] = clusters.as_slice() {
+      continue;
+    }
+    let id = func.basic_blocks[i].id;
+    let old_succs = func.basic_blocks[i].ending.successors();
+    if clusters.is_empty() {
+      func.basic_blocks[i].ending = IR3EndOp::Br { block: default };
+      continue;
+    }
+    lowering.search(id, cond, ty, default, &clusters);
+    let mut blocks = take(&mut lowering.blocks);
+    // the root of the search continues the original block
+    let root = blocks.remove(0);
+    let bb = &mut func.basic_blocks[i];
+    bb.instructions.extend(root.instructions);
+    bb.ending = root.ending;
+    let mut new_preds: HashMap<IR3BBID, Vec<IR3BBID>> = HashMap::new();
+    for bb in blocks.iter().chain([&func.basic_blocks[i]]) {
+      for succ in bb.ending.successors() {
+        new_preds.entry(succ).or_default().push(bb.id);
+      }
+    }
+    func.basic_blocks.append(&mut blocks);
+    fix_phis(func, id, &old_succs, &new_preds);
+  }
+}
+
+/// Phis in the old successors of `block` have to take the value they got from `block`
+/// from every block of the search that branches to them instead.
+fn fix_phis(func: &mut IR3Function, block: IR3BBID, old_succs: &[IR3BBID], new_preds: &HashMap<IR3BBID, Vec<IR3BBID>>) {
+  for bb in &mut func.basic_blocks {
+    if !old_succs.contains(&bb.id) {
+      continue;
+    }
+    for op in &mut bb.instructions {
+      let IR3OpKind::Phi(phi) = &mut op.kind else { continue };
+      let Some(idx) = phi.blocks.iter().position(|v| *v == block) else { continue };
+      phi.blocks.remove(idx);
+      let value = op.input.remove(idx);
+      for pred in &new_preds[&bb.id] {
+        phi.blocks.push(*pred);
+        op.input.push(value);
+      }
+    }
+  }
+}
+
+/// Groups sorted cases into clusters. Without `tables`, no jump tables are formed.
+fn cluster_cases(mut cases: Vec<(u64, IR3BBID)>, tables: bool) -> Vec<Cluster> {
+  cases.sort();
+  let mut clusters = vec![];
+  let mut i = 0;
+  while i < cases.len() {
+    // the longest dense run starting here
+    let table_end = if tables {
+      (i + MIN_JUMP_TABLE_CASES..=cases.len()).rev()
+        .find(|end| {
+          let span = (cases[end - 1].0 - cases[i].0) as u128 + 1;
+          (end - i) as u128 * 100 >= span * MIN_JUMP_TABLE_DENSITY
+        })
+    } else {
+      None
+    };
+    if let Some(end) = table_end {
+      clusters.push(Cluster::Table(cases[i..end].to_vec()));
+      i = end;
+      continue;
+    }
+    let (low, block) = cases[i];
+    let mut high = low;
+    i += 1;
+    while i < cases.len() && cases[i].1 == block && high.checked_add(1) == Some(cases[i].0) {
+      high += 1;
+      i += 1;
+    }
+    clusters.push(if low == high { Cluster::Value(low, block) } else { Cluster::Range(low, high, block) });
+  }
+  clusters
+}
+
+struct SwitchLowering {
+  next_block: IR3BBID,
+  b: IR3OpBuilder,
+  /// Blocks of the search, the root first.
+  blocks: Vec<IR3BasicBlock>,
+}
+
+impl SwitchLowering {
+  fn new_block(&mut self) -> IR3BBID {
+    self.next_block += 1;
+    self.next_block - 1
+  }
+
+  /// Emits block `id`, which branches to the cluster `cond` falls into, or to `default` if there is none.
+  fn search(&mut self, id: IR3BBID, cond: IR3VarID, ty: IR3Type, default: IR3BBID, clusters: &[Cluster]) {
+    if let [cluster] = clusters {
+      let ending = self.test(cond, ty, default, cluster);
+      let bb = self.b.finish_block(id, ending);
+      self.blocks.push(bb);
+      return;
+    }
+    let mid = clusters.len() / 2;
+    let pivot = self.b.push_const(ty, clusters[mid].low());
+    let is_low = self.b.push(IR3OpKind::Cmp(IR3CompareMode::ULt), ty, vec![cond, pivot]);
+    let low = self.new_block();
+    let high = self.new_block();
+    let bb = self.b.finish_block(id, IR3EndOp::BrIf { block1: low, block2: high, cond: is_low });
+    self.blocks.push(bb);
+    self.search(low, cond, ty, default, &clusters[..mid]);
+    self.search(high, cond, ty, default, &clusters[mid..]);
+  }
+
+  fn test(&mut self, cond: IR3VarID, ty: IR3Type, default: IR3BBID, cluster: &Cluster) -> IR3EndOp {
+    match cluster {
+      Cluster::Table(cases) => IR3EndOp::Switch { cond, cases: cases.clone(), default },
+      Cluster::Range(low, high, block) => {
+        // one unsigned compare checks both ends of the range
+        let low_v = self.b.push_const(ty, *low);
+        let offset = self.b.push(IR3OpKind::Sub, ty, vec![cond, low_v]);
+        let len = self.b.push_const(ty, high - low);
+        let in_range = self.b.push(IR3OpKind::Cmp(IR3CompareMode::ULe), ty, vec![offset, len]);
+        IR3EndOp::BrIf { block1: *block, block2: default, cond: in_range }
+      }
+      Cluster::Value(value, block) => {
+        let value = self.b.push_const(ty, *value);
+        let eq = self.b.push(IR3OpKind::Cmp(IR3CompareMode::Eq), ty, vec![cond, value]);
+        IR3EndOp::BrIf { block1: *block, block2: default, cond: eq }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use super::*;
+
+  /// Sparse cases, several of which go to a block with a phi.
+  const SPARSE: &str = r#"ir3function f args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  $1 = const d32 100
+  switch $0 @1 1 @2 2 @2 3 @2 11 @3 20 @4 50 @5 1000 @4 7 @1
+
+@1:
+  $2 = const d32 7
+  br @4
+
+@2:
+  $3 = const d32 2
+  ret d32 $3
+
+@3:
+  $4 = const d32 3
+  ret d32 $4
+
+@4:
+  $5 = phi d32 $1 @0 $2 @1
+  ret d32 $5
+
+@5:
+  $6 = const d32 5
+  ret d32 $6
+}
+"#;
+
+  /// A dense run of cases, followed by a sparse one.
+  const MIXED: &str = r#"ir3function f args d32 returns d32 {
+@0:
+  $0 = arg d32 0
+  switch $0 @1 0 @2 1 @3 2 @2 4 @3 5 @2 6 @3 500 @2
+
+@1:
+  $1 = const d32 1
+  ret d32 $1
+
+@2:
+  $2 = const d32 2
+  ret d32 $2
+
+@3:
+  $3 = const d32 3
+  ret d32 $3
+}
+"#;
+
+  fn check_lowering(text: &str, values: &[u64]) -> IR3Function {
+    let module = parse_module(text).unwrap();
+    let mut lowered = module.clone();
+    let target = Target::rv64();
+    lower_switches(&mut lowered.functions[0], &target);
+    verify_module(&lowered).unwrap();
+    let mut orig_interp = Interpreter::new(&module, &target);
+    let mut lowered_interp = Interpreter::new(&lowered, &target);
+    for v in values {
+      assert_eq!(lowered_interp.call("f", &[*v]).unwrap(), orig_interp.call("f", &[*v]).unwrap(), "{}", v);
+    }
+    lowered.functions[0].clone()
+  }
+
+  fn switches(func: &IR3Function) -> Vec<&IR3EndOp> {
+    func.basic_blocks.iter().map(|bb| &bb.ending).filter(|e| matches!(e, IR3EndOp::Switch { .. })).collect()
+  }
+
+  #[test]
+  fn clusters_at_density_threshold() {
+    // 4 cases over 10 values is exactly 40% dense, over 11 values it is not
+    let cases = vec![(0, 1), (3, 2), (6, 1), (9, 2)];
+    assert_eq!(cluster_cases(cases.clone(), true), vec![Cluster::Table(cases.clone())]);
+    assert_eq!(cluster_cases(cases.clone(), false), cases.iter().map(|(v, b)| Cluster::Value(*v, *b)).collect::<Vec<_>>());
+    let cases = vec![(0, 1), (3, 2), (6, 1), (10, 2)];
+    assert_eq!(cluster_cases(cases.clone(), true), cases.iter().map(|(v, b)| Cluster::Value(*v, *b)).collect::<Vec<_>>());
+    // too few cases for a table, but consecutive with the same target
+    let cases = vec![(200, 3), (2, 1), (0, 1), (1, 1), (100, 2)];
+    assert_eq!(
+      cluster_cases(cases, true),
+      vec![Cluster::Range(0, 2, 1), Cluster::Value(100, 2), Cluster::Value(200, 3)]
+    );
+    // the table takes the longest dense run, the rest is clustered separately
+    let cases = vec![(0, 1), (1, 2), (2, 1), (3, 2), (50, 3), (51, 3)];
+    assert_eq!(
+      cluster_cases(cases, true),
+      vec![Cluster::Table(vec![(0, 1), (1, 2), (2, 1), (3, 2)]), Cluster::Range(50, 51, 3)]
+    );
+  }
+
+  #[test]
+  fn lowered_switch_is_equivalent() {
+    let values = [0, 1, 2, 3, 4, 7, 10, 11, 12, 20, 21, 50, 999, 1000, 1001, u32::MAX as u64];
+    let func = check_lowering(SPARSE, &values);
+    assert!(switches(&func).is_empty());
+    let func = check_lowering(MIXED, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 499, 500, 501, u32::MAX as u64]);
+    // the dense run stays as a smaller switch
+    let [IR3EndOp::Switch { cases, .. }] = switches(&func)[..] else { panic!("{}", func) };
+    assert_eq!(cases.len(), 6);
+  }
+
+  #[test]
+  fn single_table_is_untouched() {
+    let text = MIXED.replace(" 500 @2", "");
+    let module = parse_module(&text).unwrap();
+    let mut func = module.functions[0].clone();
+    lower_switches(&mut func, &Target::rv64());
+    assert_eq!(func, module.functions[0]);
+  }
+
+  #[test]
+  fn phis_get_every_search_block() {
+    let func = check_lowering(SPARSE, &[20, 1000, 5]);
+    let join = func.basic_blocks.iter().find(|bb| bb.id == 4).unwrap();
+    let IR3OpKind::Phi(phi) = &join.instructions[0].kind else { panic!("{}", func) };
+    // @1, plus the separate compares for 20 and 1000
+    assert_eq!(phi.blocks.len(), 3);
+    assert!(phi.blocks.contains(&1) && !phi.blocks.contains(&0));
+    assert_eq!(join.instructions[0].input.iter().filter(|v| **v == 1).count(), 2);
+  }
+}
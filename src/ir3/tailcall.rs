@@ -0,0 +1,180 @@
+//! Tail calls.
+//!
+//! A tail call is a `call` that is the last op of its block, where the block returns exactly the results of the call,
+//! either itself or by branching to an empty block that does (as the join block of an `if` does).
+//! Self tail calls are turned into loops: the entry block becomes a loop header whose `arg` ops are replaced
+//! with phis, and a new entry block holds the `arg` ops. Each tail call becomes a branch back to the header.
+//! Functions with `stack_alloc`s are left alone, since the next iteration would reuse the slots that the call
+//! could still be pointed at.
+//!
+//! Tail calls to other functions (sibling calls) are out of scope and stay ordinary calls.
+
+use crate::ir3::cfg::remove_unreachable_blocks;
+use crate::ir3::model::{IR3BasicBlock, IR3EndOp, IR3Function, IR3Op, IR3OpKind, IR3Phi};
+
+/// Returns the name of the called function and the call op if `bb` ends in a tail call.
+fn tail_call<'a>(func: &'a IR3Function, bb: &'a IR3BasicBlock) -> Option<(&'a str, &'a IR3Op)> {
+  let op = bb.instructions.last()?;
+  let IR3OpKind::Call(call) = &op.kind else { return None };
+  let ending = match bb.ending {
+    IR3EndOp::Br { block } => {
+      let next = func.basic_blocks.iter().find(|v| v.id == block)?;
+      if !next.instructions.is_empty() {
+        return None;
+      }
+      &next.ending
+    }
+    ref ending => ending,
+  };
+  let IR3EndOp::Ret { values } = ending else { return None };
+  let returns_results = values.len() == op.output.len()
+    && values.iter().zip(&op.output).all(|((_, v), out)| v == out);
+  returns_results.then_some((call.symbol_name.as_str(), op))
+}
+
+pub fn eliminate_tail_calls(func: &mut IR3Function) {
+  let tail_calls = func.basic_blocks.iter()
+    .enumerate()
+    .filter_map(|(i, bb)| tail_call(func, bb).filter(|(name, _)| *name == func.name).map(|(_, op)| (i, op.input.clone())))
+    .collect::<Vec<_>>();
+  let has_stack = func.basic_blocks.iter()
+    .flat_map(|bb| &bb.instructions)
+    .any(|op| matches!(op.kind, IR3OpKind::StackAlloc { .. }));
+  if tail_calls.is_empty() || has_stack {
+    return;
+  }
+  let header = func.basic_blocks[0].id;
+  let entry = func.basic_blocks.iter().map(|v| v.id).max().unwrap() + 1;
+  let mut next_var = func.next_var_id();
+  // the arg ops move to the new entry block, with new outputs, and phis take their place
+  let mut entry_ops = vec![];
+  let mut phis = vec![];
+  func.basic_blocks[0].instructions.retain(|op| {
+    let IR3OpKind::Arg(idx) = op.kind else { return true };
+    entry_ops.push(IR3Op { kind: op.kind.clone(), ty: op.ty, input: vec![], output: vec![next_var] });
+    phis.push((idx as usize, IR3Op {
+      kind: IR3OpKind::Phi(IR3Phi { blocks: vec![entry] }),
+      ty: op.ty,
+      input: vec![next_var],
+      output: op.output.clone(),
+    }));
+    next_var += 1;
+    false
+  });
+  for (i, args) in tail_calls {
+    let bb = &mut func.basic_blocks[i];
+    bb.instructions.pop();
+    bb.ending = IR3EndOp::Br { block: header };
+    for (idx, phi) in &mut phis {
+      let IR3OpKind::Phi(IR3Phi { blocks }) = &mut phi.kind else { unreachable!() };
+      blocks.push(bb.id);
+      phi.input.push(args[*idx]);
+    }
+  }
+  let header_ops = &mut func.basic_blocks[0].instructions;
+  header_ops.splice(0..0, phis.into_iter().map(|(_, phi)| phi));
+  func.basic_blocks.insert(0, IR3BasicBlock {
+    id: entry,
+    instructions: entry_ops,
+    ending: IR3EndOp::Br { block: header },
+  });
+  // empty blocks that only returned the results of a tail call are no longer reached
+  remove_unreachable_blocks(func);
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::model::IR3Module;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use crate::target::Target;
+  use super::*;
+
+  /// Adds up `n + ... + 1` onto an accumulator.
+  const SUM: &str = r#"ir3function sum args d64 d64 returns d64 {
+@0:
+  $0 = arg d64 0
+  $1 = arg d64 1
+  $2 = const d64 0
+  $3 = cmp d64 eq $0 $2
+  br_if $3 @1 @2
+
+@1:
+  ret d64 $1
+
+@2:
+  $4 = const d64 1
+  $5 = sub d64 $0 $4
+  $6 = add d64 $1 $0
+  $7 = call d64 sum d64 $5 d64 $6
+  ret d64 $7
+}
+"#;
+
+  fn calls(func: &IR3Function) -> usize {
+    func.basic_blocks.iter().flat_map(|bb| &bb.instructions).filter(|op| matches!(op.kind, IR3OpKind::Call(_))).count()
+  }
+
+  /// Eliminates tail calls in `text`, and checks the result against the original on small inputs.
+  fn eliminate(text: &str) -> IR3Module {
+    let orig = parse_module(text).unwrap();
+    let mut module = orig.clone();
+    eliminate_tail_calls(&mut module.functions[0]);
+    verify_module(&module).unwrap();
+    let target = Target::rv64();
+    let mut orig_interp = Interpreter::new(&orig, &target);
+    let mut interp = Interpreter::new(&module, &target);
+    for n in [0, 1, 2, 10, 100] {
+      assert_eq!(interp.call("sum", &[n, 5]).unwrap(), orig_interp.call("sum", &[n, 5]).unwrap(), "{}", n);
+    }
+    drop(interp);
+    module
+  }
+
+  #[test]
+  fn direct_tail_call() {
+    let module = eliminate(SUM);
+    let func = &module.functions[0];
+    assert_eq!(calls(func), 0);
+    // a new entry block holds the args, and the old one became the loop header
+    assert_eq!(func.basic_blocks[0].ending, IR3EndOp::Br { block: 0 });
+    let phis = func.basic_blocks[1].instructions.iter().take_while(|op| matches!(op.kind, IR3OpKind::Phi(_))).count();
+    assert_eq!(phis, 2);
+  }
+
+  #[test]
+  fn through_empty_join() {
+    let text = SUM.replace("  ret d64 $7\n}", "  br @3\n\n@3:\n  ret d64 $7\n}");
+    let module = eliminate(&text);
+    assert_eq!(calls(&module.functions[0]), 0);
+    // the join block is unreachable now
+    assert!(module.functions[0].basic_blocks.iter().all(|bb| bb.id != 3));
+  }
+
+  #[test]
+  fn not_tail_calls() {
+    // the result is used after the call
+    let text = SUM.replace("  ret d64 $7\n}", "  $8 = add d64 $7 $4\n  ret d64 $8\n}");
+    assert_eq!(calls(&eliminate(&text).functions[0]), 1);
+    // the join block is not empty
+    let text = SUM.replace("  ret d64 $7\n}", "  br @3\n\n@3:\n  $8 = const d64 0\n  ret d64 $7\n}");
+    assert_eq!(calls(&eliminate(&text).functions[0]), 1);
+  }
+
+  #[test]
+  fn rejects_stack_alloc() {
+    let text = SUM.replace("  $2 = const d64 0\n", "  $2 = const d64 0\n  $9 = stack_alloc ptr 8 8\n");
+    let module = eliminate(&text);
+    assert_eq!(module, parse_module(&text).unwrap());
+  }
+
+  #[test]
+  fn deep_recursion() {
+    // far too deep for the interpreter to recurse, but fine as a loop
+    let module = eliminate(SUM);
+    let n = 200_000u64;
+    let result = Interpreter::new(&module, &Target::rv64()).call("sum", &[n, 0]).unwrap();
+    assert_eq!(result, vec![n * (n + 1) / 2]);
+  }
+}
@@ -0,0 +1,398 @@
+//! Loop unrolling.
+//!
+//! Only innermost loops with a constant trip count (see `ir3::loops`) are unrolled.
+//! Loops that run at most `MAX_FULL_UNROLL` times are replaced by that many copies of their body.
+//! Longer loops are unrolled partially: a new loop runs `factor` copies of the body per trip for as long as
+//! at least `factor` trips are left, and the original loop is kept behind it to run the remaining trips.
+//! Either way, the loop may grow to at most `MAX_UNROLLED_SIZE` ops.
+//! With a profile, loops whose header never ran are left alone.
+
+use std::collections::{HashMap, HashSet};
+use crate::ir3::cfg::{Cfg, DomTree};
+use crate::ir3::layout::BlockCounts;
+use crate::ir3::loops::{constants, find_loops, trip_count, Loop};
+use crate::ir3::model::{IR3BasicBlock, IR3BBID, IR3CompareMode, IR3EndOp, IR3Function, IR3Op, IR3OpKind, IR3Phi, IR3Type, IR3VarID};
+
+/// Loops with at most this many trips are unrolled fully.
+const MAX_FULL_UNROLL: u64 = 8;
+/// Maximum number of ops in an unrolled loop.
+const MAX_UNROLLED_SIZE: usize = 128;
+
+/// Unrolls loops in `func`. Partially unrolled loops run `factor` copies of their body per trip;
+/// a `factor` of 1 turns partial unrolling off. `counts` are the block counts of a profile of `func`, if there is one.
+pub fn unroll_loops(func: &mut IR3Function, factor: u32, counts: Option<&BlockCounts>) {
+  let mut done = HashSet::new();
+  // cold loops are not worth the code size
+  if let Some(counts) = counts {
+    done.extend(counts.iter().filter(|(_, count)| **count == 0).map(|(block, _)| *block));
+  }
+  while unroll_one(func, factor as u64, &mut done) {}
+}
+
+/// Finds and unrolls one loop. Returns false if there is none left.
+fn unroll_one(func: &mut IR3Function, factor: u64, done: &mut HashSet<IR3BBID>) -> bool {
+  let cfg = Cfg::new(func);
+  let dom = DomTree::new(&cfg);
+  let loops = find_loops(&cfg, &dom);
+  for lp in &loops {
+    let innermost = !loops.iter().any(|v| v.header != lp.header && lp.blocks.contains(&v.header));
+    if !innermost || done.contains(&lp.header) || lp.latches.len() != 1 || cfg.preds[&lp.header].len() != 2 {
+      continue;
+    }
+    let blocks = func.basic_blocks.iter().filter(|v| lp.blocks.contains(&v.id)).collect::<Vec<_>>();
+    let size = blocks.iter().map(|v| v.instructions.len() + 1).sum::<usize>();
+    let has_stack = blocks.iter()
+      .flat_map(|v| &v.instructions)
+      .any(|op| matches!(op.kind, IR3OpKind::StackAlloc { .. }));
+    if has_stack {
+      continue;
+    }
+    let Some(trips) = trip_count(func, &cfg, lp) else { continue };
+    done.insert(lp.header);
+    if trips.count <= MAX_FULL_UNROLL && size * (trips.count as usize + 1) <= MAX_UNROLLED_SIZE {
+      unroll_fully(func, lp, trips.count, trips.exit);
+      return true;
+    }
+    let IR3Type::Data(width) = trips.iv.ty else { continue };
+    let consts = constants(func);
+    let step = trips.iv.step;
+    let magnitude = step.min(mask(step.wrapping_neg(), width));
+    // the unrolled loop tests its induction variable for the value it has after the last full group of trips,
+    // which only works if the induction variable does not wrap around before that
+    let wraps = (trips.count as u128) * (magnitude as u128) >= 1u128 << width;
+    if factor < 2 || trips.count < factor * 2 || size * factor as usize > MAX_UNROLLED_SIZE || wraps {
+      continue;
+    }
+    let end = consts[&trips.iv.init].wrapping_add((trips.count / factor * factor).wrapping_mul(step));
+    let new_header = unroll_partially(func, lp, factor, trips.iv.var, mask(end, width));
+    done.insert(new_header);
+    return true;
+  }
+  false
+}
+
+fn mask(v: u64, width: u32) -> u64 {
+  if width >= 64 { v } else { v & ((1 << width) - 1) }
+}
+
+/// The only predecessor of the loop header outside the loop.
+fn preheader(func: &IR3Function, lp: &Loop) -> IR3BBID {
+  let cfg = Cfg::new(func);
+  *cfg.preds[&lp.header].iter().find(|v| !lp.blocks.contains(v)).unwrap()
+}
+
+/// Hands out fresh variable and block IDs.
+struct Ids {
+  var: IR3VarID,
+  block: IR3BBID,
+}
+
+impl Ids {
+  fn new(func: &IR3Function) -> Ids {
+    Ids { var: func.next_var_id(), block: func.next_block_id() }
+  }
+
+  fn var(&mut self) -> IR3VarID {
+    self.var += 1;
+    self.var - 1
+  }
+
+  fn block(&mut self) -> IR3BBID {
+    self.block += 1;
+    self.block - 1
+  }
+}
+
+/// One copy of the loop body.
+struct Trip {
+  blocks: Vec<IR3BasicBlock>,
+  /// Values of the header phis for the next trip.
+  next_values: HashMap<IR3VarID, IR3VarID>,
+}
+
+/// Copies one trip around the loop, with the header's copy getting the ID `header`.
+/// `values` gives the values of the header phis, the exit test of the header is dropped,
+/// and the latch branches to `next` instead of the header.
+fn copy_trip(func: &IR3Function, lp: &Loop, values: &HashMap<IR3VarID, IR3VarID>, header: IR3BBID, next: IR3BBID, ids: &mut Ids) -> Trip {
+  let blocks = func.basic_blocks.iter().filter(|v| lp.blocks.contains(&v.id)).collect::<Vec<_>>();
+  let block_map = blocks.iter()
+    .map(|v| (v.id, if v.id == lp.header { header } else { ids.block() }))
+    .collect::<HashMap<_, _>>();
+  let mut vars = values.clone();
+  for op in blocks.iter().flat_map(|v| &v.instructions) {
+    for out in &op.output {
+      vars.entry(*out).or_insert_with(|| ids.var());
+    }
+  }
+  let var = |v: &IR3VarID| vars.get(v).copied().unwrap_or(*v);
+  let mut copies = vec![];
+  for bb in &blocks {
+    let mut instructions = vec![];
+    for op in &bb.instructions {
+      let mut kind = op.kind.clone();
+      match &mut kind {
+        IR3OpKind::Phi(_) if bb.id == lp.header => continue,
+        IR3OpKind::Phi(phi) => phi.blocks.iter_mut().for_each(|v| *v = block_map[v]),
+        _ => {}
+      }
+      instructions.push(IR3Op {
+        kind,
+        ty: op.ty,
+        input: op.input.iter().map(var).collect(),
+        output: op.output.iter().map(var).collect(),
+      });
+    }
+    let mut ending = bb.ending.clone();
+    if bb.id == lp.header {
+      let body = *bb.ending.successors().iter().find(|v| lp.blocks.contains(v)).unwrap();
+      ending = IR3EndOp::Br { block: body };
+    }
+    ending.inputs_mut().into_iter().for_each(|v| *v = var(v));
+    ending.successors_mut().into_iter().for_each(|v| *v = if *v == lp.header { next } else { block_map[v] });
+    copies.push(IR3BasicBlock { id: block_map[&bb.id], instructions, ending });
+  }
+  let latch = lp.latches[0];
+  let header_bb = blocks.iter().find(|v| v.id == lp.header).unwrap();
+  let next_values = header_bb.instructions.iter()
+    .filter_map(|op| match &op.kind {
+      IR3OpKind::Phi(phi) => {
+        let idx = phi.blocks.iter().position(|v| *v == latch).unwrap();
+        Some((op.output[0], var(&op.input[idx])))
+      }
+      _ => None,
+    })
+    .collect();
+  // the header copy comes first, so that trips are laid out in order
+  copies.sort_by_key(|v| v.id != header);
+  Trip { blocks: copies, next_values }
+}
+
+/// Values of the header phis on entry to the loop from `preheader`.
+fn entry_values(func: &IR3Function, lp: &Loop, preheader: IR3BBID) -> HashMap<IR3VarID, IR3VarID> {
+  let header = func.basic_blocks.iter().find(|v| v.id == lp.header).unwrap();
+  header.instructions.iter()
+    .filter_map(|op| match &op.kind {
+      IR3OpKind::Phi(phi) => {
+        let idx = phi.blocks.iter().position(|v| *v == preheader).unwrap();
+        Some((op.output[0], op.input[idx]))
+      }
+      _ => None,
+    })
+    .collect()
+}
+
+/// Points the branches of `block` to `from` at `to` instead.
+fn retarget(func: &mut IR3Function, block: IR3BBID, from: IR3BBID, to: IR3BBID) {
+  let bb = func.basic_blocks.iter_mut().find(|v| v.id == block).unwrap();
+  bb.ending.successors_mut().into_iter().filter(|v| **v == from).for_each(|v| *v = to);
+}
+
+/// Replaces a loop that runs `count` times with `count` copies of its body, followed by a last copy of its header.
+fn unroll_fully(func: &mut IR3Function, lp: &Loop, count: u64, exit: IR3BBID) {
+  let preheader = preheader(func, lp);
+  let mut ids = Ids::new(func);
+  let headers = (0..=count).map(|_| ids.block()).collect::<Vec<_>>();
+  let mut values = entry_values(func, lp, preheader);
+  let mut new_blocks = vec![];
+  for trip in 0..count as usize {
+    let copy = copy_trip(func, lp, &values, headers[trip], headers[trip + 1], &mut ids);
+    new_blocks.extend(copy.blocks);
+    values = copy.next_values;
+  }
+  // the header runs once more to find that the loop is done
+  let header = func.basic_blocks.iter().find(|v| v.id == lp.header).unwrap();
+  let mut last = IR3BasicBlock { id: headers[count as usize], instructions: vec![], ending: IR3EndOp::Br { block: exit } };
+  for op in &header.instructions {
+    if let IR3OpKind::Phi(_) = op.kind {
+      continue;
+    }
+    let mut op = op.clone();
+    op.input.iter_mut().for_each(|v| *v = values.get(v).copied().unwrap_or(*v));
+    for out in &mut op.output {
+      let new = ids.var();
+      values.insert(*out, new);
+      *out = new;
+    }
+    last.instructions.push(op);
+  }
+  new_blocks.push(last);
+  retarget(func, preheader, lp.header, headers[0]);
+  let at = func.basic_blocks.iter().position(|v| v.id == lp.header).unwrap();
+  func.basic_blocks.retain(|v| !lp.blocks.contains(&v.id));
+  func.basic_blocks.splice(at..at, new_blocks);
+  // code after the loop sees the values of the last header copy
+  for bb in &mut func.basic_blocks {
+    for op in &mut bb.instructions {
+      if let IR3OpKind::Phi(phi) = &mut op.kind {
+        phi.blocks.iter_mut().filter(|v| **v == lp.header).for_each(|v| *v = headers[count as usize]);
+      }
+    }
+  }
+  func.replace_uses(&values);
+}
+
+/// Puts a loop running `factor` copies of the body in front of the loop, which runs while `iv` is not `end`.
+/// Returns the header of the new loop.
+fn unroll_partially(func: &mut IR3Function, lp: &Loop, factor: u64, iv: IR3VarID, end: u64) -> IR3BBID {
+  let preheader = preheader(func, lp);
+  let mut ids = Ids::new(func);
+  let new_header = ids.block();
+  let headers = (0..factor).map(|_| ids.block()).collect::<Vec<_>>();
+  let header = func.basic_blocks.iter().find(|v| v.id == lp.header).unwrap();
+  let phis = header.instructions.iter()
+    .filter(|op| matches!(op.kind, IR3OpKind::Phi(_)))
+    .map(|op| (op.output[0], op.ty))
+    .collect::<Vec<_>>();
+  let new_phis = phis.iter().map(|(var, _)| (*var, ids.var())).collect::<HashMap<_, _>>();
+  let mut values = new_phis.clone();
+  let mut new_blocks = vec![];
+  for trip in 0..factor as usize {
+    let next = headers.get(trip + 1).copied().unwrap_or(new_header);
+    let copy = copy_trip(func, lp, &values, headers[trip], next, &mut ids);
+    new_blocks.extend(copy.blocks);
+    values = copy.next_values;
+  }
+  let entry = entry_values(func, lp, preheader);
+  // the latch of the last copy branches back to the new header
+  let latch = new_blocks.iter().find(|v| v.ending.successors().contains(&new_header)).unwrap().id;
+  let mut instructions = phis.iter()
+    .map(|(var, ty)| IR3Op {
+      kind: IR3OpKind::Phi(IR3Phi { blocks: vec![preheader, latch] }),
+      ty: *ty,
+      input: vec![entry[var], values[var]],
+      output: vec![new_phis[var]],
+    })
+    .collect::<Vec<_>>();
+  let iv_ty = phis.iter().find(|(var, _)| *var == iv).unwrap().1;
+  let end_var = ids.var();
+  let cond = ids.var();
+  instructions.push(IR3Op { kind: IR3OpKind::Const(end), ty: iv_ty, input: vec![], output: vec![end_var] });
+  instructions.push(IR3Op { kind: IR3OpKind::Cmp(IR3CompareMode::Ne), ty: iv_ty, input: vec![new_phis[&iv], end_var], output: vec![cond] });
+  new_blocks.insert(0, IR3BasicBlock {
+    id: new_header,
+    instructions,
+    ending: IR3EndOp::BrIf { block1: headers[0], block2: lp.header, cond },
+  });
+  // the original loop picks up where the new one left off
+  let header = func.basic_blocks.iter_mut().find(|v| v.id == lp.header).unwrap();
+  for op in &mut header.instructions {
+    if let IR3OpKind::Phi(phi) = &mut op.kind {
+      let idx = phi.blocks.iter().position(|v| *v == preheader).unwrap();
+      phi.blocks[idx] = new_header;
+      op.input[idx] = new_phis[&op.output[0]];
+    }
+  }
+  retarget(func, preheader, lp.header, new_header);
+  let at = func.basic_blocks.iter().position(|v| v.id == lp.header).unwrap();
+  func.basic_blocks.splice(at..at, new_blocks);
+  new_header
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use crate::hxx_ir1::from_hxx::hxx_to_ir1;
+  use crate::hxx_ir1::to_ir2::ir1_to_ir2;
+  use crate::ir2::to_ir3::ir2_to_ir3;
+  use crate::ir3::interp::Interpreter;
+  use crate::ir3::mem2reg::mem2reg;
+  use crate::ir3::model::IR3Module;
+  use crate::ir3::parse::parse_module;
+  use crate::ir3::verify::verify_module;
+  use crate::target::Target;
+  use super::*;
+
+  fn compile(src: &str) -> IR3Module {
+    let srcs = [include_str!("../../support/builtins.hx"), src];
+    let ir1s = srcs.iter().map(|v| hxx_to_ir1("loop.hx", v).unwrap()).collect::<Vec<_>>();
+    let mut module = ir2_to_ir3(&ir1_to_ir2(&ir1s).unwrap(), &Target::rv64()).unwrap();
+    module.functions.iter_mut().for_each(mem2reg);
+    module
+  }
+
+  /// Runs `main` and returns what it printed.
+  fn run(module: &IR3Module) -> Vec<u64> {
+    let target = Target::rv64();
+    let printed = RefCell::new(vec![]);
+    let mut interp = Interpreter::new(module, &target);
+    for ext in &module.externs {
+      interp.define_extern(&ext.name, |args| {
+        printed.borrow_mut().extend(args);
+        vec![]
+      });
+    }
+    interp.call("main", &[1, 0]).unwrap();
+    drop(interp);
+    printed.into_inner()
+  }
+
+  fn loop_count(func: &IR3Function) -> usize {
+    let cfg = Cfg::new(func);
+    find_loops(&cfg, &DomTree::new(&cfg)).len()
+  }
+
+  /// Unrolls `main` and checks that it prints the same as before. Returns the number of loops left.
+  fn unroll_main(mut module: IR3Module, factor: u32) -> usize {
+    let expected = run(&module);
+    let main = module.functions.iter_mut().find(|v| v.name == "main").unwrap();
+    unroll_loops(main, factor, None);
+    let loops = loop_count(main);
+    verify_module(&module).unwrap();
+    assert_eq!(run(&module), expected, "factor {}:\n{}", factor, module);
+    loops
+  }
+
+  #[test]
+  fn unrolled_loops_compute_the_same() {
+    for n in [0, 1, 5, 8, 9, 37, 100] {
+      for factor in [1, 3, 4] {
+        let module = compile(&format!("(:fn (main (argc u64) (argv **u8)) i32 (
+          (:let (i i32) 0)
+          (:let (s i32) 7)
+          (:while (lt i {n}) (
+            (:set s (add (mul s 3) i))
+            (:set i (add i 1))
+          ))
+          (println (to_f64 (to_i64 s)))
+          (println (to_f64 (to_i64 i)))
+        ))"));
+        let loops = unroll_main(module, factor);
+        // short loops are unrolled fully, and longer ones get an unrolled copy in front of the original
+        let expected = if n <= MAX_FULL_UNROLL { 0 } else if factor > 1 { 2 } else { 1 };
+        assert_eq!(loops, expected, "n {} factor {}", n, factor);
+      }
+    }
+  }
+
+  #[test]
+  fn wrapping_ivs_are_not_unrolled_partially() {
+    // the induction variable goes 0, 3, ..., 255, 2, ..., 254, 1, wrapping around twice,
+    // so only the original loop is kept
+    let module = parse_module("extern print args d8 returns void
+
+ir3function main returns void {
+@0:
+  $0 = const d8 0
+  $1 = const d8 3
+  $2 = const d8 1
+  br @1
+
+@1:
+  $3 = phi d8 $0 @0 $5 @2
+  $4 = cmp d8 ne $3 $2
+  br_if $4 @2 @3
+
+@2:
+  call void print d8 $3
+  $5 = add d8 $3 $1
+  br @1
+
+@3:
+  ret void
+
+}
+").unwrap();
+    assert_eq!(run(&module).len(), 171);
+    assert_eq!(unroll_main(module, 4), 1);
+  }
+}
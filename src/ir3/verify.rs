@@ -0,0 +1,429 @@
+//! IR3 verifier.
+//!
+//! Checks the structural rules from `doc/ir3.md` that passes rely on: every block ends in a
+//! valid terminator, every variable is defined exactly once and before all of its uses,
+//! operand types match, phis list exactly the predecessors of their block, and every
+//! referenced symbol exists in the module.
+//!
+//! The verifier accepts legalized IR3, which relaxes a few type rules (see the "Legalization" section).
+
+use std::collections::{HashMap, HashSet};
+use crate::common::err::{IR3Err, IR3ErrKind, IR3Result};
+use crate::ir3::cfg::{Cfg, DomTree};
+use crate::ir3::model::{IR3BBID, IR3EndOp, IR3Function, IR3FunctionAttr, IR3GlobalInit, IR3Module, IR3Op, IR3OpKind, IR3Ordering, IR3RmwOp, IR3Type, IR3VarID};
+
+pub fn verify_module(module: &IR3Module) -> IR3Result<()> {
+  let mut symbols = HashSet::new();
+  let names = module.functions.iter().map(|v| &v.name)
+    .chain(module.externs.iter().map(|v| &v.name))
+    .chain(module.globals.iter().map(|v| &v.name))
+    .chain(module.rodata.iter().map(|v| &v.name));
+  for name in names {
+    if !symbols.insert(name) {
+      return Err(IR3Err::new(IR3ErrKind::DuplicateSymbol(name.clone()), name));
+    }
+  }
+  for global in &module.globals {
+    let err = |msg: &str| Err(IR3Err::new(IR3ErrKind::InvalidGlobal(msg.to_owned()), &global.name));
+    match &global.init {
+      IR3GlobalInit::Const(_) if !matches!(global.ty, IR3Type::Data(_)) => return err("constant initializer for non-data global"),
+      IR3GlobalInit::Addr(_) if global.ty != IR3Type::Ptr => return err("address initializer for non-pointer global"),
+      IR3GlobalInit::Addr(name) if !module.has_symbol(name) => return err("initializer refers to an undefined symbol"),
+      _ => {}
+    }
+    if global.ty == IR3Type::Void {
+      return err("global of type void");
+    }
+  }
+  for func in &module.functions {
+    verify_function(func, Some(module))?;
+  }
+  Ok(())
+}
+
+/// Verifies a single function. Without a module, calls and symbol references are not checked.
+pub fn verify_function(func: &IR3Function, module: Option<&IR3Module>) -> IR3Result<()> {
+  let err = |block: IR3BBID, msg: String| Err(IR3Err::new(IR3ErrKind::Invalid(block, msg), &func.name));
+  if func.basic_blocks.is_empty() {
+    return err(0, "function has no blocks".to_owned());
+  }
+  let mut block_ids = HashSet::new();
+  for bb in &func.basic_blocks {
+    if !block_ids.insert(bb.id) {
+      return err(bb.id, "duplicate block".to_owned());
+    }
+  }
+  let entry = func.basic_blocks[0].id;
+  for bb in &func.basic_blocks {
+    for succ in bb.ending.successors() {
+      if !block_ids.contains(&succ) {
+        return err(bb.id, format!("branch to undefined block @{}", succ));
+      }
+      if succ == entry {
+        return err(bb.id, "branch to the entry block".to_owned());
+      }
+    }
+  }
+  let mut def_block = HashMap::new();
+  let mut var_types = HashMap::new();
+  for bb in &func.basic_blocks {
+    for op in &bb.instructions {
+      let types = op.output_types();
+      if op.output.len() != types.len() {
+        return err(bb.id, format!("`{}` must have {} outputs", op, types.len()));
+      }
+      for (var, ty) in op.output.iter().zip(types) {
+        if def_block.insert(*var, bb.id).is_some() {
+          return err(bb.id, format!("${} is defined more than once", var));
+        }
+        var_types.insert(*var, ty);
+      }
+    }
+  }
+  let cfg = Cfg::new(func);
+  let dom = DomTree::new(&cfg);
+  Verifier {
+    func,
+    module,
+    var_types,
+    def_block,
+    cfg,
+    dom,
+  }.verify()
+}
+
+struct Verifier<'a> {
+  func: &'a IR3Function,
+  module: Option<&'a IR3Module>,
+  var_types: HashMap<IR3VarID, IR3Type>,
+  /// Block every variable is defined in.
+  def_block: HashMap<IR3VarID, IR3BBID>,
+  cfg: Cfg,
+  dom: DomTree,
+}
+
+impl<'a> Verifier<'a> {
+  fn err<T>(&self, block: IR3BBID, msg: impl Into<String>) -> IR3Result<T> {
+    Err(IR3Err::new(IR3ErrKind::Invalid(block, msg.into()), &self.func.name))
+  }
+
+  fn verify(&self) -> IR3Result<()> {
+    let entry = self.func.basic_blocks[0].id;
+    for bb in &self.func.basic_blocks {
+      let mut seen_non_phi = false;
+      let mut defined_here = HashSet::new();
+      for op in &bb.instructions {
+        if let IR3OpKind::Phi(phi) = &op.kind {
+          if seen_non_phi {
+            return self.err(bb.id, format!("`{}` is not at the start of the block", op));
+          }
+          if self.cfg.is_reachable(bb.id) {
+            let preds = &self.cfg.preds[&bb.id];
+            let listed = phi.blocks.iter().copied().collect::<HashSet<_>>();
+            if listed.len() != phi.blocks.len() || listed != preds.iter().copied().collect() {
+              return self.err(bb.id, format!("`{}` does not list exactly the predecessors of its block", op));
+            }
+          }
+          // phi inputs have to be available at the end of the incoming block
+          for (var, pred) in op.input.iter().zip(phi.blocks.iter()) {
+            self.check_available(bb.id, *var, *pred, None)?;
+          }
+        } else {
+          seen_non_phi = true;
+          for var in &op.input {
+            self.check_available(bb.id, *var, bb.id, Some(&defined_here))?;
+          }
+        }
+        if matches!(op.kind, IR3OpKind::StackAlloc { .. }) && bb.id != entry {
+          return self.err(bb.id, "`stack_alloc` outside of the entry block");
+        }
+        self.check_op(bb.id, op)?;
+        defined_here.extend(op.output.iter().copied());
+      }
+      for var in bb.ending.inputs() {
+        self.check_available(bb.id, var, bb.id, Some(&defined_here))?;
+      }
+      self.check_ending(bb.id, &bb.ending)?;
+    }
+    Ok(())
+  }
+
+  /// Checks that `var` is defined, and that its definition dominates the end of `block`
+  /// (or the current position in `block` if `defined_here` is given).
+  fn check_available(&self, user: IR3BBID, var: IR3VarID, block: IR3BBID, defined_here: Option<&HashSet<IR3VarID>>) -> IR3Result<()> {
+    let Some(def) = self.def_block.get(&var) else {
+      return self.err(user, format!("${} is used but never defined", var));
+    };
+    // uses in unreachable code are never executed
+    if !self.cfg.is_reachable(block) {
+      return Ok(());
+    }
+    let available = match defined_here {
+      Some(defined_here) if *def == block => defined_here.contains(&var),
+      _ => self.dom.dominates(*def, block),
+    };
+    if !available {
+      return self.err(user, format!("${} is used before it is defined", var));
+    }
+    Ok(())
+  }
+
+  fn ty(&self, var: IR3VarID) -> IR3Type {
+    self.var_types[&var]
+  }
+
+  fn check_inputs(&self, block: IR3BBID, op: &IR3Op, types: &[IR3Type]) -> IR3Result<()> {
+    if op.input.len() != types.len() {
+      return self.err(block, format!("`{}` must have {} inputs", op, types.len()));
+    }
+    for (var, ty) in op.input.iter().zip(types) {
+      if self.ty(*var) != *ty {
+        return self.err(block, format!("`{}` expects ${} to be {}, but it is {}", op, var, ty, self.ty(*var)));
+      }
+    }
+    Ok(())
+  }
+
+  /// Checks the inputs of an op that accesses memory: a pointer followed by `values` values of the op's type.
+  fn check_memory_operands(&self, block: IR3BBID, op: &IR3Op, values: usize) -> IR3Result<()> {
+    if op.input.len() != values + 1 {
+      return self.err(block, format!("`{}` must have {} inputs", op, values + 1));
+    }
+    // legalized IR3 may store the lower bits of a wider value
+    let fits = |var: &IR3VarID| match (op.ty, self.ty(*var)) {
+      (IR3Type::Data(w), IR3Type::Data(value_w)) => w <= value_w,
+      (ty, value_ty) => ty == value_ty && ty != IR3Type::Void,
+    };
+    if self.ty(op.input[0]) != IR3Type::Ptr || !op.input[1..].iter().all(fits) {
+      return self.err(block, format!("`{}` has mismatched operand types", op));
+    }
+    Ok(())
+  }
+
+  fn check_op(&self, block: IR3BBID, op: &IR3Op) -> IR3Result<()> {
+    let is_data = matches!(op.ty, IR3Type::Data(_));
+    let require_data = || if is_data {
+      Ok(())
+    } else {
+      self.err(block, format!("`{}` requires a data type", op))
+    };
+    let is_float = |ty: IR3Type| matches!(ty, IR3Type::Float(32 | 64));
+    let require_float = || if is_float(op.ty) {
+      Ok(())
+    } else {
+      self.err(block, format!("`{}` requires f32 or f64", op))
+    };
+    match &op.kind {
+      IR3OpKind::Add | IR3OpKind::Sub | IR3OpKind::And | IR3OpKind::Or | IR3OpKind::Xor |
+      IR3OpKind::Sll | IR3OpKind::Srl | IR3OpKind::Sra | IR3OpKind::Rotl | IR3OpKind::Rotr |
+      IR3OpKind::Smull | IR3OpKind::Umull | IR3OpKind::Smulh | IR3OpKind::Umulh |
+      IR3OpKind::Sdiv | IR3OpKind::Udiv | IR3OpKind::Srem | IR3OpKind::Urem |
+      IR3OpKind::SaddOverflow | IR3OpKind::UaddOverflow | IR3OpKind::SsubOverflow |
+      IR3OpKind::UsubOverflow | IR3OpKind::SmulOverflow | IR3OpKind::UmulOverflow => {
+        require_data()?;
+        self.check_inputs(block, op, &[op.ty, op.ty])
+      }
+      IR3OpKind::Not | IR3OpKind::Clz | IR3OpKind::Ctz | IR3OpKind::Popcnt => {
+        require_data()?;
+        self.check_inputs(block, op, &[op.ty])
+      }
+      IR3OpKind::Bswap => {
+        if !matches!(op.ty, IR3Type::Data(w) if w % 8 == 0) {
+          return self.err(block, format!("`{}` requires a whole number of bytes", op));
+        }
+        self.check_inputs(block, op, &[op.ty])
+      }
+      IR3OpKind::Cmp(_) => {
+        if matches!(op.ty, IR3Type::Void | IR3Type::Float(_)) {
+          return self.err(block, format!("`{}` cannot compare {}", op, op.ty));
+        }
+        self.check_inputs(block, op, &[op.ty, op.ty])
+      }
+      IR3OpKind::Fadd | IR3OpKind::Fsub | IR3OpKind::Fmul | IR3OpKind::Fdiv | IR3OpKind::Fcmp(_) => {
+        require_float()?;
+        self.check_inputs(block, op, &[op.ty, op.ty])
+      }
+      IR3OpKind::Fneg | IR3OpKind::Fsqrt => {
+        require_float()?;
+        self.check_inputs(block, op, &[op.ty])
+      }
+      IR3OpKind::Const(v) => {
+        let w = match op.ty {
+          IR3Type::Data(w) => w,
+          IR3Type::Float(w) if is_float(op.ty) => w,
+          _ => return self.err(block, format!("`{}` requires a data or float type", op)),
+        };
+        if w < 64 && *v >> w != 0 {
+          return self.err(block, format!("`{}` does not fit in its type", op));
+        }
+        self.check_inputs(block, op, &[])
+      }
+      IR3OpKind::Fconv(src) => {
+        if !is_float(op.ty) || !is_float(*src) {
+          return self.err(block, format!("`{}` must convert between floats", op));
+        }
+        self.check_inputs(block, op, &[*src])
+      }
+      IR3OpKind::SiToFp(src) | IR3OpKind::UiToFp(src) => {
+        if !is_float(op.ty) || !matches!(src, IR3Type::Data(_)) {
+          return self.err(block, format!("`{}` must convert data to a float", op));
+        }
+        self.check_inputs(block, op, &[*src])
+      }
+      IR3OpKind::FpToSi(src) | IR3OpKind::FpToUi(src) => {
+        if !is_data || !is_float(*src) {
+          return self.err(block, format!("`{}` must convert a float to data", op));
+        }
+        self.check_inputs(block, op, &[*src])
+      }
+      IR3OpKind::Zext(src) | IR3OpKind::Sext(src) => {
+        match (op.ty, *src) {
+          (IR3Type::Data(w), IR3Type::Data(src_w)) if src_w <= w => {}
+          _ => return self.err(block, format!("`{}` must extend data to wider data", op)),
+        }
+        self.check_inputs(block, op, &[*src])
+      }
+      IR3OpKind::Trunc(src) => {
+        match (op.ty, *src) {
+          (IR3Type::Data(w), IR3Type::Data(src_w)) if src_w >= w => {}
+          _ => return self.err(block, format!("`{}` must truncate data to narrower data", op)),
+        }
+        self.check_inputs(block, op, &[*src])
+      }
+      IR3OpKind::PtrLoad => {
+        if op.ty == IR3Type::Void {
+          return self.err(block, format!("`{}` cannot load void", op));
+        }
+        self.check_inputs(block, op, &[IR3Type::Ptr])
+      }
+      IR3OpKind::PtrStore => self.check_memory_operands(block, op, 1),
+      IR3OpKind::AtomicLoad(ord) | IR3OpKind::AtomicStore(ord) | IR3OpKind::AtomicRmw(_, ord) | IR3OpKind::CmpXchg(ord) => {
+        let valid_width = match op.ty {
+          IR3Type::Data(w) => w % 8 == 0 && w.is_power_of_two(),
+          ty => ty == IR3Type::Ptr && !matches!(op.kind, IR3OpKind::AtomicRmw(rmw, _) if rmw != IR3RmwOp::Xchg),
+        };
+        if !valid_width {
+          return self.err(block, format!("`{}` requires data of a power of two number of bytes", op));
+        }
+        let valid_ordering = match op.kind {
+          IR3OpKind::AtomicLoad(_) => !matches!(ord, IR3Ordering::Release | IR3Ordering::AcqRel),
+          IR3OpKind::AtomicStore(_) => !matches!(ord, IR3Ordering::Acquire | IR3Ordering::AcqRel),
+          _ => true,
+        };
+        if !valid_ordering {
+          return self.err(block, format!("`{}` cannot have {} ordering", op, ord));
+        }
+        let values = match op.kind {
+          IR3OpKind::AtomicLoad(_) => 0,
+          IR3OpKind::CmpXchg(_) => 2,
+          _ => 1,
+        };
+        self.check_memory_operands(block, op, values)
+      }
+      IR3OpKind::Fence(ord) => {
+        if op.ty != IR3Type::Void || *ord == IR3Ordering::Relaxed {
+          return self.err(block, format!("`{}` must be void and cannot be relaxed", op));
+        }
+        self.check_inputs(block, op, &[])
+      }
+      IR3OpKind::PtrUadd | IR3OpKind::PtrSadd => {
+        require_data()?;
+        self.check_inputs(block, op, &[IR3Type::Ptr, op.ty])
+      }
+      IR3OpKind::StackAlloc { align, .. } => {
+        if op.ty != IR3Type::Ptr || !align.is_power_of_two() {
+          return self.err(block, format!("`{}` must be a ptr with a power of two alignment", op));
+        }
+        self.check_inputs(block, op, &[])
+      }
+      IR3OpKind::GlobalAddr(name) => {
+        if op.ty != IR3Type::Ptr {
+          return self.err(block, format!("`{}` must be a ptr", op));
+        }
+        if self.module.is_some_and(|m| !m.has_symbol(name)) {
+          return self.err(block, format!("`{}` refers to an undefined symbol", op));
+        }
+        self.check_inputs(block, op, &[])
+      }
+      IR3OpKind::Arg(idx) => {
+        if self.func.args.get(*idx as usize) != Some(&op.ty) {
+          return self.err(block, format!("`{}` does not match the function's arguments", op));
+        }
+        self.check_inputs(block, op, &[])
+      }
+      IR3OpKind::Call(call) => {
+        if op.ty != IR3Type::Void || call.ret_types.contains(&IR3Type::Void) {
+          return self.err(block, format!("`{}` has an invalid return type", op));
+        }
+        self.check_inputs(block, op, &call.arg_types)?;
+        let Some(module) = self.module else { return Ok(()) };
+        let signature = module.function(&call.symbol_name)
+          .map(|f| (&f.args, &f.ret))
+          .or_else(|| module.externs.iter().find(|v| v.name == call.symbol_name).map(|f| (&f.args, &f.ret)));
+        match signature {
+          None => self.err(block, format!("`{}` calls an undefined function", op)),
+          Some((args, ret)) if *args != call.arg_types || *ret != call.ret_types => {
+            self.err(block, format!("`{}` does not match the signature of the callee", op))
+          }
+          _ => Ok(()),
+        }
+      }
+      IR3OpKind::Phi(phi) => {
+        if phi.blocks.len() != op.input.len() {
+          return self.err(block, format!("`{}` must have one input per block", op));
+        }
+        let types = vec![op.ty; op.input.len()];
+        self.check_inputs(block, op, &types)
+      }
+      IR3OpKind::Select => {
+        if op.ty == IR3Type::Void {
+          return self.err(block, format!("`{}` cannot select void", op));
+        }
+        // like `br_if`, the condition may be promoted by legalization
+        let cond_ty = op.input.first().map_or(IR3Type::Void, |v| self.ty(*v));
+        if !matches!(cond_ty, IR3Type::Data(_)) {
+          return self.err(block, format!("`{}` selects on a non-data value", op));
+        }
+        self.check_inputs(block, op, &[cond_ty, op.ty, op.ty])
+      }
+    }
+  }
+
+  fn check_ending(&self, block: IR3BBID, ending: &IR3EndOp) -> IR3Result<()> {
+    match ending {
+      IR3EndOp::Br { .. } => Ok(()),
+      // legalization promotes `d1`, so any data is accepted as a condition
+      IR3EndOp::BrIf { cond, .. } => match self.ty(*cond) {
+        IR3Type::Data(_) => Ok(()),
+        ty => self.err(block, format!("`{}` branches on {}", ending, ty)),
+      },
+      IR3EndOp::Unreachable | IR3EndOp::Trap => Ok(()),
+      IR3EndOp::Ret { .. } if self.func.has_attr(IR3FunctionAttr::NoReturn) => {
+        self.err(block, format!("`{}` in a noreturn function", ending))
+      }
+      IR3EndOp::Ret { values } => {
+        let matches = values.len() == self.func.ret.len() &&
+          values.iter().zip(&self.func.ret).all(|((ty, var), ret)| ty == ret && self.ty(*var) == *ty);
+        if !matches {
+          return self.err(block, format!("`{}` does not match the return types of the function", ending));
+        }
+        Ok(())
+      }
+      IR3EndOp::Switch { cond, cases, .. } => {
+        let IR3Type::Data(w) = self.ty(*cond) else {
+          return self.err(block, format!("`{}` switches on a non-data value", ending));
+        };
+        let mut values = HashSet::new();
+        for (value, _) in cases {
+          if !values.insert(*value) {
+            return self.err(block, format!("`{}` has duplicate case {}", ending, value));
+          }
+          if w < 64 && *value >> w != 0 {
+            return self.err(block, format!("`{}` has case {} which does not fit in d{}", ending, value, w));
+          }
+        }
+        Ok(())
+      }
+    }
+  }
+}
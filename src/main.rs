@@ -1,18 +1,238 @@
 use std::env::args;
-use std::fs::{read_to_string};
+use std::collections::HashMap;
+use std::fs::{read_to_string, write};
 use std::path::PathBuf;
+use std::process::Command;
 use crate::hxx_ir1::from_hxx::hxx_to_ir1;
 use crate::hxx_ir1::to_ir2::ir1_to_ir2;
+use crate::ir2::to_ir3::ir2_to_ir3;
+use crate::ir3::bisect::{find_first_bad, BisectResult, OptBisect};
+use crate::ir3::deadfn::remove_dead_functions;
+use crate::ir3::dse::eliminate_dead_stores;
+use crate::ir3::effects::infer_function_attrs;
+use crate::ir3::emulate::emulate_extended_ops;
+use crate::ir3::legalize::legalize_module;
+use crate::common::err::{IR3Err, IR3ErrKind, IR3Result};
+use crate::ir3::interp::Interpreter;
+use crate::ir3::ipcp::propagate_constants;
+use crate::ir3::layout::layout_blocks;
+use crate::ir3::mem2reg::mem2reg;
+use crate::ir3::profile::{instrument, read_counters, FunctionCounters, Profile};
+use crate::ir3::ifconv::if_convert;
+use crate::ir3::sret::lower_sret;
+use crate::ir3::schedule::schedule_blocks;
+use crate::ir3::model::{IR3Function, IR3Module};
+use crate::ir3::parse::parse_module;
+use crate::ir3::switch::lower_switches;
+use crate::ir3::tailcall::eliminate_tail_calls;
+use crate::ir3::unroll::unroll_loops;
+use crate::ir3::verify::verify_module;
+use crate::target::Target;
 
 mod common;
 mod hxx_ir1;
 mod ir2;
 mod ir3;
+mod target;
 
-fn main() {
-  let fpath = PathBuf::from(args().nth(1).unwrap());
+struct Options {
+  input: PathBuf,
+  target: Target,
+  /// Run `main` with the IR3 interpreter instead of printing IR3.
+  interpret: bool,
+  /// How many copies of the body partially unrolled loops run per trip. 1 turns partial unrolling off.
+  unroll_factor: u32,
+  /// Count how often every block runs, and write the counts here when the program exits.
+  profile_generate: Option<PathBuf>,
+  /// Block counts written by `profile_generate`, which guide unrolling and block layout.
+  profile_use: Option<PathBuf>,
+  /// Stop running optional passes after this many runs, logging each decision.
+  opt_bisect_limit: Option<u64>,
+  /// Instead of compiling, find the first pass run that makes this shell command fail, by running it with
+  /// `OPT_BISECT_LIMIT` set to different limits.
+  opt_bisect: Option<String>,
+}
+
+fn parse_args() -> Options {
+  let mut input = None;
+  let mut target = Target::rv64();
+  let mut interpret = false;
+  let mut unroll_factor = 4;
+  let mut profile_generate = None;
+  let mut profile_use = None;
+  let mut opt_bisect_limit = None;
+  let mut opt_bisect = None;
+  for arg in args().skip(1) {
+    if let Some(name) = arg.strip_prefix("--target=") {
+      target = Target::from_name(name).unwrap_or_else(|| {
+        eprintln!("error: unknown target \"{}\", expected one of: {}", name, Target::NAMES.join(", "));
+        panic!("Invalid arguments");
+      });
+    } else if arg == "--interpret" {
+      interpret = true;
+    } else if let Some(factor) = arg.strip_prefix("--unroll-factor=") {
+      unroll_factor = factor.parse().ok().filter(|v| *v >= 1).unwrap_or_else(|| {
+        eprintln!("error: invalid unroll factor \"{}\", expected a positive integer", factor);
+        panic!("Invalid arguments");
+      });
+    } else if arg == "--profile-generate" {
+      profile_generate = Some(PathBuf::from("default.hxprof"));
+    } else if let Some(path) = arg.strip_prefix("--profile-generate=") {
+      profile_generate = Some(PathBuf::from(path));
+    } else if let Some(path) = arg.strip_prefix("--profile-use=") {
+      profile_use = Some(PathBuf::from(path));
+    } else if let Some(limit) = arg.strip_prefix("--opt-bisect-limit=") {
+      opt_bisect_limit = Some(limit.parse().unwrap_or_else(|_| {
+        eprintln!("error: invalid opt bisect limit \"{}\", expected a non-negative integer", limit);
+        panic!("Invalid arguments");
+      }));
+    } else if let Some(command) = arg.strip_prefix("--opt-bisect=") {
+      opt_bisect = Some(command.to_owned());
+    } else if arg.starts_with("--") {
+      eprintln!("error: unknown option \"{}\"", arg);
+      panic!("Invalid arguments");
+    } else {
+      input = Some(PathBuf::from(arg));
+    }
+  }
+  if profile_generate.is_some() && profile_use.is_some() {
+    eprintln!("error: --profile-generate and --profile-use cannot be used together");
+    panic!("Invalid arguments");
+  }
+  Options {
+    input: input.expect("No input file"),
+    target,
+    interpret,
+    unroll_factor,
+    profile_generate,
+    profile_use,
+    opt_bisect_limit,
+    opt_bisect,
+  }
+}
+
+/// Runs an optional pass on every function that `bisect` allows it to.
+fn run_pass(module: &mut IR3Module, bisect: &mut OptBisect, name: &str, mut pass: impl FnMut(&mut IR3Function)) {
+  for func in &mut module.functions {
+    if bisect.should_run(name, &func.name) {
+      pass(func);
+    }
+  }
+}
+
+/// Runs an optional pass on the whole module if `bisect` allows it to.
+fn run_module_pass(module: &mut IR3Module, bisect: &mut OptBisect, name: &str, pass: impl FnOnce(&mut IR3Module)) {
+  if bisect.should_run(name, "module") {
+    pass(module);
+  }
+}
+
+/// Returns the counters added for `--profile-generate`.
+fn compile_ir3(module: &mut IR3Module, options: &Options, profile: Option<&Profile>, bisect: &mut OptBisect) -> IR3Result<Vec<FunctionCounters>> {
+  let target = &options.target;
+  verify_module(module)?;
+  run_module_pass(module, bisect, "deadfn", remove_dead_functions);
+  run_pass(module, bisect, "mem2reg", mem2reg);
+  run_pass(module, bisect, "ifconv", if_convert);
+  run_module_pass(module, bisect, "ipcp", propagate_constants);
+  // specialization can leave the original functions without callers
+  run_module_pass(module, bisect, "deadfn", remove_dead_functions);
+  run_pass(module, bisect, "tailcall", eliminate_tail_calls);
+  // profiles are taken here, before the CFG is changed for the target
+  let mut counts = HashMap::new();
+  if let Some(profile) = profile {
+    let stale;
+    (counts, stale) = profile.matching_counts(module);
+    for name in stale {
+      eprintln!("warning: profile of {} does not match its code, ignoring it", name);
+    }
+  }
+  let counters = if options.profile_generate.is_some() { instrument(module) } else { vec![] };
+  run_pass(module, bisect, "unroll", |func| unroll_loops(func, options.unroll_factor, counts.get(&func.name)));
+  emulate_extended_ops(module, target)?;
+  run_module_pass(module, bisect, "effects", infer_function_attrs);
+  // uses the inferred attributes to look past calls
+  run_module_pass(module, bisect, "dse", |module| eliminate_dead_stores(module, target));
+  module.functions.iter_mut().for_each(|func| lower_switches(func, target));
+  legalize_module(module, target)?;
+  lower_sret(module, target);
+  run_pass(module, bisect, "schedule", |func| schedule_blocks(func, target));
+  run_pass(module, bisect, "layout", |func| layout_blocks(func, counts.get(&func.name)));
+  verify_module(module)?;
+  Ok(counters)
+}
+
+/// Returns the exit code, and the counts of the given counters.
+fn interpret(module: &IR3Module, target: &Target, counters: &[FunctionCounters]) -> IR3Result<(u64, Profile)> {
+  let mut interp = Interpreter::new(module, target);
+  for ext in &module.externs {
+    if ext.name == "_HX$println$f64" {
+      interp.define_extern(&ext.name, |args| {
+        println!("{}", f64::from_bits(args[0]));
+        vec![]
+      });
+    } else if ext.name.starts_with("_HX$println$") {
+      interp.define_extern(&ext.name, |args| {
+        println!("{}", args[0]);
+        vec![]
+      });
+    } else if ext.name == "_HX$exit$i32" {
+      interp.define_exit(&ext.name);
+    }
+  }
+  // argc is 1 (in the low half if it was split by legalization), argv is null
+  let Some(main) = module.function("main") else {
+    return Err(IR3Err::new(IR3ErrKind::Trap("no main function".to_owned()), "main"));
+  };
+  let mut args = vec![0; main.args.len()];
+  if let Some(argc) = args.first_mut() {
+    *argc = 1;
+  }
+  // a void main exits with 0
+  let code = match interp.call("main", &args) {
+    Ok(values) => values.first().copied().unwrap_or(0),
+    Err(IR3Err { kind: IR3ErrKind::Exit(code), .. }) => code,
+    Err(err) => return Err(err),
+  };
+  Ok((code, read_counters(counters, &interp)))
+}
+
+/// Finds the first pass run after which `command` fails, and prints it.
+fn bisect(module: IR3Module, options: &Options, profile: Option<&Profile>, command: &str) {
+  // a compilation without a limit numbers all the runs
+  let mut runs = OptBisect::new(None);
+  compile_ir3(&mut module.clone(), options, profile, &mut runs)
+    .map_err(|v| {
+      eprintln!("{}", v);
+      panic!("Compilation failed (IR3 stage)");
+    })
+    .unwrap();
+  let runs = runs.runs;
+  let result = find_first_bad(runs.len() as u64, |limit| {
+    let status = Command::new("sh")
+      .arg("-c")
+      .arg(command)
+      .env("OPT_BISECT_LIMIT", limit.to_string())
+      .status()
+      .expect("Failed to run bisect command");
+    eprintln!("limit {}: {}", limit, if status.success() { "passed" } else { "failed" });
+    status.success()
+  });
+  match result {
+    BisectResult::NoFailure => println!("the command passes with all {} pass runs", runs.len()),
+    BisectResult::AlwaysFails => println!("the command fails even with no optimizations"),
+    BisectResult::FirstBad(n) => {
+      let (pass, func) = &runs[n as usize - 1];
+      println!("the command first fails at pass ({}) {} on {}", n, pass, func);
+    }
+  }
+}
+
+/// Runs the HXX frontend on the input file, with the builtins loaded first.
+fn compile_hxx(options: &Options) -> IR3Module {
+  let fpath = &options.input;
   let stdlib = read_to_string("support/builtins.hx").expect("File read failed");
-  let file = read_to_string(&fpath).expect("File read failed");
+  let file = read_to_string(fpath).expect("File read failed");
   let srcs = vec![
     ("internal:builtins.hx".to_owned(), stdlib),
     (fpath.file_name().unwrap().to_string_lossy().to_string(), file)
@@ -31,5 +251,61 @@ fn main() {
       panic!("Compilation failed (IR1->IR2 stage)");
     })
     .unwrap();
-  println!("{:#?}", ir2);
+  ir2_to_ir3(&ir2, &options.target)
+    .map_err(|v| {
+      eprintln!("{}", v);
+      panic!("Compilation failed (IR2->IR3 stage)");
+    })
+    .unwrap()
+}
+
+fn main() {
+  let options = parse_args();
+  let mut ir3 = if options.input.extension().is_some_and(|v| v == "ir3") {
+    let text = read_to_string(&options.input).expect("File read failed");
+    parse_module(&text)
+      .map_err(|v| {
+        eprintln!("{}", v);
+        panic!("Invalid IR3 input");
+      })
+      .unwrap()
+  } else {
+    compile_hxx(&options)
+  };
+  let profile = options.profile_use.as_ref().map(|path| {
+    let text = read_to_string(path).expect("File read failed");
+    Profile::parse(&text)
+      .map_err(|v| {
+        eprintln!("error: {}", v);
+        panic!("Invalid profile");
+      })
+      .unwrap()
+  });
+  if let Some(command) = &options.opt_bisect {
+    bisect(ir3, &options, profile.as_ref(), command);
+    return;
+  }
+  let counters = compile_ir3(&mut ir3, &options, profile.as_ref(), &mut OptBisect::new(options.opt_bisect_limit))
+    .map_err(|v| {
+      eprintln!("{}", v);
+      panic!("Compilation failed (IR3 stage)");
+    })
+    .unwrap();
+  if options.interpret {
+    let (code, profile) = interpret(&ir3, &options.target, &counters)
+      .map_err(|v| {
+        eprintln!("{}", v);
+        panic!("Interpretation failed");
+      })
+      .unwrap();
+    println!("main returned {}", code);
+    if let Some(path) = &options.profile_generate {
+      write(path, profile.to_string()).expect("File write failed");
+    }
+  } else {
+    if options.profile_generate.is_some() {
+      eprintln!("warning: counts are only written when the program is run with --interpret");
+    }
+    print!("{}", ir3);
+  }
 }
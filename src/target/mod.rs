@@ -0,0 +1,351 @@
+//! Target descriptions.
+//!
+//! A target tells the later stages of the compiler what the machine looks like:
+//! how wide pointers are, which `d<n>` widths can be computed on natively,
+//! what registers exist and how functions pass values between each other.
+//! IR3 itself is target-independent, but lowering, legalization and codegen all
+//! consult a `Target` when making decisions.
+
+use crate::ir3::model::IR3Type;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Target {
+  pub name: &'static str,
+  /// Width of a `ptr` in bits.
+  pub ptr_width: u32,
+  /// `d<n>` widths that arithmetic can be natively performed on, smallest first.
+  pub legal_widths: &'static [u32],
+  /// Widths that atomic read-modify-write ops and `cmpxchg` are available for, smallest first.
+  /// Atomic loads and stores work on any width up to the widest of these.
+  pub atomic_widths: &'static [u32],
+  pub registers: RegisterFile,
+  pub call_conv: CallingConvention,
+  /// Alignment of the stack pointer at call boundaries, in bytes.
+  pub stack_align: u32,
+  pub features: TargetFeatures,
+  /// Latencies of the in-order pipeline that `ir3::schedule` schedules for.
+  /// Out-of-order targets and targets without a fixed register file leave it out.
+  pub sched: Option<SchedModel>,
+}
+
+/// Cycles until the result of an instruction can be used. Everything not listed takes one cycle.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SchedModel {
+  /// Loads from memory, including atomic ones.
+  pub load: u32,
+  /// Integer multiplication.
+  pub mul: u32,
+  /// Integer division and remainder.
+  pub div: u32,
+  /// Float addition, subtraction, multiplication and conversions.
+  pub float: u32,
+  /// Float division and square root.
+  pub fdiv: u32,
+}
+
+/// Extended IR3 instructions that the target implements natively.
+/// Anything missing here is emulated by `ir3::emulate`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TargetFeatures {
+  /// `smull`, `umull`, `smulh` and `umulh`
+  pub mul: bool,
+  /// `sdiv`, `udiv`, `srem` and `urem`
+  pub div: bool,
+  /// `clz`, `ctz`, `popcnt`, `rotl` and `rotr`
+  pub bitmanip: bool,
+  /// `bswap`
+  pub bswap: bool,
+  /// `*_overflow` ops, with the flag read from the hardware (like `jo` and `jc` on x86).
+  /// Without it, the flag is computed with compares.
+  pub overflow: bool,
+  /// `f32`, `f64` and the ops on them. Floats are rejected during legalization without it.
+  pub float: bool,
+}
+
+/// Registers are referred to by their assembler names.
+/// Targets that do not have a fixed register file (such as wasm32 and C) leave the lists empty.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RegisterFile {
+  /// Width of a general purpose register in bits.
+  pub width: u32,
+  pub gprs: &'static [&'static str],
+  /// Floating-point registers, which hold both `f32` and `f64`.
+  pub fprs: &'static [&'static str],
+  pub stack_ptr: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CallingConvention {
+  pub arg_regs: &'static [&'static str],
+  pub ret_regs: &'static [&'static str],
+  /// Registers for float arguments and return values. Floats use these before the integer ones.
+  pub float_arg_regs: &'static [&'static str],
+  pub float_ret_regs: &'static [&'static str],
+  pub callee_saved: &'static [&'static str],
+  pub caller_saved: &'static [&'static str],
+}
+
+impl Target {
+  pub const NAMES: &'static [&'static str] = &["rv64", "rv64i", "rv32", "x86_64", "wasm32", "c"];
+
+  pub fn from_name(name: &str) -> Option<Target> {
+    Some(match name {
+      "rv64" => Target::rv64(),
+      "rv64i" => Target::rv64i(),
+      "rv32" => Target::rv32(),
+      "x86_64" => Target::x86_64(),
+      "wasm32" => Target::wasm32(),
+      "c" => Target::c(),
+      &_ => return None
+    })
+  }
+
+  /// 64-bit RISC-V (RV64IMAFD) with the standard (LP64D) calling convention.
+  pub fn rv64() -> Target {
+    Target {
+      name: "rv64",
+      ptr_width: 64,
+      legal_widths: &[32, 64],
+      atomic_widths: &[32, 64],
+      registers: RegisterFile {
+        width: 64,
+        gprs: &[
+          "ra", "sp", "gp", "tp",
+          "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+          "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+          "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+        ],
+        fprs: &[
+          "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "ft8", "ft9", "ft10", "ft11",
+          "fs0", "fs1", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11",
+          "fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7",
+        ],
+        stack_ptr: Some("sp"),
+      },
+      call_conv: CallingConvention {
+        arg_regs: &["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"],
+        ret_regs: &["a0", "a1"],
+        float_arg_regs: &["fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7"],
+        float_ret_regs: &["fa0", "fa1"],
+        callee_saved: &[
+          "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+          "fs0", "fs1", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11",
+        ],
+        caller_saved: &[
+          "ra",
+          "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+          "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+          "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "ft8", "ft9", "ft10", "ft11",
+          "fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7",
+        ],
+      },
+      stack_align: 16,
+      features: TargetFeatures {
+        mul: true,
+        div: true,
+        bitmanip: false,
+        bswap: false,
+        overflow: false,
+        float: true,
+      },
+      // a 5-stage single-issue core, like the SiFive E and U series
+      sched: Some(SchedModel {
+        load: 3,
+        mul: 3,
+        div: 20,
+        float: 4,
+        fdiv: 20,
+      }),
+    }
+  }
+
+  /// 64-bit RISC-V (RV64IA) without the M, F and D extensions, using the LP64 calling convention.
+  pub fn rv64i() -> Target {
+    let rv64 = Target::rv64();
+    Target {
+      name: "rv64i",
+      registers: RegisterFile {
+        fprs: &[],
+        ..rv64.registers
+      },
+      call_conv: CallingConvention {
+        float_arg_regs: &[],
+        float_ret_regs: &[],
+        callee_saved: &["s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11"],
+        caller_saved: &[
+          "ra",
+          "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+          "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+        ],
+        ..rv64.call_conv
+      },
+      features: TargetFeatures {
+        mul: false,
+        div: false,
+        bitmanip: false,
+        bswap: false,
+        overflow: false,
+        float: false,
+      },
+      ..rv64
+    }
+  }
+
+  /// 32-bit RISC-V (RV32IMAFD) with the standard (ILP32D) calling convention.
+  /// `d64` is not legal here, so it gets split into pairs of `d32` during legalization.
+  pub fn rv32() -> Target {
+    let rv64 = Target::rv64();
+    Target {
+      name: "rv32",
+      ptr_width: 32,
+      legal_widths: &[32],
+      atomic_widths: &[32],
+      registers: RegisterFile {
+        width: 32,
+        ..rv64.registers
+      },
+      ..rv64
+    }
+  }
+
+  /// x86-64 with the System V calling convention. Floats use SSE2, which every x86-64 CPU has.
+  pub fn x86_64() -> Target {
+    Target {
+      name: "x86_64",
+      ptr_width: 64,
+      legal_widths: &[8, 16, 32, 64],
+      atomic_widths: &[8, 16, 32, 64],
+      registers: RegisterFile {
+        width: 64,
+        gprs: &[
+          "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp",
+          "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+        ],
+        fprs: &[
+          "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7",
+          "xmm8", "xmm9", "xmm10", "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+        ],
+        stack_ptr: Some("rsp"),
+      },
+      call_conv: CallingConvention {
+        arg_regs: &["rdi", "rsi", "rdx", "rcx", "r8", "r9"],
+        ret_regs: &["rax", "rdx"],
+        float_arg_regs: &["xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7"],
+        float_ret_regs: &["xmm0", "xmm1"],
+        callee_saved: &["rbx", "rbp", "r12", "r13", "r14", "r15"],
+        caller_saved: &[
+          "rax", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11",
+          "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7",
+          "xmm8", "xmm9", "xmm10", "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+        ],
+      },
+      stack_align: 16,
+      features: TargetFeatures {
+        mul: true,
+        div: true,
+        bitmanip: true,
+        bswap: true,
+        overflow: true,
+        float: true,
+      },
+      sched: None,
+    }
+  }
+
+  /// WebAssembly with 32-bit linear memory. Wasm has no register file,
+  /// values live in an unbounded set of locals instead.
+  pub fn wasm32() -> Target {
+    Target {
+      name: "wasm32",
+      ptr_width: 32,
+      legal_widths: &[32, 64],
+      atomic_widths: &[8, 16, 32, 64],
+      registers: RegisterFile {
+        width: 64,
+        gprs: &[],
+        fprs: &[],
+        stack_ptr: None,
+      },
+      call_conv: CallingConvention {
+        arg_regs: &[],
+        ret_regs: &[],
+        float_arg_regs: &[],
+        float_ret_regs: &[],
+        callee_saved: &[],
+        caller_saved: &[],
+      },
+      stack_align: 16,
+      features: TargetFeatures {
+        mul: true,
+        div: true,
+        bitmanip: true,
+        bswap: false,
+        overflow: false,
+        float: true,
+      },
+      sched: None,
+    }
+  }
+
+  /// Portable C. Assumes an LP64 host, since that is what the generated code is compiled for.
+  pub fn c() -> Target {
+    Target {
+      name: "c",
+      ptr_width: 64,
+      legal_widths: &[8, 16, 32, 64],
+      atomic_widths: &[8, 16, 32, 64],
+      registers: RegisterFile {
+        width: 64,
+        gprs: &[],
+        fprs: &[],
+        stack_ptr: None,
+      },
+      call_conv: CallingConvention {
+        arg_regs: &[],
+        ret_regs: &[],
+        float_arg_regs: &[],
+        float_ret_regs: &[],
+        callee_saved: &[],
+        caller_saved: &[],
+      },
+      stack_align: 16,
+      features: TargetFeatures {
+        mul: true,
+        div: true,
+        bitmanip: true,
+        bswap: true,
+        overflow: true,
+        float: true,
+      },
+      sched: None,
+    }
+  }
+
+  pub fn is_legal_width(&self, width: u32) -> bool {
+    self.legal_widths.contains(&width)
+  }
+
+  /// Returns the smallest legal width that can hold a `d<width>`, if any.
+  pub fn promoted_width(&self, width: u32) -> Option<u32> {
+    self.legal_widths.iter().copied().find(|v| *v >= width)
+  }
+
+  /// Number of values a function can return directly. Functions returning more use an sret pointer.
+  pub fn max_ret_values(&self) -> usize {
+    self.call_conv.ret_regs.len().max(1)
+  }
+
+  /// Size of a value of type `ty` in memory, in bytes.
+  pub fn size_of(&self, ty: IR3Type) -> u32 {
+    match ty {
+      IR3Type::Data(w) | IR3Type::Float(w) => w.div_ceil(8),
+      IR3Type::Ptr => self.ptr_width / 8,
+      IR3Type::Void => 0,
+    }
+  }
+
+  /// Natural alignment of a value of type `ty` in memory, in bytes.
+  pub fn align_of(&self, ty: IR3Type) -> u32 {
+    self.size_of(ty).max(1)
+  }
+}
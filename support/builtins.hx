@@ -11,8 +11,128 @@
 
 ((:attr builtin-function sub$d32) :fn (sub (a i32) (b i32)) i32)
 
-((:attr builtin-function lt$d32) :fn (lt (a i32) (b i32)) bool)
+((:attr builtin-function slt$d32) :fn (lt (a i32) (b i32)) bool)
 
-((:attr builtin-function smul$d32) :fn (mul (a i32) (b i32)) i32)
+((:attr builtin-function smull$d32) :fn (mul (a i32) (b i32)) i32)
 
-(:fn (println (a u32)) void)
\ No newline at end of file
+((:attr builtin-function trunc$d32$d64) :fn (to_u32 (a u64)) u32)
+((:attr builtin-function trunc$d32$d64) :fn (to_i32 (a i64)) i32)
+((:attr builtin-function trunc$d8$d32) :fn (to_u8 (a u32)) u8)
+((:attr builtin-function trunc$d16$d32) :fn (to_u16 (a u32)) u16)
+((:attr builtin-function zext$d64$d32) :fn (to_u64 (a u32)) u64)
+((:attr builtin-function sext$d64$d32) :fn (to_i64 (a i32)) i64)
+
+((:attr builtin-function clz$d32) :fn (clz (a u32)) u32)
+((:attr builtin-function ctz$d32) :fn (ctz (a u32)) u32)
+((:attr builtin-function popcnt$d32) :fn (popcnt (a u32)) u32)
+((:attr builtin-function rotl$d32) :fn (rotl (a u32) (b u32)) u32)
+((:attr builtin-function rotr$d32) :fn (rotr (a u32) (b u32)) u32)
+((:attr builtin-function clz$d64) :fn (clz (a u64)) u64)
+((:attr builtin-function ctz$d64) :fn (ctz (a u64)) u64)
+((:attr builtin-function popcnt$d64) :fn (popcnt (a u64)) u64)
+((:attr builtin-function rotl$d64) :fn (rotl (a u64) (b u64)) u64)
+((:attr builtin-function rotr$d64) :fn (rotr (a u64) (b u64)) u64)
+((:attr builtin-function bswap$d16) :fn (bswap (a u16)) u16)
+((:attr builtin-function bswap$d32) :fn (bswap (a u32)) u32)
+((:attr builtin-function bswap$d64) :fn (bswap (a u64)) u64)
+
+((:attr builtin-function sadd_overflow$d32) :fn (add_overflows (a i32) (b i32)) bool)
+((:attr builtin-function uadd_overflow$d32) :fn (add_overflows (a u32) (b u32)) bool)
+((:attr builtin-function ssub_overflow$d32) :fn (sub_overflows (a i32) (b i32)) bool)
+((:attr builtin-function usub_overflow$d32) :fn (sub_overflows (a u32) (b u32)) bool)
+((:attr builtin-function smul_overflow$d32) :fn (mul_overflows (a i32) (b i32)) bool)
+((:attr builtin-function umul_overflow$d32) :fn (mul_overflows (a u32) (b u32)) bool)
+((:attr builtin-function sadd_overflow$d64) :fn (add_overflows (a i64) (b i64)) bool)
+((:attr builtin-function uadd_overflow$d64) :fn (add_overflows (a u64) (b u64)) bool)
+((:attr builtin-function ssub_overflow$d64) :fn (sub_overflows (a i64) (b i64)) bool)
+((:attr builtin-function usub_overflow$d64) :fn (sub_overflows (a u64) (b u64)) bool)
+((:attr builtin-function smul_overflow$d64) :fn (mul_overflows (a i64) (b i64)) bool)
+((:attr builtin-function umul_overflow$d64) :fn (mul_overflows (a u64) (b u64)) bool)
+
+((:attr builtin-function add$d128) :fn (add (a i128) (b i128)) i128)
+((:attr builtin-function add$d128) :fn (add (a u128) (b u128)) u128)
+((:attr builtin-function sub$d128) :fn (sub (a i128) (b i128)) i128)
+((:attr builtin-function sub$d128) :fn (sub (a u128) (b u128)) u128)
+((:attr builtin-function smull$d128) :fn (mul (a i128) (b i128)) i128)
+((:attr builtin-function umull$d128) :fn (mul (a u128) (b u128)) u128)
+((:attr builtin-function sdiv$d128) :fn (div (a i128) (b i128)) i128)
+((:attr builtin-function udiv$d128) :fn (div (a u128) (b u128)) u128)
+((:attr builtin-function srem$d128) :fn (rem (a i128) (b i128)) i128)
+((:attr builtin-function urem$d128) :fn (rem (a u128) (b u128)) u128)
+((:attr builtin-function slt$d128) :fn (lt (a i128) (b i128)) bool)
+((:attr builtin-function ult$d128) :fn (lt (a u128) (b u128)) bool)
+((:attr builtin-function sll$d128) :fn (shl (a u128) (b u128)) u128)
+((:attr builtin-function srl$d128) :fn (shr (a u128) (b u128)) u128)
+((:attr builtin-function sra$d128) :fn (shr (a i128) (b i128)) i128)
+((:attr builtin-function zext$d128$d64) :fn (to_u128 (a u64)) u128)
+((:attr builtin-function sext$d128$d64) :fn (to_i128 (a i64)) i128)
+((:attr builtin-function trunc$d64$d128) :fn (low_u64 (a u128)) u64)
+((:attr builtin-function trunc$d64$d128) :fn (low_i64 (a i128)) i64)
+
+((:attr builtin-function atomic_load$d32$seq_cst) :fn (atomic_load (p *u32)) u32)
+((:attr builtin-function atomic_store$d32$seq_cst) :fn (atomic_store (p *u32) (v u32)) void)
+((:attr builtin-function atomic_add$d32$seq_cst) :fn (atomic_add (p *u32) (v u32)) u32)
+((:attr builtin-function atomic_and$d32$seq_cst) :fn (atomic_and (p *u32) (v u32)) u32)
+((:attr builtin-function atomic_or$d32$seq_cst) :fn (atomic_or (p *u32) (v u32)) u32)
+((:attr builtin-function atomic_xor$d32$seq_cst) :fn (atomic_xor (p *u32) (v u32)) u32)
+((:attr builtin-function atomic_xchg$d32$seq_cst) :fn (atomic_xchg (p *u32) (v u32)) u32)
+((:attr builtin-function cmpxchg$d32$seq_cst) :fn (compare_exchange (p *u32) (expected u32) (new u32)) bool)
+((:attr builtin-function atomic_load$d64$seq_cst) :fn (atomic_load (p *u64)) u64)
+((:attr builtin-function atomic_store$d64$seq_cst) :fn (atomic_store (p *u64) (v u64)) void)
+((:attr builtin-function atomic_add$d64$seq_cst) :fn (atomic_add (p *u64) (v u64)) u64)
+((:attr builtin-function atomic_and$d64$seq_cst) :fn (atomic_and (p *u64) (v u64)) u64)
+((:attr builtin-function atomic_or$d64$seq_cst) :fn (atomic_or (p *u64) (v u64)) u64)
+((:attr builtin-function atomic_xor$d64$seq_cst) :fn (atomic_xor (p *u64) (v u64)) u64)
+((:attr builtin-function atomic_xchg$d64$seq_cst) :fn (atomic_xchg (p *u64) (v u64)) u64)
+((:attr builtin-function cmpxchg$d64$seq_cst) :fn (compare_exchange (p *u64) (expected u64) (new u64)) bool)
+((:attr builtin-function fence$void$seq_cst) :fn (fence) void)
+
+((:attr builtin-function fadd$f32) :fn (add (a f32) (b f32)) f32)
+((:attr builtin-function fsub$f32) :fn (sub (a f32) (b f32)) f32)
+((:attr builtin-function fmul$f32) :fn (mul (a f32) (b f32)) f32)
+((:attr builtin-function fdiv$f32) :fn (div (a f32) (b f32)) f32)
+((:attr builtin-function fneg$f32) :fn (neg (a f32)) f32)
+((:attr builtin-function fsqrt$f32) :fn (sqrt (a f32)) f32)
+((:attr builtin-function fcmp$f32$eq) :fn (eq (a f32) (b f32)) bool)
+((:attr builtin-function fcmp$f32$ne) :fn (ne (a f32) (b f32)) bool)
+((:attr builtin-function fcmp$f32$lt) :fn (lt (a f32) (b f32)) bool)
+((:attr builtin-function fcmp$f32$gt) :fn (gt (a f32) (b f32)) bool)
+((:attr builtin-function fcmp$f32$le) :fn (le (a f32) (b f32)) bool)
+((:attr builtin-function fcmp$f32$ge) :fn (ge (a f32) (b f32)) bool)
+((:attr builtin-function fcmp$f32$uno) :fn (is_unordered (a f32) (b f32)) bool)
+((:attr builtin-function fadd$f64) :fn (add (a f64) (b f64)) f64)
+((:attr builtin-function fsub$f64) :fn (sub (a f64) (b f64)) f64)
+((:attr builtin-function fmul$f64) :fn (mul (a f64) (b f64)) f64)
+((:attr builtin-function fdiv$f64) :fn (div (a f64) (b f64)) f64)
+((:attr builtin-function fneg$f64) :fn (neg (a f64)) f64)
+((:attr builtin-function fsqrt$f64) :fn (sqrt (a f64)) f64)
+((:attr builtin-function fcmp$f64$eq) :fn (eq (a f64) (b f64)) bool)
+((:attr builtin-function fcmp$f64$ne) :fn (ne (a f64) (b f64)) bool)
+((:attr builtin-function fcmp$f64$lt) :fn (lt (a f64) (b f64)) bool)
+((:attr builtin-function fcmp$f64$gt) :fn (gt (a f64) (b f64)) bool)
+((:attr builtin-function fcmp$f64$le) :fn (le (a f64) (b f64)) bool)
+((:attr builtin-function fcmp$f64$ge) :fn (ge (a f64) (b f64)) bool)
+((:attr builtin-function fcmp$f64$uno) :fn (is_unordered (a f64) (b f64)) bool)
+
+((:attr builtin-function fconv$f64$f32) :fn (to_f64 (a f32)) f64)
+((:attr builtin-function fconv$f32$f64) :fn (to_f32 (a f64)) f32)
+((:attr builtin-function sitofp$f32$d32) :fn (to_f32 (a i32)) f32)
+((:attr builtin-function uitofp$f32$d32) :fn (to_f32 (a u32)) f32)
+((:attr builtin-function sitofp$f32$d64) :fn (to_f32 (a i64)) f32)
+((:attr builtin-function uitofp$f32$d64) :fn (to_f32 (a u64)) f32)
+((:attr builtin-function sitofp$f64$d32) :fn (to_f64 (a i32)) f64)
+((:attr builtin-function uitofp$f64$d32) :fn (to_f64 (a u32)) f64)
+((:attr builtin-function sitofp$f64$d64) :fn (to_f64 (a i64)) f64)
+((:attr builtin-function uitofp$f64$d64) :fn (to_f64 (a u64)) f64)
+((:attr builtin-function fptosi$d32$f32) :fn (to_i32 (a f32)) i32)
+((:attr builtin-function fptoui$d32$f32) :fn (to_u32 (a f32)) u32)
+((:attr builtin-function fptosi$d64$f32) :fn (to_i64 (a f32)) i64)
+((:attr builtin-function fptoui$d64$f32) :fn (to_u64 (a f32)) u64)
+((:attr builtin-function fptosi$d32$f64) :fn (to_i32 (a f64)) i32)
+((:attr builtin-function fptoui$d32$f64) :fn (to_u32 (a f64)) u32)
+((:attr builtin-function fptosi$d64$f64) :fn (to_i64 (a f64)) i64)
+((:attr builtin-function fptoui$d64$f64) :fn (to_u64 (a f64)) u64)
+
+((:attr willreturn) :fn (println (a u32)) void)
+((:attr willreturn) :fn (println (a f64)) void)
+((:attr noreturn) :fn (exit (code i32)) void)